@@ -0,0 +1,38 @@
+//! Generates the shell completions and the man page of the command line
+//! into the `dist` directory next to the binary, e.g. `target/release/dist`.
+
+use std::{env, fs, io, path::PathBuf};
+
+use clap::CommandFactory;
+use clap_complete::{generate_to, Shell};
+use clap_mangen::Man;
+
+#[allow(dead_code)]
+#[path = "src/cli/args.rs"]
+mod args;
+
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-changed=src/cli/args.rs");
+
+    //OUT_DIR is target/<profile>/build/<package>-<hash>/out
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap_or_default());
+    let Some(profile_dir) = out_dir.ancestors().nth(3) else {
+        return Ok(());
+    };
+    let dist_dir = profile_dir.join("dist");
+    let completions_dir = dist_dir.join("completions");
+    fs::create_dir_all(&completions_dir)?;
+
+    let mut cmd = args::Cli::command();
+    let bin_name = cmd.get_name().to_string();
+
+    for shell in [Shell::Bash, Shell::Zsh, Shell::Fish] {
+        generate_to(shell, &mut cmd, &bin_name, &completions_dir)?;
+    }
+
+    let mut man_page = Vec::new();
+    Man::new(cmd).render(&mut man_page)?;
+    fs::write(dist_dir.join(format!("{}.1", bin_name)), man_page)?;
+
+    Ok(())
+}
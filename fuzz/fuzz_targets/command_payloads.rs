@@ -0,0 +1,26 @@
+//! Complete command buffers, the first byte picks the command
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webcam_direct_linux::ble::{api::CmdApi, server::DecodedCommand};
+
+const CMDS: [CmdApi; 10] = [
+    CmdApi::MobileDisconnected,
+    CmdApi::RegisterMobile,
+    CmdApi::SdpOffer,
+    CmdApi::SdpAnswerAck,
+    CmdApi::UpdateHostSettings,
+    CmdApi::RunLinkTest,
+    CmdApi::SdpReply,
+    CmdApi::RemapCamera,
+    CmdApi::RequestHandover,
+    CmdApi::CompleteHandover,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let [selector, payload @ ..] = data else {
+        return;
+    };
+    let cmd_type = &CMDS[*selector as usize % CMDS.len()];
+    let _ = DecodedCommand::decode(cmd_type, payload.to_vec());
+});
@@ -0,0 +1,40 @@
+//! Sequences of GATT writes of a mobile, each write is a command selector, a
+//! length and the bytes written. The complete buffers are decoded as the
+//! server does.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webcam_direct_linux::ble::{
+    api::{CmdApi, CommandReq},
+    server::{mobile_buffer::MobileBufferMap, DecodedCommand},
+};
+
+const CMDS: [CmdApi; 10] = [
+    CmdApi::MobileDisconnected,
+    CmdApi::RegisterMobile,
+    CmdApi::SdpOffer,
+    CmdApi::SdpAnswerAck,
+    CmdApi::UpdateHostSettings,
+    CmdApi::RunLinkTest,
+    CmdApi::SdpReply,
+    CmdApi::RemapCamera,
+    CmdApi::RequestHandover,
+    CmdApi::CompleteHandover,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let mut buffer_map = MobileBufferMap::new(16);
+    let mut rest = data;
+    while let [selector, len, tail @ ..] = rest {
+        let (write, next) = tail.split_at((*len as usize).min(tail.len()));
+        rest = next;
+
+        let cmd_type = CMDS[*selector as usize % CMDS.len()].clone();
+        let cmd = CommandReq { cmd_type, payload: write.to_vec() };
+        if let Ok(Some(buffer)) =
+            buffer_map.get_complete_buffer("AA:BB:CC:DD:EE:FF", &cmd)
+        {
+            let _ = DecodedCommand::decode(&cmd.cmd_type, buffer);
+        }
+    }
+});
@@ -0,0 +1,17 @@
+//! Writes to the multiplexed transport characteristic
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use webcam_direct_linux::ble::{
+    clients::transport::{channel, Channel, Frame},
+    comm_types::QueryOffset,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(frame) = Frame::decode(data) else {
+        return;
+    };
+    if let Some(Channel::Query(_)) = channel(frame.channel) {
+        let _ = QueryOffset::try_from(frame.payload.as_slice());
+    }
+});
@@ -0,0 +1,135 @@
+//! # Captive portal suppression.
+//! Phones probe well-known URLs when they join a network and may leave the
+//! access point as "no internet" if the probes fail. The DHCP server
+//! resolves the probe hosts to the host, where this responder answers every
+//! probe the way an open internet connection would.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::{debug, info};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+};
+
+use crate::{
+    error::Result,
+    supervisor::{spawn_supervised, RestartPolicy},
+};
+
+/// Hosts of the connectivity checks of Android, iOS, Windows and Firefox
+pub const PROBE_HOSTS: &[&str] = &[
+    "connectivitycheck.gstatic.com",
+    "connectivitycheck.android.com",
+    "clients3.google.com",
+    "captive.apple.com",
+    "www.msftconnecttest.com",
+    "www.msftncsi.com",
+    "detectportal.firefox.com",
+];
+
+const NO_CONTENT: &str =
+    "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+const APPLE_SUCCESS: &str =
+    "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>";
+
+/// Response expected by the probe of the request head, the unknown probes
+/// get a 204 as the Android ones
+pub fn probe_response(request: &str) -> String {
+    let path = request.split_whitespace().nth(1).unwrap_or("/");
+    let body = match path {
+        "/hotspot-detect.html" | "/library/test/success.html" => APPLE_SUCCESS,
+        "/connecttest.txt" => "Microsoft Connect Test",
+        "/ncsi.txt" => "Microsoft NCSI",
+        "/success.txt" => "success\n",
+        _ => return NO_CONTENT.to_string(),
+    };
+
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+}
+
+/// HTTP responder of the probes, stopped on drop
+pub struct CaptivePortal {
+    task: JoinHandle<()>,
+}
+
+impl CaptivePortal {
+    /// Answers the probes on port 80 of the access point address
+    pub async fn spawn(router_ip: Ipv4Addr) -> Result<Self> {
+        let listener =
+            TcpListener::bind(SocketAddr::from((router_ip, 80))).await?;
+        info!("Answering the connectivity checks on {}", router_ip);
+
+        //the probes of a connection run apart, a panic drops only them
+        let listener = Arc::new(listener);
+        let task = spawn_supervised(
+            "Captive portal",
+            RestartPolicy::DEFAULT,
+            move || {
+                let listener = listener.clone();
+                async move {
+                    while let Ok((mut stream, peer)) = listener.accept().await {
+                        tokio::spawn(async move {
+                            let mut request = [0; 1024];
+                            let Ok(n) = stream.read(&mut request).await else {
+                                return;
+                            };
+                            let request =
+                                String::from_utf8_lossy(&request[..n]);
+                            debug!(
+                                "Connectivity check from {}: {:?}",
+                                peer,
+                                request.lines().next()
+                            );
+
+                            let response = probe_response(&request);
+                            let _ = stream.write_all(response.as_bytes()).await;
+                        });
+                    }
+                }
+            },
+        );
+
+        Ok(Self { task })
+    }
+}
+
+impl Drop for CaptivePortal {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_responses() {
+        let android = probe_response(
+            "GET /generate_204 HTTP/1.1\r\nHost: connectivitycheck.gstatic.com",
+        );
+        assert!(android.starts_with("HTTP/1.1 204"));
+
+        let apple = probe_response(
+            "GET /hotspot-detect.html HTTP/1.1\r\nHost: captive.apple.com",
+        );
+        assert!(apple.starts_with("HTTP/1.1 200"));
+        assert!(apple.ends_with(APPLE_SUCCESS));
+
+        let windows = probe_response("GET /connecttest.txt HTTP/1.1");
+        assert!(windows.ends_with("\r\n\r\nMicrosoft Connect Test"));
+
+        assert!(probe_response("").starts_with("HTTP/1.1 204"));
+    }
+}
@@ -88,6 +88,7 @@ pub trait DhcpServerCtl {
 /// Struct to control the dnsmasq process.
 pub struct DnsmasqProc<T: ProcessHdlOps> {
     process: T,
+    local_names: Vec<String>,
 }
 
 impl<T: ProcessHdlOps> DnsmasqProc<T> {
@@ -124,7 +125,29 @@ impl<T: ProcessHdlOps> DnsmasqProc<T> {
     /// let dnsmasq = DnsmasqProc::new(MockProcess);
     /// ```
     pub fn new(process: T) -> Self {
-        Self { process }
+        Self { process, local_names: Vec::new() }
+    }
+
+    /// Enables the DNS server to resolve `local_name` to the router address.
+    ///
+    /// # Arguments
+    ///
+    /// * `local_name` - The name of the host on the access point, the DNS
+    ///   server stays disabled if `None`.
+    pub fn with_local_name(mut self, local_name: Option<String>) -> Self {
+        self.local_names.extend(local_name);
+        self
+    }
+
+    /// Enables the DNS server to resolve `hosts` to the router address,
+    /// instead of their internet address.
+    ///
+    /// # Arguments
+    ///
+    /// * `hosts` - The names redirected to the host.
+    pub fn with_redirected_hosts(mut self, hosts: &[&str]) -> Self {
+        self.local_names.extend(hosts.iter().map(|host| host.to_string()));
+        self
     }
 }
 
@@ -145,17 +168,21 @@ impl<T: ProcessHdlOps> DhcpServerCtl for DnsmasqProc<T> {
             return Err(anyhow::anyhow!("Invalid interface name"));
         }
 
+        let router_ip = ip_range.get_router_ip();
         let ip_range =
             format!("{},{}", ip_range.get_start_ip(), ip_range.get_end_ip());
         let mut cmd = Command::new("dnsmasq");
-        cmd.arg("-p")
-            .arg("0")
-            .arg("-i")
-            .arg(iw_name)
-            .arg("-F")
-            .arg(ip_range)
-            .arg("-n")
-            .arg("-d");
+        if self.local_names.is_empty() {
+            cmd.arg("-p").arg("0");
+        } else {
+            // Bound to the interface only, the host resolver keeps port 53
+            cmd.arg("-z").arg(format!(
+                "--address=/{}/{}",
+                self.local_names.join("/"),
+                router_ip
+            ));
+        }
+        cmd.arg("-i").arg(iw_name).arg("-F").arg(ip_range).arg("-n").arg("-d");
 
         self.process.spawn(&mut cmd)?;
         Ok(())
@@ -216,6 +243,39 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_start_dnsmasq_with_local_names() {
+        init_logger();
+        let mut mock_process = MockProcessHdlOps::new();
+        let ip_range =
+            DhcpIpRange::new("192.168.1.100", "192.168.1.200").unwrap();
+
+        // The DNS server answers the local name with the router address
+        mock_process
+            .expect_spawn()
+            .withf(|cmd: &Command| {
+                cmd.get_args().collect::<Vec<_>>()
+                    == vec![
+                        "-z",
+                        "--address=/host.webcamdirect/captive.apple.com/192.168.1.1",
+                        "-i",
+                        "wlan0",
+                        "-F",
+                        "192.168.1.100,192.168.1.200",
+                        "-n",
+                        "-d",
+                    ]
+            })
+            .returning(|_| Ok(()));
+
+        let mut dnsmasq_ctl = DnsmasqProc::new(mock_process)
+            .with_local_name(Some("host.webcamdirect".to_string()))
+            .with_redirected_hosts(&["captive.apple.com"]);
+
+        let result = dnsmasq_ctl.start("wlan0", ip_range);
+        assert!(result.is_ok());
+    }
+
     #[test]
     fn test_start_dnsmasq_spawn_fails() {
         init_logger();
@@ -91,6 +91,11 @@ impl<T: WirelessDriver> IwLink<T> {
             if_idx,
         })
     }
+
+    /// Returns the index of the interface.
+    pub fn if_index(&self) -> InterfaceIndex {
+        self.if_idx
+    }
 }
 
 impl<T: WirelessDriver> IwLinkHandler for IwLink<T> {
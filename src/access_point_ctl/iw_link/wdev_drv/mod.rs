@@ -39,4 +39,41 @@ pub trait WirelessDriver {
     /// Deletes the link with the given interface index.
     fn delete_link(&self, ifindex: InterfaceIndex) -> Result<()>;
 
+    /// Returns the traffic and error counters of the given interface.
+    fn link_counters(&self, ifindex: InterfaceIndex) -> Result<LinkCounters>;
+}
+
+/// Traffic and error counters of an interface since it was created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LinkCounters {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+impl LinkCounters {
+    /// Parses the leading counters of a `rtnl_link_stats64`, in native
+    /// endianness. Returns `None` if the buffer is too short.
+    pub fn from_stats64(buffer: &[u8]) -> Option<Self> {
+        let mut counters = buffer.chunks_exact(8).map(|counter| {
+            u64::from_ne_bytes(counter.try_into().unwrap_or_default())
+        });
+        let mut next = || counters.next();
+
+        Some(Self {
+            rx_packets: next()?,
+            tx_packets: next()?,
+            rx_bytes: next()?,
+            tx_bytes: next()?,
+            rx_errors: next()?,
+            tx_errors: next()?,
+            rx_dropped: next()?,
+            tx_dropped: next()?,
+        })
+    }
 }
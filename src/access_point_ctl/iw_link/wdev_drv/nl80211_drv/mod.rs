@@ -8,6 +8,7 @@
 //! - Creating new wireless interfaces.
 //! - Deleting existing wireless interfaces.
 //! - Adding IPv4 addresses to interfaces.
+//! - Reading the traffic counters of interfaces.
 //!
 //! The module leverages the `neli` crate to handle netlink communication and provides a
 //! high-level API for managing wireless interfaces. It includes the following key components:
@@ -25,15 +26,21 @@ use std::net::Ipv4Addr;
 use std::str::FromStr;
 
 use super::InterfaceIndex;
+use super::LinkCounters;
 use super::WirelessDriver;
 
+use anyhow::anyhow;
 use log::error;
 use log::info;
+use neli::consts::rtnl::Arphrd;
 use neli::consts::rtnl::Ifa;
 use neli::consts::rtnl::IfaFFlags;
+use neli::consts::rtnl::IffFlags;
+use neli::consts::rtnl::Ifla;
 use neli::consts::rtnl::RtAddrFamily;
 use neli::consts::rtnl::Rtm;
 use neli::rtnl::Ifaddrmsg;
+use neli::rtnl::Ifinfomsg;
 use neli::rtnl::Rtattr;
 use neli::types::RtBuffer;
 use neli::{
@@ -345,4 +352,54 @@ impl WirelessDriver for Nl80211Driver {
 
         Ok(())
     }
+
+    /// Reads the 64 bit counters of the interface with the given index.
+    ///
+    /// # Parameters
+    /// - `ifindex`: The interface index to read the counters of.
+    ///
+    /// # Returns
+    /// - `Ok(LinkCounters)` with the counters of the interface.
+    /// - `Err` if the interface doesn't exist or reports no counters.
+    fn link_counters(&self, ifindex: InterfaceIndex) -> Result<LinkCounters> {
+        let mut sock = NlSocketHandle::connect(
+            NlFamily::Route, /* family */
+            None,            /* pid */
+            &[],             /* groups */
+        )?;
+
+        let ifindex: u16 = ifindex.into();
+        let ifinfomsg = Ifinfomsg::new(
+            RtAddrFamily::Unspecified,
+            Arphrd::Netrom,
+            ifindex as i32,
+            IffFlags::empty(),
+            IffFlags::empty(),
+            RtBuffer::new(),
+        );
+
+        let nlmsg = Nlmsghdr::new(
+            None,
+            Rtm::Getlink,
+            NlmFFlags::new(&[NlmF::Request]),
+            Some(1),
+            Some(0),
+            NlPayload::Payload(ifinfomsg),
+        );
+
+        sock.send(nlmsg)?;
+
+        let msg: Nlmsghdr<Rtm, Ifinfomsg> = sock
+            .recv()?
+            .ok_or_else(|| anyhow!("No link info for interface {}", ifindex))?;
+        let link = msg.get_payload()?;
+
+        link.rtattrs
+            .get_attr_handle()
+            .get_attribute(Ifla::Stats64)
+            .and_then(|stats| {
+                LinkCounters::from_stats64(stats.rta_payload.as_ref())
+            })
+            .ok_or_else(|| anyhow!("No counters for interface {}", ifindex))
+    }
 }
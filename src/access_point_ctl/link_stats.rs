@@ -0,0 +1,147 @@
+//! # Access point link statistics.
+//! The counters of the access point interface are polled from the wireless
+//! driver and turned into throughput, so a quality drop can be put down to
+//! the WiFi link or to the pipelines.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::{sync::watch, task::JoinHandle};
+
+use super::iw_link::wdev_drv::{InterfaceIndex, LinkCounters, WirelessDriver};
+use crate::{
+    ble::comm_types::InterfaceStats,
+    supervisor::{spawn_supervised, RestartPolicy},
+};
+
+/// Period of the counters reads
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Statistics of the interface from two reads of its counters
+pub fn link_stats(
+    name: &str, previous: &LinkCounters, current: &LinkCounters,
+    elapsed: Duration,
+) -> InterfaceStats {
+    //the counters restart if the interface is recreated
+    let kbps = |previous: u64, current: u64| {
+        let bits = current.saturating_sub(previous) as f64 * 8.0;
+        (bits / 1000.0 / elapsed.as_secs_f64().max(0.001)) as u32
+    };
+
+    InterfaceStats {
+        name: name.to_string(),
+        rx_kbps: kbps(previous.rx_bytes, current.rx_bytes),
+        tx_kbps: kbps(previous.tx_bytes, current.tx_bytes),
+        rx_bytes: current.rx_bytes,
+        tx_bytes: current.tx_bytes,
+        rx_errors: current.rx_errors,
+        tx_errors: current.tx_errors,
+        rx_dropped: current.rx_dropped,
+        tx_dropped: current.tx_dropped,
+    }
+}
+
+/// Polls the counters of the interface, stopped on drop
+pub struct LinkMonitor {
+    stats: watch::Sender<Option<InterfaceStats>>,
+    poll_task: JoinHandle<()>,
+}
+
+impl LinkMonitor {
+    pub fn spawn<D: WirelessDriver + Send + Sync + 'static>(
+        driver: D, ifindex: InterfaceIndex, name: &str,
+    ) -> Self {
+        let (stats, _) = watch::channel(None);
+        let name = name.to_string();
+        let driver = Arc::new(driver);
+
+        //polled again from scratch after a panic
+        let tx = stats.clone();
+        let poll_task = spawn_supervised(
+            "Link monitor",
+            RestartPolicy::DEFAULT,
+            move || {
+                let (driver, tx, name) =
+                    (driver.clone(), tx.clone(), name.clone());
+                async move {
+                    let mut poll = tokio::time::interval(POLL_INTERVAL);
+                    let mut previous: Option<(LinkCounters, Instant)> = None;
+                    loop {
+                        poll.tick().await;
+                        let current = match driver.link_counters(ifindex) {
+                            Ok(counters) => counters,
+                            Err(e) => {
+                                debug!("No counters for {}: {:?}", name, e);
+                                continue;
+                            }
+                        };
+                        let now = Instant::now();
+
+                        if let Some((counters, read_at)) = previous {
+                            tx.send_replace(Some(link_stats(
+                                &name,
+                                &counters,
+                                &current,
+                                now - read_at,
+                            )));
+                        }
+                        previous = Some((current, now));
+                    }
+                }
+            },
+        );
+
+        Self { stats, poll_task }
+    }
+
+    /// Receiver of the statistics, `None` until two reads are done
+    pub fn subscribe(&self) -> watch::Receiver<Option<InterfaceStats>> {
+        self.stats.subscribe()
+    }
+}
+
+impl Drop for LinkMonitor {
+    fn drop(&mut self) {
+        self.poll_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_stats_from_counters() {
+        let stats64: Vec<u8> = [10u64, 20, 1000, 2000, 1, 2, 3, 4, 99]
+            .iter()
+            .flat_map(|counter| counter.to_ne_bytes())
+            .collect();
+        let previous = LinkCounters::from_stats64(&stats64).unwrap();
+        assert_eq!(previous.tx_bytes, 2000);
+        assert_eq!(previous.tx_dropped, 4);
+        assert!(LinkCounters::from_stats64(&stats64[..60]).is_none());
+
+        let current = LinkCounters {
+            rx_bytes: 251000,
+            tx_bytes: 2000,
+            rx_errors: 5,
+            ..previous
+        };
+        let stats = link_stats(
+            "wcdirect0",
+            &previous,
+            &current,
+            Duration::from_secs(2),
+        );
+        assert_eq!(stats.rx_kbps, 1000);
+        assert_eq!(stats.tx_kbps, 0);
+        assert_eq!(stats.rx_errors, 5);
+
+        //recreated interface
+        let stats = link_stats("wcdirect0", &current, &previous, POLL_INTERVAL);
+        assert_eq!(stats.rx_kbps, 0);
+    }
+}
@@ -1,8 +1,10 @@
 //! This module provides control over the access point functionalities, including
 //! configuration, starting/stopping WiFi, and managing DHCP server.
 
+pub mod captive_portal;
 pub mod dhcp_server;
 pub mod iw_link;
+pub mod link_stats;
 pub mod process_hdl;
 pub mod wifi_manager;
 
@@ -3,10 +3,14 @@
 //! The `file_hdl` module defines the `FileHdl` struct, which is responsible for creating,
 //! writing to, and managing a file. It ensures that the file is removed when the `FileHdl`
 //! instance is dropped, providing a convenient way to handle temporary files.
+//!
+//! The files are only readable by the owner, as the generated configs hold
+//! secrets such as the access point passphrase.
 
 use std::{
-    fs::{remove_file, File},
-    io::Write,
+    fs::{self, remove_file, File, Permissions},
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
     path::{Path, PathBuf},
 };
 
@@ -14,6 +18,7 @@ use std::fs::OpenOptions;
 
 use anyhow::anyhow;
 use log::{error, info};
+use uuid::Uuid;
 
 use crate::error::Result;
 
@@ -34,27 +39,79 @@ pub trait FileHdlOps {
 pub struct FileHdl {
     path: PathBuf,
     file: Option<File>,
+    /// Prefix and extension of a temp file, its name is picked when opened
+    temp_name: Option<(String, String)>,
+}
+
+/// Mode of the files, readable and writable by the owner only
+const FILE_MODE: u32 = 0o600;
+
+/// Attempts to find a free temp file name
+const TEMP_NAME_TRIES: usize = 8;
+
+/// Creates the runtime directory of the generated files and sockets, only
+/// accessible by the owner.
+///
+/// # Errors
+///
+/// This function will return an error if the directory cannot be created
+/// or if the path is a symlink or a file.
+pub fn prepare_runtime_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("Runtime path is not a directory: {:?}", dir));
+    }
+    fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+
+    Ok(())
 }
 
 impl FileHdl {
-    /// Creates a new `FileHdl` for the specified path.
+    /// Creates a new `FileHdl` for a temp file in `dir`.
     ///
-    /// This method attempts to create a new file at the given path. If the file already exists,
-    /// it opens the existing file. The file is opened with read and write permissions.
+    /// The file is named from the prefix, a random part and the extension
+    /// when opened, an existing file is never reused.
     ///
     /// # Arguments
     ///
-    /// * `path` - A reference to the path of the file to be managed.
-    ///
-    /// # Returns
-    ///
-    /// * `Result<Self>` - A result containing the `FileHdl` or an error.
-    ///
-    /// # Errors
-    ///
-    /// This function will return an error if the file cannot be created or opened.
-    pub fn from_path(path: &str) -> Self {
-        Self { path: path.into(), file: None }
+    /// * `dir` - The directory of the file, usually the runtime directory.
+    /// * `prefix` - The start of the file name.
+    /// * `extension` - The extension of the file name.
+    pub fn temp_in(dir: &Path, prefix: &str, extension: &str) -> Self {
+        Self {
+            path: dir.to_path_buf(),
+            file: None,
+            temp_name: Some((prefix.to_string(), extension.to_string())),
+        }
+    }
+
+    /// Creates a temp file with a unique name, retried on name collisions.
+    fn create_temp(&mut self, prefix: &str, extension: &str) -> Result<File> {
+        let dir = self.path.clone();
+        for _ in 0..TEMP_NAME_TRIES {
+            let name =
+                format!("{}-{}.{}", prefix, Uuid::new_v4().simple(), extension);
+            let path = dir.join(name);
+
+            match OpenOptions::new()
+                .write(true)
+                .read(true)
+                .create_new(true)
+                .mode(FILE_MODE)
+                .open(&path)
+            {
+                Ok(file) => {
+                    self.path = path;
+                    return Ok(file);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(anyhow!("No free temp file name in {:?}", dir))
     }
 
     /// Gets the file object or returns an error.
@@ -78,7 +135,8 @@ impl FileHdlOps for FileHdl {
     /// Creates a new file at the specified path.
     ///
     /// This method opens a file at the path specified when the `FileHdl` instance was
-    /// created. If the file does not exist, it will be created.
+    /// created. If the file does not exist, it will be created, an existing file is
+    /// truncated. Temp files get a new unique name in their directory.
     ///
     /// # Errors
     ///
@@ -89,14 +147,25 @@ impl FileHdlOps for FileHdl {
             return Ok(());
         }
 
+        if let Some((prefix, extension)) = self.temp_name.clone() {
+            let file = self.create_temp(&prefix, &extension)?;
+            info!("Created temp file: {:?}", self.path);
+            self.file = Some(file);
+            return Ok(());
+        }
+
         info!("Creating file: {:?}", self.path);
-        self.file = Some(
-            OpenOptions::new()
-                .write(true)
-                .read(true)
-                .create(true) // Create the file if it doesn't exist
-                .open(&self.path)?,
-        );
+        let file = OpenOptions::new()
+            .write(true)
+            .read(true)
+            .create(true) // Create the file if it doesn't exist
+            .truncate(true)
+            .mode(FILE_MODE)
+            .open(&self.path)?;
+
+        //the mode is only applied to new files
+        file.set_permissions(Permissions::from_mode(FILE_MODE))?;
+        self.file = Some(file);
 
         Ok(())
     }
@@ -138,10 +207,48 @@ impl Drop for FileHdl {
     ///
     /// This method ensures that the file is removed from the filesystem when the `FileHdl`
     /// instance goes out of scope. If the file cannot be removed, an error is logged.
+    /// Files never opened are left untouched.
     fn drop(&mut self) {
+        if self.file.take().is_none() {
+            return;
+        }
+
         info!("Removing file: {:?}", self.path);
         if let Err(e) = remove_file(&self.path) {
             error!("Failed to remove file: {:?}, error: {}", self.path, e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temp_file_private_and_removed() {
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-runtime-{}", std::process::id()));
+        prepare_runtime_dir(&dir).unwrap();
+
+        let mut first = FileHdl::temp_in(&dir, "hostapd", "conf");
+        let mut second = FileHdl::temp_in(&dir, "hostapd", "conf");
+        first.open().unwrap();
+        second.open().unwrap();
+        first.write_data(b"wpa_passphrase=secret").unwrap();
+
+        let path = first.get_path().to_path_buf();
+        assert_ne!(path, second.get_path());
+        assert!(path.file_name().unwrap().to_string_lossy().ends_with(".conf"));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, FILE_MODE);
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        drop(first);
+        assert!(!path.exists());
+
+        drop(second);
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -3,6 +3,7 @@
 
 use super::super::process_hdl::ProcessHdlOps;
 use super::file_hdl::FileHdlOps;
+use crate::app_data::ApBand;
 use crate::error::Result;
 use log::{info, warn};
 use std::process::Command;
@@ -59,6 +60,7 @@ where
 {
     config_file: F,
     process: P,
+    band: ApBand,
 }
 
 impl<P: ProcessHdlOps, F: FileHdlOps> HostapdProc<P, F> {
@@ -73,7 +75,13 @@ impl<P: ProcessHdlOps, F: FileHdlOps> HostapdProc<P, F> {
     ///
     /// * `Self` - Returns a new instance of HostapdProc.
     pub fn new(config_file: F, process: P) -> Self {
-        Self { config_file, process }
+        Self { config_file, process, band: ApBand::default() }
+    }
+
+    /// Sets the wifi band of the access point, 2.4GHz by default.
+    pub fn with_band(mut self, band: ApBand) -> Self {
+        self.band = band;
+        self
     }
 }
 
@@ -98,14 +106,19 @@ impl<P: ProcessHdlOps, F: FileHdlOps> HostapdProcCtl for HostapdProc<P, F> {
         // Create the hostapd config file
         self.config_file.open()?;
 
+        let (hw_mode, channel) = match self.band {
+            ApBand::Band2_4GHz => ("g", 6),
+            ApBand::Band5GHz => ("a", 36),
+        };
+
         // Format the hostapd configuration
         let hostap_config = format!(
             r#"ctrl_interface={}
 interface={}
 driver=nl80211
 ssid={}
-hw_mode=g
-channel=6
+hw_mode={}
+channel={}
 wpa=2
 wpa_passphrase={}
 wpa_key_mgmt=WPA-PSK
@@ -113,7 +126,7 @@ rsn_pairwise=CCMP
 ieee80211n=1
 wmm_enabled=1
 "#,
-            control_dir, iw_name, creds.ssid, creds.password
+            control_dir, iw_name, creds.ssid, hw_mode, channel, creds.password
         );
 
         // Write the configuration to the file
@@ -191,6 +204,7 @@ mod tests {
                 let config_str = String::from_utf8_lossy(data);
                 config_str.contains("ssid=test_ssid")
                     && config_str.contains("wpa_passphrase=test_password")
+                    && config_str.contains("hw_mode=g\nchannel=6\n")
             })
             .times(1)
             .returning(|_| Ok(()));
@@ -219,6 +233,43 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_hostapd_proc_start_5ghz() {
+        init_logger();
+        let mut mock_file_hdl = MockFileHdlOps::new();
+        let mut mock_process_hdl = MockProcessHdlOps::new();
+
+        // The 5GHz band switches the hardware mode and the channel
+        mock_file_hdl.expect_open().times(1).returning(|| Ok(()));
+        mock_file_hdl
+            .expect_write_data()
+            .withf(|data| {
+                let config_str = String::from_utf8_lossy(data);
+                config_str.contains("hw_mode=a\nchannel=36\n")
+                    && !config_str.contains("hw_mode=g")
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+        mock_file_hdl
+            .expect_get_path()
+            .times(1)
+            .return_const("/tmp/hostapd.conf".into());
+        mock_process_hdl.expect_spawn().times(1).returning(|_| Ok(()));
+
+        let mut hostapd_proc =
+            HostapdProc::new(mock_file_hdl, mock_process_hdl)
+                .with_band(ApBand::Band5GHz);
+
+        let creds = WifiCredentials {
+            ssid: "test_ssid".to_string(),
+            password: "test_password".to_string(),
+        };
+
+        assert!(hostapd_proc
+            .start(&creds, "wlan0", "/var/run/hostapd")
+            .is_ok());
+    }
+
     #[test]
     fn test_hostapd_proc_start_fail_open() {
         init_logger();
@@ -7,11 +7,12 @@ mod hostapd_proc;
 mod wpa_ctl;
 
 // Export the `HostapdProcCtl` trait and `WifiCredentials` struct from the `hostapd_proc` module.
-pub use file_hdl::FileHdl;
+pub use file_hdl::{prepare_runtime_dir, FileHdl};
 pub use hostapd_proc::{HostapdProc, HostapdProcCtl, WifiCredentials};
 pub use wpa_ctl::WpaCtl;
 
 use crate::error::Result;
+use crate::retry::{retry_blocking, RetryPolicy};
 use anyhow::anyhow;
 use log::info;
 use wpa_ctl::WpaCtlClientOps;
@@ -89,17 +90,11 @@ impl<P: HostapdProcCtl, C: WpaCtlClientOps> WifiManager<P, C> {
 
         hostapd.start(&creds, iw_name, control_dir)?;
 
-        // Try to connect during 5 seconds to the AP process
         // This has to wait until the process is ready to accept connections
-        let mut tries = 0;
-        while tries < 5 {
-            if wpa_ctl.connect().is_ok() {
-                info!("Connected to WPA control socket");
-                break;
-            }
-            tries += 1;
-            std::thread::sleep(std::time::Duration::from_secs(1));
-        }
+        retry_blocking("Connecting to hostapd", RetryPolicy::BOOT, || {
+            wpa_ctl.connect()
+        })?;
+        info!("Connected to WPA control socket");
 
         info!(
             "Wifi configured successfully, pausing the wifi broadcast for now"
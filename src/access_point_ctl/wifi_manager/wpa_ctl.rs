@@ -94,6 +94,7 @@ pub trait WpaCtlClientOps {
 pub struct WpaCtl {
     client: Option<Client>,
     control_dir: PathBuf,
+    client_dir: Option<PathBuf>,
     iw_name: String,
 }
 
@@ -108,10 +109,21 @@ impl WpaCtl {
         Self {
             client: None,
             control_dir: control_dir.as_ref().to_path_buf(),
+            client_dir: None,
             iw_name: iw_name.to_string(),
         }
     }
 
+    /// Binds the client socket in `client_dir` instead of `/tmp`.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_dir` - Path to the directory of the client socket.
+    pub fn with_client_dir<P: AsRef<Path>>(mut self, client_dir: P) -> Self {
+        self.client_dir = Some(client_dir.as_ref().to_path_buf());
+        self
+    }
+
     /// Helper method to get a mutable reference to the connected client.
     ///
     /// # Errors
@@ -152,7 +164,12 @@ impl WpaCtlClientOps for WpaCtl {
 
         info!("Connecting to WPA control socket");
         let soc_path = self.control_dir.join(&self.iw_name);
-        self.client = Some(Client::builder().ctrl_path(&soc_path).open()?);
+        self.client = Some(
+            Client::builder()
+                .ctrl_path(&soc_path)
+                .cli_path::<_, PathBuf>(self.client_dir.clone())
+                .open()?,
+        );
         Ok(())
     }
 
@@ -10,12 +10,24 @@ pub use kv_db::DiskBasedDb;
 pub use kv_db::KvDbOps;
 use log::error;
 use log::info;
+pub use schemas::ApBand;
+pub use schemas::AutoBlock;
+pub use schemas::BlocklistSchema;
 pub use schemas::ConnectionType;
 pub use schemas::HostSchema;
+pub use schemas::HostSettingsSchema;
+pub use schemas::LastCamera;
+pub use schemas::LastCamerasSchema;
+pub use schemas::LatencyProfile;
 pub use schemas::MobileSchema;
+pub use schemas::MobileUsage;
+pub use schemas::SelfTestLogSchema;
+pub use schemas::SelfTestRun;
+pub use schemas::SelfTestStep;
+pub use schemas::UsageStatsSchema;
 use uuid::Uuid;
 
-use crate::ble::comm_types::HostProvInfo;
+use crate::ble::comm_types::{HostProvInfo, PROTOCOL_VERSION};
 use crate::ble::server::mobile_comm::AppDataStore;
 
 use crate::error::Result;
@@ -26,7 +38,7 @@ pub struct AppData<Db> {
 }
 
 /// A struct that holds information about the host.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HostInfo {
     pub name: String,
     pub connection_type: ConnectionType,
@@ -60,6 +72,11 @@ where
 
         Ok(AppData { data_db })
     }
+
+    /// Opens an existing data store without initializing the host info.
+    pub fn open(data_db: Db) -> Self {
+        AppData { data_db }
+    }
 }
 
 impl<Db> AppDataStore for AppData<Db>
@@ -78,12 +95,34 @@ where
                 } else {
                     "AP".to_string()
                 },
+                version: PROTOCOL_VERSION,
+                ..Default::default()
             });
         }
         error!("Failed to retrieve host info: Host info not found.");
         Err(anyhow!("Host info not found"))
     }
 
+    fn update_host_info(&mut self, host_info: &HostInfo) -> Result<bool> {
+        let Some(mut host) = self.data_db.read::<HostSchema>("host_info")?
+        else {
+            error!("Failed to update host info: Host info not found.");
+            return Err(anyhow!("Host info not found"));
+        };
+
+        if host.name == host_info.name
+            && host.connection_type == host_info.connection_type
+        {
+            return Ok(false);
+        }
+
+        host.name = host_info.name.clone();
+        host.connection_type = host_info.connection_type.clone();
+        self.data_db.update("host_info", &host)?;
+        info!("Host info updated successfully.");
+        Ok(true)
+    }
+
     fn add_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
         if let Some(mut host) = self.data_db.read::<HostSchema>("host_info")? {
             // Update the host info with the new mobile id
@@ -99,6 +138,79 @@ where
         Err(anyhow!("Host info not found"))
     }
 
+    fn get_host_settings(&self) -> Result<HostSettingsSchema> {
+        Ok(self
+            .data_db
+            .read::<HostSettingsSchema>("host_settings")?
+            .unwrap_or_default())
+    }
+
+    fn update_host_settings(
+        &mut self, settings: &HostSettingsSchema,
+    ) -> Result<()> {
+        self.data_db.update("host_settings", settings)?;
+        info!("Host settings updated successfully.");
+        Ok(())
+    }
+
+    fn get_blocklist(&self) -> Result<BlocklistSchema> {
+        Ok(self
+            .data_db
+            .read::<BlocklistSchema>("blocklist")?
+            .unwrap_or_default())
+    }
+
+    fn update_blocklist(&mut self, blocklist: &BlocklistSchema) -> Result<()> {
+        self.data_db.update("blocklist", blocklist)?;
+        info!("Blocklist updated successfully.");
+        Ok(())
+    }
+
+    fn get_last_cameras(&self, mobile_id: &str) -> Result<LastCamerasSchema> {
+        Ok(self
+            .data_db
+            .read::<LastCamerasSchema>(mobile_id)?
+            .unwrap_or_default())
+    }
+
+    fn update_last_cameras(
+        &mut self, mobile_id: &str, cameras: &LastCamerasSchema,
+    ) -> Result<()> {
+        self.data_db.update(mobile_id, cameras)
+    }
+
+    fn remove_mobile(&mut self, id: &str) -> Result<()> {
+        if let Some(mut host) = self.data_db.read::<HostSchema>("host_info")? {
+            host.registered_mobiles.retain(|mobile_id| mobile_id != id);
+            self.data_db.update("host_info", &host)?;
+        }
+        self.data_db.delete::<MobileSchema>(id)?;
+        info!("Mobile device removed successfully.");
+        Ok(())
+    }
+
+    fn get_usage_stats(&self) -> Result<UsageStatsSchema> {
+        Ok(self
+            .data_db
+            .read::<UsageStatsSchema>("usage_stats")?
+            .unwrap_or_default())
+    }
+
+    fn update_usage_stats(&mut self, stats: &UsageStatsSchema) -> Result<()> {
+        self.data_db.update("usage_stats", stats)
+    }
+
+    fn get_self_tests(&self) -> Result<SelfTestLogSchema> {
+        Ok(self
+            .data_db
+            .read::<SelfTestLogSchema>("self_tests")?
+            .unwrap_or_default())
+    }
+
+    fn update_self_tests(&mut self, log: &SelfTestLogSchema) -> Result<()> {
+        self.data_db.update("self_tests", log)
+    }
+
     fn get_mobile(&self, id: &str) -> Result<MobileSchema> {
         if let Some(mobile) = self.data_db.read::<MobileSchema>(id)? {
             info!("Mobile info retrieved successfully.");
@@ -107,12 +219,22 @@ where
         error!("Failed to retrieve mobile info: Mobile info not found.");
         Err(anyhow!("Mobile info not found"))
     }
+
+    fn update_mobile(&mut self, mobile: &MobileSchema) -> Result<()> {
+        if self.data_db.read::<MobileSchema>(&mobile.id)?.is_none() {
+            return Err(anyhow!("Mobile info not found"));
+        }
+        self.data_db.update(&mobile.id, mobile)?;
+        info!("Mobile info updated successfully.");
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use chrono::{Days, NaiveDate};
     use kv_db::MockKvDbOps;
     use mockall::predicate::eq;
 
@@ -183,4 +305,237 @@ mod tests {
         let result = app_data.add_mobile(&mobile_schema);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_update_host_info() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+        let host_schema = HostSchema {
+            id: "123".to_string(),
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+            registered_mobiles: Vec::new(),
+        };
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(move |_| Ok(Some(host_schema.clone())));
+
+        //only a change is written
+        mock_db
+            .expect_update::<HostSchema>()
+            .withf(|key, host| {
+                key == "host_info" && host.connection_type == ConnectionType::AP
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+
+        let mut app_data = AppData::open(mock_db);
+        let mut host_info = HostInfo {
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+        };
+        assert!(!app_data.update_host_info(&host_info).unwrap());
+
+        host_info.connection_type = ConnectionType::AP;
+        assert!(app_data.update_host_info(&host_info).unwrap());
+    }
+
+    #[test]
+    fn test_remove_mobile() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+        let host_schema = HostSchema {
+            id: "123".to_string(),
+            name: "TestHost".to_string(),
+            connection_type: ConnectionType::WLAN,
+            registered_mobiles: vec!["mobile_1".to_string()],
+        };
+
+        mock_db
+            .expect_read::<HostSchema>()
+            .with(eq("host_info"))
+            .returning(move |_| Ok(Some(host_schema.clone())));
+
+        mock_db
+            .expect_update::<HostSchema>()
+            .withf(|key, host| {
+                key == "host_info" && host.registered_mobiles.is_empty()
+            })
+            .returning(|_, _| Ok(()));
+
+        mock_db
+            .expect_delete::<MobileSchema>()
+            .with(eq("mobile_1"))
+            .returning(|_| Ok(None));
+
+        let mut app_data = AppData::open(mock_db);
+        assert!(app_data.remove_mobile("mobile_1").is_ok());
+    }
+
+    #[test]
+    fn test_get_host_settings_default() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        mock_db
+            .expect_read::<HostSettingsSchema>()
+            .with(eq("host_settings"))
+            .returning(|_| Ok(None));
+
+        let app_data = AppData { data_db: mock_db };
+        assert_eq!(
+            app_data.get_host_settings().unwrap(),
+            HostSettingsSchema::default()
+        );
+    }
+
+    #[test]
+    fn test_update_host_settings() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        let settings = HostSettingsSchema {
+            ap_band: ApBand::Band5GHz,
+            latency_profile: LatencyProfile::LowLatency,
+            auto_record: true,
+        };
+
+        mock_db
+            .expect_update::<HostSettingsSchema>()
+            .withf(|key, settings| {
+                key == "host_settings" && settings.ap_band == ApBand::Band5GHz
+            })
+            .returning(|_, _| Ok(()));
+
+        let mut app_data = AppData { data_db: mock_db };
+        assert!(app_data.update_host_settings(&settings).is_ok());
+    }
+
+    #[test]
+    fn test_get_blocklist_default() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        mock_db
+            .expect_read::<BlocklistSchema>()
+            .with(eq("blocklist"))
+            .returning(|_| Ok(None));
+
+        let app_data = AppData::open(mock_db);
+        assert!(app_data.get_blocklist().unwrap().entries.is_empty());
+    }
+
+    #[test]
+    fn test_update_blocklist() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        let mut blocklist = BlocklistSchema::default();
+        assert!(blocklist.add("AA:BB:CC:DD:EE:FF"));
+        assert!(!blocklist.add("AA:BB:CC:DD:EE:FF"));
+
+        mock_db
+            .expect_update::<BlocklistSchema>()
+            .withf(|key, blocklist| {
+                key == "blocklist" && blocklist.contains("AA:BB:CC:DD:EE:FF")
+            })
+            .returning(|_, _| Ok(()));
+
+        let mut app_data = AppData::open(mock_db);
+        assert!(app_data.update_blocklist(&blocklist).is_ok());
+
+        assert!(blocklist.remove("AA:BB:CC:DD:EE:FF"));
+        assert!(!blocklist.remove("AA:BB:CC:DD:EE:FF"));
+    }
+
+    #[test]
+    fn test_automatic_blocks_bounded() {
+        let today = NaiveDate::from_ymd_opt(2024, 5, 10).unwrap();
+        let mut blocklist = BlocklistSchema::default();
+        assert!(blocklist.add_automatic("addr-1", today, 2));
+        assert!(blocklist.add_automatic("addr-2", today, 2));
+        assert!(blocklist.add_automatic("addr-3", today + Days::new(1), 2));
+
+        //the oldest block is dropped
+        assert!(!blocklist.contains("addr-1"));
+        assert!(blocklist.remove("addr-2"));
+
+        assert!(!blocklist.expire(today + Days::new(7), 7));
+        assert!(blocklist.expire(today + Days::new(8), 7));
+        assert!(blocklist.automatic.is_empty());
+    }
+
+    #[test]
+    fn test_last_cameras() {
+        init_logger();
+        let mut mock_db = MockKvDbOps::new();
+
+        let cameras = LastCamerasSchema {
+            cameras: vec![LastCamera {
+                name: "Back".to_string(),
+                ..Default::default()
+            }],
+        };
+        let stored = cameras.clone();
+
+        mock_db
+            .expect_read::<LastCamerasSchema>()
+            .with(eq("mobile_1"))
+            .returning(move |_| Ok(Some(stored.clone())));
+        mock_db
+            .expect_read::<LastCamerasSchema>()
+            .with(eq("mobile_2"))
+            .returning(|_| Ok(None));
+        mock_db
+            .expect_update::<LastCamerasSchema>()
+            .withf(|key, cameras| {
+                key == "mobile_1" && cameras.cameras[0].name == "Back"
+            })
+            .returning(|_, _| Ok(()));
+
+        let mut app_data = AppData::open(mock_db);
+        assert!(app_data.update_last_cameras("mobile_1", &cameras).is_ok());
+        assert_eq!(app_data.get_last_cameras("mobile_1").unwrap(), cameras);
+        assert!(app_data
+            .get_last_cameras("mobile_2")
+            .unwrap()
+            .cameras
+            .is_empty());
+    }
+
+    #[test]
+    fn test_usage_stats_rollup() {
+        let day = |d| chrono::NaiveDate::from_ymd_opt(2026, 10, d).unwrap();
+        let usage = |id: &str, secs| MobileUsage {
+            mobile_id: id.to_string(),
+            calls: 1,
+            streaming_secs: secs,
+            failures: 0,
+            ..Default::default()
+        };
+
+        let mut stats = UsageStatsSchema::default();
+        stats.add(day(5), &usage("mobile_1", 600));
+        stats.add(day(11), &usage("mobile_1", 1200));
+        stats.add(day(12), &usage("mobile_1", 1800));
+        stats.add(day(12), &usage("mobile_2", 300));
+        stats.add(day(12), &usage("mobile_1", 200));
+
+        assert_eq!(stats.days.len(), 3);
+        assert_eq!(stats.days[2].mobiles[0].calls, 2);
+        assert_eq!(stats.days[2].mobiles[0].streaming_secs, 2000);
+
+        //the 5th to the 11th is an ISO week, the 12th starts the next one
+        let weekly = stats.weekly();
+        assert_eq!(weekly.len(), 2);
+        assert_eq!(weekly[0].0, "2026-W41");
+        assert_eq!(weekly[0].1[0].streaming_secs, 1800);
+        assert_eq!(weekly[1].1.len(), 2);
+
+        stats.prune(day(12), 7);
+        assert_eq!(stats.days.len(), 2);
+        assert_eq!(stats.days[0].day, "2026-10-11");
+    }
 }
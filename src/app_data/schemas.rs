@@ -2,9 +2,11 @@
 //! It includes the necessary types and implementations for serialization and deserialization,
 //! as well as the required traits for database schema handling.
 
+use chrono::{Datelike, Days, NaiveDate};
 use serde::{Deserialize, Serialize};
 
 use super::kv_db::SchemaType;
+use crate::ble::comm_types::VideoProp;
 
 /// Type alias for Mobile ID, represented as a String.
 pub type MobileId = String;
@@ -15,6 +17,15 @@ pub type MobileId = String;
 pub struct MobileSchema {
     pub id: MobileId,
     pub name: String,
+    /// User the mobile belongs to on multi-user hosts, set by the host
+    #[serde(default)]
+    pub owner: Option<u32>,
+    /// Records the cameras of the mobile whenever they stream
+    #[serde(default)]
+    pub always_record: bool,
+    /// Changes the settings of the whole host, set by the host
+    #[serde(default)]
+    pub trusted: bool,
 }
 
 impl SchemaType for MobileSchema {
@@ -45,3 +56,303 @@ pub struct HostSchema {
 impl SchemaType for HostSchema {
     const KEYSPACE_NAME: &'static str = "host_information";
 }
+
+/// Wifi band used by the host access point.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub enum ApBand {
+    #[default]
+    Band2_4GHz,
+    Band5GHz,
+}
+
+/// Trade-off between latency and quality of the video pipelines.
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub enum LatencyProfile {
+    LowLatency,
+    #[default]
+    Balanced,
+    Quality,
+}
+
+impl LatencyProfile {
+    /// Milliseconds of received video buffered against the network jitter
+    pub fn jitter_ms(self) -> u32 {
+        match self {
+            LatencyProfile::LowLatency => 0,
+            LatencyProfile::Balanced => 50,
+            LatencyProfile::Quality => 200,
+        }
+    }
+}
+
+/// Represents the host settings that can be administered from a mobile.
+/// Kept apart from `HostSchema` so existing databases remain readable.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HostSettingsSchema {
+    pub ap_band: ApBand,
+    pub latency_profile: LatencyProfile,
+    pub auto_record: bool,
+}
+
+impl SchemaType for HostSettingsSchema {
+    const KEYSPACE_NAME: &'static str = "host_settings";
+}
+
+/// Entry blocked after repeated failures, `day` is the local date of the
+/// block as `YYYY-MM-DD`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AutoBlock {
+    pub entry: String,
+    pub day: String,
+}
+
+/// Represents the BLE addresses and mobile ids refused by the host, the
+/// automatic blocks expire.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BlocklistSchema {
+    pub entries: Vec<String>,
+    #[serde(default)]
+    pub automatic: Vec<AutoBlock>,
+}
+
+impl BlocklistSchema {
+    pub fn contains(&self, entry: &str) -> bool {
+        self.entries.iter().any(|blocked| blocked == entry)
+            || self.automatic.iter().any(|blocked| blocked.entry == entry)
+    }
+
+    /// Blocks the entry from `today` on, the oldest automatic block is
+    /// dropped once `max` are kept. Returns false if it was already blocked
+    pub fn add_automatic(
+        &mut self, entry: &str, today: NaiveDate, max: usize,
+    ) -> bool {
+        if self.contains(entry) {
+            return false;
+        }
+        //oldest first
+        while !self.automatic.is_empty() && self.automatic.len() >= max {
+            self.automatic.remove(0);
+        }
+        self.automatic.push(AutoBlock {
+            entry: entry.to_string(),
+            day: today.format(DAY_FORMAT).to_string(),
+        });
+        true
+    }
+
+    /// Removes the automatic blocks older than `days`, a day that can't be
+    /// parsed is removed too. Returns whether any was removed
+    pub fn expire(&mut self, today: NaiveDate, days: u32) -> bool {
+        let oldest = today - Days::new(days as u64);
+        let len = self.automatic.len();
+
+        self.automatic.retain(|blocked| {
+            NaiveDate::parse_from_str(&blocked.day, DAY_FORMAT)
+                .is_ok_and(|day| day > oldest)
+        });
+        self.automatic.len() != len
+    }
+
+    /// Adds the entry, returns false if it was already blocked
+    pub fn add(&mut self, entry: &str) -> bool {
+        if self.contains(entry) {
+            return false;
+        }
+        self.entries.push(entry.to_string());
+        true
+    }
+
+    /// Removes the entry, returns false if it was not blocked
+    pub fn remove(&mut self, entry: &str) -> bool {
+        let len = self.entries.len() + self.automatic.len();
+        self.entries.retain(|blocked| blocked != entry);
+        self.automatic.retain(|blocked| blocked.entry != entry);
+        self.entries.len() + self.automatic.len() != len
+    }
+}
+
+impl SchemaType for BlocklistSchema {
+    const KEYSPACE_NAME: &'static str = "blocklist";
+}
+
+/// Camera of the last call of a mobile.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LastCamera {
+    pub name: String,
+    pub format: VideoProp,
+}
+
+/// Represents the cameras used in the last call of a mobile, stored by
+/// mobile id to prepare them before the next call.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LastCamerasSchema {
+    pub cameras: Vec<LastCamera>,
+}
+
+impl SchemaType for LastCamerasSchema {
+    const KEYSPACE_NAME: &'static str = "last_cameras";
+}
+
+/// Usage of a mobile, the calls are counted when they end.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MobileUsage {
+    pub mobile_id: MobileId,
+    pub calls: u32,
+    pub streaming_secs: u64,
+    /// Cameras of the calls that failed to start
+    pub failures: u32,
+    /// Cameras that streamed, with the milliseconds from the offer to
+    /// their first frame summed up
+    #[serde(default)]
+    pub first_frames: u32,
+    #[serde(default)]
+    pub first_frame_ms: u64,
+}
+
+impl MobileUsage {
+    fn add(&mut self, other: &MobileUsage) {
+        self.calls += other.calls;
+        self.streaming_secs += other.streaming_secs;
+        self.failures += other.failures;
+        self.first_frames += other.first_frames;
+        self.first_frame_ms += other.first_frame_ms;
+    }
+}
+
+/// Usage of the mobiles in a day, `day` is the local date as `YYYY-MM-DD`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DailyUsage {
+    pub day: String,
+    pub mobiles: Vec<MobileUsage>,
+}
+
+/// Represents the daily usage rollups of the host, oldest day first.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UsageStatsSchema {
+    pub days: Vec<DailyUsage>,
+}
+
+impl UsageStatsSchema {
+    /// Adds the usage of a mobile to the rollup of the day
+    pub fn add(&mut self, day: NaiveDate, usage: &MobileUsage) {
+        let day = day.format(DAY_FORMAT).to_string();
+
+        if self.days.last().is_none_or(|last| last.day != day) {
+            self.days.push(DailyUsage { day, mobiles: Vec::new() });
+        }
+        let Some(daily) = self.days.last_mut() else {
+            return;
+        };
+
+        merge_usage(&mut daily.mobiles, usage);
+    }
+
+    /// Removes the days older than the retention, a day that can't be
+    /// parsed is removed too
+    pub fn prune(&mut self, today: NaiveDate, retention_days: u32) {
+        let oldest = today - Days::new(retention_days as u64);
+
+        self.days.retain(|daily| {
+            NaiveDate::parse_from_str(&daily.day, DAY_FORMAT)
+                .is_ok_and(|day| day > oldest)
+        });
+    }
+
+    /// Usage per ISO week as `YYYY-Www`, oldest week first
+    pub fn weekly(&self) -> Vec<(String, Vec<MobileUsage>)> {
+        let mut weeks: Vec<(String, Vec<MobileUsage>)> = Vec::new();
+
+        for daily in self.days.iter() {
+            let Ok(day) = NaiveDate::parse_from_str(&daily.day, DAY_FORMAT)
+            else {
+                continue;
+            };
+            let week = day.iso_week();
+            let week = format!("{}-W{:02}", week.year(), week.week());
+
+            if weeks.last().is_none_or(|(last, _)| *last != week) {
+                weeks.push((week, Vec::new()));
+            }
+            if let Some((_, mobiles)) = weeks.last_mut() {
+                daily
+                    .mobiles
+                    .iter()
+                    .for_each(|usage| merge_usage(mobiles, usage));
+            }
+        }
+
+        weeks
+    }
+}
+
+impl SchemaType for UsageStatsSchema {
+    const KEYSPACE_NAME: &'static str = "usage_stats";
+}
+
+/// Step of a self-test, e.g. the module load or the device creation.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SelfTestStep {
+    pub name: String,
+    pub passed: bool,
+    /// Reason of the failure, empty if passed
+    pub detail: String,
+}
+
+/// Self-test run, `at` is the local start time as `YYYY-MM-DD HH:MM`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SelfTestRun {
+    pub at: String,
+    pub steps: Vec<SelfTestStep>,
+}
+
+impl SelfTestRun {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|step| step.passed)
+    }
+}
+
+/// Represents the log of the self-test runs, oldest first.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SelfTestLogSchema {
+    pub runs: Vec<SelfTestRun>,
+}
+
+impl SelfTestLogSchema {
+    /// Runs kept in the log
+    pub const MAX_RUNS: usize = 60;
+
+    /// Adds a run, the oldest ones are dropped over the max
+    pub fn add(&mut self, run: SelfTestRun) {
+        self.runs.push(run);
+        let excess = self.runs.len().saturating_sub(Self::MAX_RUNS);
+        self.runs.drain(..excess);
+    }
+}
+
+impl SchemaType for SelfTestLogSchema {
+    const KEYSPACE_NAME: &'static str = "self_tests";
+}
+
+const DAY_FORMAT: &str = "%Y-%m-%d";
+
+fn merge_usage(mobiles: &mut Vec<MobileUsage>, usage: &MobileUsage) {
+    match mobiles.iter_mut().find(|m| m.mobile_id == usage.mobile_id) {
+        Some(mobile) => mobile.add(usage),
+        None => mobiles.push(usage.clone()),
+    }
+}
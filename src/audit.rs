@@ -0,0 +1,430 @@
+//! # Resource audit.
+//! Lists every path, socket, kernel module, program and capability the host
+//! touches with the built features and the config, so locked-down
+//! deployments can write their SELinux or AppArmor policy. The list is
+//! logged at startup and `audit --generate-apparmor-profile` turns it into
+//! a profile.
+
+use std::{
+    env,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use serde::Serialize;
+
+use crate::config::{AppConfig, ContainerMode};
+
+//directories of the system programs, often missing from the PATH of users
+const SBIN_DIRS: [&str; 2] = ["/usr/sbin", "/sbin"];
+
+//socket of the system bus
+const SYSTEM_BUS: &str = "/run/dbus/system_bus_socket";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+impl Access {
+    fn apparmor(&self) -> &'static str {
+        match self {
+            Access::Read => "r",
+            Access::ReadWrite => "rwk",
+        }
+    }
+}
+
+/// File or directory tree, `/**` matches everything under a directory
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditPath {
+    pub path: String,
+    pub access: Access,
+    pub reason: &'static str,
+}
+
+/// Socket of an address family, with its address if bound
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditSocket {
+    /// AppArmor domain, e.g. `inet` or `netlink`
+    pub domain: &'static str,
+    /// AppArmor type, e.g. `dgram` or `stream`
+    pub kind: &'static str,
+    pub address: Option<String>,
+    pub reason: &'static str,
+}
+
+/// Program, kernel module or capability
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct AuditItem {
+    pub name: String,
+    pub reason: &'static str,
+}
+
+/// Resources of the host for a config
+#[derive(Debug, Default, Serialize)]
+pub struct Audit {
+    pub paths: Vec<AuditPath>,
+    pub sockets: Vec<AuditSocket>,
+    pub modules: Vec<AuditItem>,
+    pub programs: Vec<AuditItem>,
+    pub capabilities: Vec<AuditItem>,
+}
+
+impl Audit {
+    /// Resources of the host running `config` with the database at
+    /// `db_path` and the config file at `config_path`
+    pub fn for_config(
+        config: &AppConfig, db_path: &Path, config_path: Option<&Path>,
+    ) -> Self {
+        let mut audit = Self::default();
+
+        audit.path(
+            &format!("{}/**", db_path.display()),
+            Access::ReadWrite,
+            "database",
+        );
+        if let Some(dir) = config_path.and_then(Path::parent) {
+            audit.path(
+                &format!("{}/**", dir.display()),
+                Access::Read,
+                "config file, watched for changes",
+            );
+        }
+        audit.path(
+            "/run/webcam-direct.pid",
+            Access::ReadWrite,
+            "reload requests",
+        );
+        if let Some(log_file) = config.log_file.as_ref() {
+            audit.path(
+                &format!("{}*", log_file.path.display()),
+                Access::ReadWrite,
+                "log file and its rotations",
+            );
+        }
+        if config.power.enabled {
+            audit.path(
+                "/sys/class/power_supply/**",
+                Access::Read,
+                "battery level and AC status",
+            );
+        }
+        audit.socket("inet", "dgram", None, "link tests of the mobiles");
+
+        if cfg!(any(feature = "ble", feature = "desktop", feature = "logind")) {
+            audit.path(SYSTEM_BUS, Access::ReadWrite, "system bus");
+        }
+
+        if cfg!(any(feature = "ap", feature = "ble")) {
+            let access = if config.rfkill_unblock {
+                Access::ReadWrite
+            } else {
+                Access::Read
+            };
+            audit.path("/sys/class/rfkill/**", access, "radio kill switches");
+        }
+
+        if cfg!(feature = "ble") {
+            audit.socket("bluetooth", "seqpacket", None, "GATT transport");
+            if config.le_privacy {
+                audit.program("btmgmt", "LE privacy of the adapters");
+            }
+        }
+
+        if cfg!(feature = "ap") {
+            audit.path(
+                &format!("{}/**", config.runtime_dir.display()),
+                Access::ReadWrite,
+                "hostapd config and control sockets",
+            );
+            audit.socket("netlink", "raw", None, "access point interface");
+            audit.socket("unix", "dgram", None, "hostapd control");
+            audit.program("hostapd", "access point");
+            audit.program("dnsmasq", "DHCP and DNS of the access point");
+            audit
+                .program("nmcli", "releases the interface from NetworkManager");
+            audit.capability("net_admin", "access point interface");
+            if config.suppress_captive_portal {
+                audit.socket(
+                    "inet",
+                    "stream",
+                    Some("<access point>:80".to_string()),
+                    "connectivity checks of the phones",
+                );
+                audit.capability(
+                    "net_bind_service",
+                    "connectivity checks on port 80",
+                );
+            }
+        }
+
+        if cfg!(feature = "webrtc") {
+            audit.webrtc(config);
+        }
+
+        audit
+    }
+
+    fn webrtc(&mut self, config: &AppConfig) {
+        //the container mode is detected at startup
+        let mode = &config.container.mode;
+        if *mode == ContainerMode::Auto {
+            for marker in [
+                "/.dockerenv",
+                "/run/.containerenv",
+                "/run/systemd/container",
+                "/proc/1/environ",
+                "/proc/self/cgroup",
+            ] {
+                self.path(marker, Access::Read, "container detection");
+            }
+        }
+        if *mode != ContainerMode::Container {
+            self.path(
+                "/proc/self/status",
+                Access::Read,
+                "missing capabilities",
+            );
+        }
+        if *mode != ContainerMode::Host {
+            for device in config.container.devices.iter() {
+                self.path(device, Access::ReadWrite, "virtual camera");
+            }
+        }
+        if *mode != ContainerMode::Container {
+            self.path("/proc/modules", Access::Read, "loaded modules");
+            self.path(
+                "/etc/webcam-direct/devices.json",
+                Access::Read,
+                "provisioned devices",
+            );
+            self.path(
+                "/dev/v4l2loopback",
+                Access::ReadWrite,
+                "loopback control",
+            );
+            self.path("/dev/video*", Access::ReadWrite, "virtual cameras");
+            self.module("videodev", "video4linux");
+            self.module("v4l2loopback", "virtual cameras");
+            self.program("modprobe", "loads the modules");
+            self.program("chmod", "opens the loopback control to the users");
+            self.capability("sys_module", "loads the modules");
+        }
+
+        self.path("/proc/stat", Access::Read, "host CPU pressure");
+        self.path(
+            "/proc/self/task/*/stat",
+            Access::Read,
+            "CPU usage of the pipelines",
+        );
+        self.path(
+            "/proc/self/fdinfo/*",
+            Access::Read,
+            "GPU usage of the decoders",
+        );
+        self.socket("inet", "dgram", None, "WebRTC media");
+        self.socket("inet6", "dgram", None, "WebRTC media");
+        //the decoders are probed at every startup, used with hw_decoding
+        self.path("/dev/dri/**", Access::ReadWrite, "hardware decoders");
+
+        if config.turn.is_some() {
+            self.program("curl", "TURN credentials");
+            self.socket("inet", "stream", None, "TURN credentials");
+        }
+        if config.tracing.otlp_endpoint.is_some() {
+            self.program("curl", "call setup traces");
+            self.socket("inet", "stream", None, "call setup traces");
+        }
+        if config.session_firewall {
+            self.program("nft", "media ports of the calls");
+            self.capability("net_admin", "media ports of the calls");
+        }
+        if config.rtsp.enabled {
+            self.socket(
+                "inet",
+                "stream",
+                Some(format!("0.0.0.0:{}", config.rtsp.port)),
+                "RTSP output",
+            );
+        }
+        //any mobile can be set to always record
+        self.path(
+            &format!("{}/**", config.recording.directory.display()),
+            Access::ReadWrite,
+            "recordings of the cameras",
+        );
+        if !config.outputs.is_empty() {
+            self.socket("inet", "dgram", None, "SRT and NDI outputs");
+            self.socket("inet", "stream", None, "SRT and NDI outputs");
+        }
+    }
+
+    //the same path is listed once, with the widest access
+    fn path(&mut self, path: &str, access: Access, reason: &'static str) {
+        match self.paths.iter_mut().find(|known| known.path == path) {
+            Some(known) if access == Access::ReadWrite => known.access = access,
+            Some(_) => {}
+            None => self.paths.push(AuditPath {
+                path: path.to_string(),
+                access,
+                reason,
+            }),
+        }
+    }
+
+    fn socket(
+        &mut self, domain: &'static str, kind: &'static str,
+        address: Option<String>, reason: &'static str,
+    ) {
+        self.sockets.push(AuditSocket { domain, kind, address, reason });
+    }
+
+    fn module(&mut self, name: &str, reason: &'static str) {
+        self.modules.push(AuditItem { name: name.to_string(), reason });
+    }
+
+    fn program(&mut self, name: &str, reason: &'static str) {
+        self.programs.push(AuditItem { name: name.to_string(), reason });
+    }
+
+    fn capability(&mut self, name: &str, reason: &'static str) {
+        if !self.capabilities.iter().any(|known| known.name == name) {
+            self.capabilities
+                .push(AuditItem { name: name.to_string(), reason });
+        }
+    }
+
+    /// Logs the resources, one per line
+    pub fn log(&self) {
+        info!("Resources used with this config:");
+        for path in self.paths.iter() {
+            info!("  path {} ({:?}): {}", path.path, path.access, path.reason);
+        }
+        for socket in self.sockets.iter() {
+            let address = socket.address.as_deref().unwrap_or("any");
+            info!(
+                "  socket {} {} {}: {}",
+                socket.domain, socket.kind, address, socket.reason
+            );
+        }
+        for module in self.modules.iter() {
+            info!("  module {}: {}", module.name, module.reason);
+        }
+        for program in self.programs.iter() {
+            info!("  program {}: {}", program.name, program.reason);
+        }
+        for capability in self.capabilities.iter() {
+            info!("  capability {}: {}", capability.name, capability.reason);
+        }
+    }
+
+    /// AppArmor profile of the host binary at `binary`, the programs run
+    /// under their own profile or unconfined
+    pub fn apparmor_profile(&self, binary: &Path) -> String {
+        let mut profile = String::new();
+        let _ = writeln!(
+            profile,
+            "# Generated by `webcam-direct-linux audit \
+             --generate-apparmor-profile`, regenerate it when the config \
+             changes\nabi <abi/3.0>,\ninclude <tunables/global>\n\n\
+             profile webcam-direct-linux {} {{\n  \
+             include <abstractions/base>",
+            binary.display()
+        );
+
+        for capability in self.capabilities.iter() {
+            let _ = writeln!(profile, "  capability {},", capability.name);
+        }
+
+        let mut networks = Vec::new();
+        for socket in self.sockets.iter() {
+            let network =
+                format!("  network {} {},", socket.domain, socket.kind);
+            if !networks.contains(&network) {
+                networks.push(network);
+            }
+        }
+        for network in networks {
+            let _ = writeln!(profile, "{}", network);
+        }
+        if self.paths.iter().any(|path| path.path == SYSTEM_BUS) {
+            let _ = writeln!(profile, "  dbus bus=system,");
+        }
+
+        for path in self.paths.iter() {
+            let _ = writeln!(
+                profile,
+                "  {} {},",
+                apparmor_path(&path.path),
+                path.access.apparmor()
+            );
+        }
+        for program in self.programs.iter() {
+            let _ = writeln!(profile, "  {} PUx,", program_path(&program.name));
+        }
+
+        profile.push_str("}\n");
+        profile
+    }
+}
+
+//the own process is seen through its pid by AppArmor
+fn apparmor_path(path: &str) -> String {
+    match path.strip_prefix("/proc/self/") {
+        Some(rest) => format!("owner @{{PROC}}/@{{pid}}/{}", rest),
+        None => path.to_string(),
+    }
+}
+
+//full path of the program, both bin directories if it's not installed
+fn program_path(name: &str) -> String {
+    let path_dirs: Vec<PathBuf> = env::var_os("PATH")
+        .map(|path| env::split_paths(&path).collect())
+        .unwrap_or_default();
+
+    path_dirs
+        .into_iter()
+        .chain(SBIN_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| format!("/usr/{{,s}}bin/{}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LogFileConfig, PowerConfig};
+
+    #[test]
+    fn test_audit_follows_config() {
+        let config = AppConfig {
+            power: PowerConfig { enabled: false, ..Default::default() },
+            log_file: Some(LogFileConfig {
+                path: PathBuf::from("/var/log/wcd.log"),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let audit = Audit::for_config(&config, Path::new("/var/lib/wcd"), None);
+        assert!(!audit
+            .paths
+            .iter()
+            .any(|path| path.path.starts_with("/sys/class/power_supply")));
+
+        let profile =
+            audit.apparmor_profile(Path::new("/usr/bin/webcam-direct-linux"));
+        assert!(profile.contains(
+            "profile webcam-direct-linux /usr/bin/webcam-direct-linux {"
+        ));
+        assert!(profile.contains("  /var/lib/wcd/** rwk,\n"));
+        assert!(profile.contains("  /var/log/wcd.log* rwk,\n"));
+        assert!(profile.contains("  /dev/dri/** rw"));
+        assert!(profile.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,315 @@
+//! # Bluetooth adapters of the host.
+//! The GATT services are served on every adapter, but only one adapter
+//! advertises at a time. The advertisement moves to the next adapter on
+//! every connection, so the mobiles are spread round-robin over the
+//! adapters and the connection limit of a single controller stops capping
+//! the concurrent users. The adapters that can't advertise are skipped,
+//! they only serve the mobiles that know the host from its pairing code.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use bluer::{adv::Advertisement, Adapter};
+use log::{error, info, warn};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::ble::{
+    api::Address,
+    comm_types::{AdapterMode, AdapterStatus},
+};
+use crate::config::AdvertisingFallback;
+use crate::error::Result;
+use crate::retry::{retry, RetryPolicy};
+
+/// Mode of a powered adapter, the controllers without a free advertising
+/// instance serve the GATT services only, or fail with `fallback`
+pub async fn probe_mode(
+    adapter: &Adapter, fallback: AdvertisingFallback,
+) -> Result<AdapterMode> {
+    let instances = adapter.supported_advertising_instances().await;
+    if matches!(instances, Ok(free) if free > 0) {
+        return Ok(AdapterMode::Advertising);
+    }
+
+    match fallback {
+        AdvertisingFallback::GattOnly => {
+            warn!(
+                "Adapter {} can't advertise ({:?}), the mobiles find the \
+                 host only with the pairing code",
+                adapter.name(),
+                instances
+            );
+            Ok(AdapterMode::GattOnly)
+        }
+        AdvertisingFallback::Fail => Err(anyhow!(
+            "Adapter {} can't advertise: {:?}",
+            adapter.name(),
+            instances
+        )),
+    }
+}
+
+/// Adapters sharing the mobiles and the address owned by each of them
+pub struct AdapterPool {
+    names: Vec<String>,
+    //index of the adapter advertising
+    turn: watch::Sender<usize>,
+    owners: Mutex<HashMap<Address, String>>,
+    //mode of every adapter, in the order of the names
+    status: watch::Sender<Vec<AdapterStatus>>,
+}
+
+impl AdapterPool {
+    /// Pool of the adapters with their mode at startup, the modes are
+    /// published on `status` as they change
+    pub fn new(
+        adapters: Vec<AdapterStatus>, status: watch::Sender<Vec<AdapterStatus>>,
+    ) -> Arc<Self> {
+        let names =
+            adapters.iter().map(|adapter| adapter.name.clone()).collect();
+        status.send_replace(adapters);
+
+        let pool = Self {
+            names,
+            turn: watch::Sender::new(0),
+            owners: Mutex::new(HashMap::new()),
+            status,
+        };
+        if let Some(first) = pool.advertising_from(0) {
+            pool.turn.send_replace(first);
+        }
+
+        Arc::new(pool)
+    }
+
+    pub fn mode(&self, adapter: &str) -> AdapterMode {
+        self.status
+            .borrow()
+            .iter()
+            .find(|status| status.name == adapter)
+            .map(|status| status.mode)
+            .unwrap_or_default()
+    }
+
+    /// Changes the mode of the adapter, the advertisement moves to the next
+    /// adapter if it was its turn
+    pub fn set_mode(&self, adapter: &str, mode: AdapterMode) {
+        let Some(index) = self.names.iter().position(|name| name == adapter)
+        else {
+            return;
+        };
+
+        info!("Adapter {} mode: {:?}", adapter, mode);
+        self.status.send_modify(|status| {
+            if let Some(status) = status.get_mut(index) {
+                status.mode = mode;
+            }
+        });
+
+        if *self.turn.borrow() == index && mode != AdapterMode::Advertising {
+            if let Some(next) = self.advertising_from(index + 1) {
+                self.turn.send_replace(next);
+            }
+        }
+    }
+
+    //first adapter advertising from `start` in the round-robin order
+    fn advertising_from(&self, start: usize) -> Option<usize> {
+        let status = self.status.borrow();
+        (0..self.names.len())
+            .map(|offset| (start + offset) % self.names.len())
+            .find(|index| {
+                status.get(*index).is_some_and(|adapter| {
+                    adapter.mode == AdapterMode::Advertising
+                })
+            })
+    }
+
+    /// Records the adapter owning the address and passes the advertisement
+    /// to the next adapter
+    pub fn connected(&self, adapter: &str, addr: Address) {
+        let Ok(mut owners) = self.owners.lock() else {
+            return;
+        };
+
+        if owners.insert(addr.clone(), adapter.to_string()).is_some() {
+            return;
+        }
+
+        let on_adapter =
+            owners.values().filter(|owner| *owner == adapter).count();
+        info!(
+            "Mobile {} connected on {}, {} mobiles on it",
+            addr, adapter, on_adapter
+        );
+
+        if self.names.len() > 1 {
+            self.turn.send_modify(|turn| {
+                if let Some(next) = self.advertising_from(*turn + 1) {
+                    *turn = next;
+                }
+            });
+        }
+    }
+
+    /// Forgets the address, returns the adapter that owned it
+    pub fn disconnected(&self, addr: &Address) -> Option<String> {
+        self.owners.lock().ok()?.remove(addr)
+    }
+
+    /// Keeps the advertisement on the adapter while it is its turn and it
+    /// can advertise, it is withdrawn when the returned handle is dropped
+    pub fn advertise(
+        &self, adapter: Adapter, le_advertisement: Advertisement,
+    ) -> AdvertiserHandle {
+        self.advertise_updated(adapter, watch::channel(le_advertisement).1)
+    }
+
+    /// Like `advertise`, advertising again whenever `content` changes
+    pub fn advertise_updated(
+        &self, adapter: Adapter, mut content: watch::Receiver<Advertisement>,
+    ) -> AdvertiserHandle {
+        let index = self
+            .names
+            .iter()
+            .position(|name| name == adapter.name())
+            .unwrap_or_default();
+        let mut turn = self.turn.subscribe();
+        let mut status = self.status.subscribe();
+
+        let task = tokio::spawn(async move {
+            let mut adv_handle = None;
+            loop {
+                let is_turn = *turn.borrow_and_update() == index
+                    && status.borrow_and_update().get(index).is_some_and(
+                        |adapter| adapter.mode == AdapterMode::Advertising,
+                    );
+
+                if !is_turn {
+                    adv_handle = None;
+                } else if adv_handle.is_none() {
+                    info!("Advertising on adapter {}", adapter.name());
+                    let what = format!("Advertising on {}", adapter.name());
+                    let le_advertisement = content.borrow_and_update().clone();
+                    match retry(&what, RetryPolicy::BOOT, || {
+                        adapter.advertise(le_advertisement.clone())
+                    })
+                    .await
+                    {
+                        Ok(handle) => adv_handle = Some(handle),
+                        Err(e) => {
+                            error!(
+                                "Failed to advertise on adapter {}: {:?}",
+                                adapter.name(),
+                                e
+                            );
+                        }
+                    }
+                }
+
+                let changed = tokio::select! {
+                    changed = turn.changed() => changed,
+                    changed = status.changed() => changed,
+                    //the static content has no sender left
+                    Ok(()) = content.changed() => {
+                        adv_handle = None;
+                        Ok(())
+                    }
+                };
+                if changed.is_err() {
+                    break;
+                }
+            }
+        });
+
+        AdvertiserHandle { task }
+    }
+
+    #[cfg(test)]
+    fn turn(&self) -> usize {
+        *self.turn.borrow()
+    }
+}
+
+pub struct AdvertiserHandle {
+    task: JoinHandle<()>,
+}
+
+impl Drop for AdvertiserHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(adapters: &[(&str, AdapterMode)]) -> Arc<AdapterPool> {
+        let adapters = adapters
+            .iter()
+            .map(|(name, mode)| AdapterStatus {
+                name: name.to_string(),
+                mode: *mode,
+            })
+            .collect();
+        AdapterPool::new(adapters, watch::Sender::new(Vec::new()))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(&[
+            ("hci0", AdapterMode::Advertising),
+            ("hci1", AdapterMode::Advertising),
+        ]);
+        assert_eq!(pool.turn(), 0);
+
+        pool.connected("hci0", "AA:AA:AA:AA:AA:AA".into());
+        assert_eq!(pool.turn(), 1);
+
+        //already connected, the turn is kept
+        pool.connected("hci0", "AA:AA:AA:AA:AA:AA".into());
+        assert_eq!(pool.turn(), 1);
+
+        pool.connected("hci1", "BB:BB:BB:BB:BB:BB".into());
+        assert_eq!(pool.turn(), 0);
+
+        assert_eq!(
+            pool.disconnected(&"BB:BB:BB:BB:BB:BB".into()),
+            Some("hci1".to_string())
+        );
+        assert_eq!(pool.disconnected(&"BB:BB:BB:BB:BB:BB".into()), None);
+    }
+
+    #[test]
+    fn test_single_adapter() {
+        let pool = pool(&[("hci0", AdapterMode::Advertising)]);
+
+        pool.connected("hci0", "AA:AA:AA:AA:AA:AA".into());
+        assert_eq!(pool.turn(), 0);
+    }
+
+    #[test]
+    fn test_adapters_without_advertising_skipped() {
+        let pool = pool(&[
+            ("hci0", AdapterMode::GattOnly),
+            ("hci1", AdapterMode::Advertising),
+            ("hci2", AdapterMode::Advertising),
+        ]);
+        assert_eq!(pool.turn(), 1);
+
+        pool.connected("hci1", "AA:AA:AA:AA:AA:AA".into());
+        assert_eq!(pool.turn(), 2);
+        pool.connected("hci2", "BB:BB:BB:BB:BB:BB".into());
+        assert_eq!(pool.turn(), 1);
+
+        //the advertisement moves on from a failed adapter
+        pool.set_mode("hci1", AdapterMode::Failed);
+        assert_eq!(pool.turn(), 2);
+        assert_eq!(pool.mode("hci1"), AdapterMode::Failed);
+        assert_eq!(pool.status.borrow()[1].mode, AdapterMode::Failed);
+    }
+}
@@ -1,3 +1,4 @@
+use super::comm_types::QueryOffset;
 use crate::error::Result;
 use tokio::sync::{broadcast, oneshot};
 
@@ -15,6 +16,11 @@ pub struct QueryReq {
     pub query_type: QueryApi,
     /// Maximum length of the buffer.
     pub resp_buffer_len: usize,
+    /// Bypass the server cache, applied when a new read starts
+    pub force_refresh: bool,
+    /// Mobile and bytes already received, the data is kept for a while
+    /// after a disconnect so the read can resume
+    pub offset: Option<QueryOffset>,
 }
 
 /// Type alias for a query response.
@@ -93,6 +99,27 @@ pub enum CmdApi {
     RegisterMobile,
     /// Mobile PNP ID command and sdp offer.
     SdpOffer,
+    /// Mobile acknowledges the sdp answer ready notification.
+    SdpAnswerAck,
+    /// Registered mobile updates the host settings.
+    UpdateHostSettings,
+    /// Registered mobile starts a LAN link test.
+    RunLinkTest,
+    /// Mobile answers the host sdp offers.
+    SdpReply,
+    /// Mobile switches the camera feeding a virtual device.
+    RemapCamera,
+    /// Mobile asks for a handover of its call to another host.
+    RequestHandover,
+    /// Mobile reports its new session to the previous host of a handover.
+    CompleteHandover,
+}
+
+impl CmdApi {
+    /// Commands sent without a body, their payload is ignored
+    pub fn has_payload(&self) -> bool {
+        !matches!(self, CmdApi::MobileDisconnected)
+    }
 }
 
 /// Enum representing different BLE query APIs.
@@ -102,6 +129,14 @@ pub enum QueryApi {
     HostInfo,
     ///Query to read sdp offer.
     SdpAnswer,
+    /// Query to read the host diagnostics, no registration required.
+    Diagnostics,
+    /// Query to read the link test port and results.
+    LinkTest,
+    /// Query to read the privacy switch state.
+    Privacy,
+    /// Query to read the host battery level and AC status.
+    PowerState,
 }
 
 /// Enum representing different PubSub topics.
@@ -109,4 +144,16 @@ pub enum QueryApi {
 pub enum PubSubTopic {
     /// Notify the mobile that the answer is ready for him.
     SdpAnswerReady,
+    /// Warn a guest mobile that its session is about to expire.
+    SessionExpiry,
+    /// Notify the mobiles that the privacy switch changed.
+    Privacy,
+    /// Notify the mobiles that the host power state changed.
+    PowerState,
+    /// Notify the mobiles that the host info changed.
+    HostInfo,
+    /// Report to the mobile the failures of its acknowledged commands.
+    OperationFailed,
+    /// Notify the mobile of the stages of its handovers.
+    Handover,
 }
@@ -5,9 +5,50 @@ pub const SERV_PROV_INFO_UUID: Uuid =
     Uuid::from_u128(0x124ddac5b10746a0ade04ae8b2b700f5); //service
 pub const CHAR_PROV_INFO_UUID: Uuid =
     Uuid::from_u128(0x124ddac6b10746a0ade04ae8b2b700f5); //characteristic to read host info
+pub const CHAR_DIAGNOSTICS_UUID: Uuid =
+    Uuid::from_u128(0x124ddac9b10746a0ade04ae8b2b700f5); //characteristic to read host health
+pub const CHAR_PROV_INFO_REFRESH_UUID: Uuid =
+    Uuid::from_u128(0x124ddaccb10746a0ade04ae8b2b700f5); //written to read the host info again bypassing the cache
 
 //Webrtc SDP offer and answer
 // The service for this characteristic will be the same host Id
 // that way I can filter out for only that host from the mobiles
 pub const CHAR_PNP_EXCHANGE_SDP_UUID: Uuid =
     Uuid::from_u128(0x124ddac7b10746a0ade04ae8b2b700f5);
+
+//Acknowledge from the mobile that the sdp answer ready notification was processed
+pub const CHAR_SDP_ANSWER_ACK_UUID: Uuid =
+    Uuid::from_u128(0x124ddac8b10746a0ade04ae8b2b700f5);
+
+//Host settings pushed from a registered mobile
+pub const CHAR_HOST_SETTINGS_UUID: Uuid =
+    Uuid::from_u128(0x124ddacab10746a0ade04ae8b2b700f5);
+
+//LAN link test, written to start it and read for the port and results
+pub const CHAR_LINK_TEST_UUID: Uuid =
+    Uuid::from_u128(0x124ddacbb10746a0ade04ae8b2b700f5);
+
+//Notified to guest mobiles before their session expires
+pub const CHAR_SESSION_EXPIRY_UUID: Uuid =
+    Uuid::from_u128(0x124ddacdb10746a0ade04ae8b2b700f5);
+
+//Answers of the mobile to the host offers, when the host drives the negotiation
+pub const CHAR_SDP_REPLY_UUID: Uuid =
+    Uuid::from_u128(0x124ddaceb10746a0ade04ae8b2b700f5);
+
+//Privacy switch state, read on connection and notified on every change
+pub const CHAR_PRIVACY_UUID: Uuid =
+    Uuid::from_u128(0x124ddacfb10746a0ade04ae8b2b700f5);
+
+//Host battery level and AC status, read on connection and notified on every change
+pub const CHAR_POWER_STATE_UUID: Uuid =
+    Uuid::from_u128(0x124ddad0b10746a0ade04ae8b2b700f5);
+
+//Virtual channels of every API of the call service, written and notified
+pub const CHAR_TRANSPORT_UUID: Uuid =
+    Uuid::from_u128(0x124ddad1b10746a0ade04ae8b2b700f5);
+
+//Service data of the host advertisement with its free camera slots, a
+//16-bit uuid so it fits next to the host id
+pub const CAMERA_SLOTS_UUID: Uuid =
+    Uuid::from_u128(0x0000fff000001000800000805f9b34fb);
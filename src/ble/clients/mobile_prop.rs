@@ -1,11 +1,12 @@
 //! Discover Bluetooth devices and list them.
 use crate::{
-    ble::{api::CmdApi, requester::BleRequester},
+    ble::{adapters::AdapterPool, api::CmdApi, requester::BleRequester},
     error::Result,
 };
 use bluer::{Adapter, AdapterEvent, DeviceEvent, DeviceProperty};
 use futures::{pin_mut, stream::SelectAll, StreamExt};
 use log::{info, trace};
+use std::sync::Arc;
 
 use tokio::sync::oneshot;
 
@@ -14,12 +15,17 @@ pub struct MobilePropClient {
 }
 
 impl MobilePropClient {
-    pub fn new(ble_adapter: Adapter, server_conn: BleRequester) -> Self {
+    pub fn new(
+        ble_adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+        server_conn: BleRequester,
+    ) -> Self {
         info!("Starting MobilePropClient");
 
         let (tx, rx) = oneshot::channel();
         tokio::spawn(async move {
-            if let Err(e) = device_props(ble_adapter, server_conn, rx).await {
+            if let Err(e) =
+                device_props(ble_adapter, adapter_pool, server_conn, rx).await
+            {
                 info!("MobilePropClient failed: {:?}", e);
             }
         });
@@ -29,7 +35,8 @@ impl MobilePropClient {
 }
 
 pub async fn device_props(
-    adapter: Adapter, server_conn: BleRequester, mut _rx: oneshot::Receiver<()>,
+    adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+    server_conn: BleRequester, mut _rx: oneshot::Receiver<()>,
 ) -> Result<()> {
     //let filter_addr: HashSet<_> = env::args().filter_map(|arg| arg.parse::<Address>().ok()).collect();
 
@@ -76,7 +83,11 @@ pub async fn device_props(
             Some((addr, DeviceEvent::PropertyChanged(property))) = all_change_events.next() => {
                 trace!("Property Device changed: {addr}");
                 trace!("    {property:?}");
+                if let DeviceProperty::Connected(true) = property {
+                    adapter_pool.connected(adapter.name(), addr.to_string());
+                }
                 if let DeviceProperty::Connected(false) = property {
+                    adapter_pool.disconnected(&addr.to_string());
                     if let Err(e)  = server_conn.cmd(addr.to_string(), CmdApi::MobileDisconnected, vec![]).await{
                         info!("Failed to send mobile disconnected: {:?}", e);
                     } else if let Err(e) = adapter.remove_device(addr).await {
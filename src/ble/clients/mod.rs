@@ -2,3 +2,5 @@ pub mod gatt_uuids;
 pub mod mobile_prop;
 pub mod provisioner;
 pub mod sdp_exchanger;
+pub mod transport;
+pub mod write_sessions;
@@ -1,6 +1,14 @@
 //! Serves a Bluetooth GATT application using the IO programming model.
-use super::gatt_uuids::{CHAR_PROV_INFO_UUID, SERV_PROV_INFO_UUID};
+use super::gatt_uuids::{
+    CHAR_DIAGNOSTICS_UUID, CHAR_PROV_INFO_REFRESH_UUID, CHAR_PROV_INFO_UUID,
+    SERV_PROV_INFO_UUID,
+};
+use super::write_sessions::WriteSessions;
+use crate::ble::adapters::AdapterPool;
 use crate::ble::api::{CmdApi, QueryApi};
+use crate::ble::comm_types::{
+    host_group_tag, AdapterMode, HOST_GROUP_COMPANY_ID,
+};
 use crate::ble::requester::BleRequester;
 use crate::error::Result;
 use bluer::gatt::local::{
@@ -15,9 +23,10 @@ use bluer::{
     },
     Adapter,
 };
-use futures::{future, pin_mut, FutureExt, StreamExt};
+use futures::{pin_mut, FutureExt, StreamExt};
 use log::{error, info};
-use tokio::io::AsyncReadExt;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 use tokio::sync::oneshot::{self, Receiver};
 
 pub struct ProvisionerClient {
@@ -26,15 +35,31 @@ pub struct ProvisionerClient {
 
 impl ProvisionerClient {
     pub fn new(
-        ble_adapter: Adapter, server_conn: BleRequester, host_name: String,
+        ble_adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+        server_conn: BleRequester, host_name: String,
+        host_group: Option<String>, diagnostics_enabled: bool,
     ) -> Self {
         let (_tx_drop, _rx_drop) = oneshot::channel();
 
         tokio::spawn(async move {
-            if let Err(e) =
-                provisioner(ble_adapter, _rx_drop, server_conn, host_name).await
+            let adapter_name = ble_adapter.name().to_string();
+            if let Err(e) = provisioner(
+                ble_adapter,
+                adapter_pool.clone(),
+                _rx_drop,
+                server_conn,
+                host_name,
+                host_group,
+                diagnostics_enabled,
+            )
+            .await
             {
-                error!("Provisioner Client failed to start, error: {:?}", e);
+                error!(
+                    "Provisioner Client failed on adapter {}, error: {:?}",
+                    adapter_name, e
+                );
+                //reported in the diagnostics, the other adapters advertise
+                adapter_pool.set_mode(&adapter_name, AdapterMode::Failed);
             } else {
                 info!("Provisioner Client stopped");
             }
@@ -45,23 +70,25 @@ impl ProvisionerClient {
 }
 
 pub async fn provisioner(
-    adapter: Adapter, mut rx_drop: Receiver<()>, server_conn: BleRequester,
-    host_name: String,
+    adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+    mut rx_drop: Receiver<()>, server_conn: BleRequester, host_name: String,
+    host_group: Option<String>, diagnostics_enabled: bool,
 ) -> Result<()> {
-    info!(
-        "Advertising Provisioner on Bluetooth adapter {} with address {}",
-        adapter.name(),
-        adapter.address().await?
-    );
     let le_advertisement = Advertisement {
         service_uuids: vec![SERV_PROV_INFO_UUID].into_iter().collect(),
         discoverable: Some(true),
         local_name: Some(host_name),
+        //lets the mobiles recognize the hosts of their group before
+        //connecting
+        manufacturer_data: host_group
+            .iter()
+            .map(|group| {
+                (HOST_GROUP_COMPANY_ID, host_group_tag(group).to_vec())
+            })
+            .collect(),
         ..Default::default()
     };
 
-    let _adv_handle = adapter.advertise(le_advertisement).await?;
-
     info!(
         "Serving Provisioner GATT service on Bluetooth adapter {}",
         adapter.name()
@@ -71,8 +98,12 @@ pub async fn provisioner(
     let (char_provisioner_control, char_provisioner_handle) =
         characteristic_control();
 
+    //mobiles whose next host info read bypasses the cache
+    let refresh_pending = Arc::new(Mutex::new(HashSet::new()));
+    let reader_refresh_pending = refresh_pending.clone();
+
     let reader_server_requester = server_conn.clone();
-    let app = Application {
+    let mut app = Application {
         services: vec![Service {
             uuid: SERV_PROV_INFO_UUID,
             primary: true,
@@ -83,15 +114,30 @@ pub async fn provisioner(
                     fun: Box::new(move |req| {
                         let reader_server_requester =
                             reader_server_requester.clone();
+                        let addr = req.device_address.to_string();
+                        let force_refresh = reader_refresh_pending
+                            .lock()
+                            .is_ok_and(|mut pending| pending.remove(&addr));
                         async move {
-                            match reader_server_requester
-                                .query(
-                                    req.device_address.to_string(),
-                                    QueryApi::HostInfo,
-                                    req.mtu as usize,
-                                )
-                                .await
-                            {
+                            let host_info = if force_refresh {
+                                reader_server_requester
+                                    .query_refreshed(
+                                        addr,
+                                        QueryApi::HostInfo,
+                                        req.mtu as usize,
+                                    )
+                                    .await
+                            } else {
+                                reader_server_requester
+                                    .query(
+                                        addr,
+                                        QueryApi::HostInfo,
+                                        req.mtu as usize,
+                                    )
+                                    .await
+                            };
+
+                            match host_info {
                                 Ok(data) => {
                                     return Ok(data);
                                 }
@@ -154,12 +200,29 @@ pub async fn provisioner(
         ..Default::default()
     };
 
+    app.services[0]
+        .characteristics
+        .push(refresh_characteristic(refresh_pending));
+
+    if diagnostics_enabled {
+        app.services[0]
+            .characteristics
+            .push(diagnostics_characteristic(server_conn.clone()));
+    }
+
     let _app_handle = adapter.serve_gatt_application(app).await?;
 
-    let mut current_device_addr = String::new();
+    //advertised only once the services can be reached, unless the
+    //adapter can't advertise
+    info!(
+        "Advertising Provisioner on Bluetooth adapter {} with address {}",
+        adapter.name(),
+        adapter.address().await?
+    );
+    let _adv_handle = adapter_pool.advertise(adapter.clone(), le_advertisement);
 
-    let mut prov_read_buf = Vec::new();
-    let mut prov_reader_opt: Option<CharacteristicReader> = None;
+    //registrations of the mobiles writing at the same time are kept apart
+    let mut writers = WriteSessions::<CharacteristicReader>::new();
 
     pin_mut!(char_provisioner_control);
 
@@ -169,40 +232,19 @@ pub async fn provisioner(
                 match evt {
                     Some(CharacteristicControlEvent::Write(req)) => {
                         info!("Accepting write event for provisioner with MTU {} from {}", req.mtu(), req.device_address());
-                        prov_read_buf = vec![0; req.mtu()];
-                        current_device_addr = req.device_address().to_string();
-                        prov_reader_opt = Some(req.accept()?);
+                        let addr = req.device_address().to_string();
+                        let mtu = req.mtu();
+                        writers.add(addr, req.accept()?, mtu);
                     }
                     _ => {}
                 }
 
             }
-            _ = async {
-                let read_res = match &mut prov_reader_opt {
-                    Some(reader) => {
-                        reader.read(&mut prov_read_buf).await
-                    }
-                    None => future::pending().await
-                };
-
-                match read_res {
-                    Ok(0) => {
-                        info!("Provisioner writing stream ended");
-                        prov_reader_opt = None;
-                    }
-                    Ok(n) => {
-                        if let Err(e) = server_conn.cmd(current_device_addr.clone(), CmdApi::RegisterMobile, prov_read_buf[..n].to_vec()).await {
-                            error!("Error registering mobile info, {:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        error!("Error reading provisioner char, {:?}", e);
-                        prov_reader_opt = None;
-                    }
+            (addr, data) = writers.next_write() => {
+                if let Err(e) = server_conn.cmd(addr, CmdApi::RegisterMobile, data).await {
+                    error!("Error registering mobile info, {:?}", e);
                 }
-
-
-            } => {}
+            }
 
             _ = &mut rx_drop => {
                 break;
@@ -212,3 +254,63 @@ pub async fn provisioner(
 
     Ok(())
 }
+
+/// Write-only characteristic, the next host info read of the mobile is
+/// served bypassing the cache, e.g. after the mobile knows it changed
+fn refresh_characteristic(
+    refresh_pending: Arc<Mutex<HashSet<String>>>,
+) -> Characteristic {
+    Characteristic {
+        uuid: CHAR_PROV_INFO_REFRESH_UUID,
+        write: Some(CharacteristicWrite {
+            write: true,
+            method: CharacteristicWriteMethod::Fun(Box::new(
+                move |_new_value, req| {
+                    let addr = req.device_address.to_string();
+                    if let Ok(mut pending) = refresh_pending.lock() {
+                        pending.insert(addr);
+                    }
+                    async move { Ok(()) }.boxed()
+                },
+            )),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Read-only characteristic with the host health, it can be read without
+/// registration, e.g. from a generic BLE scanner app
+fn diagnostics_characteristic(server_conn: BleRequester) -> Characteristic {
+    Characteristic {
+        uuid: CHAR_DIAGNOSTICS_UUID,
+        read: Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |req| {
+                let server_conn = server_conn.clone();
+                async move {
+                    match server_conn
+                        .query(
+                            req.device_address.to_string(),
+                            QueryApi::Diagnostics,
+                            req.mtu as usize,
+                        )
+                        .await
+                    {
+                        Ok(data) => {
+                            return Ok(data);
+                        }
+                        Err(e) => {
+                            error!("Error reading diagnostics, {:?}", e);
+                        }
+                    }
+
+                    Ok(vec![])
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
@@ -1,23 +1,40 @@
-use super::gatt_uuids::CHAR_PNP_EXCHANGE_SDP_UUID;
+use super::gatt_uuids::{
+    CAMERA_SLOTS_UUID, CHAR_HOST_SETTINGS_UUID, CHAR_LINK_TEST_UUID,
+    CHAR_PNP_EXCHANGE_SDP_UUID, CHAR_POWER_STATE_UUID, CHAR_PRIVACY_UUID,
+    CHAR_SDP_ANSWER_ACK_UUID, CHAR_SDP_REPLY_UUID, CHAR_SESSION_EXPIRY_UUID,
+    CHAR_TRANSPORT_UUID,
+};
+use super::transport::transport_characteristic;
+use super::write_sessions::WriteSessions;
+use crate::ble::adapters::AdapterPool;
 use crate::ble::api::{CmdApi, PubSubTopic, QueryApi};
-use crate::ble::requester::{BleRequester, BleSubscriber};
+use crate::ble::requester::{
+    BleRequester, BleSubscriber, ChunkProgress, ChunkedMessage,
+};
 use crate::error::Result;
 use bluer::adv::Advertisement;
 use bluer::gatt::local::{
     characteristic_control, service_control, Application, Characteristic,
     CharacteristicControlEvent, CharacteristicNotify,
     CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
-    CharacteristicWriteMethod, Service,
+    CharacteristicWriteMethod, ReqError, Service,
 };
 
-use bluer::gatt::{CharacteristicReader, CharacteristicWriter};
 use bluer::Adapter;
 use bluer::Uuid;
+use futures::future::BoxFuture;
 use futures::FutureExt;
 use futures::{future, pin_mut, StreamExt};
-use log::{error, info};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::sync::oneshot::{self, Receiver};
+use tokio::sync::watch;
+
+/// Longest wait for the next chunk of a message before it is dropped
+pub(super) const CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct SdpExchangerClient {
     _tx_drop: oneshot::Sender<()>,
@@ -25,8 +42,9 @@ pub struct SdpExchangerClient {
 
 impl SdpExchangerClient {
     pub fn new(
-        ble_adapter: Adapter, server_conn: BleRequester, host_name: String,
-        host_id: String,
+        ble_adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+        server_conn: BleRequester, host_name: String, host_id: String,
+        camera_slots: watch::Receiver<u8>,
     ) -> Self {
         info!("Starting SdpExchangerClient");
 
@@ -34,10 +52,12 @@ impl SdpExchangerClient {
         tokio::spawn(async move {
             if let Err(e) = sdp_exchanger(
                 ble_adapter,
+                adapter_pool,
                 _rx_drop,
                 server_conn,
                 host_name,
                 host_id,
+                camera_slots,
             )
             .await
             {
@@ -52,8 +72,9 @@ impl SdpExchangerClient {
 }
 
 async fn sdp_exchanger(
-    ble_adapter: Adapter, mut rx_drop: Receiver<()>, server_conn: BleRequester,
-    host_name: String, host_id: String,
+    ble_adapter: Adapter, adapter_pool: Arc<AdapterPool>,
+    mut rx_drop: Receiver<()>, server_conn: BleRequester, host_name: String,
+    host_id: String, mut camera_slots: watch::Receiver<u8>,
 ) -> Result<()> {
     info!(
         "Advertising Sdp Exchanger on Bluetooth adapter {} with address {}",
@@ -61,14 +82,19 @@ async fn sdp_exchanger(
         ble_adapter.address().await?
     );
     let host_id = Uuid::parse_str(&host_id)?;
-    let le_advertisement = Advertisement {
+    //the mobiles see whether their cameras fit before connecting
+    let le_advertisement = |slots: u8| Advertisement {
         service_uuids: vec![host_id].into_iter().collect(),
+        service_data: [(CAMERA_SLOTS_UUID, vec![slots])].into(),
         discoverable: Some(true),
-        local_name: Some(host_name),
+        local_name: Some(host_name.clone()),
         ..Default::default()
     };
+    let (content, content_rx) =
+        watch::channel(le_advertisement(*camera_slots.borrow_and_update()));
 
-    let _adv_handle = ble_adapter.advertise(le_advertisement).await?;
+    let _adv_handle =
+        adapter_pool.advertise_updated(ble_adapter.clone(), content_rx);
 
     info!(
         "Serving SDP Exhange GATT service on Bluetooth adapter {}",
@@ -86,55 +112,119 @@ async fn sdp_exchanger(
         services: vec![Service {
             uuid: host_id,
             primary: true,
-            characteristics: vec![Characteristic {
-                uuid: CHAR_PNP_EXCHANGE_SDP_UUID,
-                write: Some(CharacteristicWrite {
-                    write: true,
-                    method: CharacteristicWriteMethod::Io,
-                    ..Default::default()
-                }),
-                notify: Some(CharacteristicNotify {
-                    notify: true,
-                    method: CharacteristicNotifyMethod::Io,
-                    ..Default::default()
-                }),
-                read: Some(CharacteristicRead {
-                    read: true,
-                    fun: Box::new(move |req| {
-                        let reader_server_requester =
-                            reader_server_requester.clone();
-                        info!(
+            characteristics: vec![
+                Characteristic {
+                    uuid: CHAR_PNP_EXCHANGE_SDP_UUID,
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Io,
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Io,
+                        ..Default::default()
+                    }),
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(move |req| {
+                            let reader_server_requester =
+                                reader_server_requester.clone();
+                            info!(
                             "Accepting read event for pnp with MTU {} from {}",
                             req.mtu,
                             req.device_address.to_string()
                         );
-                        async move {
-                            match reader_server_requester
-                                .query(
-                                    req.device_address.to_string(),
-                                    QueryApi::SdpAnswer,
-                                    (req.mtu as usize) - mtu_metadata_overhead,
-                                )
-                                .await
-                            {
-                                Ok(data) => {
-                                    info!("data len: {:?}", data.len());
-                                    return Ok(data);
+                            async move {
+                                match reader_server_requester
+                                    .query(
+                                        req.device_address.to_string(),
+                                        QueryApi::SdpAnswer,
+                                        (req.mtu as usize).saturating_sub(
+                                            mtu_metadata_overhead,
+                                        ),
+                                    )
+                                    .await
+                                {
+                                    Ok(data) => {
+                                        info!("data len: {:?}", data.len());
+                                        return Ok(data);
+                                    }
+                                    Err(e) => {
+                                        error!(
+                                            "Error reading sdp answer, {:?}",
+                                            e
+                                        );
+                                    }
                                 }
-                                Err(e) => {
-                                    error!("Error reading sdp answer, {:?}", e);
-                                }
-                            }
 
-                            Ok(vec![])
-                        }
-                        .boxed()
+                                Ok(vec![])
+                            }
+                            .boxed()
+                        }),
+                        ..Default::default()
                     }),
+                    control_handle: char_pnp_exchange_handle,
                     ..Default::default()
-                }),
-                control_handle: char_pnp_exchange_handle,
-                ..Default::default()
-            }],
+                },
+                cmd_characteristic(
+                    CHAR_SDP_ANSWER_ACK_UUID,
+                    CmdApi::SdpAnswerAck,
+                    server_conn.clone(),
+                ),
+                cmd_characteristic(
+                    CHAR_SDP_REPLY_UUID,
+                    CmdApi::SdpReply,
+                    server_conn.clone(),
+                ),
+                cmd_characteristic(
+                    CHAR_HOST_SETTINGS_UUID,
+                    CmdApi::UpdateHostSettings,
+                    server_conn.clone(),
+                ),
+                Characteristic {
+                    read: Some(query_read(
+                        QueryApi::LinkTest,
+                        server_conn.clone(),
+                    )),
+                    ..cmd_characteristic(
+                        CHAR_LINK_TEST_UUID,
+                        CmdApi::RunLinkTest,
+                        server_conn.clone(),
+                    )
+                },
+                notify_characteristic(
+                    CHAR_SESSION_EXPIRY_UUID,
+                    PubSubTopic::SessionExpiry,
+                    server_conn.clone(),
+                ),
+                Characteristic {
+                    read: Some(query_read(
+                        QueryApi::Privacy,
+                        server_conn.clone(),
+                    )),
+                    ..notify_characteristic(
+                        CHAR_PRIVACY_UUID,
+                        PubSubTopic::Privacy,
+                        server_conn.clone(),
+                    )
+                },
+                Characteristic {
+                    read: Some(query_read(
+                        QueryApi::PowerState,
+                        server_conn.clone(),
+                    )),
+                    ..notify_characteristic(
+                        CHAR_POWER_STATE_UUID,
+                        PubSubTopic::PowerState,
+                        server_conn.clone(),
+                    )
+                },
+                transport_characteristic(
+                    CHAR_TRANSPORT_UUID,
+                    server_conn.clone(),
+                ),
+            ],
             control_handle: service_handle,
             ..Default::default()
         }],
@@ -143,16 +233,7 @@ async fn sdp_exchanger(
 
     let _app_handle = ble_adapter.serve_gatt_application(app).await?;
 
-    //current device address
-    let mut current_device_addr = String::new();
-
-    // Webcam pnp id write event
-    let mut pnp_read_buf = Vec::new();
-    let mut pnp_reader_opt: Option<CharacteristicReader> = None;
-
-    //Webcam sdp exchange notify
-    let mut notifier_opt: Option<CharacteristicWriter> = None;
-    let mut sub_recv_opt: Option<BleSubscriber> = None;
+    let mut sessions = SdpSessions::new();
 
     pin_mut!(char_pnp_exchange_control);
 
@@ -163,28 +244,23 @@ async fn sdp_exchanger(
                     //write sdp offer
                     Some(CharacteristicControlEvent::Write(req)) => {
                         info!("Accepting write event for pnp with MTU {} from {}", req.mtu(), req.device_address());
-                        pnp_read_buf = vec![0; req.mtu()];
-                        current_device_addr = req.device_address().to_string();
-                        pnp_reader_opt = Some(req.accept()?);
+                        let addr = req.device_address().to_string();
+                        let mtu = req.mtu();
+                        sessions.writers.add(addr, req.accept()?, mtu);
                     },
 
                     //notify sdp answer
                     Some(CharacteristicControlEvent::Notify(notifier)) => {
                         info!("Accepting notify request event with MTU {} from {}", notifier.mtu(), notifier.device_address());
 
+                        let addr = notifier.device_address().to_string();
                         match server_conn.subscribe(
-                            notifier.device_address().to_string(),
+                            addr.clone(),
                             PubSubTopic::SdpAnswerReady,
                             notifier.mtu(),
                         ).await {
                             Ok(subscriber) => {
-                                if notifier_opt.is_none() {
-                                    notifier_opt = Some(notifier);
-                                }
-
-                                if sub_recv_opt.is_none() {
-                                    sub_recv_opt = Some(subscriber);
-                                }
+                                sessions.add_notifier(addr, notifier, subscriber);
                             },
                             Err(e) => {
                                 error!("Failed to subscribe to sdp call: {:?}", e);
@@ -197,64 +273,313 @@ async fn sdp_exchanger(
                 }
             }
 
-            _ = async {
-                let read_res = match &mut pnp_reader_opt {
-                    Some(reader) => reader.read(&mut pnp_read_buf).await,
-                    None => future::pending().await,
-                };
+            evt = sessions.next_event() => {
+                sessions.handle_event(&server_conn, evt).await;
+            }
 
-                match read_res {
-                    Ok(0) => {
-                        info!("Sdp Exchanger writing stream ended");
-                        pnp_reader_opt = None;
-                    }
-                    Ok(n) => {
-                        if let Err(e) = server_conn.cmd(
-                            current_device_addr.clone(),
-                            CmdApi::SdpOffer,
-                            pnp_read_buf[0..n].to_vec(),
-                        ).await {
-                            error!("Failed to send mobile pnp id: {:?}", e);
-                        }
-                    }
-                    Err(err) => {
-                        info!("Sdp Exchanges writing stream error: {}", &err);
-                        pnp_reader_opt = None;
-                    }
+            Ok(()) = camera_slots.changed() => {
+                let slots = *camera_slots.borrow_and_update();
+                debug!("Advertising {} free camera slots", slots);
+                content.send_replace(le_advertisement(slots));
+            }
+
+            _ = &mut rx_drop => {
+                break;
+            }
+
+        }
+    }
+
+    Ok(())
+}
+
+/// Event of the sdp exchange of a mobile
+#[derive(Debug)]
+enum SdpEvent {
+    Offer(String, Vec<u8>),
+    Answer(String, Result<ChunkedMessage>),
+}
+
+/// Offer writers and answer notifiers of the mobiles by device address, so
+/// the exchanges of several mobiles don't replace each other
+struct SdpSessions<R, W> {
+    writers: WriteSessions<R>,
+    notifiers: HashMap<String, (W, BleSubscriber)>,
+}
+
+impl<R, W> SdpSessions<R, W>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    fn new() -> Self {
+        Self { writers: WriteSessions::new(), notifiers: HashMap::new() }
+    }
+
+    /// The first notify session of a mobile is kept
+    fn add_notifier(
+        &mut self, addr: String, notifier: W, subscriber: BleSubscriber,
+    ) {
+        self.notifiers.entry(addr).or_insert((notifier, subscriber));
+    }
+
+    /// Next offer or answer of any mobile, pending while there is none
+    async fn next_event(&mut self) -> SdpEvent {
+        let answers =
+            self.notifiers.iter_mut().map(|(addr, (_, subscriber))| {
+                async move {
+                    let message = subscriber
+                        .recv_message(CHUNK_TIMEOUT, log_progress)
+                        .await;
+                    SdpEvent::Answer(addr.clone(), message)
+                }
+                .boxed()
+            });
+        let answers: Vec<BoxFuture<SdpEvent>> = answers.collect();
+        let next_answer = async {
+            if answers.is_empty() {
+                return future::pending().await;
+            }
+            future::select_all(answers).await.0
+        };
+
+        tokio::select! {
+            (addr, data) = self.writers.next_write() => SdpEvent::Offer(addr, data),
+            evt = next_answer => evt,
+        }
+    }
+
+    /// Forwards the offer to the server or the answer to its mobile
+    async fn handle_event(
+        &mut self, server_conn: &BleRequester, evt: SdpEvent,
+    ) {
+        match evt {
+            SdpEvent::Offer(addr, data) => {
+                if let Err(e) =
+                    server_conn.cmd(addr, CmdApi::SdpOffer, data).await
+                {
+                    error!("Failed to send mobile pnp id: {:?}", e);
                 }
-            } => {}
+            }
+            SdpEvent::Answer(addr, Ok(message)) => {
+                info!("Received message from server: {:?}", message.data);
 
-            //receive data from server
-            _ = async {
-                let sub_data = match &mut sub_recv_opt {
-                    Some(pub_recv) => pub_recv.recv().await,
-                    None => future::pending().await,
+                let Some((notifier, _)) = self.notifiers.get_mut(&addr) else {
+                    return;
                 };
+                if let Err(e) = notify_message(notifier, message).await {
+                    error!("Failed to write notify to {}: {:?}", addr, e);
+                    self.notifiers.remove(&addr);
+                }
+            }
+            SdpEvent::Answer(addr, Err(e)) => {
+                error!("Error receiving data from server: {:?}", e);
+                self.notifiers.remove(&addr);
+            }
+        }
+    }
+}
 
-                match sub_data {
-                    Ok(data) => {
-                        info!("Received data from server: {:?}", data);
+//the mobile reassembles the chunks, only complete messages are forwarded
+async fn notify_message(
+    notifier: &mut (impl AsyncWrite + Unpin), message: ChunkedMessage,
+) -> std::io::Result<()> {
+    for chunk in message.chunks {
+        notifier.write_all(&chunk).await?;
+    }
+    Ok(())
+}
 
-                        if let Some(notifier) = notifier_opt.as_mut() {
-                            if let Err(e) = notifier.write(&data).await {
-                                error!("Failed to write notify: {:?}", e);
-                                notifier_opt = None;
-                            }
+fn log_progress(progress: ChunkProgress) {
+    debug!(
+        "Received {} of {} bytes from server",
+        progress.received, progress.total
+    );
+}
+
+/// Notify-only characteristic forwarding the messages of the topic to every
+/// subscribed mobile, until the mobile stops the notifications
+fn notify_characteristic(
+    uuid: Uuid, topic: PubSubTopic, server_conn: BleRequester,
+) -> Characteristic {
+    let (control, control_handle) = characteristic_control();
+
+    tokio::spawn(async move {
+        pin_mut!(control);
+
+        while let Some(evt) = control.next().await {
+            let CharacteristicControlEvent::Notify(mut notifier) = evt else {
+                continue;
+            };
+
+            let mut subscriber = match server_conn
+                .subscribe(
+                    notifier.device_address().to_string(),
+                    topic.clone(),
+                    notifier.mtu(),
+                )
+                .await
+            {
+                Ok(subscriber) => subscriber,
+                Err(e) => {
+                    error!("Failed to subscribe to {:?}: {:?}", topic, e);
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                while let Ok(message) =
+                    subscriber.recv_message(CHUNK_TIMEOUT, log_progress).await
+                {
+                    if let Err(e) = notify_message(&mut notifier, message).await
+                    {
+                        info!("Notify session ended: {:?}", e);
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    Characteristic {
+        uuid,
+        notify: Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Io,
+            ..Default::default()
+        }),
+        control_handle,
+        ..Default::default()
+    }
+}
+
+/// Write-only characteristic forwarding every write as a command, the write
+/// fails if the command is rejected
+fn cmd_characteristic(
+    uuid: Uuid, cmd: CmdApi, server_conn: BleRequester,
+) -> Characteristic {
+    Characteristic {
+        uuid,
+        write: Some(CharacteristicWrite {
+            write: true,
+            method: CharacteristicWriteMethod::Fun(Box::new(
+                move |new_value, req| {
+                    let server_conn = server_conn.clone();
+                    let cmd = cmd.clone();
+                    async move {
+                        if let Err(e) = server_conn
+                            .cmd(req.device_address.to_string(), cmd, new_value)
+                            .await
+                        {
+                            error!("Error executing command, {:?}", e);
+                            return Err(ReqError::Failed);
                         }
+                        Ok(())
                     }
+                    .boxed()
+                },
+            )),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Read forwarding every read as a query, an empty value is returned if the
+/// query fails
+fn query_read(
+    query: QueryApi, server_conn: BleRequester,
+) -> CharacteristicRead {
+    CharacteristicRead {
+        read: true,
+        fun: Box::new(move |req| {
+            let server_conn = server_conn.clone();
+            let query = query.clone();
+            async move {
+                match server_conn
+                    .query(
+                        req.device_address.to_string(),
+                        query,
+                        req.mtu as usize,
+                    )
+                    .await
+                {
+                    Ok(data) => Ok(data),
                     Err(e) => {
-                        error!("Error receiving data from server: {:?}", e);
+                        error!("Error executing query, {:?}", e);
+                        Ok(vec![])
                     }
                 }
-
-            } => {
             }
-            _ = &mut rx_drop => {
-                break;
+            .boxed()
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::api::{BleApi, BleComm};
+    use crate::ble::requester::BlePublisher;
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_interleaved_sessions_routed_by_address() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let server_conn = BleRequester::new(tx);
+        let (offers_tx, mut offers) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(BleComm { addr, comm_api }) = rx.recv().await {
+                if let BleApi::Command(req, resp) = comm_api {
+                    offers_tx.send((addr, req.payload)).unwrap();
+                    resp.send(Ok(())).unwrap();
+                }
             }
+        });
+
+        let mut sessions = SdpSessions::<DuplexStream, DuplexStream>::new();
+        let (mut phone_a, writer_a) = duplex(64);
+        let (mut phone_b, writer_b) = duplex(64);
+        sessions.writers.add("a".to_string(), writer_a, 64);
+        sessions.writers.add("b".to_string(), writer_b, 64);
+
+        let (notifier_a, mut notified_a) = duplex(64);
+        let (notifier_b, mut notified_b) = duplex(64);
+        let answers_a = BlePublisher::new(32);
+        let answers_b = BlePublisher::new(32);
+        sessions.add_notifier(
+            "a".to_string(),
+            notifier_a,
+            BleSubscriber::new(answers_a.get_subscriber().await),
+        );
+        sessions.add_notifier(
+            "b".to_string(),
+            notifier_b,
+            BleSubscriber::new(answers_b.get_subscriber().await),
+        );
 
+        //the writes of both phones alternate, none replaces the other
+        for (addr, data) in
+            [("a", b"offer a1"), ("b", b"offer b1"), ("a", b"offer a2")]
+        {
+            let phone = if addr == "a" { &mut phone_a } else { &mut phone_b };
+            phone.write_all(data).await.unwrap();
+            let evt = sessions.next_event().await;
+            sessions.handle_event(&server_conn, evt).await;
+            let (offer_addr, offer) = offers.recv().await.unwrap();
+            assert_eq!((offer_addr.as_str(), &offer[..]), (addr, &data[..]));
         }
-    }
 
-    Ok(())
+        //the answer goes to the notifier of its phone only
+        answers_b.publish(b"answer b".to_vec()).await.unwrap();
+        let evt = sessions.next_event().await;
+        sessions.handle_event(&server_conn, evt).await;
+        let mut chunk = [0; 64];
+        assert!(notified_b.read(&mut chunk).await.unwrap() > 0);
+        let idle = Duration::from_millis(50);
+        let read_a = tokio::time::timeout(idle, notified_a.read(&mut chunk));
+        assert!(read_a.await.is_err());
+    }
 }
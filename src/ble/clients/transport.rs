@@ -0,0 +1,412 @@
+//! # Multiplexed transport.
+//! A single write and notify characteristic carrying every API of the call
+//! service as framed virtual channels. Every frame starts with the channel
+//! id and the flags, so a new API only needs a new channel instead of a new
+//! characteristic. The per-API characteristics are kept for the mobiles
+//! that don't use the transport yet.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use bluer::{
+    gatt::{
+        local::{
+            characteristic_control, Characteristic, CharacteristicControlEvent,
+            CharacteristicNotify, CharacteristicNotifyMethod,
+            CharacteristicWrite, CharacteristicWriteMethod,
+        },
+        CharacteristicReader,
+    },
+    Uuid,
+};
+use futures::{pin_mut, StreamExt};
+use log::{debug, error, info, warn};
+use tokio::{io::AsyncWriteExt, sync::mpsc, task::JoinHandle};
+
+use super::sdp_exchanger::CHUNK_TIMEOUT;
+use crate::ble::{
+    api::{CmdApi, PubSubTopic, QueryApi},
+    comm_types::QueryOffset,
+    requester::BleRequester,
+};
+use crate::error::Result;
+
+/// Length of the channel id and the flags
+pub const HEADER_LEN: usize = 2;
+
+/// Mobile to host: reads the query, runs the command with the payload or
+/// subscribes to the topic of the channel
+pub const FLAG_REQUEST: u8 = 0x01;
+/// Mobile to host: unsubscribes from the topic of the channel
+pub const FLAG_CLOSE: u8 = 0x02;
+/// Host to mobile: result of a request, empty for the commands
+pub const FLAG_RESPONSE: u8 = 0x04;
+/// Host to mobile: chunk of a message published on the topic
+pub const FLAG_EVENT: u8 = 0x08;
+/// Host to mobile: set with the response of a failed request, the payload
+/// holds the reason
+pub const FLAG_ERROR: u8 = 0x10;
+
+const KNOWN_FLAGS: u8 =
+    FLAG_REQUEST | FLAG_CLOSE | FLAG_RESPONSE | FLAG_EVENT | FLAG_ERROR;
+
+/// API carried by a virtual channel
+#[derive(Debug, Clone, PartialEq)]
+pub enum Channel {
+    Query(QueryApi),
+    Cmd(CmdApi),
+    Topic(PubSubTopic),
+}
+
+/// Ids of the virtual channels, never reused once released
+const CHANNELS: &[(u8, Channel)] = &[
+    (0x01, Channel::Query(QueryApi::SdpAnswer)),
+    (0x02, Channel::Query(QueryApi::LinkTest)),
+    (0x03, Channel::Query(QueryApi::Privacy)),
+    (0x04, Channel::Query(QueryApi::PowerState)),
+    (0x10, Channel::Cmd(CmdApi::SdpOffer)),
+    (0x11, Channel::Cmd(CmdApi::SdpAnswerAck)),
+    (0x12, Channel::Cmd(CmdApi::SdpReply)),
+    (0x13, Channel::Cmd(CmdApi::UpdateHostSettings)),
+    (0x14, Channel::Cmd(CmdApi::RunLinkTest)),
+    (0x15, Channel::Cmd(CmdApi::RemapCamera)),
+    (0x16, Channel::Cmd(CmdApi::RequestHandover)),
+    (0x17, Channel::Cmd(CmdApi::CompleteHandover)),
+    (0x20, Channel::Topic(PubSubTopic::SdpAnswerReady)),
+    (0x21, Channel::Topic(PubSubTopic::SessionExpiry)),
+    (0x22, Channel::Topic(PubSubTopic::Privacy)),
+    (0x23, Channel::Topic(PubSubTopic::PowerState)),
+    (0x24, Channel::Topic(PubSubTopic::HostInfo)),
+    (0x25, Channel::Topic(PubSubTopic::OperationFailed)),
+    (0x26, Channel::Topic(PubSubTopic::Handover)),
+];
+
+/// Looks up the API of a channel id
+pub fn channel(id: u8) -> Option<Channel> {
+    CHANNELS
+        .iter()
+        .find(|(channel_id, _)| *channel_id == id)
+        .map(|(_, channel)| channel.clone())
+}
+
+/// Frame of a virtual channel, one per write or notification
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub channel: u8,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(channel: u8, flags: u8, payload: Vec<u8>) -> Self {
+        Self { channel, flags, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buffer.push(self.channel);
+        buffer.push(self.flags);
+        buffer.extend_from_slice(&self.payload);
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self> {
+        let [channel, flags, payload @ ..] = buffer else {
+            return Err(anyhow!("Frame shorter than its header"));
+        };
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(anyhow!("Unknown frame flags {:#04x}", flags));
+        }
+
+        Ok(Self::new(*channel, *flags, payload.to_vec()))
+    }
+
+    fn error(channel: u8, reason: String) -> Self {
+        Self::new(channel, FLAG_RESPONSE | FLAG_ERROR, reason.into_bytes())
+    }
+}
+
+/// Notifiers of the connected mobiles, by address
+type Outbox = Arc<Mutex<HashMap<String, mpsc::Sender<Frame>>>>;
+
+fn send_frame(outbox: &Outbox, addr: &str, frame: Frame) {
+    let tx = outbox.lock().ok().and_then(|outbox| outbox.get(addr).cloned());
+    let Some(tx) = tx else {
+        debug!("No notifications enabled by {}, frame dropped", addr);
+        return;
+    };
+    if tx.try_send(frame).is_err() {
+        warn!("Transport notifier of {} is full, frame dropped", addr);
+    }
+}
+
+/// Runs the request of a query or command channel, the failures are
+/// returned in an error frame
+pub async fn handle_request(
+    server_conn: &BleRequester, addr: &str, frame: &Frame, mtu: usize,
+) -> Frame {
+    let id = frame.channel;
+    let result = match channel(id) {
+        Some(Channel::Query(query)) => {
+            let resp_len = mtu.saturating_sub(HEADER_LEN);
+            //a resumable read carries the bytes received by the mobile
+            if frame.payload.is_empty() {
+                server_conn.query(addr.to_string(), query, resp_len).await
+            } else {
+                match QueryOffset::try_from(frame.payload.as_slice()) {
+                    Ok(offset) => {
+                        server_conn
+                            .query_from(
+                                addr.to_string(),
+                                query,
+                                resp_len,
+                                offset,
+                            )
+                            .await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+        Some(Channel::Cmd(cmd)) => server_conn
+            .cmd(addr.to_string(), cmd, frame.payload.clone())
+            .await
+            .map(|_| vec![]),
+        Some(Channel::Topic(_)) => Err(anyhow!("Not a request channel")),
+        None => Err(anyhow!("Unknown channel {:#04x}", id)),
+    };
+
+    match result {
+        Ok(payload) => Frame::new(id, FLAG_RESPONSE, payload),
+        Err(e) => Frame::error(id, e.to_string()),
+    }
+}
+
+/// Forwards the messages of the topic as events until aborted
+async fn subscribe(
+    server_conn: &BleRequester, outbox: Outbox, addr: String, id: u8,
+    topic: PubSubTopic, mtu: usize,
+) -> Result<JoinHandle<()>> {
+    let mut subscriber = server_conn
+        .subscribe(addr.clone(), topic, mtu.saturating_sub(HEADER_LEN))
+        .await?;
+
+    Ok(tokio::spawn(async move {
+        while let Ok(message) =
+            subscriber.recv_message(CHUNK_TIMEOUT, |_| {}).await
+        {
+            for chunk in message.chunks {
+                send_frame(&outbox, &addr, Frame::new(id, FLAG_EVENT, chunk));
+            }
+        }
+    }))
+}
+
+/// Reads the frames written by a mobile until its write stream ends, the
+/// subscriptions of the mobile end with it
+async fn serve_mobile(
+    reader: CharacteristicReader, server_conn: BleRequester, outbox: Outbox,
+) {
+    let addr = reader.device_address().to_string();
+    let mtu = reader.mtu();
+    let mut subscriptions: HashMap<u8, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        let buffer = match reader.recv().await {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                info!("Transport write stream of {} ended: {:?}", addr, e);
+                break;
+            }
+        };
+        let frame = match Frame::decode(&buffer) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Invalid frame from {}: {:?}", addr, e);
+                continue;
+            }
+        };
+        let id = frame.channel;
+
+        match channel(id) {
+            Some(Channel::Topic(topic)) if frame.flags & FLAG_CLOSE != 0 => {
+                if let Some(task) = subscriptions.remove(&id) {
+                    task.abort();
+                }
+                debug!("{} unsubscribed from {:?}", addr, topic);
+            }
+            Some(Channel::Topic(topic)) => {
+                let response = match subscribe(
+                    &server_conn,
+                    outbox.clone(),
+                    addr.clone(),
+                    id,
+                    topic,
+                    mtu,
+                )
+                .await
+                {
+                    Ok(task) => {
+                        if let Some(previous) = subscriptions.insert(id, task) {
+                            previous.abort();
+                        }
+                        Frame::new(id, FLAG_RESPONSE, vec![])
+                    }
+                    Err(e) => Frame::error(id, e.to_string()),
+                };
+                send_frame(&outbox, &addr, response);
+            }
+            _ => {
+                let response =
+                    handle_request(&server_conn, &addr, &frame, mtu).await;
+                send_frame(&outbox, &addr, response);
+            }
+        }
+    }
+
+    for task in subscriptions.into_values() {
+        task.abort();
+    }
+}
+
+/// Writes the frames for a mobile until its notifications stop or a newer
+/// session replaces it
+async fn notify_mobile(
+    mut notifier: bluer::gatt::CharacteristicWriter,
+    mut frames: mpsc::Receiver<Frame>, outbox: Outbox,
+) {
+    while let Some(frame) = frames.recv().await {
+        if let Err(e) = notifier.write_all(&frame.encode()).await {
+            info!("Transport notify session ended: {:?}", e);
+            break;
+        }
+    }
+
+    //closes the sender of this session, a newer session of the mobile
+    //keeps its own
+    drop(frames);
+    if let Ok(mut outbox) = outbox.lock() {
+        outbox.retain(|_, tx| !tx.is_closed());
+    }
+}
+
+/// Write and notify characteristic serving the virtual channels of every
+/// connected mobile
+pub fn transport_characteristic(
+    uuid: Uuid, server_conn: BleRequester,
+) -> Characteristic {
+    let (control, control_handle) = characteristic_control();
+    let outbox = Outbox::default();
+
+    tokio::spawn(async move {
+        pin_mut!(control);
+
+        while let Some(evt) = control.next().await {
+            match evt {
+                CharacteristicControlEvent::Write(req) => {
+                    let reader = match req.accept() {
+                        Ok(reader) => reader,
+                        Err(e) => {
+                            error!("Failed to accept transport write: {:?}", e);
+                            continue;
+                        }
+                    };
+                    tokio::spawn(serve_mobile(
+                        reader,
+                        server_conn.clone(),
+                        outbox.clone(),
+                    ));
+                }
+                CharacteristicControlEvent::Notify(notifier) => {
+                    let (tx, rx) = mpsc::channel(32);
+                    let Ok(mut mobiles) = outbox.lock() else {
+                        error!("Transport outbox lock poisoned");
+                        continue;
+                    };
+                    mobiles.insert(notifier.device_address().to_string(), tx);
+                    drop(mobiles);
+                    tokio::spawn(notify_mobile(notifier, rx, outbox.clone()));
+                }
+            }
+        }
+    });
+
+    Characteristic {
+        uuid,
+        write: Some(CharacteristicWrite {
+            write: true,
+            write_without_response: true,
+            method: CharacteristicWriteMethod::Io,
+            ..Default::default()
+        }),
+        notify: Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Io,
+            ..Default::default()
+        }),
+        control_handle,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::api::{BleApi, BleComm};
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = Frame::new(0x10, FLAG_REQUEST, vec![1, 2, 3]);
+        let buffer = frame.encode();
+        assert_eq!(buffer, vec![0x10, FLAG_REQUEST, 1, 2, 3]);
+        assert_eq!(Frame::decode(&buffer).unwrap(), frame);
+
+        //a header alone is an empty frame
+        let empty = Frame::decode(&[0x22, FLAG_CLOSE]).unwrap();
+        assert!(empty.payload.is_empty());
+
+        assert!(Frame::decode(&[0x01]).is_err());
+        assert!(Frame::decode(&[0x01, 0x80]).is_err());
+        assert_eq!(channel(0x22), Some(Channel::Topic(PubSubTopic::Privacy)));
+        assert_eq!(channel(0xff), None);
+    }
+
+    #[tokio::test]
+    async fn test_requests_routed_to_their_api() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let server_conn = BleRequester::new(tx);
+
+        tokio::spawn(async move {
+            while let Some(BleComm { comm_api, .. }) = rx.recv().await {
+                match comm_api {
+                    BleApi::Query(req, resp) => {
+                        assert_eq!(req.query_type, QueryApi::PowerState);
+                        assert_eq!(req.resp_buffer_len, 98);
+                        resp.send(Ok(vec![9])).unwrap();
+                    }
+                    BleApi::Command(req, resp) => {
+                        assert_eq!(req.cmd_type, CmdApi::SdpAnswerAck);
+                        resp.send(Err(anyhow!("rejected"))).unwrap();
+                    }
+                    _ => panic!("unexpected request"),
+                }
+            }
+        });
+
+        let query = Frame::new(0x04, FLAG_REQUEST, vec![]);
+        let response = handle_request(&server_conn, "addr", &query, 100).await;
+        assert_eq!(response, Frame::new(0x04, FLAG_RESPONSE, vec![9]));
+
+        let cmd = Frame::new(0x11, FLAG_REQUEST, vec![1]);
+        let response = handle_request(&server_conn, "addr", &cmd, 100).await;
+        assert_eq!(response.flags, FLAG_RESPONSE | FLAG_ERROR);
+        assert_eq!(response.payload, b"rejected");
+
+        let topic = Frame::new(0x21, FLAG_REQUEST, vec![]);
+        let response = handle_request(&server_conn, "addr", &topic, 100).await;
+        assert_eq!(response.flags, FLAG_RESPONSE | FLAG_ERROR);
+    }
+}
@@ -0,0 +1,86 @@
+//! Write streams of the mobiles by device address, so a mobile writing
+//! while another one is mid-transfer doesn't replace its stream.
+
+use std::collections::HashMap;
+
+use futures::{future, FutureExt};
+use log::info;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub struct WriteSessions<R> {
+    //reader and buffer of the MTU of the write
+    readers: HashMap<String, (R, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin + Send> WriteSessions<R> {
+    pub fn new() -> Self {
+        Self { readers: HashMap::new() }
+    }
+
+    /// A new write of the mobile replaces its previous one only
+    pub fn add(&mut self, addr: String, reader: R, mtu: usize) {
+        self.readers.insert(addr, (reader, vec![0; mtu]));
+    }
+
+    /// Next write of any mobile, the stream of a mobile is dropped once it
+    /// ends. Pending while no mobile writes.
+    pub async fn next_write(&mut self) -> (String, Vec<u8>) {
+        loop {
+            if self.readers.is_empty() {
+                return future::pending().await;
+            }
+
+            let reads = self.readers.iter_mut().map(|(addr, (reader, buf))| {
+                async move {
+                    let read_res = reader.read(buf).await;
+                    (addr.clone(), read_res.map(|n| buf[..n].to_vec()))
+                }
+                .boxed()
+            });
+            let ((addr, read_res), _, _) = future::select_all(reads).await;
+
+            match read_res {
+                Ok(data) if !data.is_empty() => return (addr, data),
+                Ok(_) => info!("Writing stream of {} ended", addr),
+                Err(e) => info!("Writing stream of {} error: {}", addr, e),
+            }
+            self.readers.remove(&addr);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> Default for WriteSessions<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_interleaved_writes_kept_apart() {
+        let mut sessions = WriteSessions::<DuplexStream>::new();
+        let (mut phone_a, reader_a) = duplex(64);
+        let (mut phone_b, reader_b) = duplex(64);
+        sessions.add("a".to_string(), reader_a, 64);
+        sessions.add("b".to_string(), reader_b, 64);
+
+        phone_a.write_all(b"a1").await.unwrap();
+        assert_eq!(sessions.next_write().await, ("a".into(), b"a1".to_vec()));
+        phone_b.write_all(b"b1").await.unwrap();
+        assert_eq!(sessions.next_write().await, ("b".into(), b"b1".to_vec()));
+
+        //the end of a stream drops that mobile only
+        drop(phone_a);
+        let idle = Duration::from_millis(50);
+        assert!(timeout(idle, sessions.next_write()).await.is_err());
+        assert!(!sessions.readers.contains_key("a"));
+        phone_b.write_all(b"b2").await.unwrap();
+        assert_eq!(sessions.next_write().await, ("b".into(), b"b2".to_vec()));
+    }
+}
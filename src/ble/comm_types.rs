@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use std::{collections::BTreeMap, convert::TryFrom};
 
-use crate::app_data::MobileSchema;
+use crate::app_data::{
+    ApBand, HostSettingsSchema, LatencyProfile, MobileSchema,
+};
 
 use anyhow::anyhow;
 use std::io::Cursor;
@@ -20,6 +22,10 @@ pub fn msgpack_des<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T> {
         .map_err(|e| anyhow!("Failed to deserialize data: {}", e))
 }
 
+/// Remaining length of a chunk telling the mobile the data changed during
+/// the transfer, the received chunks are discarded and the read restarts
+pub const CHUNK_TRANSFER_RESTARTED: usize = u32::MAX as usize;
+
 /// Represents a chunk of data with remaining length and buffer.
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
@@ -48,7 +54,7 @@ impl TryFrom<DataChunk> for Vec<u8> {
 
 // SDP Offer and Answer
 /// Represents the properties of a video, including resolution and frames per second.
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
 pub struct VideoProp {
     pub resolution: (u32, u32),
     pub fps: u32,
@@ -61,11 +67,34 @@ pub struct CameraSdp {
     pub sdp: String,
 }
 
+/// Side creating the SDP offers of a call
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq,
+)]
+pub enum Negotiation {
+    #[default]
+    MobileOffer,
+    /// The host offers and the mobile answers with `MobileSdpReply`, the
+    /// camera sdps of the request are left empty
+    HostOffer,
+}
+
 /// Mobile Sdp Offer will be sent to the host to establish the connection
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct MobileSdpOffer {
     pub mobile_id: String,
     pub camera_offer: Vec<CameraSdp>,
+    /// One of the negotiations supported by the host
+    #[serde(default)]
+    pub negotiation: Negotiation,
+    /// Token of a handover from another host of the group, reported back
+    /// once every camera streams
+    #[serde(default)]
+    pub handover_token: Option<String>,
+    /// The cameras send the latency test pattern, the host measures the
+    /// latency of the call from it
+    #[serde(default)]
+    pub latency_test: bool,
 }
 
 impl TryFrom<Vec<u8>> for MobileSdpOffer {
@@ -106,12 +135,235 @@ impl TryFrom<MobileSdpAnswer> for Vec<u8> {
     }
 }
 
+/// Answers of the mobile to the host offers
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MobileSdpReply {
+    pub mobile_id: String,
+    pub camera_answer: Vec<CameraSdp>,
+}
+
+impl TryFrom<Vec<u8>> for MobileSdpReply {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        msgpack_des(&bytes)
+    }
+}
+
+impl TryFrom<MobileSdpReply> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: MobileSdpReply) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Switches the camera of the mobile feeding a virtual device during a
+/// call, the device keeps its path. The mobile swaps the track of the
+/// stream once the command is acknowledged.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CameraRemap {
+    pub mobile_id: String,
+    /// Camera feeding the device now
+    pub device: String,
+    /// Camera feeding it from now on, if it feeds another device both
+    /// devices swap their cameras
+    pub camera: String,
+}
+
+impl TryFrom<&[u8]> for CameraRemap {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<CameraRemap> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: CameraRemap) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Asks the host streaming the cameras of the mobile for a handover to
+/// another host of its group
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HandoverRequest {
+    pub mobile_id: String,
+}
+
+impl TryFrom<&[u8]> for HandoverRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<HandoverRequest> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: HandoverRequest) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Stage of a handover
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HandoverStage {
+    /// Token issued by the previous host, its cameras run until the new
+    /// host reports the session
+    Requested,
+    /// Every camera of the new session streams, reported by the new host
+    Connected,
+}
+
+/// Handover of a call between two hosts of a group. The previous host
+/// publishes the token, the mobile sends it with its offer to the new host,
+/// which publishes it back once connected, and the mobile completes the
+/// handover on the previous host with it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Handover {
+    pub mobile_id: String,
+    pub token: String,
+    pub stage: HandoverStage,
+}
+
+impl TryFrom<&[u8]> for Handover {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<Handover> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: Handover) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Version of the provisioning protocol exposed in `HostProvInfo`.
+/// Version 2 appends the protocol version, the AP credentials and the
+/// pairing token to the provisioning information. Version 3 appends the
+/// host group. Version 4 reports the readiness of every camera in the
+/// answer ready notification. Version 5 appends the supported
+/// negotiations. Version 6 adds the privacy switch state. Version 7 adds
+/// the host power state. Version 8 adds the multiplexed transport. Version 9
+/// appends the local hostname. Version 10 notifies the host info updates.
+/// Version 11 resumes the transport reads after a reconnection. Version 12
+/// reports the failures of the acknowledged operations. Version 13 remaps
+/// the cameras feeding the virtual devices during a call. Version 14 reports
+/// the devices another camera software writes to. Version 15 hands the
+/// calls over between the hosts of a group. Version 16 appends the clock
+/// of the host. Version 17 measures the latency of the calls in test mode.
+/// Version 18 records the cameras of the mobiles set to always record.
+/// Version 19 notifies the shutdown of the host in the power state. Version
+/// 20 advertises the free camera slots and refuses the cameras over them.
+/// Version 21 adds the hardware video decoding of the host to the host info.
+/// Version 22 numbers the answer ready notifications, the acknowledgement
+/// echoes the number.
+pub const PROTOCOL_VERSION: u32 = 22;
+
+/// Company id of the advertisement manufacturer data carrying the host
+/// group tag, reserved by the Bluetooth SIG for testing
+pub const HOST_GROUP_COMPANY_ID: u16 = 0xFFFF;
+
+/// Tag of the host group in the advertisement, 32-bit FNV-1a of the group
+/// id so it fits next to the service uuid
+pub fn host_group_tag(group: &str) -> [u8; 4] {
+    let hash = group.bytes().fold(0x811c9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+
+    hash.to_be_bytes()
+}
+
+/// Camera slots advertised by a host without camera budget
+pub const UNLIMITED_CAMERA_SLOTS: u8 = u8::MAX;
+
+/// Free camera slots in the service data of the advertisement, from the
+/// budget of the host and the cameras in use
+pub fn camera_slots(budget: Option<u32>, in_use: u32) -> u8 {
+    match budget {
+        Some(budget) => {
+            budget.saturating_sub(in_use).min(UNLIMITED_CAMERA_SLOTS as u32 - 1)
+                as u8
+        }
+        None => UNLIMITED_CAMERA_SLOTS,
+    }
+}
+
+/// Credentials of the host access point shared while pairing
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ApCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
 /// Provisioning information of the host
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct HostProvInfo {
     pub id: String,
     pub name: String,
     pub connection_type: String,
+    /// Provisioning protocol version
+    #[serde(default)]
+    pub version: u32,
+    /// Access point credentials, only filled while pairing mode is active
+    #[serde(default)]
+    pub ap_creds: Option<ApCredentials>,
+    /// Pairing token, only filled while pairing mode is active
+    #[serde(default)]
+    pub pairing_token: Option<String>,
+    /// Group of hosts sharing the imported pairing data, a mobile paired
+    /// with one of them is known by the others
+    #[serde(default)]
+    pub host_group: Option<String>,
+    /// Negotiations supported by the host, the mobile picks one in its
+    /// offer
+    #[serde(default)]
+    pub negotiations: Vec<Negotiation>,
+    /// Name resolving to the host on its access point, for the endpoints
+    /// reached over the LAN
+    #[serde(default)]
+    pub local_hostname: Option<String>,
+    /// Clock of the host when the read started
+    #[serde(default)]
+    pub clock: Option<HostClock>,
+    /// Video decoding of the host, probed at startup
+    #[serde(default)]
+    pub capabilities: HostCapabilities,
+}
+
+/// Hardware video decoding found on the host, the mobile prefers the codecs
+/// decoded in hardware in its offer
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostCapabilities {
+    /// A VA-API decoder opened its device
+    pub vaapi: bool,
+    /// A Vulkan video decoder opened its device
+    pub vulkan: bool,
+    /// Codecs decoded in hardware, e.g. `H264`
+    pub hw_codecs: Vec<String>,
+}
+
+/// Clock of the host, a mobile with a skewed clock takes its offset from
+/// it to check the expiry of the sessions and to line up the stats
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq,
+)]
+pub struct HostClock {
+    /// Wall clock, milliseconds since the Unix epoch
+    pub wall_ms: u64,
+    /// Milliseconds since the host booted, unaffected by the wall clock
+    /// changes, a lower value than in a previous read tells a reboot
+    pub monotonic_ms: u64,
 }
 
 impl TryFrom<Vec<u8>> for HostProvInfo {
@@ -130,10 +382,38 @@ impl TryFrom<HostProvInfo> for Vec<u8> {
     }
 }
 
-/// Call notification to mobile that the answer is ready
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+/// Build state of a camera of the call
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq,
+)]
+pub enum CameraState {
+    #[default]
+    Pending,
+    /// The answer of the camera can be read
+    Ready,
+    Failed,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CameraReadiness {
+    pub name: String,
+    pub state: CameraState,
+}
+
+/// Call notification to mobile that answers are ready, published again
+/// every time a camera is built
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SdpAnswerReady {
     pub mobile_id: String,
+    /// State of every camera of the offer, missing in the acknowledgements
+    /// of mobiles older than protocol version 4
+    #[serde(default)]
+    pub cameras: Vec<CameraReadiness>,
+    /// Number of the notification in the connection of the mobile, from 1,
+    /// echoed in the acknowledgement. 0 in the acknowledgements of mobiles
+    /// older than protocol version 22
+    #[serde(default)]
+    pub seq: u32,
 }
 
 impl TryFrom<&[u8]> for SdpAnswerReady {
@@ -152,6 +432,519 @@ impl TryFrom<SdpAnswerReady> for Vec<u8> {
     }
 }
 
+/// Notification to a guest mobile that its session is about to expire,
+/// the mobile must pair again afterwards
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SessionExpiring {
+    pub mobile_id: String,
+    pub remaining_secs: u64,
+}
+
+impl TryFrom<&[u8]> for SessionExpiring {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<SessionExpiring> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: SessionExpiring) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// State of the privacy switch of the host, read on connection and notified
+/// on every change. While enabled the cameras show a placeholder frame.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PrivacyState {
+    pub enabled: bool,
+}
+
+impl TryFrom<&[u8]> for PrivacyState {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<PrivacyState> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: PrivacyState) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Power state of the host, read on connection and notified on every
+/// change, so the mobile can warn before a laptop sleeps or dies
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostPowerState {
+    /// Whether the host runs on AC power
+    pub ac_online: bool,
+    /// Battery level, None for hosts without battery
+    pub battery_percent: Option<u8>,
+    /// The host is stopping, the calls end and the mobile shouldn't
+    /// reconnect until it advertises again
+    #[serde(default)]
+    pub shutting_down: bool,
+}
+
+impl HostPowerState {
+    pub fn on_battery(&self) -> bool {
+        !self.ac_online && self.battery_percent.is_some()
+    }
+}
+
+impl TryFrom<&[u8]> for HostPowerState {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<HostPowerState> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: HostPowerState) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Notified when the host info changes while the mobile is connected, e.g.
+/// the host is renamed, the mobile reads the host info again
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostInfoUpdated {
+    /// Increases on every update since the host started
+    pub revision: u32,
+}
+
+impl TryFrom<&[u8]> for HostInfoUpdated {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<HostInfoUpdated> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: HostInfoUpdated) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Payload of a transport query request, the read resumes from `offset` if
+/// it was interrupted by a disconnect
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QueryOffset {
+    pub mobile_id: String,
+    /// Bytes of the data already received, 0 for a new read
+    pub offset: u64,
+}
+
+impl TryFrom<&[u8]> for QueryOffset {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<QueryOffset> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: QueryOffset) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Operation of the host that failed after its command was acknowledged
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailedOperation {
+    /// Build of the virtual device of a camera
+    PipelineBuild,
+    /// Start of a camera of the offer
+    StreamStart,
+    /// Stream of a running camera
+    Stream,
+}
+
+/// Machine-readable reason of a failure
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureCode {
+    /// Refused by the policy of the host
+    Denied,
+    /// The device or its pipeline could not be built
+    BuildFailed,
+    /// The pipeline stopped with an error
+    PipelineError,
+    /// Another camera software writes to the devices of the host
+    DeviceConflict,
+    /// No free camera slot on the host
+    Busy,
+}
+
+/// Build failure of a device written by another producer, e.g. the virtual
+/// camera of OBS, reported with `FailureCode::DeviceConflict`
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("{device} is in use by {holders}, close it or pass another device")]
+pub struct DeviceConflict {
+    pub device: String,
+    /// Names of the processes writing to the device
+    pub holders: String,
+}
+
+/// Failure of an operation the mobile may be waiting on, `camera` is None
+/// when it affects the whole call
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OperationFailed {
+    pub mobile_id: String,
+    pub operation: FailedOperation,
+    pub camera: Option<String>,
+    pub code: FailureCode,
+    /// Human-readable description, for the logs of the mobile
+    pub detail: String,
+}
+
+impl TryFrom<&[u8]> for OperationFailed {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        msgpack_des(bytes)
+    }
+}
+
+impl TryFrom<OperationFailed> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: OperationFailed) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Host health snapshot, readable without registration for support purposes
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostDiagnostics {
+    /// Host application version
+    pub version: String,
+    /// Provisioning protocol version
+    pub protocol_version: u32,
+    /// Seconds since the host started
+    pub uptime_secs: u64,
+    /// Connection type offered to the mobiles, AP when the access point is up
+    pub connection_type: String,
+    /// Whether the pairing window is open
+    pub pairing_active: bool,
+    /// Mobiles with an open session
+    pub sessions: usize,
+    /// Virtual cameras currently streaming
+    pub cameras: usize,
+    /// Resource usage of every streaming pipeline
+    #[serde(default)]
+    pub streams: Vec<StreamStats>,
+    /// Traffic of the access point interface, when the access point is up
+    #[serde(default)]
+    pub ap_link: Option<InterfaceStats>,
+    /// Sizes and failures of the requests since the host started
+    #[serde(default)]
+    pub requests: RequestMetrics,
+    /// Clock of the host when the diagnostics were read
+    #[serde(default)]
+    pub clock: Option<HostClock>,
+    /// Bluetooth adapters of the host and how they serve the mobiles
+    #[serde(default)]
+    pub ble_adapters: Vec<AdapterStatus>,
+}
+
+/// How a Bluetooth adapter serves the mobiles
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdapterMode {
+    /// Advertised in turns with the other adapters, and served
+    #[default]
+    Advertising,
+    /// Served without advertising, the controller can't advertise
+    GattOnly,
+    /// Not served, its GATT services could not be registered
+    Failed,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AdapterStatus {
+    /// Adapter name, e.g. hci0
+    pub name: String,
+    pub mode: AdapterMode,
+}
+
+/// Sizes and failures of the requests served to the mobiles
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RequestMetrics {
+    /// Bytes of the data of the reads, by query
+    pub query_bytes: BTreeMap<String, Histogram>,
+    /// Chunks of the complete reads
+    pub read_chunks: Histogram,
+    /// Chunks of the complete command writes
+    pub write_chunks: Histogram,
+    /// Failed requests by reason
+    pub failures: FailureCounts,
+}
+
+/// Distribution of the observed values
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Histogram {
+    /// Inclusive upper bounds of the buckets
+    pub bounds: Vec<u64>,
+    /// Values per bucket, the last one counts the values above the bounds
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            count: 0,
+            sum: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: u64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        if let Some(count) = self.counts.get_mut(bucket) {
+            *count += 1;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+}
+
+/// Failed requests by reason
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FailureCounts {
+    /// Writes whose chunks or payload could not be decoded
+    pub deserialize_error: u64,
+    /// Requests of mobile ids that are not registered
+    pub not_registered: u64,
+    /// Failed builds and stopped streams of the camera pipelines
+    pub pipeline_failed: u64,
+}
+
+/// Throughput and error counters of a network interface
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InterfaceStats {
+    /// Interface name
+    pub name: String,
+    /// Received and sent throughput over the last poll
+    pub rx_kbps: u32,
+    pub tx_kbps: u32,
+    /// Bytes since the interface was created
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    /// Faulty and dropped packets since the interface was created
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+}
+
+/// Resource usage of a streaming pipeline
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct StreamStats {
+    /// Virtual device name
+    pub name: String,
+    /// CPU used by the pipeline threads, 100 is a full core
+    pub cpu_percent: f32,
+    /// CPU used by the whole host process, 100 is a full core
+    pub process_cpu_percent: f32,
+    /// GPU used by the whole host process, the busiest engine of its DRM
+    /// clients, 100 is an engine always busy. None without a GPU client,
+    /// e.g. without hardware decoding.
+    #[serde(default)]
+    pub process_gpu_percent: Option<f32>,
+    /// Streaming threads of the pipeline
+    pub threads: usize,
+    /// Milliseconds from the offer to the first frame of the camera, once
+    /// it streams
+    #[serde(default)]
+    pub first_frame_ms: Option<u64>,
+    /// Label of the loopback device created on the host, the name without
+    /// the emoji and made unique
+    #[serde(default)]
+    pub device_label: Option<String>,
+    /// Latency from the capture to the virtual device, in a latency test
+    #[serde(default)]
+    pub latency: Option<LatencyStats>,
+}
+
+/// Latency decoded from the test pattern of a camera, in milliseconds
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LatencyStats {
+    /// Frames whose timestamp was decoded
+    pub samples: u64,
+    pub last_ms: u32,
+    pub min_ms: u32,
+    pub max_ms: u32,
+    pub avg_ms: f32,
+}
+
+impl TryFrom<Vec<u8>> for HostDiagnostics {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        msgpack_des(&bytes)
+    }
+}
+
+impl TryFrom<HostDiagnostics> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: HostDiagnostics) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Subset of the host settings a registered mobile can change, only the
+/// fields present are updated
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostSettingsUpdate {
+    pub mobile_id: String,
+    pub ap_band: Option<ApBand>,
+    pub latency_profile: Option<LatencyProfile>,
+    pub auto_record: Option<bool>,
+    /// Records the cameras of the sending mobile whenever they stream,
+    /// kept with its registration
+    pub always_record: Option<bool>,
+}
+
+impl HostSettingsUpdate {
+    /// The update changes the settings shared by every mobile
+    pub fn changes_host(&self) -> bool {
+        self.ap_band.is_some()
+            || self.latency_profile.is_some()
+            || self.auto_record.is_some()
+    }
+
+    /// Validates the update and applies it to the current settings
+    pub fn apply_to(&self, settings: &mut HostSettingsSchema) -> Result<()> {
+        if self.ap_band.is_none()
+            && self.latency_profile.is_none()
+            && self.auto_record.is_none()
+            && self.always_record.is_none()
+        {
+            return Err(anyhow!("Host settings update without settings"));
+        }
+
+        if let Some(ap_band) = self.ap_band {
+            settings.ap_band = ap_band;
+        }
+        if let Some(latency_profile) = self.latency_profile {
+            settings.latency_profile = latency_profile;
+        }
+        if let Some(auto_record) = self.auto_record {
+            settings.auto_record = auto_record;
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<Vec<u8>> for HostSettingsUpdate {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        msgpack_des(&bytes)
+    }
+}
+
+impl TryFrom<HostSettingsUpdate> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: HostSettingsUpdate) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+/// Link test requested by a registered mobile before the call
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LinkTestRequest {
+    pub mobile_id: String,
+    /// Test duration, capped by the host
+    pub duration_secs: u8,
+}
+
+impl TryFrom<Vec<u8>> for LinkTestRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        msgpack_des(&bytes)
+    }
+}
+
+impl TryFrom<LinkTestRequest> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: LinkTestRequest) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum LinkTestState {
+    /// The mobile sends its packets to the UDP port
+    #[default]
+    Running,
+    /// The results are final
+    Done,
+}
+
+/// Link test measured by the host
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LinkTestReport {
+    pub state: LinkTestState,
+    /// Host UDP port echoing the packets
+    pub port: u16,
+    /// Packets received
+    pub packets: u64,
+    /// Packets missing from the sequence
+    pub lost: u64,
+    /// Received bandwidth
+    pub bandwidth_kbps: u32,
+    /// Interarrival jitter
+    pub jitter_ms: f32,
+}
+
+impl TryFrom<Vec<u8>> for LinkTestReport {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        msgpack_des(&bytes)
+    }
+}
+
+impl TryFrom<LinkTestReport> for Vec<u8> {
+    type Error = anyhow::Error;
+
+    fn try_from(data: LinkTestReport) -> Result<Self, Self::Error> {
+        msgpack_ser(&data)
+    }
+}
+
 //MobileSchema
 impl TryFrom<Vec<u8>> for MobileSchema {
     type Error = anyhow::Error;
@@ -168,3 +961,55 @@ impl TryFrom<MobileSchema> for Vec<u8> {
         msgpack_ser(&data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_group_tag() {
+        //FNV-1a reference values
+        assert_eq!(host_group_tag(""), 0x811c9dc5u32.to_be_bytes());
+        assert_eq!(host_group_tag("a"), 0xe40c292cu32.to_be_bytes());
+        assert_ne!(host_group_tag("office-1"), host_group_tag("office-2"));
+    }
+
+    #[test]
+    fn test_sdp_answer_ready_without_cameras() {
+        #[derive(Serialize)]
+        struct SdpAnswerReadyV3 {
+            mobile_id: String,
+        }
+
+        let bytes =
+            msgpack_ser(&SdpAnswerReadyV3 { mobile_id: "m1".to_string() })
+                .unwrap();
+
+        assert_eq!(
+            SdpAnswerReady::try_from(bytes.as_slice()).unwrap(),
+            SdpAnswerReady {
+                mobile_id: "m1".to_string(),
+                cameras: vec![],
+                seq: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_sdp_offer_without_negotiation() {
+        #[derive(Serialize)]
+        struct MobileSdpOfferV4 {
+            mobile_id: String,
+            camera_offer: Vec<CameraSdp>,
+        }
+
+        let bytes = msgpack_ser(&MobileSdpOfferV4 {
+            mobile_id: "m1".to_string(),
+            camera_offer: vec![],
+        })
+        .unwrap();
+
+        let offer = MobileSdpOffer::try_from(bytes).unwrap();
+        assert_eq!(offer.negotiation, Negotiation::MobileOffer);
+    }
+}
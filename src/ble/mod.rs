@@ -1,5 +1,10 @@
+#[cfg(feature = "ble")]
+pub mod adapters;
 pub mod api;
+#[cfg(feature = "ble")]
 pub mod clients;
 pub mod comm_types;
+#[cfg(feature = "ble")]
+pub mod privacy;
 pub mod requester;
 pub mod server;
@@ -0,0 +1,158 @@
+//! LE privacy for the host advertisements.
+//! With privacy enabled the controller advertises with a resolvable private
+//! address that rotates periodically, the identity resolving key (IRK) is
+//! distributed to the mobiles during bonding so they can still resolve the
+//! host. BlueZ does not expose privacy over D-Bus, so it is set through the
+//! management interface with `btmgmt` while the adapter is powered off.
+
+use crate::error::Result;
+use anyhow::anyhow;
+use log::info;
+use std::process::Command;
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Trait to run Bluetooth management commands.
+#[cfg_attr(test, automock)]
+pub trait MgmtCmdOps {
+    /// Runs a management command on the adapter and returns its output.
+    fn run(&self, adapter_index: u16, args: &[&'static str]) -> Result<String>;
+}
+
+/// Runs the management commands with the `btmgmt` tool.
+pub struct BtMgmtCmd;
+
+impl MgmtCmdOps for BtMgmtCmd {
+    fn run(&self, adapter_index: u16, args: &[&'static str]) -> Result<String> {
+        let output = Command::new("btmgmt")
+            .arg("--index")
+            .arg(adapter_index.to_string())
+            .args(args)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "btmgmt {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+/// Controls the LE privacy of a Bluetooth adapter.
+pub struct LePrivacy<MgmtCmd> {
+    mgmt_cmd: MgmtCmd,
+    adapter_index: u16,
+}
+
+impl<MgmtCmd: MgmtCmdOps> LePrivacy<MgmtCmd> {
+    /// Creates the privacy controller for an adapter name like `hci0`.
+    pub fn new(mgmt_cmd: MgmtCmd, adapter_name: &str) -> Result<Self> {
+        let adapter_index = adapter_name
+            .strip_prefix("hci")
+            .and_then(|index| index.parse().ok())
+            .ok_or_else(|| anyhow!("Invalid adapter name: {}", adapter_name))?;
+
+        Ok(Self { mgmt_cmd, adapter_index })
+    }
+
+    /// Checks if the controller supports LE privacy.
+    pub fn is_supported(&self) -> Result<bool> {
+        let info = self.mgmt_cmd.run(self.adapter_index, &["info"])?;
+
+        Ok(info
+            .lines()
+            .filter(|line| line.trim_start().starts_with("supported settings"))
+            .any(|line| line.split_whitespace().any(|s| s == "privacy")))
+    }
+
+    /// Enables LE privacy, the adapter is powered off and must be powered on
+    /// afterwards for the setting to take effect.
+    pub fn enable(&self) -> Result<()> {
+        if !self.is_supported()? {
+            return Err(anyhow!(
+                "LE privacy not supported by adapter hci{}",
+                self.adapter_index
+            ));
+        }
+
+        self.mgmt_cmd.run(self.adapter_index, &["power", "off"])?;
+        self.mgmt_cmd.run(self.adapter_index, &["privacy", "on"])?;
+
+        info!("LE privacy enabled on adapter hci{}", self.adapter_index);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockall::predicate::*;
+    use mockall::Sequence;
+
+    const INFO_PRIVACY: &str = "hci0:\tPrimary controller\n\
+        \taddr 00:11:22:33:44:55 version 9 manufacturer 2 class 0x000000\n\
+        \tsupported settings: powered connectable discoverable bondable le \
+        advertising secure-conn privacy\n\
+        \tcurrent settings: powered bondable le secure-conn\n";
+
+    const INFO_NO_PRIVACY: &str = "hci0:\tPrimary controller\n\
+        \tsupported settings: powered connectable discoverable le\n\
+        \tcurrent settings: powered le privacy\n";
+
+    #[test]
+    fn test_invalid_adapter_name() {
+        assert!(LePrivacy::new(MockMgmtCmdOps::new(), "wlan0").is_err());
+    }
+
+    #[test]
+    fn test_enable_privacy() {
+        let mut mock_cmd = MockMgmtCmdOps::new();
+        let mut seq = Sequence::new();
+
+        mock_cmd
+            .expect_run()
+            .with(eq(1), function(|args: &[&str]| args == ["info"]))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(INFO_PRIVACY.to_string()));
+
+        mock_cmd
+            .expect_run()
+            .with(eq(1), function(|args: &[&str]| args == ["power", "off"]))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(String::new()));
+
+        mock_cmd
+            .expect_run()
+            .with(eq(1), function(|args: &[&str]| args == ["privacy", "on"]))
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(String::new()));
+
+        let privacy = LePrivacy::new(mock_cmd, "hci1").unwrap();
+
+        assert!(privacy.enable().is_ok());
+    }
+
+    #[test]
+    fn test_enable_privacy_not_supported() {
+        let mut mock_cmd = MockMgmtCmdOps::new();
+
+        mock_cmd
+            .expect_run()
+            .with(eq(0), function(|args: &[&str]| args == ["info"]))
+            .times(1)
+            .returning(|_, _| Ok(INFO_NO_PRIVACY.to_string()));
+
+        let privacy = LePrivacy::new(mock_cmd, "hci0").unwrap();
+
+        assert!(privacy.enable().is_err());
+    }
+}
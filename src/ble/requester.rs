@@ -1,7 +1,15 @@
 use crate::error::Result;
 use anyhow::anyhow;
-use tokio::sync::{broadcast, mpsc, oneshot};
-
+use log::{debug, error, warn};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::timeout,
+};
 
 use super::{
     api::{
@@ -9,7 +17,7 @@ use super::{
         PubSubPublisher, PubSubSubscriber, PubSubTopic, QueryApi, QueryReq,
         SubReq,
     },
-    comm_types::DataChunk,
+    comm_types::{DataChunk, QueryOffset},
 };
 
 #[derive(Clone)]
@@ -25,8 +33,56 @@ impl BleRequester {
     pub async fn query(
         &self, addr: String, query_type: QueryApi, resp_buffer_len: usize,
     ) -> Result<CommBuffer> {
-        let query_req = QueryReq { query_type, resp_buffer_len };
+        self.send_query(
+            addr,
+            QueryReq {
+                query_type,
+                resp_buffer_len,
+                force_refresh: false,
+                offset: None,
+            },
+        )
+        .await
+    }
+
+    /// Query resumed from the offset if the mobile was disconnected in the
+    /// middle of the read
+    pub async fn query_from(
+        &self, addr: String, query_type: QueryApi, resp_buffer_len: usize,
+        offset: QueryOffset,
+    ) -> Result<CommBuffer> {
+        self.send_query(
+            addr,
+            QueryReq {
+                query_type,
+                resp_buffer_len,
+                force_refresh: false,
+                offset: Some(offset),
+            },
+        )
+        .await
+    }
 
+    /// Query bypassing the server cache, used when the mobile knows the
+    /// host data changed
+    pub async fn query_refreshed(
+        &self, addr: String, query_type: QueryApi, resp_buffer_len: usize,
+    ) -> Result<CommBuffer> {
+        self.send_query(
+            addr,
+            QueryReq {
+                query_type,
+                resp_buffer_len,
+                force_refresh: true,
+                offset: None,
+            },
+        )
+        .await
+    }
+
+    async fn send_query(
+        &self, addr: String, query_req: QueryReq,
+    ) -> Result<CommBuffer> {
         let (tx, rx) = oneshot::channel();
 
         let ble_comm = BleComm { addr, comm_api: BleApi::Query(query_req, tx) };
@@ -81,35 +137,141 @@ impl BleRequester {
     }
 }
 
+/// Publisher of a topic, shared by the mobiles subscribed to it. Every
+/// message is chunked once per payload size of the subscribers, so a mobile
+/// with a small MTU gets chunks it can receive.
 #[derive(Clone, Debug)]
 pub struct BlePublisher {
     publisher_tx: PubSubPublisher,
     resp_buffer_len: usize,
+    //channels of the other payload sizes, shared by the clones
+    sized_tx: Arc<Mutex<HashMap<usize, PubSubPublisher>>>,
 }
 
 impl BlePublisher {
     pub fn new(resp_buffer_len: usize) -> Self {
         let (publisher_tx, _) = broadcast::channel(128);
 
-        Self { publisher_tx, resp_buffer_len }
+        Self { publisher_tx, resp_buffer_len, sized_tx: Arc::default() }
     }
 
+    /// Publishes the buffer to every subscriber, fails if there is none
     pub async fn publish(&self, buffer: Vec<u8>) -> Result<()> {
+        let mut channels =
+            vec![(self.resp_buffer_len, self.publisher_tx.clone())];
+        if let Ok(mut sized_tx) = self.sized_tx.lock() {
+            //the channels of the mobiles gone are dropped
+            sized_tx.retain(|_, tx| tx.receiver_count() > 0);
+            channels
+                .extend(sized_tx.iter().map(|(len, tx)| (*len, tx.clone())));
+        }
+
+        let mut published = false;
+        for (payload_len, tx) in channels {
+            if tx.receiver_count() == 0 {
+                continue;
+            }
+            Self::send_chunks(&tx, &buffer, payload_len)?;
+            published = true;
+        }
+
+        if !published {
+            return Err(anyhow!("No subscriber to publish to"));
+        }
+        Ok(())
+    }
+
+    fn send_chunks(
+        tx: &PubSubPublisher, buffer: &[u8], payload_len: usize,
+    ) -> Result<()> {
         let mut remain_len = buffer.len();
 
-        for chunk in buffer.chunks(self.resp_buffer_len) {
+        for chunk in buffer.chunks(payload_len) {
             remain_len -= chunk.len();
             let data_chunk = DataChunk { r: remain_len, d: chunk.to_owned() };
 
-            self.publisher_tx.send(data_chunk.try_into()?)?;
+            tx.send(data_chunk.try_into()?)?;
         }
 
         Ok(())
     }
 
+    /// Publishes the buffer and keeps re-publishing it until the returned
+    /// sender is used to acknowledge it or the retries are exhausted.
+    /// Dropping the sender stops the retries.
+    pub fn publish_with_ack(
+        &self, buffer: Vec<u8>, retries: usize, ack_timeout: Duration,
+    ) -> oneshot::Sender<()> {
+        let (ack_tx, mut ack_rx) = oneshot::channel();
+        let publisher = self.clone();
+
+        tokio::spawn(async move {
+            for attempt in 1..=retries {
+                if let Err(e) = publisher.publish(buffer.clone()).await {
+                    error!("Failed to publish message: {:?}", e);
+                }
+
+                match tokio::time::timeout(ack_timeout, &mut ack_rx).await {
+                    Ok(Ok(())) => {
+                        debug!(
+                            "Message acknowledged after {} attempts",
+                            attempt
+                        );
+                        return;
+                    }
+                    Ok(Err(_)) => {
+                        debug!("Acknowledge channel dropped, stop publishing");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Message not acknowledged, attempt {}", attempt);
+                    }
+                }
+            }
+
+            error!("Message not acknowledged after {} attempts", retries);
+        });
+
+        ack_tx
+    }
+
+    #[allow(dead_code)]
     pub async fn get_subscriber(&self) -> PubSubSubscriber {
         self.publisher_tx.subscribe()
     }
+
+    /// Subscriber getting the messages in chunks of `payload_len` bytes
+    pub async fn get_sized_subscriber(
+        &self, payload_len: usize,
+    ) -> PubSubSubscriber {
+        if payload_len == self.resp_buffer_len {
+            return self.publisher_tx.subscribe();
+        }
+
+        match self.sized_tx.lock() {
+            Ok(mut sized_tx) => sized_tx
+                .entry(payload_len)
+                .or_insert_with(|| broadcast::channel(128).0)
+                .subscribe(),
+            //the chunks may exceed the MTU of the mobile
+            Err(_) => self.publisher_tx.subscribe(),
+        }
+    }
+}
+
+/// Message reassembled from its chunks, the encoded chunks are kept so the
+/// clients forward them within the MTU
+#[derive(Debug)]
+pub struct ChunkedMessage {
+    pub chunks: Vec<Vec<u8>>,
+    pub data: Vec<u8>,
+}
+
+/// Bytes of the message received so far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkProgress {
+    pub received: usize,
+    pub total: usize,
 }
 
 pub struct BleSubscriber {
@@ -121,10 +283,148 @@ impl BleSubscriber {
         Self { subscriber_rx }
     }
 
+    /// Next raw chunk, as published
+    #[allow(dead_code)]
     pub async fn recv(&mut self) -> Result<Vec<u8>> {
         self.subscriber_rx
             .recv()
             .await
             .map_err(|_| anyhow!("Subscriber dropped"))
     }
+
+    /// Receives the next complete message, `on_progress` is called after
+    /// every chunk. A message missing chunks, because the next one doesn't
+    /// arrive within `chunk_timeout` or the subscriber lagged, is dropped
+    /// and the next message is awaited.
+    pub async fn recv_message(
+        &mut self, chunk_timeout: Duration,
+        mut on_progress: impl FnMut(ChunkProgress),
+    ) -> Result<ChunkedMessage> {
+        let mut message = ChunkedMessage { chunks: Vec::new(), data: vec![] };
+        let mut total = 0;
+
+        loop {
+            let received = if message.chunks.is_empty() {
+                self.subscriber_rx.recv().await
+            } else {
+                match timeout(chunk_timeout, self.subscriber_rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        warn!(
+                            "Message dropped, chunk timeout after {} of {} \
+                             bytes",
+                            message.data.len(),
+                            total
+                        );
+                        message.chunks.clear();
+                        message.data.clear();
+                        continue;
+                    }
+                }
+            };
+
+            let buffer = match received {
+                Ok(buffer) => buffer,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Message dropped, {} chunks skipped", skipped);
+                    message.chunks.clear();
+                    message.data.clear();
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(anyhow!("Subscriber dropped"));
+                }
+            };
+
+            let chunk = DataChunk::try_from(buffer.clone())?;
+
+            //a chunk not following the previous one starts a new message
+            let remaining = total - message.data.len();
+            if message.chunks.is_empty() || chunk.d.len() + chunk.r != remaining
+            {
+                if !message.chunks.is_empty() {
+                    warn!("Message dropped, a new one started");
+                }
+                message.chunks.clear();
+                message.data.clear();
+                total = chunk.d.len() + chunk.r;
+            }
+
+            message.data.extend_from_slice(&chunk.d);
+            message.chunks.push(buffer);
+            on_progress(ChunkProgress { received: message.data.len(), total });
+
+            if chunk.r == 0 {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+    //messages published until none comes for a minute
+    async fn published(subscriber: &mut BleSubscriber) -> usize {
+        let mut published = 0;
+        while timeout(Duration::from_secs(60), subscriber.recv())
+            .await
+            .is_ok_and(|message| message.is_ok())
+        {
+            published += 1;
+        }
+        published
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acked_after_attempts() {
+        let publisher = BlePublisher::new(512);
+        let mut subscriber =
+            BleSubscriber::new(publisher.get_subscriber().await);
+        let ack_tx = publisher.publish_with_ack(vec![1], 3, ACK_TIMEOUT);
+
+        //the second attempt is acknowledged
+        subscriber.recv().await.unwrap();
+        subscriber.recv().await.unwrap();
+        ack_tx.send(()).unwrap();
+        assert_eq!(published(&mut subscriber).await, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_exhausted() {
+        let publisher = BlePublisher::new(512);
+        let mut subscriber =
+            BleSubscriber::new(publisher.get_subscriber().await);
+        let ack_tx = publisher.publish_with_ack(vec![1], 3, ACK_TIMEOUT);
+
+        assert_eq!(published(&mut subscriber).await, 3);
+        //the retries were given up
+        assert!(ack_tx.send(()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_recv_message_reassembled() {
+        let publisher = BlePublisher::new(4);
+        let mut subscriber =
+            BleSubscriber::new(publisher.get_subscriber().await);
+
+        //the first message misses its last chunk
+        let first: Vec<u8> =
+            DataChunk { r: 2, d: vec![1; 4] }.try_into().unwrap();
+        publisher.publisher_tx.send(first).unwrap();
+        publisher.publish(vec![7; 10]).await.unwrap();
+
+        let mut progress = Vec::new();
+        let message = subscriber
+            .recv_message(Duration::from_secs(1), |p| progress.push(p.received))
+            .await
+            .unwrap();
+
+        assert_eq!(message.data, vec![7; 10]);
+        assert_eq!(message.chunks.len(), 3);
+        assert_eq!(progress, vec![4, 4, 8, 10]);
+    }
 }
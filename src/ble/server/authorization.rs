@@ -0,0 +1,141 @@
+//! Authorization hook for downstream integrators. `MobileComm` consults the
+//! policy when a mobile registers, sends an offer and before each camera
+//! stream starts, so directory or MDM checks can be wired in without
+//! patching it.
+
+use std::ops::Range;
+
+use anyhow::anyhow;
+use chrono::{Datelike, Local, NaiveDateTime, Timelike, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::{app_data::MobileSchema, ble::api::Address, error::Result};
+
+/// Policy consulted on the mobile requests, an error denies the request.
+/// Every request is allowed by default.
+pub trait AuthorizationPolicy: Send + Sync + 'static {
+    fn authorize_register(
+        &self, _addr: &Address, _mobile: &MobileSchema,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn authorize_offer(
+        &self, _addr: &Address, _mobile: &MobileSchema,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn authorize_stream_start(
+        &self, _addr: &Address, _mobile: &MobileSchema, _camera: &str,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Default policy
+pub struct AllowAll;
+
+impl AuthorizationPolicy for AllowAll {}
+
+/// Sample policy, the streams can only start on weekdays within the hours
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkingHours {
+    /// Local hours, start included and end excluded, a start after the end
+    /// crosses midnight, e.g. 22..6
+    pub hours: Range<u32>,
+    /// Allow the weekends too
+    #[serde(default)]
+    pub weekends: bool,
+}
+
+impl WorkingHours {
+    fn allows(&self, now: NaiveDateTime) -> bool {
+        let Range { start, end } = self.hours;
+        let hour = now.hour();
+
+        //the hours after midnight belong to the day the range started on
+        let (within, day) = if start <= end {
+            (self.hours.contains(&hour), now.weekday())
+        } else if hour >= start {
+            (true, now.weekday())
+        } else {
+            (hour < end, now.weekday().pred())
+        };
+        let is_weekend = matches!(day, Weekday::Sat | Weekday::Sun);
+
+        within && (self.weekends || !is_weekend)
+    }
+}
+
+impl AuthorizationPolicy for WorkingHours {
+    fn authorize_stream_start(
+        &self, _addr: &Address, _mobile: &MobileSchema, camera: &str,
+    ) -> Result<()> {
+        if self.allows(Local::now().naive_local()) {
+            return Ok(());
+        }
+
+        Err(anyhow!("Camera {} denied outside working hours", camera))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(day: u32, hour: u32) -> NaiveDateTime {
+        //2024-06-03 is a monday
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, 30, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_working_hours() {
+        let policy = WorkingHours { hours: 9..18, weekends: false };
+
+        assert!(policy.allows(at(3, 9)));
+        assert!(policy.allows(at(7, 17)));
+        assert!(!policy.allows(at(3, 8)));
+        assert!(!policy.allows(at(3, 18)));
+        //saturday
+        assert!(!policy.allows(at(8, 10)));
+    }
+
+    #[test]
+    fn test_working_hours_weekends() {
+        let policy = WorkingHours { hours: 9..18, weekends: true };
+
+        assert!(policy.allows(at(8, 10)));
+        assert!(!policy.allows(at(9, 20)));
+    }
+
+    #[test]
+    fn test_working_hours_across_midnight() {
+        let policy = WorkingHours { hours: 22..6, weekends: false };
+
+        assert!(policy.allows(at(3, 22)));
+        assert!(policy.allows(at(4, 5)));
+        assert!(!policy.allows(at(4, 6)));
+        assert!(!policy.allows(at(4, 12)));
+        //a friday night goes on into saturday, a sunday night doesn't
+        assert!(policy.allows(at(8, 2)));
+        assert!(!policy.allows(at(9, 23)));
+        assert!(!policy.allows(at(10, 2)));
+    }
+
+    #[test]
+    fn test_allow_all() {
+        let mobile = MobileSchema::default();
+        let addr = "AA:BB:CC:DD:EE:FF".to_string();
+
+        assert!(AllowAll.authorize_register(&addr, &mobile).is_ok());
+        assert!(AllowAll.authorize_offer(&addr, &mobile).is_ok());
+        assert!(AllowAll
+            .authorize_stream_start(&addr, &mobile, "Back")
+            .is_ok());
+    }
+}
@@ -15,17 +15,80 @@ use crate::ble::api::MAX_BUFFER_LEN;
 use crate::ble::api::{
     Address, CmdApi, CommBuffer, CommandReq, QueryApi, QueryReq,
 };
-use crate::ble::comm_types::DataChunk;
+use crate::ble::comm_types::{DataChunk, CHUNK_TRANSFER_RESTARTED};
 use crate::error::Result;
-use anyhow::anyhow;
-use log::{error, info, warn};
-use std::collections::HashMap;
+use crate::metrics::{Metrics, Transfer};
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Time a read interrupted by a disconnect is kept for the mobile to
+/// resume it
+pub const RESUME_GRACE: Duration = Duration::from_secs(30);
+
+/// Errors of the chunked transfers.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ChunkError {
+    #[error(
+        "Response buffer length {resp_buffer_len} too small for the \
+         {overhead} bytes chunk overhead"
+    )]
+    BufferTooSmall { resp_buffer_len: usize, overhead: usize },
+    #[error("Empty payload for command {0:?}")]
+    EmptyPayload(CmdApi),
+    #[error("Malformed chunk for command {0:?}")]
+    MalformedChunk(CmdApi),
+}
+
+/// Payload bytes of a chunk fitting in `resp_buffer_len` after the
+/// `DataChunk` overhead. A buffer without room for the payload is an error,
+/// it would lead to an endless loop of empty chunks.
+pub fn chunk_payload_len(
+    resp_buffer_len: usize, overhead: usize,
+) -> std::result::Result<usize, ChunkError> {
+    match resp_buffer_len.checked_sub(overhead) {
+        Some(payload_len) if payload_len > 0 => Ok(payload_len),
+        _ => Err(ChunkError::BufferTooSmall { resp_buffer_len, overhead }),
+    }
+}
+
+/// Progress of a chunked read.
+pub struct ReadCursor {
+    /// Bytes not sent yet.
+    remain_len: usize,
+    /// Length of the data when the transfer started.
+    total_len: usize,
+    /// Payload size of the last chunk, it follows the MTU.
+    chunk_size: usize,
+    /// Mobile and data of a resumable read.
+    resume: Option<(String, Vec<u8>)>,
+    /// Chunks sent so far.
+    chunks: usize,
+}
+
+/// Progress of a chunked command write.
+#[derive(Default)]
+pub struct WriteCursor {
+    /// Data received so far.
+    buffer: CommBuffer,
+    /// Chunks received so far.
+    chunks: usize,
+}
+
+/// Read interrupted by a disconnect, by mobile id and query.
+struct ParkedRead {
+    data: Vec<u8>,
+    chunk_size: usize,
+    parked_at: Instant,
+}
 
 /// Represents the current state of a mobile buffer.
 #[derive(Default)]
 pub struct BufferCursor {
-    writer: HashMap<CmdApi, CommBuffer>,
-    reader: HashMap<QueryApi, usize>,
+    writer: HashMap<CmdApi, WriteCursor>,
+    reader: HashMap<QueryApi, ReadCursor>,
 }
 
 /// Manages the buffer states for multiple mobile devices.
@@ -35,6 +98,12 @@ pub struct MobileBufferMap {
 
     /// Datachunk overhead len
     chunk_len: usize,
+
+    /// Resumable reads of the disconnected mobiles.
+    parked: HashMap<(String, QueryApi), ParkedRead>,
+
+    /// Chunks of the complete transfers.
+    metrics: Metrics,
 }
 
 impl MobileBufferMap {
@@ -50,12 +119,24 @@ impl MobileBufferMap {
     /// ```
     pub fn new(chunk_len: usize) -> Self {
         info!("DataChunk length: {}", chunk_len);
-        Self { mobile_buffer_status: HashMap::new(), chunk_len }
+        Self {
+            mobile_buffer_status: HashMap::new(),
+            chunk_len,
+            parked: HashMap::new(),
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Records the chunks of the complete transfers in `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
     }
 
     /// Removes a mobile device from the buffer map.
     ///
-    /// If the device does not exist, a warning is logged.
+    /// If the device does not exist, a warning is logged. Its resumable
+    /// reads are kept for `RESUME_GRACE`.
     ///
     /// # Arguments
     ///
@@ -67,12 +148,94 @@ impl MobileBufferMap {
     /// buffer_map.remove_mobile("00:11:22:33:44:55");
     /// ```
     pub fn remove_mobile(&mut self, addr: &str) {
-        if let None = self.mobile_buffer_status.remove(addr) {
+        let Some(cursors) = self.mobile_buffer_status.remove(addr) else {
             warn!(
                 "Mobile with addr: {} does not exist in the buffer map",
                 addr
             );
+            return;
+        };
+
+        for (query_type, cursor) in cursors.reader {
+            let Some((mobile_id, data)) = cursor.resume else {
+                continue;
+            };
+            debug!("Read of {:?} by {} kept to resume", query_type, mobile_id);
+            self.parked.insert(
+                (mobile_id, query_type),
+                ParkedRead {
+                    data,
+                    chunk_size: cursor.chunk_size,
+                    parked_at: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Goes on with a read interrupted by a disconnect, from the offset
+    /// received by the mobile. If the read is no longer kept the chunk
+    /// tells the mobile to restart it. The chunks of a read with an offset
+    /// are taken from its snapshot, the cache of the new address is empty.
+    ///
+    /// # Returns
+    ///
+    /// None for the reads without offset and the new reads, they are served
+    /// by `get_next_data_chunk`.
+    pub fn resume_read(
+        &mut self, addr: &str, query: &QueryReq,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(offset) = query.offset.as_ref() else {
+            return Ok(None);
+        };
+
+        let snapshot = self
+            .mobile_buffer_status
+            .get(addr)
+            .and_then(|cursors| cursors.reader.get(&query.query_type))
+            .and_then(|cursor| cursor.resume.as_ref())
+            .map(|(_, data)| data.clone());
+        if let Some(data) = snapshot {
+            return self.get_next_data_chunk(addr, query, &data).map(Some);
+        }
+        if offset.offset == 0 {
+            return Ok(None);
         }
+
+        self.parked
+            .retain(|_, parked| parked.parked_at.elapsed() < RESUME_GRACE);
+
+        let key = (offset.mobile_id.clone(), query.query_type.clone());
+        let Some(parked) = self
+            .parked
+            .remove(&key)
+            .filter(|parked| offset.offset as usize <= parked.data.len())
+        else {
+            debug!("No read of {:?} to resume for {}", key.1, key.0);
+            return DataChunk { r: CHUNK_TRANSFER_RESTARTED, d: vec![] }
+                .try_into()
+                .map(Some);
+        };
+
+        info!(
+            "Resuming the read of {:?} by {} at {} of {} bytes",
+            key.1,
+            key.0,
+            offset.offset,
+            parked.data.len()
+        );
+        let data = parked.data;
+        self.get_cursors(addr).reader.insert(
+            query.query_type.clone(),
+            ReadCursor {
+                remain_len: data.len() - offset.offset as usize,
+                total_len: data.len(),
+                chunk_size: parked.chunk_size,
+                resume: Some((offset.mobile_id.clone(), data.clone())),
+                chunks: 0,
+            },
+        );
+
+        self.get_next_data_chunk(addr, query, &data).map(Some)
     }
 
     /// Retrieves the buffer cursor for a mobile device.
@@ -92,6 +255,22 @@ impl MobileBufferMap {
             .or_insert(Default::default())
     }
 
+    /// Checks if a chunked read is in progress for a mobile device.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The address of the mobile device.
+    /// * `query_type` - The query being read.
+    ///
+    /// # Returns
+    ///
+    /// `true` if there are still chunks pending to be read.
+    pub fn is_reading(&self, addr: &str, query_type: &QueryApi) -> bool {
+        self.mobile_buffer_status
+            .get(addr)
+            .is_some_and(|cursor| cursor.reader.contains_key(query_type))
+    }
+
     /// Retrieves a data chunk for a mobile device based on the current buffer state.
     ///
     /// If the buffer is idle, it initializes the remaining length.
@@ -107,6 +286,10 @@ impl MobileBufferMap {
     ///
     /// An `Option<DataChunk>` containing the data chunk if available.
     ///
+    /// An MTU change between chunks is followed from the current offset. If
+    /// the data length changed the transfer is restarted, the chunk carries
+    /// `CHUNK_TRANSFER_RESTARTED` as remaining length and no data.
+    ///
     /// # Examples
     ///
     /// ```
@@ -115,37 +298,57 @@ impl MobileBufferMap {
     pub fn get_next_data_chunk<P: AsRef<[u8]>>(
         &mut self, addr: &str, query: &QueryReq, data: &P,
     ) -> Result<Vec<u8>> {
-        let QueryReq { query_type, resp_buffer_len } = query;
+        let QueryReq { query_type, resp_buffer_len, offset, .. } = query;
 
         // Subtract the `DataChunk` overhead from the maximum buffer length.
-        // Use `saturating_sub` to avoid underflow if the provided
-        // `resp_buffer_len` is smaller than the overhead length. If the result
-        // is zero no payload bytes would fit, so return an error to avoid an
-        // endless loop of empty chunks.
-        let resp_buffer_len = resp_buffer_len.saturating_sub(self.chunk_len);
-        if resp_buffer_len == 0 {
-            return Err(anyhow!("Response buffer length too small"));
-        }
+        let resp_buffer_len =
+            chunk_payload_len(*resp_buffer_len, self.chunk_len)?;
 
         let data = data.as_ref();
 
         let BufferCursor { reader, .. } = self.get_cursors(addr);
 
         //Add the query type to the map if not present
-        let remain_len = reader.entry(query_type.clone()).or_insert(data.len());
+        let cursor =
+            reader.entry(query_type.clone()).or_insert_with(|| ReadCursor {
+                remain_len: data.len(),
+                total_len: data.len(),
+                chunk_size: resp_buffer_len,
+                resume: offset
+                    .as_ref()
+                    .map(|offset| (offset.mobile_id.clone(), data.to_vec())),
+                chunks: 0,
+            });
+
+        //the offsets of the received chunks are no longer valid
+        if cursor.total_len != data.len() {
+            warn!(
+                "Data of {:?} changed during the transfer to {}, restarting",
+                query_type, addr
+            );
+            reader.remove(query_type);
+
+            return DataChunk { r: CHUNK_TRANSFER_RESTARTED, d: vec![] }
+                .try_into();
+        }
 
-        let chunk_start = data.len() - *remain_len;
+        if cursor.chunk_size != resp_buffer_len {
+            debug!(
+                "MTU changed during the transfer to {}, chunk size {} -> {}",
+                addr, cursor.chunk_size, resp_buffer_len
+            );
+            cursor.chunk_size = resp_buffer_len;
+        }
+
+        let chunk_start = data.len() - cursor.remain_len;
         let chunk_end = (chunk_start + resp_buffer_len).min(data.len());
 
         // Update remaining length
-        if chunk_end == data.len() {
-            *remain_len = 0;
-        } else {
-            *remain_len -= resp_buffer_len;
-        }
+        cursor.remain_len = data.len() - chunk_end;
+        cursor.chunks += 1;
 
         let data_chunk = DataChunk {
-            r: *remain_len,
+            r: cursor.remain_len,
             d: data[chunk_start..chunk_end].to_owned(),
         };
 
@@ -157,7 +360,10 @@ impl MobileBufferMap {
                 );
             }
 
-            reader.remove(query_type); //remove the reader channel when done
+            if let Some(cursor) = reader.remove(query_type) {
+                //remove the reader channel when done
+                self.metrics.observe_chunks(Transfer::Read, cursor.chunks);
+            }
         }
 
         info!("DataChunk payload len: {}", data_chunk.d.len());
@@ -181,6 +387,9 @@ impl MobileBufferMap {
     ///
     /// An `Option<String>` containing the full buffer if all data has been received.
     ///
+    /// An empty or malformed chunk is an error and drops the partial buffer
+    /// of the command, so the next write starts a new transfer.
+    ///
     /// # Examples
     ///
     /// ```
@@ -199,27 +408,38 @@ impl MobileBufferMap {
         // Initialize current buffer if idle
         let CommandReq { cmd_type, payload } = cmd;
 
-        //deserialize the data chunk
-        let payload: DataChunk = payload.clone().try_into()?;
-
         //get the writer cursor
         let BufferCursor { writer, .. } = self.get_cursors(addr);
 
-        let curr_buffer = writer.entry(cmd_type.clone()).or_default();
+        if payload.is_empty() {
+            writer.remove(cmd_type);
+            return Err(ChunkError::EmptyPayload(cmd_type.clone()).into());
+        }
+
+        //deserialize the data chunk
+        let Ok(payload) = DataChunk::try_from(payload.clone()) else {
+            warn!("Malformed chunk of {:?} from {}", cmd_type, addr);
+            writer.remove(cmd_type);
+            return Err(ChunkError::MalformedChunk(cmd_type.clone()).into());
+        };
+
+        let cursor = writer.entry(cmd_type.clone()).or_default();
 
         //check if the buffer limit is reached
-        if curr_buffer.len() + payload.d.len() > MAX_BUFFER_LEN {
+        if cursor.buffer.len() + payload.d.len() > MAX_BUFFER_LEN {
             error!("Buffer limit reached for mobile with addr: {}", addr);
             writer.remove(cmd_type); //remove the writer channel when done
             return Ok(None);
         }
 
-        curr_buffer.extend_from_slice(&payload.d);
+        cursor.buffer.extend_from_slice(&payload.d);
+        cursor.chunks += 1;
 
         if payload.r == 0 {
-            // Finalize and reset to idle state
-            let buffer = curr_buffer.to_owned();
-            writer.remove(cmd_type); //remove the writer channel when done
+            // Finalize and reset to idle state, remove the writer channel
+            let WriteCursor { buffer, chunks } =
+                writer.remove(cmd_type).unwrap_or_default();
+            self.metrics.observe_chunks(Transfer::Write, chunks);
             return Ok(Some(buffer));
         }
 
@@ -231,6 +451,7 @@ impl MobileBufferMap {
 mod tests {
 
     use super::*;
+    use crate::ble::comm_types::QueryOffset;
     use env_logger;
     use log::{debug, info};
 
@@ -268,6 +489,8 @@ mod tests {
         let query = QueryReq {
             query_type: QueryApi::HostInfo,
             resp_buffer_len: expected_len,
+            force_refresh: false,
+            offset: None,
         };
 
         let chunk: DataChunk = buffer_map
@@ -288,7 +511,12 @@ mod tests {
 
         let data = vec![0u8; 10];
         // resp_buffer_len smaller than the overhead should return an error
-        let query = QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len: CHUNK_LEN - 1 };
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len: CHUNK_LEN - 1,
+            force_refresh: false,
+            offset: None,
+        };
 
         assert!(buffer_map.get_next_data_chunk(addr, &query, &data).is_err());
     }
@@ -302,8 +530,12 @@ mod tests {
         let expected_len = 5000;
         let data = vec![55; expected_len]; // Large data
         let resp_buffer_len = 1024;
-        let query =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len };
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len,
+            force_refresh: false,
+            offset: None,
+        };
 
         let allowed_data_len = resp_buffer_len - CHUNK_LEN;
 
@@ -336,6 +568,30 @@ mod tests {
         assert_eq!(chunks[4].r, 0);
     }
 
+    #[test]
+    fn test_is_reading() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        let data = vec![55; 20];
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len: 10 + CHUNK_LEN,
+            force_refresh: false,
+            offset: None,
+        };
+
+        assert!(!buffer_map.is_reading(addr, &QueryApi::HostInfo));
+
+        buffer_map.get_next_data_chunk(addr, &query, &data).unwrap();
+        assert!(buffer_map.is_reading(addr, &QueryApi::HostInfo));
+        assert!(!buffer_map.is_reading(addr, &QueryApi::SdpAnswer));
+
+        buffer_map.get_next_data_chunk(addr, &query, &data).unwrap();
+        assert!(!buffer_map.is_reading(addr, &QueryApi::HostInfo));
+    }
+
     #[test]
     fn test_get_next_data_chunk_large_data_changing_max_buffer() {
         init_test();
@@ -350,6 +606,8 @@ mod tests {
         let mut query = QueryReq {
             query_type: QueryApi::HostInfo,
             resp_buffer_len: max_buffer_len,
+            force_refresh: false,
+            offset: None,
         };
         loop {
             let chunk: DataChunk = buffer_map
@@ -369,6 +627,85 @@ mod tests {
         assert!(chunks[chunks.len() - 1].r == 0);
     }
 
+    #[test]
+    fn test_get_next_data_chunk_mtu_change_reassembly() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        let data = (0..=255).cycle().take(700).collect::<Vec<u8>>();
+        let mut received = Vec::new();
+
+        //the MTU shrinks and grows between the chunks
+        for resp_buffer_len in [100, 30, 200, 23].into_iter().cycle() {
+            let query = QueryReq {
+                query_type: QueryApi::HostInfo,
+                resp_buffer_len,
+                force_refresh: false,
+                offset: None,
+            };
+            let chunk: DataChunk = buffer_map
+                .get_next_data_chunk(addr, &query, &data)
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+            let remain_len = data.len() - received.len();
+            assert_eq!(
+                chunk.d.len(),
+                (resp_buffer_len - CHUNK_LEN).min(remain_len)
+            );
+            received.extend(chunk.d);
+            assert_eq!(chunk.r, data.len() - received.len());
+            if chunk.r == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(received, data);
+        assert!(!buffer_map.is_reading(addr, &QueryApi::HostInfo));
+    }
+
+    #[test]
+    fn test_get_next_data_chunk_data_changed_restarts() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len: 50 + CHUNK_LEN,
+            force_refresh: false,
+            offset: None,
+        };
+
+        let chunk: DataChunk = buffer_map
+            .get_next_data_chunk(addr, &query, &vec![1; 200])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk.r, 150);
+
+        //shorter data would underflow the offset
+        let chunk: DataChunk = buffer_map
+            .get_next_data_chunk(addr, &query, &vec![2; 40])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk.r, CHUNK_TRANSFER_RESTARTED);
+        assert!(chunk.d.is_empty());
+        assert!(!buffer_map.is_reading(addr, &QueryApi::HostInfo));
+
+        //the next read starts from the beginning of the new data
+        let chunk: DataChunk = buffer_map
+            .get_next_data_chunk(addr, &query, &vec![2; 40])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk.r, 0);
+        assert_eq!(chunk.d, vec![2; 40]);
+    }
+
     #[test]
     fn test_get_next_data_chunk_large_data_twice() {
         init_test();
@@ -380,8 +717,12 @@ mod tests {
 
         let resp_buffer_len = 15;
 
-        let query =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len };
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len,
+            force_refresh: false,
+            offset: None,
+        };
 
         let allowed_data_len = resp_buffer_len - CHUNK_LEN;
 
@@ -409,8 +750,12 @@ mod tests {
         //start again
         chunks = Vec::new();
         let resp_buffer_len = 13;
-        let new_query =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len };
+        let new_query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len,
+            force_refresh: false,
+            offset: None,
+        };
 
         let allowed_data_len = resp_buffer_len - CHUNK_LEN;
         loop {
@@ -445,6 +790,8 @@ mod tests {
         let query = QueryReq {
             query_type: QueryApi::HostInfo,
             resp_buffer_len: expected_len,
+            force_refresh: false,
+            offset: None,
         };
 
         let chunk: DataChunk = buffer_map
@@ -475,8 +822,12 @@ mod tests {
 
         let expected_len = 3355;
         let data = vec![55; expected_len]; // Large data
-        let query =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len: 512 };
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len: 512,
+            force_refresh: false,
+            offset: None,
+        };
         let mut chunks = Vec::new();
 
         loop {
@@ -520,10 +871,18 @@ mod tests {
         let data2 = vec![66; expected_len]; // Large data
 
         let resp_buffer_len = 100 + CHUNK_LEN;
-        let query1 =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len };
-        let query2 =
-            QueryReq { query_type: QueryApi::HostInfo, resp_buffer_len };
+        let query1 = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len,
+            force_refresh: false,
+            offset: None,
+        };
+        let query2 = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len,
+            force_refresh: false,
+            offset: None,
+        };
 
         let allowed_data_len = resp_buffer_len - CHUNK_LEN;
 
@@ -697,4 +1056,193 @@ mod tests {
 
         assert!(buffer.is_none());
     }
+
+    //every MTU from the BLE minimum to the usual maximum
+    const MTU_RANGE: std::ops::RangeInclusive<usize> = 23..=512;
+
+    #[test]
+    fn test_chunk_payload_len_small_buffers() {
+        for resp_buffer_len in 0..=CHUNK_LEN {
+            assert_eq!(
+                chunk_payload_len(resp_buffer_len, CHUNK_LEN),
+                Err(ChunkError::BufferTooSmall {
+                    resp_buffer_len,
+                    overhead: CHUNK_LEN
+                })
+            );
+        }
+
+        for mtu in MTU_RANGE {
+            assert_eq!(chunk_payload_len(mtu, CHUNK_LEN), Ok(mtu - CHUNK_LEN));
+        }
+    }
+
+    #[test]
+    fn test_get_next_data_chunk_too_small_typed_error() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let query = QueryReq {
+            query_type: QueryApi::HostInfo,
+            resp_buffer_len: CHUNK_LEN,
+            force_refresh: false,
+            offset: None,
+        };
+
+        let err = buffer_map
+            .get_next_data_chunk("AA:BB:CC:DD:EE:FF", &query, &vec![1; 10])
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<ChunkError>(),
+            Some(ChunkError::BufferTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_property_chunks_reassemble_across_mtus() {
+        init_test();
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        for mtu in MTU_RANGE {
+            let payload_len = mtu - CHUNK_LEN;
+
+            //lengths around the chunk boundaries and a long transfer
+            for data_len in [
+                0,
+                1,
+                payload_len - 1,
+                payload_len,
+                payload_len + 1,
+                3 * payload_len,
+                MAX_BUFFER_LEN,
+            ] {
+                let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+                let data = (0..data_len).map(|i| i as u8).collect::<Vec<u8>>();
+                let query = QueryReq {
+                    query_type: QueryApi::HostInfo,
+                    resp_buffer_len: mtu,
+                    force_refresh: false,
+                    offset: None,
+                };
+
+                let mut reassembled = None;
+                let mut chunks = 0;
+                while reassembled.is_none() {
+                    let chunk = buffer_map
+                        .get_next_data_chunk(addr, &query, &data)
+                        .unwrap();
+                    let cmd = CommandReq {
+                        cmd_type: CmdApi::SdpOffer,
+                        payload: chunk,
+                    };
+
+                    reassembled =
+                        buffer_map.get_complete_buffer(addr, &cmd).unwrap();
+                    chunks += 1;
+                }
+
+                assert_eq!(reassembled.unwrap(), data, "mtu {}", mtu);
+                assert_eq!(chunks, data_len.div_ceil(payload_len).max(1));
+                assert!(!buffer_map.is_reading(addr, &QueryApi::HostInfo));
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_complete_buffer_empty_payload() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        let cmd = CommandReq { cmd_type: CmdApi::SdpOffer, payload: vec![] };
+        let err = buffer_map.get_complete_buffer(addr, &cmd).unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<ChunkError>(),
+            Some(&ChunkError::EmptyPayload(CmdApi::SdpOffer))
+        );
+    }
+
+    #[test]
+    fn test_get_complete_buffer_malformed_chunk_resets_writer() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let addr = "AA:BB:CC:DD:EE:FF";
+
+        //first half of a transfer
+        let cmd = CommandReq {
+            cmd_type: CmdApi::SdpOffer,
+            payload: DataChunk { r: 3, d: vec![1, 2, 3] }.try_into().unwrap(),
+        };
+        assert!(buffer_map.get_complete_buffer(addr, &cmd).unwrap().is_none());
+
+        let cmd = CommandReq {
+            cmd_type: CmdApi::SdpOffer,
+            payload: vec![0xc1, 0xff],
+        };
+        let err = buffer_map.get_complete_buffer(addr, &cmd).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ChunkError>(),
+            Some(&ChunkError::MalformedChunk(CmdApi::SdpOffer))
+        );
+
+        //the stale half is not prepended to the next transfer
+        let cmd = CommandReq {
+            cmd_type: CmdApi::SdpOffer,
+            payload: DataChunk { r: 0, d: vec![7, 8] }.try_into().unwrap(),
+        };
+        assert_eq!(
+            buffer_map.get_complete_buffer(addr, &cmd).unwrap(),
+            Some(vec![7, 8])
+        );
+    }
+
+    #[test]
+    fn test_read_resumed_after_reconnect() {
+        init_test();
+        let mut buffer_map = MobileBufferMap::new(CHUNK_LEN);
+        let data: Vec<u8> = (0..25).collect();
+        let query = |offset| QueryReq {
+            query_type: QueryApi::SdpAnswer,
+            resp_buffer_len: 10 + CHUNK_LEN,
+            force_refresh: false,
+            offset: Some(QueryOffset {
+                mobile_id: "mobile_1".to_string(),
+                offset,
+            }),
+        };
+
+        let chunk: DataChunk = buffer_map
+            .get_next_data_chunk("AA:AA:AA:AA:AA:AA", &query(0), &data)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk.r, 15);
+        buffer_map.remove_mobile("AA:AA:AA:AA:AA:AA");
+
+        //reconnected with a new address, the data is not read again
+        let chunk: DataChunk = buffer_map
+            .resume_read("BB:BB:BB:BB:BB:BB", &query(10))
+            .unwrap()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk, DataChunk { r: 5, d: (10..20).collect() });
+        let chunk: DataChunk = buffer_map
+            .resume_read("BB:BB:BB:BB:BB:BB", &query(20))
+            .unwrap()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk, DataChunk { r: 0, d: (20..25).collect() });
+
+        //nothing left to resume, the mobile restarts the read
+        let chunk: DataChunk = buffer_map
+            .resume_read("BB:BB:BB:BB:BB:BB", &query(10))
+            .unwrap()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(chunk.r, CHUNK_TRANSFER_RESTARTED);
+    }
 }
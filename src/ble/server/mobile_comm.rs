@@ -1,22 +1,60 @@
 use crate::{
-    app_data::MobileSchema,
-    ble::comm_types::{MobileSdpAnswer, SdpAnswerReady},
+    app_data::{
+        BlocklistSchema, HostInfo, HostSettingsSchema, LastCamera,
+        LastCamerasSchema, LatencyProfile, MobileSchema, MobileUsage,
+        SelfTestLogSchema, SelfTestRun, SelfTestStep, UsageStatsSchema,
+    },
+    ble::comm_types::{
+        camera_slots, CameraReadiness, CameraRemap, CameraState,
+        FailedOperation, FailureCode, Handover, HandoverRequest, HandoverStage,
+        HostSettingsUpdate, LinkTestReport, LinkTestRequest, MobileSdpAnswer,
+        OperationFailed, SdpAnswerReady, SessionExpiring,
+    },
+    call_trace::{CallTrace, StreamMilestones, TraceExporter},
+    clock::{host_clock, Clock, IdGenerator, SystemClock, UuidGenerator},
+    config::GuestSessionsConfig,
+    events::{Event, EventBus},
+    link_test::{LinkTest, PortRule},
+    metrics::{FailureReason, Metrics},
+    privacy_switch::PrivacySwitch,
+    self_test::{Schedule, SelfTestScheduler},
+    user_sessions::{Uid, UserScope},
+};
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
 };
-use std::collections::HashMap;
 
 use async_trait::async_trait;
-use log::debug;
+use log::{debug, error, info, warn};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+};
 
 use anyhow::anyhow;
 
 use crate::ble::{
     api::Address,
-    comm_types::{CameraSdp, HostProvInfo, MobileSdpOffer, VideoProp},
+    comm_types::{
+        AdapterStatus, ApCredentials, CameraSdp, DeviceConflict,
+        HostCapabilities, HostDiagnostics, HostPowerState, HostProvInfo,
+        InterfaceStats, MobileSdpOffer, MobileSdpReply, Negotiation,
+        PrivacyState, StreamStats, VideoProp, PROTOCOL_VERSION,
+    },
     requester::BlePublisher,
-    server::CommDataService,
+    server::{
+        authorization::{AllowAll, AuthorizationPolicy},
+        CommDataService,
+    },
 };
 use crate::error::Result;
-use crate::vdevice_builder::VDevice;
+use crate::live_config::LiveConfig;
 
 #[cfg(test)]
 use mockall::automock;
@@ -26,24 +64,531 @@ use mockall::automock;
 pub trait AppDataStore: Send + Sync + 'static {
     fn get_host_prov_info(&self) -> Result<HostProvInfo>;
 
+    /// Stores the host name and connection type, returns whether they
+    /// changed
+    fn update_host_info(&mut self, host_info: &HostInfo) -> Result<bool>;
+
     fn add_mobile(&mut self, mobile: &MobileSchema) -> Result<()>;
 
     fn get_mobile(&self, id: &str) -> Result<MobileSchema>;
+
+    /// Stores the preferences of a registered mobile
+    fn update_mobile(&mut self, mobile: &MobileSchema) -> Result<()>;
+
+    fn get_host_settings(&self) -> Result<HostSettingsSchema>;
+
+    fn update_host_settings(
+        &mut self, settings: &HostSettingsSchema,
+    ) -> Result<()>;
+
+    fn get_blocklist(&self) -> Result<BlocklistSchema>;
+
+    fn update_blocklist(&mut self, blocklist: &BlocklistSchema) -> Result<()>;
+
+    fn get_last_cameras(&self, mobile_id: &str) -> Result<LastCamerasSchema>;
+
+    fn update_last_cameras(
+        &mut self, mobile_id: &str, cameras: &LastCamerasSchema,
+    ) -> Result<()>;
+
+    fn remove_mobile(&mut self, id: &str) -> Result<()>;
+
+    fn get_usage_stats(&self) -> Result<UsageStatsSchema>;
+
+    fn update_usage_stats(&mut self, stats: &UsageStatsSchema) -> Result<()>;
+
+    fn get_self_tests(&self) -> Result<SelfTestLogSchema>;
+
+    fn update_self_tests(&mut self, log: &SelfTestLogSchema) -> Result<()>;
+}
+
+/// Virtual device streaming a mobile camera
+pub trait VDeviceOps: Send + Sync + 'static {
+    /// Local description, the host offer when the host negotiates
+    fn get_sdp_answer(&self) -> String;
+
+    /// Applies the answer of the mobile to the host offer
+    fn set_remote_answer(&self, sdp: &str) -> Result<()>;
+
+    fn stream_stats(&self) -> StreamStats;
+
+    /// Error that stopped the stream since the previous call
+    fn take_failure(&self) -> Option<String>;
+
+    /// Times of the ICE connection and of the first frame, once reached
+    fn milestones(&self) -> StreamMilestones;
+}
+
+pub type VDeviceMap = HashMap<String, Box<dyn VDeviceOps>>;
+
+/// Called with every camera once its device is built or failed, returns
+/// false when the call is gone so the build stops
+pub type OnCameraReady =
+    Box<dyn Fn(String, Result<Box<dyn VDeviceOps>>) -> bool + Send + Sync>;
+
+/// Options of a call, from the offer and the preferences of the mobile
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CallOptions {
+    pub negotiation: Negotiation,
+    /// The cameras send the latency test pattern
+    pub latency_test: bool,
+    /// The cameras are recorded while they stream
+    pub record: bool,
+    /// Latency the pipelines buffer the received video for
+    pub latency_profile: LatencyProfile,
+    /// User the devices are restricted to, all users if None
+    pub owner: Option<Uid>,
+}
+
+//pending acknowledge of the answer ready notification, numbered per
+//connection of the mobile so a late ack of an older one is told apart
+#[derive(Default)]
+struct PendingAck {
+    seq: u32,
+    ack_tx: Option<oneshot::Sender<()>>,
 }
 
-pub type VDeviceMap = HashMap<String, VDevice>;
+type AnswerReadyAck = Arc<Mutex<PendingAck>>;
+
+/// Times the sdp answer ready notification is published without an ack
+const ANSWER_READY_RETRIES: usize = 3;
+
+/// Time to wait for the mobile to acknowledge the answer ready notification
+const ANSWER_READY_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Days of usage stats kept when not configured
+const DEFAULT_STATS_RETENTION_DAYS: u32 = 90;
+
+/// Failures of an address before it is blocked
+const MAX_AUTH_FAILURES: u32 = 5;
+
+/// Window of the failures counted against an address
+const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// Addresses with failures tracked at once, the oldest is forgotten first
+const MAX_TRACKED_FAILURES: usize = 1024;
+
+/// Addresses blocked automatically at once, the oldest block is dropped
+/// first
+const MAX_AUTO_BLOCKS: usize = 256;
+
+/// Days an automatic block lasts
+const AUTO_BLOCK_DAYS: u32 = 7;
+
+/// Longest wait of an offer for the registration of its mobile, both are
+/// sent back to back by a new mobile
+const REGISTRATION_GRACE: Duration = Duration::from_secs(3);
+
+/// Time after the offer at which the setup is traced with the stages
+/// reached so far
+const TRACE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time the cameras of a handover are kept running without the report of
+/// the new session
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[derive(Default)]
 pub struct DeviceInfo {
     publisher: Option<BlePublisher>,
-    vdevices: VDeviceMap,
+    //set once the mobile sends its offer
+    mobile_id: Option<String>,
+    //filled in the background as the cameras are built
+    vdevices: Arc<Mutex<VDeviceMap>>,
+    answer_ready_ack: AnswerReadyAck,
+    //recorded in the usage stats when it ends
+    call: Option<ActiveCall>,
+}
+
+struct ActiveCall {
+    mobile_id: String,
+    started_at: Instant,
+    progress: Arc<CallProgress>,
+    //handover from another host, reported once every camera streams
+    handover_token: Option<String>,
+}
+
+//readiness of the cameras of a call, published as every camera is built
+struct CallProgress {
+    mobile_id: String,
+    cameras: Mutex<Vec<CameraReadiness>>,
+    //the devices of a call that ended are dropped once built
+    vdevices: Weak<Mutex<VDeviceMap>>,
+    publisher: BlePublisher,
+    answer_ready_ack: AnswerReadyAck,
+    failure_publisher: Option<BlePublisher>,
+    //setup stages, kept after the export for the first frame latencies
+    trace: Mutex<CallTrace>,
+    traced: AtomicBool,
+    clock: Arc<dyn Clock>,
+    metrics: Metrics,
+    events: EventBus,
+}
+
+impl CallProgress {
+    fn camera_built(
+        &self, name: String, vdevice: Result<Box<dyn VDeviceOps>>,
+    ) -> bool {
+        let Some(vdevices) = self.vdevices.upgrade() else {
+            return false;
+        };
+
+        let built = vdevice.is_ok();
+        self.with_trace(|trace| {
+            trace.camera_built(&name, self.clock.now(), built)
+        });
+
+        let state = match (vdevice, vdevices.lock()) {
+            (Ok(vdevice), Ok(mut vdevices)) => {
+                vdevices.insert(name.clone(), vdevice);
+                self.events.emit(Event::StreamStarted {
+                    mobile_id: self.mobile_id.clone(),
+                    camera: name.clone(),
+                });
+                CameraState::Ready
+            }
+            (Ok(_), Err(_)) => CameraState::Failed,
+            (Err(e), _) => {
+                warn!("Camera {} not started: {:?}", name, e);
+                self.metrics.record_failure(FailureReason::PipelineFailed);
+                self.report(
+                    FailedOperation::PipelineBuild,
+                    Some(name.clone()),
+                    build_failure_code(&e),
+                    format!("{:#}", e),
+                );
+                CameraState::Failed
+            }
+        };
+
+        self.update(|camera| camera.name == name, state);
+        true
+    }
+
+    //every camera that didn't fail shows its first frame
+    fn is_streaming(&self) -> bool {
+        let streaming = self.first_frame_latencies().len() as u32;
+        let cameras = self.cameras.lock().map_or(0, |cameras| cameras.len());
+        streaming > 0 && (streaming + self.failed_cameras()) as usize >= cameras
+    }
+
+    //the cameras pending or built, holding a camera slot
+    fn active_cameras(&self) -> u32 {
+        let cameras = self.cameras.lock().map_or(0, |cameras| cameras.len());
+        (cameras as u32).saturating_sub(self.failed_cameras())
+    }
+
+    fn failed_cameras(&self) -> u32 {
+        self.cameras.lock().map_or(0, |cameras| {
+            cameras
+                .iter()
+                .filter(|camera| camera.state == CameraState::Failed)
+                .count() as u32
+        })
+    }
+
+    //the cameras left pending when the build fails
+    fn fail_pending(&self, e: &anyhow::Error) {
+        self.metrics.record_failure(FailureReason::PipelineFailed);
+        self.report(
+            FailedOperation::PipelineBuild,
+            None,
+            build_failure_code(e),
+            format!("{:#}", e),
+        );
+        self.with_trace(|trace| trace.fail_pending(self.clock.now()));
+        self.update(
+            |camera| camera.state == CameraState::Pending,
+            CameraState::Failed,
+        );
+    }
+
+    //the readiness follows the cameras to their new devices
+    fn remap(&self, device: &str, camera: &str) {
+        self.with_trace(|trace| trace.remap(device, camera));
+        let Ok(mut cameras) = self.cameras.lock() else {
+            return;
+        };
+        for readiness in cameras.iter_mut() {
+            if readiness.name == device {
+                readiness.name = camera.to_string();
+            } else if readiness.name == camera {
+                readiness.name = device.to_string();
+            }
+        }
+    }
+
+    fn with_trace(&self, update: impl FnOnce(&mut CallTrace)) {
+        if let Ok(mut trace) = self.trace.lock() {
+            update(&mut trace);
+        }
+    }
+
+    fn first_frame_latencies(&self) -> Vec<(String, Duration)> {
+        self.trace
+            .lock()
+            .map(|trace| trace.first_frame_latencies())
+            .unwrap_or_default()
+    }
+
+    //exported once, when every camera streams or failed, or with the stages
+    //reached so far once `finish` is set
+    fn export_trace(&self, exporter: &TraceExporter, finish: bool) {
+        let Ok(trace) = self.trace.lock() else {
+            return;
+        };
+        let now = self.clock.now();
+        let done = finish
+            || trace.is_complete()
+            || now.saturating_duration_since(trace.offer_at()) >= TRACE_TIMEOUT;
+        if done && !self.traced.swap(true, Ordering::SeqCst) {
+            exporter.export(&trace, now);
+        }
+    }
+
+    fn report(
+        &self, operation: FailedOperation, camera: Option<String>,
+        code: FailureCode, detail: String,
+    ) {
+        self.events.emit(Event::Error {
+            mobile_id: self.mobile_id.clone(),
+            message: detail.clone(),
+        });
+        report_failure(
+            self.failure_publisher.as_ref(),
+            OperationFailed {
+                mobile_id: self.mobile_id.clone(),
+                operation,
+                camera,
+                code,
+                detail,
+            },
+        );
+    }
+
+    fn update(
+        &self, filter: impl Fn(&CameraReadiness) -> bool, state: CameraState,
+    ) {
+        let Ok(mut cameras) = self.cameras.lock() else {
+            return;
+        };
+        cameras
+            .iter_mut()
+            .filter(|camera| filter(camera))
+            .for_each(|camera| camera.state = state);
+
+        let Ok(mut pending) = self.answer_ready_ack.lock() else {
+            return;
+        };
+        let seq = pending.seq.checked_add(1).unwrap_or(1);
+        let answer_ready = SdpAnswerReady {
+            mobile_id: self.mobile_id.clone(),
+            cameras: cameras.clone(),
+            seq,
+        };
+        let payload = match answer_ready.try_into() {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Failed to encode the answer ready: {:?}", e);
+                return;
+            }
+        };
+
+        //published again until the mobile acknowledges it, a newer
+        //notification replaces the pending one
+        pending.seq = seq;
+        pending.ack_tx = Some(self.publisher.publish_with_ack(
+            payload,
+            ANSWER_READY_RETRIES,
+            ANSWER_READY_ACK_TIMEOUT,
+        ));
+    }
+}
+
+//a device written by another camera software is told apart, the user
+//has to close it
+fn build_failure_code(e: &anyhow::Error) -> FailureCode {
+    match e.downcast_ref::<DeviceConflict>() {
+        Some(_) => FailureCode::DeviceConflict,
+        None => FailureCode::BuildFailed,
+    }
+}
+
+//published in the background, lost if the mobile is not subscribed
+fn report_failure(publisher: Option<&BlePublisher>, failure: OperationFailed) {
+    let Some(publisher) = publisher.cloned() else {
+        warn!("Failure of {} not reported: no subscriber", failure.mobile_id);
+        return;
+    };
+
+    let payload: Vec<u8> = match failure.try_into() {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Failed to encode the failure: {:?}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = publisher.publish(payload).await {
+            warn!("Failed to report the failure: {:?}", e);
+        }
+    });
 }
 
 #[async_trait]
 pub trait VDeviceBuilderOps: Send + Sync + 'static {
     async fn create_from(
         &self, mobile_name: String, camera_offer: Vec<CameraSdp>,
-    ) -> Result<VDeviceMap>;
+        options: CallOptions, on_ready: OnCameraReady,
+    ) -> Result<()>;
+
+    /// Prepares the devices of the cameras ahead of the offer, so only the
+    /// webrtc transport is connected when it arrives
+    async fn prepare_standby(
+        &self, _mobile_name: String, _cameras: Vec<LastCamera>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Checks the modules, the creation of a device and a test pipeline
+    /// playing into it, while no camera streams
+    async fn self_test(&self) -> Result<Vec<SelfTestStep>> {
+        Err(anyhow!("No virtual devices to test"))
+    }
+
+    /// Removes the devices prepared ahead of the offers
+    fn release_standby(&self) {}
+
+    /// Opens the UDP `port` of the access point outside the calls, until
+    /// the returned rule is dropped, None if the inbound UDP is not
+    /// restricted
+    fn open_port(&self, _port: u16) -> Result<Option<PortRule>> {
+        Ok(None)
+    }
+}
+
+//offer of a mobile whose registration is not stored yet
+struct PendingOffer {
+    offer: MobileSdpOffer,
+    expires_at: Instant,
+}
+
+//handover of the call of a mobile to another host of the group
+struct PendingHandover {
+    token: String,
+    expires_at: Instant,
+    //devices of the mobile once it disconnected from this host
+    retained: Option<DeviceInfo>,
+}
+
+//time-limited session of a guest mobile, kept across reconnections
+struct GuestSession {
+    expires_at: Instant,
+    warned: bool,
+}
+
+//forwards the changes of a host state to the mobiles, stopped on drop
+struct StateNotifier(JoinHandle<()>);
+
+impl StateNotifier {
+    fn spawn<T: Send + Sync + 'static>(
+        mut changes: watch::Receiver<T>, publisher: BlePublisher,
+        encode: impl Fn(&T) -> Result<Vec<u8>> + Send + 'static,
+    ) -> Self {
+        Self(tokio::spawn(async move {
+            while changes.changed().await.is_ok() {
+                let data = match encode(&changes.borrow_and_update()) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!("Failed to encode the host state: {:?}", e);
+                        continue;
+                    }
+                };
+
+                //no mobile subscribed is not an error
+                if let Err(e) = publisher.publish(data).await {
+                    debug!("Host state change not notified: {:?}", e);
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for StateNotifier {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Builder used when the host is built without the webrtc feature, no
+/// virtual device can be created
+#[cfg(not(feature = "webrtc"))]
+pub struct NoVDeviceBuilder;
+
+#[cfg(not(feature = "webrtc"))]
+#[async_trait]
+impl VDeviceBuilderOps for NoVDeviceBuilder {
+    async fn create_from(
+        &self, _mobile_name: String, _camera_offer: Vec<CameraSdp>,
+        _options: CallOptions, _on_ready: OnCameraReady,
+    ) -> Result<()> {
+        Err(anyhow!("Host built without webrtc support"))
+    }
+}
+
+/// Pairing window, while it is active the host provisioning information
+/// carries the AP credentials and the pairing token in a single read.
+/// The window length follows the live config.
+pub struct PairingMode {
+    token: String,
+    ap_creds: Option<ApCredentials>,
+    opened_at: Instant,
+    live_config: LiveConfig,
+    clock: Arc<dyn Clock>,
+}
+
+impl PairingMode {
+    pub fn new(
+        ap_creds: Option<ApCredentials>, live_config: LiveConfig,
+    ) -> Self {
+        Self::with_sources(
+            ap_creds,
+            live_config,
+            Arc::new(SystemClock),
+            &UuidGenerator,
+        )
+    }
+
+    /// Opens the window at the time of `clock` with a token from `ids`
+    pub fn with_sources(
+        ap_creds: Option<ApCredentials>, live_config: LiveConfig,
+        clock: Arc<dyn Clock>, ids: &dyn IdGenerator,
+    ) -> Self {
+        Self {
+            token: ids.new_id(),
+            ap_creds,
+            opened_at: clock.now(),
+            live_config,
+            clock,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        let window =
+            Duration::from_secs(self.live_config.borrow().pairing_window_secs);
+        self.clock.now().saturating_duration_since(self.opened_at) < window
+    }
+
+    pub fn window_secs(&self) -> u64 {
+        self.live_config.borrow().pairing_window_secs
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn ap_creds(&self) -> Option<&ApCredentials> {
+        self.ap_creds.as_ref()
+    }
 }
 
 //caller to send SDP data as a publisher
@@ -54,120 +599,2545 @@ pub struct MobileComm<Db, VDevBuilder> {
     //virtual devices
     mobiles_connected: HashMap<Address, DeviceInfo>,
 
-    //virtual device builder
-    vdev_builder: VDevBuilder,
+    //virtual device builder, shared with the background builds
+    vdev_builder: Arc<VDevBuilder>,
+
+    //pairing window, shared with the desktop front-ends
+    pairing_mode: Option<Arc<PairingMode>>,
+
+    //start time, used for the uptime
+    started_at: Instant,
+
+    //failures per address with the first one of the window, cleared on
+    //success
+    auth_failures: HashMap<String, (u32, Instant)>,
+
+    //consulted on register, offer and stream start
+    policy: Box<dyn AuthorizationPolicy>,
+
+    //link tests, kept until the mobile disconnects
+    link_tests: HashMap<Address, LinkTest>,
+
+    //address of the access point the link tests listen on
+    link_test_addr: Option<Ipv4Addr>,
+
+    //trusted mobile ids with their last cameras kept on warm standby
+    warm_standby: Vec<String>,
+
+    //group shared with the hosts that imported the same pairing data
+    host_group: Option<String>,
+
+    //name of the host on its access point
+    local_hostname: Option<String>,
+
+    //guest mobiles and their running sessions by mobile id
+    guest_config: GuestSessionsConfig,
+    guest_sessions: HashMap<String, GuestSession>,
+    expiry_publisher: Option<BlePublisher>,
+
+    //failures of the acknowledged commands, shared by all the mobiles
+    failure_publisher: Option<BlePublisher>,
+
+    //handovers to other hosts by mobile id, their stages are published to
+    //the address of the mobile only
+    handovers: HashMap<String, PendingHandover>,
+    handover_publishers: HashMap<Address, BlePublisher>,
+
+    //privacy switch of the process, the changes are notified to the mobiles
+    privacy: PrivacySwitch,
+    privacy_notifier: Option<StateNotifier>,
+
+    //power state of the host, the changes are notified to the mobiles
+    power_state: watch::Receiver<HostPowerState>,
+    power_notifier: Option<StateNotifier>,
+    //the shutdown is published on the topic of the power state
+    power_publisher: Option<BlePublisher>,
+
+    //traffic of the access point interface, reported in the diagnostics
+    ap_link: watch::Receiver<Option<InterfaceStats>>,
+
+    //sizes and failures of the requests, reported in the diagnostics
+    metrics: Metrics,
+
+    //modes of the Bluetooth adapters, reported in the diagnostics
+    ble_adapters: watch::Receiver<Vec<AdapterStatus>>,
+
+    //host name and connection type, stored when they change
+    host_info: Option<watch::Receiver<HostInfo>>,
+
+    //offers received before the registration of their mobile, by address
+    pending_offers: HashMap<Address, PendingOffer>,
+
+    //time of the sessions, the calls and the queued offers
+    clock: Arc<dyn Clock>,
+
+    //users of the seats, the mobiles of the others are not served
+    user_scope: Option<UserScope>,
+
+    //days of usage stats kept
+    stats_retention_days: u32,
+
+    //logs and exports the setup of the calls
+    trace_exporter: TraceExporter,
+
+    //scheduled self-test and its latest run, signaled to the front-ends
+    self_test: Option<SelfTestScheduler>,
+    self_test_results: watch::Sender<Option<SelfTestRun>>,
+
+    //events of the mobiles and the cameras, for the library frontends
+    events: EventBus,
+
+    //cameras streaming at once, and the free slots advertised
+    camera_budget: Option<u32>,
+    camera_slots: watch::Sender<u8>,
+
+    //hardware video decoding, delivered with the host info
+    capabilities: HostCapabilities,
 }
 
 impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps>
     MobileComm<Db, VDevBuilder>
 {
     pub fn new(db: Db, vdev_builder: VDevBuilder) -> Result<Self> {
-        Ok(Self { db, mobiles_connected: HashMap::new(), vdev_builder })
+        Self::with_clock(db, vdev_builder, Arc::new(SystemClock))
     }
-}
-
-#[async_trait]
-impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> CommDataService
-    for MobileComm<Db, VDevBuilder>
-{
-    //provisioning
-    async fn get_host_info(&mut self, addr: Address) -> Result<HostProvInfo> {
-        debug!("Host info requested by: {:?}", addr);
 
-        self.db.get_host_prov_info()
+    /// Reads the time of the sessions, the calls and the queued offers
+    /// from `clock`
+    pub fn with_clock(
+        db: Db, vdev_builder: VDevBuilder, clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        Ok(Self {
+            db,
+            mobiles_connected: HashMap::new(),
+            vdev_builder: Arc::new(vdev_builder),
+            pairing_mode: None,
+            started_at: clock.now(),
+            auth_failures: HashMap::new(),
+            policy: Box::new(AllowAll),
+            link_tests: HashMap::new(),
+            link_test_addr: None,
+            warm_standby: Vec::new(),
+            host_group: None,
+            local_hostname: None,
+            guest_config: GuestSessionsConfig::default(),
+            guest_sessions: HashMap::new(),
+            expiry_publisher: None,
+            failure_publisher: None,
+            handovers: HashMap::new(),
+            handover_publishers: HashMap::new(),
+            privacy: PrivacySwitch::default(),
+            privacy_notifier: None,
+            power_state: watch::channel(HostPowerState::default()).1,
+            power_notifier: None,
+            power_publisher: None,
+            ap_link: watch::channel(None).1,
+            metrics: Metrics::default(),
+            ble_adapters: watch::channel(Vec::new()).1,
+            host_info: None,
+            pending_offers: HashMap::new(),
+            clock,
+            user_scope: None,
+            stats_retention_days: DEFAULT_STATS_RETENTION_DAYS,
+            trace_exporter: TraceExporter::default(),
+            self_test: None,
+            self_test_results: watch::channel(None).0,
+            events: EventBus::default(),
+            camera_budget: None,
+            camera_slots: watch::channel(camera_slots(None, 0)).0,
+            capabilities: HostCapabilities::default(),
+        })
     }
 
-    async fn register_mobile(
-        &mut self, addr: Address, mobile: MobileSchema,
-    ) -> Result<()> {
-        debug!("Registering mobile: {:?}", addr);
+    /// Replaces the default allow-all authorization policy
+    pub fn set_authorization_policy(
+        &mut self, policy: impl AuthorizationPolicy,
+    ) {
+        self.policy = Box::new(policy);
+    }
 
-        //add the mobile to the db
-        self.db.add_mobile(&mobile)
+    fn is_blocked(&self, entry: &str) -> Result<bool> {
+        let mut blocklist = self.db.get_blocklist()?;
+        blocklist.expire(self.clock.today(), AUTO_BLOCK_DAYS);
+        Ok(blocklist.contains(entry))
     }
 
-    //call establishment
-    async fn sub_to_ready_answer(
-        &mut self, addr: Address, publisher: BlePublisher,
-    ) -> Result<()> {
-        debug!("Subscribing to SDP call: {:?}", addr);
+    //the address is blocked once it reaches the max failures within the
+    //window, the tracked addresses and the blocks are bounded
+    fn record_auth_failure(&mut self, addr: &str) -> Result<()> {
+        let now = self.clock.now();
+        self.auth_failures.retain(|_, (_, first)| {
+            now.saturating_duration_since(*first) < AUTH_FAILURE_WINDOW
+        });
+        if !self.auth_failures.contains_key(addr)
+            && self.auth_failures.len() >= MAX_TRACKED_FAILURES
+        {
+            let oldest = self
+                .auth_failures
+                .iter()
+                .min_by_key(|(_, (_, first))| *first)
+                .map(|(oldest, _)| oldest.clone());
+            if let Some(oldest) = oldest {
+                self.auth_failures.remove(&oldest);
+            }
+        }
 
-        //add the publisher to for this mobile
-        self.mobiles_connected.insert(
-            addr,
-            DeviceInfo { publisher: Some(publisher), vdevices: HashMap::new() },
-        );
+        let (failures, _) =
+            self.auth_failures.entry(addr.to_string()).or_insert((0, now));
+        *failures += 1;
+
+        if *failures < MAX_AUTH_FAILURES {
+            return Ok(());
+        }
+
+        self.auth_failures.remove(addr);
+
+        let today = self.clock.today();
+        let mut blocklist = self.db.get_blocklist()?;
+        let expired = blocklist.expire(today, AUTO_BLOCK_DAYS);
+        let added = blocklist.add_automatic(addr, today, MAX_AUTO_BLOCKS);
+        if expired || added {
+            self.db.update_blocklist(&blocklist)?;
+        }
+        if added {
+            warn!("{} blocked after {} failures", addr, MAX_AUTH_FAILURES);
+        }
 
         Ok(())
     }
 
-    //set the SDP offer from the mobile
-    async fn set_mobile_sdp_offer(
-        &mut self, addr: Address, mobile_offer: MobileSdpOffer,
-    ) -> Result<()> {
-        debug!("Mobile Pnp ID: {:?}", addr);
+    //registered mobile, failures count against the address only, an
+    //unknown id is made up at will
+    fn authenticate(
+        &mut self, addr: &Address, mobile_id: &str,
+    ) -> Result<MobileSchema> {
+        if self.is_blocked(mobile_id)? {
+            return Err(anyhow!("Mobile {} is blocked", mobile_id));
+        }
 
-        let MobileSdpOffer { mobile_id, camera_offer } = mobile_offer;
+        match self.db.get_mobile(mobile_id) {
+            Ok(mobile) => {
+                self.auth_failures.remove(addr);
+                Ok(mobile)
+            }
+            Err(e) => {
+                self.metrics.record_failure(FailureReason::NotRegistered);
+                self.record_auth_failure(addr)?;
+                Err(e)
+            }
+        }
+    }
 
-        //check if the mobile is registered
-        let mobile = self.db.get_mobile(&mobile_id)?;
-
-        if let Some(vdevice_info) = self.mobiles_connected.get_mut(&addr) {
-            if let Some(publisher) = &vdevice_info.publisher {
-                //create the virtual devices
-                vdevice_info.vdevices = self
-                    .vdev_builder
-                    .create_from(mobile.name, camera_offer)
-                    .await?;
-
-                //notify the mobile the SDP answer are ready
-                publisher
-                    .publish(SdpAnswerReady { mobile_id }.try_into()?)
-                    .await?;
-            } else {
-                return Err(anyhow!("Publisher not found for mobile"));
+    /// Serves the mobiles only while their users have an active session,
+    /// the new mobiles belong to the user of the pairing seat
+    #[cfg_attr(not(feature = "logind"), allow(dead_code))]
+    pub fn set_user_scope(&mut self, user_scope: UserScope) {
+        self.user_scope = Some(user_scope);
+    }
+
+    //the mobiles of the users without an active session are not served
+    async fn authorize_user(&self, mobile: &MobileSchema) -> Result<()> {
+        match &self.user_scope {
+            Some(user_scope) => user_scope.authorize(mobile).await,
+            None => Ok(()),
+        }
+    }
+
+    //the devices of the mobile are dropped, it stays connected to send a
+    //new offer
+    fn end_calls(&mut self, mobile_id: &str) {
+        let mut ended = Vec::new();
+        for device in self.mobiles_connected.values_mut() {
+            if device.mobile_id.as_deref() != Some(mobile_id) {
+                continue;
             }
-        } else {
-            return Err(anyhow!("Mobile not found in connected devices"));
+            device.vdevices = Arc::new(Mutex::new(VDeviceMap::new()));
+            ended.extend(device.call.take());
         }
 
-        Ok(())
+        for call in ended {
+            self.record_call(call);
+        }
+        self.publish_camera_slots();
     }
 
-    async fn get_sdp_answer(
-        &mut self, addr: Address,
-    ) -> Result<MobileSdpAnswer> {
-        debug!("SDP answer requested by: {:?}", addr);
+    /// Sets the days of usage stats kept, older days are pruned as the
+    /// calls end
+    pub fn set_stats_retention(&mut self, days: u32) {
+        self.stats_retention_days = days;
+    }
 
-        let vdevice_info = self
-            .mobiles_connected
-            .get_mut(&addr)
-            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+    //the call is added to the usage of the day it ends
+    fn record_call(&mut self, call: ActiveCall) {
+        call.progress.export_trace(&self.trace_exporter, true);
+        let first_frames = call.progress.first_frame_latencies();
 
-        let camera_answer = vdevice_info
-            .vdevices
-            .iter()
-            .map(|(name, vdevice)| CameraSdp {
-                name: name.clone(),
-                format: VideoProp::default(),
-                sdp: vdevice.get_sdp_answer().clone(),
-            })
-            .collect::<Vec<CameraSdp>>();
+        let usage = MobileUsage {
+            mobile_id: call.mobile_id,
+            calls: 1,
+            streaming_secs: self
+                .clock
+                .now()
+                .saturating_duration_since(call.started_at)
+                .as_secs(),
+            failures: call.progress.failed_cameras(),
+            first_frames: first_frames.len() as u32,
+            first_frame_ms: first_frames
+                .iter()
+                .map(|(_, latency)| latency.as_millis() as u64)
+                .sum(),
+        };
 
-        Ok(MobileSdpAnswer { camera_answer })
+        let today = self.clock.today();
+        let recorded = self.db.get_usage_stats().and_then(|mut stats| {
+            stats.add(today, &usage);
+            stats.prune(today, self.stats_retention_days);
+            self.db.update_usage_stats(&stats)
+        });
+        if let Err(e) = recorded {
+            warn!("Call of {} not recorded: {:?}", usage.mobile_id, e);
+        }
     }
 
-    //disconnect the mobile device
-    async fn mobile_disconnected(&mut self, addr: Address) -> Result<()> {
-        if let Some(_) = self.mobiles_connected.remove(&addr) {
-            debug!(
-                "Mobile: {:?} disconnected and removed from connected devices",
-                addr
-            );
+    /// Replaces the exporter of the call setup traces, only logged by
+    /// default
+    pub fn set_trace_exporter(&mut self, exporter: TraceExporter) {
+        self.trace_exporter = exporter;
+    }
 
-            return Ok(());
+    /// Sets the host group delivered with the host info
+    pub fn set_host_group(&mut self, host_group: Option<String>) {
+        self.host_group = host_group;
+    }
+
+    /// Sets the video decoding delivered with the host info
+    pub fn set_host_capabilities(&mut self, capabilities: HostCapabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Sets the local hostname delivered with the host info
+    pub fn set_local_hostname(&mut self, local_hostname: Option<String>) {
+        self.local_hostname = local_hostname;
+    }
+
+    /// Keeps the last used cameras of the trusted mobiles prepared
+    pub fn enable_warm_standby(&mut self, mobiles: Vec<String>) {
+        self.warm_standby = mobiles;
+    }
+
+    /// Prepares the last used cameras of every trusted mobile
+    pub async fn prepare_warm_standby(&self) {
+        for mobile_id in self.warm_standby.iter() {
+            if let Err(e) = self.prepare_standby(mobile_id).await {
+                warn!("No warm standby for mobile {}: {:?}", mobile_id, e);
+            }
         }
+    }
 
-        Err(anyhow!("Mobile not found in connected devices"))
+    async fn prepare_standby(&self, mobile_id: &str) -> Result<()> {
+        let mobile = self.db.get_mobile(mobile_id)?;
+        self.authorize_user(&mobile).await?;
+        let last_cameras = self.db.get_last_cameras(mobile_id)?;
+
+        self.vdev_builder
+            .prepare_standby(mobile.name, last_cameras.cameras)
+            .await
+    }
+
+    /// Runs the self-test at the times of `schedule`, once no camera
+    /// streams
+    pub fn enable_self_test(&mut self, schedule: Schedule) {
+        self.self_test = Some(SelfTestScheduler::new(schedule));
+    }
+
+    /// Limits the cameras streaming at once, the cameras of an offer over
+    /// the free slots are refused
+    pub fn set_camera_budget(&mut self, max_cameras: Option<u32>) {
+        self.camera_budget = max_cameras;
+        self.publish_camera_slots();
+    }
+
+    /// Free camera slots, advertised to the mobiles
+    pub fn subscribe_camera_slots(&self) -> watch::Receiver<u8> {
+        self.camera_slots.subscribe()
+    }
+
+    //the cameras of the calls, the ones kept for a handover included
+    fn cameras_in_use(&self, except: Option<&Address>) -> u32 {
+        let connected = self
+            .mobiles_connected
+            .iter()
+            .filter(|(addr, _)| Some(*addr) != except)
+            .map(|(_, device)| device);
+        let retained = self
+            .handovers
+            .values()
+            .filter_map(|handover| handover.retained.as_ref());
+        connected
+            .chain(retained)
+            .filter_map(|device| device.call.as_ref())
+            .map(|call| call.progress.active_cameras())
+            .sum()
+    }
+
+    fn publish_camera_slots(&self) {
+        let slots = camera_slots(self.camera_budget, self.cameras_in_use(None));
+        self.camera_slots.send_if_modified(|current| {
+            let changed = *current != slots;
+            *current = slots;
+            changed
+        });
+    }
+
+    //reported to the mobile, the call goes on with its other cameras
+    fn refuse_camera(
+        &self, mobile_id: &str, camera: String, code: FailureCode, detail: &str,
+    ) {
+        self.events.emit(Event::Error {
+            mobile_id: mobile_id.to_string(),
+            message: format!("{}: {}", camera, detail),
+        });
+        report_failure(
+            self.failure_publisher.as_ref(),
+            OperationFailed {
+                mobile_id: mobile_id.to_string(),
+                operation: FailedOperation::StreamStart,
+                camera: Some(camera),
+                code,
+                detail: detail.to_string(),
+            },
+        );
+    }
+
+    /// Latest run of the self-test
+    pub fn subscribe_self_tests(&self) -> watch::Receiver<Option<SelfTestRun>> {
+        self.self_test_results.subscribe()
+    }
+
+    //the runs are kept in the database, a failure is logged as an error
+    fn record_self_test(&mut self, run: SelfTestRun) {
+        let recorded = self.db.get_self_tests().and_then(|mut log| {
+            log.add(run.clone());
+            self.db.update_self_tests(&log)
+        });
+        if let Err(e) = recorded {
+            warn!("Self-test of {} not recorded: {:?}", run.at, e);
+        }
+
+        let failed: Vec<String> = run
+            .steps
+            .iter()
+            .filter(|step| !step.passed)
+            .map(|step| format!("{}: {}", step.name, step.detail))
+            .collect();
+        if failed.is_empty() {
+            info!("Self-test of {} passed", run.at);
+        } else {
+            error!("Self-test of {} failed, {}", run.at, failed.join("; "));
+        }
+
+        self.self_test_results.send_replace(Some(run));
+    }
+
+    fn check_self_test(&mut self) {
+        let finished =
+            self.self_test.as_mut().and_then(|test| test.take_finished());
+        if let Some(run) = finished {
+            self.record_self_test(run);
+        }
+
+        let idle =
+            self.mobiles_connected.values().all(|dev| dev.call.is_none());
+        let now = self.clock.local_now();
+        let Some(self_test) = self.self_test.as_mut() else {
+            return;
+        };
+        if !self_test.is_due(now, idle) {
+            return;
+        }
+
+        info!("Running the scheduled self-test");
+        let vdev_builder = self.vdev_builder.clone();
+        let at = now.format("%Y-%m-%d %H:%M").to_string();
+        self_test.start(async move {
+            let steps = vdev_builder.self_test().await.unwrap_or_else(|e| {
+                vec![SelfTestStep {
+                    name: "self-test".to_string(),
+                    passed: false,
+                    detail: format!("{:?}", e),
+                }]
+            });
+            SelfTestRun { at, steps }
+        });
+    }
+
+    /// Limits the sessions of the guest mobiles
+    pub fn enable_guest_sessions(&mut self, config: GuestSessionsConfig) {
+        self.guest_config = config;
+    }
+
+    //the session starts with the first offer, reconnecting does not renew it
+    fn start_guest_session(&mut self, mobile_id: &str) {
+        if !self.guest_config.mobiles.iter().any(|id| id == mobile_id) {
+            return;
+        }
+
+        self.guest_sessions.entry(mobile_id.to_string()).or_insert_with(|| {
+            info!("Guest session started for mobile {}", mobile_id);
+            GuestSession {
+                expires_at: self.clock.now()
+                    + Duration::from_secs(self.guest_config.max_session_secs),
+                warned: false,
+            }
+        });
+    }
+
+    //tears down the devices of the guest and forgets its registration
+    fn expire_guest_session(&mut self, mobile_id: &str) -> Result<()> {
+        self.guest_sessions.remove(mobile_id);
+
+        let expired: Vec<Address> = self
+            .mobiles_connected
+            .iter()
+            .filter(|(_, device)| {
+                device.mobile_id.as_deref() == Some(mobile_id)
+            })
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            self.link_tests.remove(&addr);
+            if let Some(call) = self
+                .mobiles_connected
+                .remove(&addr)
+                .and_then(|mut device| device.call.take())
+            {
+                self.record_call(call);
+            }
+        }
+
+        info!("Guest session expired for mobile {}", mobile_id);
+
+        self.db.remove_mobile(mobile_id)
+    }
+
+    /// Opens the pairing window, the AP credentials and a new pairing token
+    /// are delivered with the host info until the window expires.
+    pub fn enable_pairing_mode(&mut self, pairing_mode: Arc<PairingMode>) {
+        info!("Pairing mode enabled for {}s", pairing_mode.window_secs());
+        self.pairing_mode = Some(pairing_mode);
+    }
+
+    /// Shares the privacy switch of the process with the mobiles
+    pub fn set_privacy_switch(&mut self, privacy: PrivacySwitch) {
+        self.privacy = privacy;
+    }
+
+    /// Reports the traffic of the access point interface in the diagnostics
+    #[cfg_attr(not(feature = "ap"), allow(dead_code))]
+    pub fn set_ap_link_stats(
+        &mut self, ap_link: watch::Receiver<Option<InterfaceStats>>,
+    ) {
+        self.ap_link = ap_link;
+    }
+
+    /// Runs the link tests on `addr`, the address of the access point, none
+    /// are run without it
+    #[cfg_attr(not(feature = "ap"), allow(dead_code))]
+    pub fn set_link_test_addr(&mut self, addr: Ipv4Addr) {
+        self.link_test_addr = Some(addr);
+    }
+
+    /// Records the failures in `metrics`, shared with the BLE server and
+    /// reported in the diagnostics
+    pub fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Reports the modes of the Bluetooth adapters in the diagnostics
+    #[cfg_attr(not(feature = "ble"), allow(dead_code))]
+    pub fn set_ble_adapters(
+        &mut self, ble_adapters: watch::Receiver<Vec<AdapterStatus>>,
+    ) {
+        self.ble_adapters = ble_adapters;
+    }
+
+    /// Shares the power state of the host with the mobiles
+    pub fn set_power_state(
+        &mut self, power_state: watch::Receiver<HostPowerState>,
+    ) {
+        self.power_state = power_state;
+    }
+
+    /// Follows the host info changes, they are notified to the mobiles
+    pub fn set_host_info_changes(
+        &mut self, host_info: watch::Receiver<HostInfo>,
+    ) {
+        self.host_info = Some(host_info);
+    }
+
+    /// Emits the connections, the streams and the failures of the mobiles
+    /// on `events`
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+}
+
+#[async_trait]
+impl<Db: AppDataStore, VDevBuilder: VDeviceBuilderOps> CommDataService
+    for MobileComm<Db, VDevBuilder>
+{
+    //provisioning
+    async fn get_host_info(&mut self, addr: Address) -> Result<HostProvInfo> {
+        debug!("Host info requested by: {:?}", addr);
+
+        let mut host_info = self.db.get_host_prov_info()?;
+        host_info.host_group = self.host_group.clone();
+        host_info.local_hostname = self.local_hostname.clone();
+        host_info.capabilities = self.capabilities.clone();
+
+        //add the pairing data only while the pairing window is open
+        if let Some(pairing_mode) =
+            self.pairing_mode.as_ref().filter(|mode| mode.is_active())
+        {
+            host_info.ap_creds = pairing_mode.ap_creds.clone();
+            host_info.pairing_token = Some(pairing_mode.token.clone());
+        }
+
+        host_info.negotiations =
+            vec![Negotiation::MobileOffer, Negotiation::HostOffer];
+
+        Ok(host_info)
+    }
+
+    async fn refresh_host_info(&mut self) -> Result<bool> {
+        let Some(host_info) = self
+            .host_info
+            .as_mut()
+            .filter(|host_info| host_info.has_changed().unwrap_or(false))
+        else {
+            return Ok(false);
+        };
+
+        let host_info = host_info.borrow_and_update().clone();
+        self.db.update_host_info(&host_info)
+    }
+
+    async fn get_diagnostics(
+        &mut self, addr: Address,
+    ) -> Result<HostDiagnostics> {
+        debug!("Diagnostics requested by: {:?}", addr);
+
+        let host_info = self.db.get_host_prov_info()?;
+
+        Ok(HostDiagnostics {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            uptime_secs: self
+                .clock
+                .now()
+                .saturating_duration_since(self.started_at)
+                .as_secs(),
+            connection_type: host_info.connection_type,
+            pairing_active: self
+                .pairing_mode
+                .as_ref()
+                .is_some_and(|mode| mode.is_active()),
+            sessions: self.mobiles_connected.len(),
+            cameras: self
+                .mobiles_connected
+                .values()
+                .filter_map(|device| device.vdevices.lock().ok())
+                .map(|vdevices| vdevices.len())
+                .sum(),
+            streams: self
+                .mobiles_connected
+                .values()
+                .flat_map(|device| {
+                    let first_frames = device
+                        .call
+                        .as_ref()
+                        .map(|call| call.progress.first_frame_latencies())
+                        .unwrap_or_default();
+                    let Ok(vdevices) = device.vdevices.lock() else {
+                        return Vec::new();
+                    };
+                    vdevices
+                        .iter()
+                        .map(|(name, vdevice)| StreamStats {
+                            first_frame_ms: first_frames
+                                .iter()
+                                .find(|(camera, _)| camera == name)
+                                .map(|(_, latency)| latency.as_millis() as u64),
+                            ..vdevice.stream_stats()
+                        })
+                        .collect()
+                })
+                .collect(),
+            ap_link: self.ap_link.borrow().clone(),
+            requests: self.metrics.snapshot(),
+            clock: host_clock(),
+            ble_adapters: self.ble_adapters.borrow().clone(),
+        })
+    }
+
+    async fn register_mobile(
+        &mut self, addr: Address, mut mobile: MobileSchema,
+    ) -> Result<()> {
+        debug!("Registering mobile: {:?}", addr);
+
+        if self.is_blocked(&mobile.id)? {
+            return Err(anyhow!("Mobile {} is blocked", mobile.id));
+        }
+
+        self.policy.authorize_register(&addr, &mobile)?;
+
+        //the owner and the trust are never taken from the mobile
+        mobile.owner = match &self.user_scope {
+            Some(user_scope) => user_scope.pairing_owner().await,
+            None => None,
+        };
+        mobile.trusted = false;
+
+        //add the mobile to the db
+        self.db.add_mobile(&mobile)?;
+
+        //the offers sent before the registration was stored
+        let pending: Vec<Address> = self
+            .pending_offers
+            .iter()
+            .filter(|(_, pending)| pending.offer.mobile_id == mobile.id)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in pending {
+            let Some(pending) = self.pending_offers.remove(&addr) else {
+                continue;
+            };
+            debug!("Processing the queued offer of {}", mobile.id);
+            if let Err(e) = self.set_mobile_sdp_offer(addr, pending.offer).await
+            {
+                error!("Queued offer of {} failed: {:?}", mobile.id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    //access control
+    async fn check_access(&mut self, addr: Address) -> Result<()> {
+        if self.is_blocked(&addr)? {
+            return Err(anyhow!("Mobile {} is blocked", addr));
+        }
+
+        Ok(())
+    }
+
+    async fn request_rejected(&mut self, addr: Address) -> Result<()> {
+        debug!("Invalid request from: {:?}", addr);
+
+        self.record_auth_failure(&addr)
+    }
+
+    //host administration
+    async fn update_host_settings(
+        &mut self, addr: Address, update: HostSettingsUpdate,
+    ) -> Result<()> {
+        debug!("Host settings update requested by: {:?}", addr);
+
+        //only registered mobiles can change settings, from their own session
+        let mut mobile = self.authenticate(&addr, &update.mobile_id)?;
+        let session_id = self
+            .mobiles_connected
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile has no open session"))?
+            .mobile_id
+            .as_deref();
+        if session_id != Some(update.mobile_id.as_str()) {
+            return Err(anyhow!(
+                "Host settings of mobile {} from {}",
+                update.mobile_id,
+                addr
+            ));
+        }
+
+        //the settings shared by every mobile need a trusted one
+        if update.changes_host() && !mobile.trusted {
+            return Err(anyhow!("Mobile {} is not trusted", update.mobile_id));
+        }
+
+        let mut settings = self.db.get_host_settings()?;
+        update.apply_to(&mut settings)?;
+        self.db.update_host_settings(&settings)?;
+
+        //the preferences of the mobile itself
+        if let Some(always_record) = update.always_record {
+            mobile.always_record = always_record;
+            self.db.update_mobile(&mobile)?;
+        }
+
+        info!("Host settings updated by mobile: {}", update.mobile_id);
+
+        Ok(())
+    }
+
+    //link test before the call
+    async fn run_link_test(
+        &mut self, addr: Address, request: LinkTestRequest,
+    ) -> Result<()> {
+        debug!("Link test requested by: {:?}", addr);
+
+        self.authenticate(&addr, &request.mobile_id)?;
+
+        let link_test_addr = self
+            .link_test_addr
+            .ok_or_else(|| anyhow!("No access point for the link test"))?;
+
+        //a new request replaces the previous test
+        let mut link_test = LinkTest::start(
+            link_test_addr,
+            Duration::from_secs(request.duration_secs as u64),
+        )
+        .await?;
+        //the session firewall would drop the test
+        if let Some(port_rule) =
+            self.vdev_builder.open_port(link_test.port())?
+        {
+            link_test.hold_port_rule(port_rule);
+        }
+        self.link_tests.insert(addr, link_test);
+
+        Ok(())
+    }
+
+    async fn get_link_test(&mut self, addr: Address) -> Result<LinkTestReport> {
+        self.link_tests
+            .get(&addr)
+            .map(|link_test| link_test.report())
+            .ok_or_else(|| anyhow!("No link test for the mobile"))
+    }
+
+    //call establishment
+    async fn sub_to_ready_answer(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to SDP call: {:?}", addr);
+
+        //add the publisher to for this mobile
+        self.mobiles_connected.insert(
+            addr,
+            DeviceInfo { publisher: Some(publisher), ..Default::default() },
+        );
+
+        Ok(())
+    }
+
+    //set the SDP offer from the mobile
+    async fn set_mobile_sdp_offer(
+        &mut self, addr: Address, mobile_offer: MobileSdpOffer,
+    ) -> Result<()> {
+        debug!("Mobile Pnp ID: {:?}", addr);
+
+        //a new mobile can send its offer before its registration is stored,
+        //the offer waits for it for a short time
+        if !self.is_blocked(&mobile_offer.mobile_id)?
+            && self.db.get_mobile(&mobile_offer.mobile_id).is_err()
+        {
+            debug!("Offer of {} queued until its registration", addr);
+            self.pending_offers.insert(
+                addr,
+                PendingOffer {
+                    offer: mobile_offer,
+                    expires_at: self.clock.now() + REGISTRATION_GRACE,
+                },
+            );
+            return Ok(());
+        }
+
+        let MobileSdpOffer {
+            mobile_id,
+            camera_offer,
+            negotiation,
+            handover_token,
+            latency_test,
+        } = mobile_offer;
+
+        //check if the mobile is registered
+        let mobile = self.authenticate(&addr, &mobile_id)?;
+
+        self.policy.authorize_offer(&addr, &mobile)?;
+        self.authorize_user(&mobile).await?;
+
+        self.start_guest_session(&mobile_id);
+
+        //the host settings are the defaults of every mobile
+        let settings = self.db.get_host_settings()?;
+        let options = CallOptions {
+            negotiation,
+            latency_test,
+            record: mobile.always_record || settings.auto_record,
+            latency_profile: settings.latency_profile,
+            owner: mobile.owner,
+        };
+
+        //the denied cameras are left out of the session
+        let (mut camera_offer, denied): (Vec<CameraSdp>, Vec<CameraSdp>) =
+            camera_offer.into_iter().partition(|camera| {
+                match self.policy.authorize_stream_start(
+                    &addr,
+                    &mobile,
+                    &camera.name,
+                ) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Camera {} not started: {:?}", camera.name, e);
+                        false
+                    }
+                }
+            });
+        for camera in denied {
+            self.refuse_camera(
+                &mobile_id,
+                camera.name,
+                FailureCode::Denied,
+                "Denied by the policy of the host",
+            );
+        }
+
+        //the offer replaces the cameras of the previous one of the mobile
+        if let Some(budget) = self.camera_budget {
+            let free = budget.saturating_sub(self.cameras_in_use(Some(&addr)));
+            let over =
+                camera_offer.split_off((free as usize).min(camera_offer.len()));
+            for camera in over {
+                warn!("Camera {} not started, no free slot", camera.name);
+                self.refuse_camera(
+                    &mobile_id,
+                    camera.name,
+                    FailureCode::Busy,
+                    "No free camera slot on the host",
+                );
+            }
+        }
+
+        let last_cameras = LastCamerasSchema {
+            cameras: camera_offer
+                .iter()
+                .map(|camera| LastCamera {
+                    name: camera.name.clone(),
+                    format: camera.format.clone(),
+                })
+                .collect(),
+        };
+
+        let vdevice_info = self
+            .mobiles_connected
+            .get_mut(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+        let publisher = vdevice_info
+            .publisher
+            .clone()
+            .ok_or_else(|| anyhow!("Publisher not found for mobile"))?;
+
+        //a new offer replaces the devices of the previous one
+        vdevice_info.vdevices = Arc::new(Mutex::new(VDeviceMap::new()));
+        //a renegotiation doesn't connect the mobile again
+        let previous = vdevice_info.mobile_id.replace(mobile_id.clone());
+        if previous.as_ref() != Some(&mobile_id) {
+            self.events.emit(Event::MobileConnected {
+                mobile_id: mobile_id.clone(),
+                name: mobile.name.clone(),
+            });
+        }
+
+        let offer_at = self.clock.now();
+        let trace = CallTrace::new(
+            mobile_id.clone(),
+            UuidGenerator.new_id().replace('-', ""),
+            offer_at,
+            camera_offer.iter().map(|camera| camera.name.clone()).collect(),
+        );
+
+        let progress = Arc::new(CallProgress {
+            mobile_id: mobile_id.clone(),
+            cameras: Mutex::new(
+                camera_offer
+                    .iter()
+                    .map(|camera| CameraReadiness {
+                        name: camera.name.clone(),
+                        state: CameraState::Pending,
+                    })
+                    .collect(),
+            ),
+            vdevices: Arc::downgrade(&vdevice_info.vdevices),
+            publisher,
+            answer_ready_ack: vdevice_info.answer_ready_ack.clone(),
+            failure_publisher: self.failure_publisher.clone(),
+            trace: Mutex::new(trace),
+            traced: AtomicBool::new(false),
+            clock: self.clock.clone(),
+            metrics: self.metrics.clone(),
+            events: self.events.clone(),
+        });
+
+        let previous_call = vdevice_info.call.replace(ActiveCall {
+            mobile_id: mobile_id.clone(),
+            started_at: offer_at,
+            progress: progress.clone(),
+            handover_token,
+        });
+        if let Some(call) = previous_call {
+            self.record_call(call);
+        }
+        self.publish_camera_slots();
+
+        //the cameras are prepared again once the call ends
+        if self.warm_standby.contains(&mobile_id) {
+            self.db.update_last_cameras(&mobile_id, &last_cameras)?;
+        }
+
+        //the cameras are built in the background, so the mobile can read
+        //the answer of every camera as soon as it is ready
+        let vdev_builder = self.vdev_builder.clone();
+        tokio::spawn(async move {
+            let on_ready_progress = progress.clone();
+            let on_ready: OnCameraReady = Box::new(move |name, vdevice| {
+                on_ready_progress.camera_built(name, vdevice)
+            });
+
+            if let Err(e) = vdev_builder
+                .create_from(mobile.name, camera_offer, options, on_ready)
+                .await
+            {
+                error!("Failed to create the virtual devices: {:?}", e);
+                progress.fail_pending(&e);
+            }
+        });
+
+        Ok(())
+    }
+
+    //set the answers of the mobile to the host offers
+    async fn set_mobile_sdp_reply(
+        &mut self, addr: Address, mobile_reply: MobileSdpReply,
+    ) -> Result<()> {
+        debug!("SDP reply from: {:?}", addr);
+
+        let MobileSdpReply { mobile_id, camera_answer } = mobile_reply;
+
+        self.authenticate(&addr, &mobile_id)?;
+
+        let vdevice_info = self
+            .mobiles_connected
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+        if vdevice_info.mobile_id.as_deref() != Some(mobile_id.as_str()) {
+            return Err(anyhow!("No host offer sent to mobile {}", mobile_id));
+        }
+
+        let vdevices = vdevice_info
+            .vdevices
+            .lock()
+            .map_err(|_| anyhow!("Virtual devices lock poisoned"))?;
+
+        let mut failed = Vec::new();
+        for camera in camera_answer {
+            let applied = vdevices
+                .get(&camera.name)
+                .ok_or_else(|| anyhow!("No host offer for the camera"))
+                .and_then(|vdevice| vdevice.set_remote_answer(&camera.sdp));
+            if let Err(e) = applied {
+                error!(
+                    "Failed to set answer of camera {}: {:?}",
+                    camera.name, e
+                );
+                failed.push(camera.name);
+            }
+        }
+
+        if !failed.is_empty() {
+            return Err(anyhow!("Answers not applied: {}", failed.join(", ")));
+        }
+
+        Ok(())
+    }
+
+    async fn get_sdp_answer(
+        &mut self, addr: Address,
+    ) -> Result<MobileSdpAnswer> {
+        debug!("SDP answer requested by: {:?}", addr);
+
+        let vdevice_info = self
+            .mobiles_connected
+            .get_mut(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+
+        let camera_answer = vdevice_info
+            .vdevices
+            .lock()
+            .map_err(|_| anyhow!("Virtual devices lock poisoned"))?
+            .iter()
+            .map(|(name, vdevice)| CameraSdp {
+                name: name.clone(),
+                format: VideoProp::default(),
+                sdp: vdevice.get_sdp_answer().clone(),
+            })
+            .collect::<Vec<CameraSdp>>();
+
+        Ok(MobileSdpAnswer { camera_answer })
+    }
+
+    async fn sdp_answer_ack(
+        &mut self, addr: Address, ack: SdpAnswerReady,
+    ) -> Result<()> {
+        debug!("SDP answer ready acknowledged by: {:?}", addr);
+
+        let vdevice_info = self
+            .mobiles_connected
+            .get_mut(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+
+        if vdevice_info.mobile_id.as_deref() != Some(ack.mobile_id.as_str()) {
+            return Err(anyhow!(
+                "SDP answer ready ack of mobile {} from {}",
+                ack.mobile_id,
+                addr
+            ));
+        }
+
+        //an unnumbered ack of an older mobile acknowledges the pending one
+        let answer_ready_ack =
+            vdevice_info.answer_ready_ack.lock().ok().and_then(
+                |mut pending| {
+                    if ack.seq != 0 && ack.seq != pending.seq {
+                        return None;
+                    }
+                    pending.ack_tx.take()
+                },
+            );
+        match answer_ready_ack {
+            Some(ack_tx) => {
+                //the retry task could be already done
+                let _ = ack_tx.send(());
+            }
+            None => {
+                warn!(
+                    "Stale SDP answer ready ack {} from mobile: {}",
+                    ack.seq, ack.mobile_id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn sub_to_session_expiry(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to session expiry: {:?}", addr);
+
+        //the topic publisher is shared by all the mobiles
+        self.expiry_publisher = Some(publisher);
+
+        Ok(())
+    }
+
+    async fn remap_camera(
+        &mut self, addr: Address, remap: CameraRemap,
+    ) -> Result<()> {
+        debug!("Camera remap from: {:?}", addr);
+
+        let CameraRemap { mobile_id, device, camera } = remap;
+
+        let mobile = self.authenticate(&addr, &mobile_id)?;
+        self.policy.authorize_stream_start(&addr, &mobile, &camera)?;
+
+        let vdevice_info = self
+            .mobiles_connected
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile not found in connected devices"))?;
+        let Some(call) = vdevice_info
+            .call
+            .as_ref()
+            .filter(|call| call.mobile_id == mobile_id)
+        else {
+            return Err(anyhow!("No call of mobile {}", mobile_id));
+        };
+
+        {
+            let mut vdevices = vdevice_info
+                .vdevices
+                .lock()
+                .map_err(|_| anyhow!("Virtual devices lock poisoned"))?;
+            let vdevice = vdevices
+                .remove(&device)
+                .ok_or_else(|| anyhow!("No device fed by camera {}", device))?;
+            //the device fed by the new camera takes the previous one
+            if let Some(other) = vdevices.remove(&camera) {
+                vdevices.insert(device.clone(), other);
+            }
+            vdevices.insert(camera.clone(), vdevice);
+        }
+        call.progress.remap(&device, &camera);
+
+        info!("Device of camera {} now fed by camera {}", device, camera);
+
+        Ok(())
+    }
+
+    async fn sub_to_operation_failures(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to operation failures: {:?}", addr);
+
+        //the topic publisher is shared by all the mobiles
+        self.failure_publisher = Some(publisher);
+
+        Ok(())
+    }
+
+    async fn request_handover(
+        &mut self, addr: Address, request: HandoverRequest,
+    ) -> Result<()> {
+        debug!("Handover requested by: {:?}", addr);
+
+        let HandoverRequest { mobile_id } = request;
+
+        self.authenticate(&addr, &mobile_id)?;
+        if self.host_group.is_none() {
+            return Err(anyhow!("No host group to hand the call over to"));
+        }
+        let in_call = self
+            .mobiles_connected
+            .get(&addr)
+            .and_then(|device| device.call.as_ref())
+            .is_some_and(|call| call.mobile_id == mobile_id);
+        if !in_call {
+            return Err(anyhow!("No call of mobile {}", mobile_id));
+        }
+        let publisher = self
+            .handover_publishers
+            .get(&addr)
+            .ok_or_else(|| anyhow!("Mobile not subscribed to the handovers"))?;
+
+        //a new request replaces the token of the previous one
+        let token = UuidGenerator.new_id().replace('-', "");
+        let handover = Handover {
+            mobile_id: mobile_id.clone(),
+            token: token.clone(),
+            stage: HandoverStage::Requested,
+        };
+        publisher.publish(handover.try_into()?).await?;
+
+        info!("Handover of mobile {} requested", mobile_id);
+        self.handovers.insert(
+            mobile_id,
+            PendingHandover {
+                token,
+                expires_at: self.clock.now() + HANDOVER_TIMEOUT,
+                retained: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn complete_handover(
+        &mut self, addr: Address, handover: Handover,
+    ) -> Result<()> {
+        debug!("Handover completed by: {:?}", addr);
+
+        let Handover { mobile_id, token, stage } = handover;
+
+        self.authenticate(&addr, &mobile_id)?;
+        if stage != HandoverStage::Connected {
+            return Err(anyhow!("New session of {} not connected", mobile_id));
+        }
+        if self.handovers.get(&mobile_id).map(|h| &h.token) != Some(&token) {
+            return Err(anyhow!("No handover of {} with the token", mobile_id));
+        }
+
+        info!("Call of mobile {} handed over", mobile_id);
+        match self.handovers.remove(&mobile_id).and_then(|h| h.retained) {
+            Some(mut device) => {
+                if let Some(call) = device.call.take() {
+                    self.record_call(call);
+                }
+            }
+            //the mobile is still connected to this host
+            None => self.end_calls(&mobile_id),
+        }
+
+        Ok(())
+    }
+
+    async fn sub_to_handovers(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to handovers: {:?}", addr);
+
+        //only a session bound to a registered mobile by its offer
+        let mobile_id = self
+            .mobiles_connected
+            .get(&addr)
+            .and_then(|device| device.mobile_id.clone())
+            .ok_or_else(|| {
+                anyhow!("No offer of {} before the handovers", addr)
+            })?;
+        self.authenticate(&addr, &mobile_id)?;
+
+        //the publisher belongs to the address of the mobile
+        self.handover_publishers.insert(addr, publisher);
+
+        Ok(())
+    }
+
+    async fn get_privacy_state(
+        &mut self, addr: Address,
+    ) -> Result<PrivacyState> {
+        debug!("Privacy state requested by: {:?}", addr);
+
+        Ok(PrivacyState { enabled: self.privacy.is_enabled() })
+    }
+
+    async fn sub_to_privacy(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to privacy changes: {:?}", addr);
+
+        //the topic publisher is shared by all the mobiles
+        if self.privacy_notifier.is_none() {
+            self.privacy_notifier = Some(StateNotifier::spawn(
+                self.privacy.subscribe(),
+                publisher,
+                |enabled| PrivacyState { enabled: *enabled }.try_into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn get_power_state(
+        &mut self, addr: Address,
+    ) -> Result<HostPowerState> {
+        debug!("Power state requested by: {:?}", addr);
+
+        Ok(self.power_state.borrow().clone())
+    }
+
+    async fn sub_to_power_state(
+        &mut self, addr: Address, publisher: BlePublisher,
+    ) -> Result<()> {
+        debug!("Subscribing to power state changes: {:?}", addr);
+
+        //the topic publisher is shared by all the mobiles
+        if self.power_notifier.is_none() {
+            self.power_publisher = Some(publisher.clone());
+            self.power_notifier = Some(StateNotifier::spawn(
+                self.power_state.clone(),
+                publisher,
+                |state| state.clone().try_into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    //disconnect the mobile device
+    async fn mobile_disconnected(&mut self, addr: Address) -> Result<()> {
+        self.link_tests.remove(&addr);
+        self.handover_publishers.remove(&addr);
+        self.pending_offers.remove(&addr);
+
+        if let Some(mut device_info) = self.mobiles_connected.remove(&addr) {
+            debug!(
+                "Mobile: {:?} disconnected and removed from connected devices",
+                addr
+            );
+
+            //the cameras of a handover run until the new session is reported
+            let handover = device_info
+                .mobile_id
+                .as_ref()
+                .and_then(|mobile_id| self.handovers.get_mut(mobile_id))
+                .filter(|handover| handover.retained.is_none());
+            if let Some(handover) =
+                handover.filter(|_| device_info.call.is_some())
+            {
+                info!("Cameras of {} kept for the handover", addr);
+                handover.retained = Some(device_info);
+                return Ok(());
+            }
+
+            if let Some(call) = device_info.call.take() {
+                self.record_call(call);
+            }
+
+            //the devices are released before preparing the standby ones
+            let mobile_id = device_info.mobile_id.clone();
+            drop(device_info);
+            self.publish_camera_slots();
+            if let Some(mobile_id) = mobile_id.clone() {
+                self.events.emit(Event::MobileDisconnected { mobile_id });
+            }
+
+            if let Some(mobile_id) =
+                mobile_id.filter(|id| self.warm_standby.contains(id))
+            {
+                if let Err(e) = self.prepare_standby(&mobile_id).await {
+                    warn!("No warm standby for mobile {}: {:?}", mobile_id, e);
+                }
+            }
+
+            return Ok(());
+        }
+
+        Err(anyhow!("Mobile not found in connected devices"))
+    }
+    async fn check_sessions(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        let warning = Duration::from_secs(self.guest_config.warning_secs);
+
+        for (mobile_id, session) in self.guest_sessions.iter_mut() {
+            let remaining = session.expires_at.saturating_duration_since(now);
+            if session.warned || remaining.is_zero() || remaining > warning {
+                continue;
+            }
+
+            session.warned = true;
+            let Some(publisher) = &self.expiry_publisher else {
+                warn!("Guest {} not subscribed to the expiry", mobile_id);
+                continue;
+            };
+
+            let expiring = SessionExpiring {
+                mobile_id: mobile_id.clone(),
+                remaining_secs: remaining.as_secs(),
+            };
+            if let Err(e) = publisher.publish(expiring.try_into()?).await {
+                warn!("Failed to warn guest {}: {:?}", mobile_id, e);
+            }
+        }
+
+        let expired: Vec<String> = self
+            .guest_sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(mobile_id, _)| mobile_id.clone())
+            .collect();
+        for mobile_id in expired {
+            self.expire_guest_session(&mobile_id)?;
+        }
+
+        //the mobile was never registered, the offer fails as unknown
+        let expired: Vec<Address> = self
+            .pending_offers
+            .iter()
+            .filter(|(_, pending)| pending.expires_at <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            let Some(pending) = self.pending_offers.remove(&addr) else {
+                continue;
+            };
+            if let Err(e) = self.authenticate(&addr, &pending.offer.mobile_id) {
+                warn!("Queued offer of {} dropped: {:?}", addr, e);
+            }
+        }
+
+        //the streams that stopped since the previous check, and the setups
+        //that completed
+        for device in self.mobiles_connected.values() {
+            let (Some(call), Ok(vdevices)) =
+                (device.call.as_ref(), device.vdevices.lock())
+            else {
+                continue;
+            };
+            for (name, vdevice) in vdevices.iter() {
+                let milestones = vdevice.milestones();
+                call.progress.with_trace(|trace| {
+                    trace.update_milestones(name, milestones)
+                });
+                let Some(detail) = vdevice.take_failure() else {
+                    continue;
+                };
+                self.metrics.record_failure(FailureReason::PipelineFailed);
+                call.progress.report(
+                    FailedOperation::Stream,
+                    Some(name.clone()),
+                    FailureCode::PipelineError,
+                    detail,
+                );
+            }
+            call.progress.export_trace(&self.trace_exporter, false);
+        }
+
+        //the new session of a handover is reported back to the mobile
+        let connected: Vec<(Address, Handover)> = self
+            .mobiles_connected
+            .iter_mut()
+            .filter_map(|(addr, device)| Some((addr, device.call.as_mut()?)))
+            .filter(|(_, call)| {
+                call.handover_token.is_some() && call.progress.is_streaming()
+            })
+            .filter_map(|(addr, call)| {
+                let handover = Handover {
+                    mobile_id: call.mobile_id.clone(),
+                    token: call.handover_token.take()?,
+                    stage: HandoverStage::Connected,
+                };
+                Some((addr.clone(), handover))
+            })
+            .collect();
+        for (addr, handover) in connected {
+            let Some(publisher) = self.handover_publishers.get(&addr) else {
+                warn!("{} not subscribed to the handovers", handover.mobile_id);
+                continue;
+            };
+            info!("Handover of {} connected", handover.mobile_id);
+            if let Err(e) = publisher.publish(handover.try_into()?).await {
+                warn!("Failed to report the handover: {:?}", e);
+            }
+        }
+
+        //the new session was never reported, the kept cameras are stopped
+        let expired: Vec<String> = self
+            .handovers
+            .iter()
+            .filter(|(_, handover)| handover.expires_at <= now)
+            .map(|(mobile_id, _)| mobile_id.clone())
+            .collect();
+        for mobile_id in expired {
+            warn!("Handover of mobile {} expired", mobile_id);
+            let retained =
+                self.handovers.remove(&mobile_id).and_then(|h| h.retained);
+            if let Some(call) =
+                retained.and_then(|mut device| device.call.take())
+            {
+                self.record_call(call);
+            }
+        }
+
+        //the user of the mobile left the seats, e.g. switched user
+        if self.user_scope.is_some() {
+            let streaming: Vec<String> = self
+                .mobiles_connected
+                .values()
+                .filter(|device| device.call.is_some())
+                .filter_map(|device| device.mobile_id.clone())
+                .collect();
+            for mobile_id in streaming {
+                let Ok(mobile) = self.db.get_mobile(&mobile_id) else {
+                    continue;
+                };
+                if let Err(e) = self.authorize_user(&mobile).await {
+                    info!("Call of {} ended: {:?}", mobile_id, e);
+                    self.end_calls(&mobile_id);
+                }
+            }
+        }
+
+        self.check_self_test();
+
+        //the cameras failed or stopped since the previous check
+        self.publish_camera_slots();
+
+        Ok(())
+    }
+
+    //shutdown
+    async fn stop_calls(&mut self) -> Result<()> {
+        self.pending_offers.clear();
+
+        //the devices of the calls are dropped with their map
+        let mut ended = Vec::new();
+        for handover in self.handovers.values_mut() {
+            ended.extend(
+                handover.retained.take().and_then(|mut d| d.call.take()),
+            );
+        }
+        for device in self.mobiles_connected.values_mut() {
+            device.vdevices = Arc::new(Mutex::new(VDeviceMap::new()));
+            ended.extend(device.call.take());
+        }
+
+        info!("Stopping {} calls", ended.len());
+        for call in ended {
+            self.record_call(call);
+        }
+        self.publish_camera_slots();
+        self.vdev_builder.release_standby();
+
+        Ok(())
+    }
+
+    async fn notify_shutdown(&mut self) -> Result<()> {
+        //no mobile subscribed to the power state
+        let Some(publisher) = self.power_publisher.take() else {
+            return Ok(());
+        };
+        //no power change is notified after the shutdown
+        self.power_notifier = None;
+
+        let state = HostPowerState {
+            shutting_down: true,
+            ..self.power_state.borrow().clone()
+        };
+        publisher.publish(state.try_into()?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app_data::ApBand,
+        ble::{
+            api::PubSubSubscriber,
+            comm_types::{DataChunk, UNLIMITED_CAMERA_SLOTS},
+            requester::BleSubscriber,
+        },
+        clock::{ManualClock, SequentialIds},
+        config::AppConfig,
+        user_sessions::MockSessionOps,
+    };
+    use chrono::NaiveDate;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time::timeout;
+
+    const ADDR: &str = "AA:BB:CC:DD:EE:FF";
+
+    struct NoCameras;
+
+    #[async_trait]
+    impl VDeviceBuilderOps for NoCameras {
+        async fn create_from(
+            &self, _mobile_name: String, _camera_offer: Vec<CameraSdp>,
+            _options: CallOptions, _on_ready: OnCameraReady,
+        ) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    //answers with the name of the camera it was built for
+    struct FakeDevice(String);
+
+    impl VDeviceOps for FakeDevice {
+        fn get_sdp_answer(&self) -> String {
+            self.0.clone()
+        }
+
+        fn set_remote_answer(&self, _sdp: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn stream_stats(&self) -> StreamStats {
+            StreamStats::default()
+        }
+
+        fn take_failure(&self) -> Option<String> {
+            None
+        }
+
+        fn milestones(&self) -> StreamMilestones {
+            StreamMilestones::default()
+        }
+    }
+
+    //shows its first frame right away
+    struct StreamingDevice;
+
+    impl VDeviceOps for StreamingDevice {
+        fn get_sdp_answer(&self) -> String {
+            String::new()
+        }
+
+        fn set_remote_answer(&self, _sdp: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn stream_stats(&self) -> StreamStats {
+            StreamStats::default()
+        }
+
+        fn take_failure(&self) -> Option<String> {
+            None
+        }
+
+        fn milestones(&self) -> StreamMilestones {
+            StreamMilestones {
+                first_frame: Some(Instant::now()),
+                ..Default::default()
+            }
+        }
+    }
+
+    struct StreamingCameras;
+
+    #[async_trait]
+    impl VDeviceBuilderOps for StreamingCameras {
+        async fn create_from(
+            &self, _mobile_name: String, camera_offer: Vec<CameraSdp>,
+            _options: CallOptions, on_ready: OnCameraReady,
+        ) -> Result<()> {
+            for camera in camera_offer {
+                on_ready(camera.name, Ok(Box::new(StreamingDevice)));
+            }
+            Ok(())
+        }
+    }
+
+    //keeps the options of every call
+    struct CapturedCalls(Arc<Mutex<Vec<CallOptions>>>);
+
+    #[async_trait]
+    impl VDeviceBuilderOps for CapturedCalls {
+        async fn create_from(
+            &self, _mobile_name: String, _camera_offer: Vec<CameraSdp>,
+            options: CallOptions, _on_ready: OnCameraReady,
+        ) -> Result<()> {
+            self.0.lock().unwrap().push(options);
+            Ok(())
+        }
+    }
+
+    struct FakeCameras {
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl VDeviceBuilderOps for FakeCameras {
+        async fn create_from(
+            &self, _mobile_name: String, camera_offer: Vec<CameraSdp>,
+            _options: CallOptions, on_ready: OnCameraReady,
+        ) -> Result<()> {
+            for camera in camera_offer {
+                let vdevice: Result<Box<dyn VDeviceOps>> = if self.fail {
+                    Err(anyhow!("No free device"))
+                } else {
+                    Ok(Box::new(FakeDevice(camera.name.clone())))
+                };
+                on_ready(camera.name, vdevice);
+            }
+            Ok(())
+        }
+    }
+
+    fn camera_offer(cameras: &[&str]) -> MobileSdpOffer {
+        MobileSdpOffer {
+            camera_offer: cameras
+                .iter()
+                .map(|name| CameraSdp {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..offer()
+        }
+    }
+
+    fn offer() -> MobileSdpOffer {
+        MobileSdpOffer {
+            mobile_id: "mobile_1".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offer_waits_for_registration() {
+        let registered = Arc::new(Mutex::new(false));
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        let is_registered = registered.clone();
+        db.expect_get_mobile().returning(move |id| {
+            if *is_registered.lock().unwrap() {
+                Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+            } else {
+                Err(anyhow!("Mobile info not found"))
+            }
+        });
+        let add_registered = registered.clone();
+        db.expect_add_mobile().times(1).returning(move |_| {
+            *add_registered.lock().unwrap() = true;
+            Ok(())
+        });
+
+        let mut mobile_comm = MobileComm::new(db, NoCameras).unwrap();
+
+        //queued instead of refused as unknown
+        assert!(mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), offer())
+            .await
+            .is_ok());
+        assert!(mobile_comm.pending_offers.contains_key(ADDR));
+
+        let mobile =
+            MobileSchema { id: "mobile_1".to_string(), ..Default::default() };
+        assert!(mobile_comm
+            .register_mobile(ADDR.to_string(), mobile)
+            .await
+            .is_ok());
+        assert!(mobile_comm.pending_offers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queued_offer_expires() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_mobile()
+            .returning(|_| Err(anyhow!("Mobile info not found")));
+
+        let clock = ManualClock::new(NaiveDate::default());
+        let mut mobile_comm =
+            MobileComm::with_clock(db, NoCameras, Arc::new(clock.clone()))
+                .unwrap();
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), offer())
+            .await
+            .unwrap();
+
+        mobile_comm.check_sessions().await.unwrap();
+        assert!(mobile_comm.pending_offers.contains_key(ADDR));
+
+        //the mobile never registered, the offer counts as a failure
+        clock.advance(REGISTRATION_GRACE);
+        mobile_comm.check_sessions().await.unwrap();
+        assert!(mobile_comm.pending_offers.is_empty());
+        assert_eq!(
+            mobile_comm.auth_failures.get(ADDR).map(|(failures, _)| *failures),
+            Some(1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_answer_ready_ack_ignored() {
+        let publisher = BlePublisher::new(512);
+        let mut subscriber =
+            BleSubscriber::new(publisher.get_subscriber().await);
+        let ack_tx = publisher.publish_with_ack(
+            vec![1],
+            ANSWER_READY_RETRIES,
+            ANSWER_READY_ACK_TIMEOUT,
+        );
+
+        let mut mobile_comm =
+            MobileComm::new(MockAppDataStore::new(), NoCameras).unwrap();
+        mobile_comm.mobiles_connected.insert(
+            ADDR.to_string(),
+            DeviceInfo {
+                mobile_id: Some("mobile_1".to_string()),
+                answer_ready_ack: Arc::new(Mutex::new(PendingAck {
+                    seq: 2,
+                    ack_tx: Some(ack_tx),
+                })),
+                ..Default::default()
+            },
+        );
+        let ack = |mobile_id: &str, seq| SdpAnswerReady {
+            mobile_id: mobile_id.to_string(),
+            seq,
+            ..Default::default()
+        };
+        subscriber.recv().await.unwrap();
+
+        //neither stops the retries of the pending notification
+        assert!(mobile_comm
+            .sdp_answer_ack(ADDR.to_string(), ack("mobile_2", 2))
+            .await
+            .is_err());
+        mobile_comm
+            .sdp_answer_ack(ADDR.to_string(), ack("mobile_1", 1))
+            .await
+            .unwrap();
+        subscriber.recv().await.unwrap();
+
+        mobile_comm
+            .sdp_answer_ack(ADDR.to_string(), ack("mobile_1", 2))
+            .await
+            .unwrap();
+        let more = timeout(ANSWER_READY_ACK_TIMEOUT * 2, subscriber.recv());
+        assert!(more.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_automatic_blocks_expire() {
+        let blocklist = Arc::new(Mutex::new(BlocklistSchema::default()));
+        let mut db = MockAppDataStore::new();
+        let stored = blocklist.clone();
+        db.expect_get_blocklist()
+            .returning(move || Ok(stored.lock().unwrap().clone()));
+        let updated = blocklist.clone();
+        db.expect_update_blocklist().returning(move |new| {
+            *updated.lock().unwrap() = new.clone();
+            Ok(())
+        });
+
+        let clock = ManualClock::new(NaiveDate::default());
+        let mut mobile_comm =
+            MobileComm::with_clock(db, NoCameras, Arc::new(clock.clone()))
+                .unwrap();
+        //the failures older than the window are forgotten
+        for _ in 1..MAX_AUTH_FAILURES {
+            mobile_comm.request_rejected(ADDR.to_string()).await.unwrap();
+        }
+        clock.advance(AUTH_FAILURE_WINDOW);
+        mobile_comm.request_rejected(ADDR.to_string()).await.unwrap();
+        assert!(mobile_comm.check_access(ADDR.to_string()).await.is_ok());
+
+        for _ in 1..MAX_AUTH_FAILURES {
+            mobile_comm.request_rejected(ADDR.to_string()).await.unwrap();
+        }
+        assert!(mobile_comm.check_access(ADDR.to_string()).await.is_err());
+        assert_eq!(blocklist.lock().unwrap().automatic.len(), 1);
+
+        clock.advance(Duration::from_secs(86_400 * AUTO_BLOCK_DAYS as u64));
+        assert!(mobile_comm.check_access(ADDR.to_string()).await.is_ok());
+    }
+
+    //the test pipeline does not play
+    struct BrokenPipeline;
+
+    #[async_trait]
+    impl VDeviceBuilderOps for BrokenPipeline {
+        async fn create_from(
+            &self, _mobile_name: String, _camera_offer: Vec<CameraSdp>,
+            _options: CallOptions, _on_ready: OnCameraReady,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn self_test(&self) -> Result<Vec<SelfTestStep>> {
+            Ok(vec![SelfTestStep {
+                name: "pipeline".to_string(),
+                passed: false,
+                detail: "no element v4l2sink".to_string(),
+            }])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_self_test_recorded() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_self_tests()
+            .returning(|| Ok(SelfTestLogSchema::default()));
+        db.expect_update_self_tests()
+            .withf(|log| log.runs.len() == 1 && !log.runs[0].passed())
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let clock = ManualClock::new(NaiveDate::default());
+        let mut mobile_comm =
+            MobileComm::with_clock(db, BrokenPipeline, Arc::new(clock.clone()))
+                .unwrap();
+        mobile_comm.enable_self_test("30 3 * * *".parse().unwrap());
+        let mut results = mobile_comm.subscribe_self_tests();
+
+        //not scheduled at midnight
+        mobile_comm.check_sessions().await.unwrap();
+        tokio::task::yield_now().await;
+        mobile_comm.check_sessions().await.unwrap();
+        assert!(!results.has_changed().unwrap());
+
+        //the run is recorded by the check after it finishes
+        clock.advance(Duration::from_secs(3 * 3600 + 30 * 60));
+        mobile_comm.check_sessions().await.unwrap();
+        tokio::task::yield_now().await;
+        mobile_comm.check_sessions().await.unwrap();
+
+        results.changed().await.unwrap();
+        let run = results.borrow().clone().unwrap();
+        assert_eq!(run.at, "1970-01-01 03:30");
+        assert!(!run.passed());
+    }
+
+    #[tokio::test]
+    async fn test_mobile_of_other_user_not_served() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_add_mobile()
+            .withf(|mobile| mobile.owner == Some(1000))
+            .times(1)
+            .returning(|_| Ok(()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema {
+                id: id.to_string(),
+                owner: Some(1000),
+                ..Default::default()
+            })
+        });
+
+        //the owner paired on the seat, then switched to another user
+        let mut sessions = MockSessionOps::new();
+        sessions.expect_seat_user().returning(|_| Ok(Some(1000)));
+        sessions.expect_active_users().returning(|| Ok(vec![1001]));
+
+        let mut mobile_comm = MobileComm::new(db, NoCameras).unwrap();
+        mobile_comm
+            .set_user_scope(UserScope::new(sessions, "seat0".to_string()));
+
+        //the owner is set by the host, whatever the mobile sends
+        let mobile = MobileSchema {
+            id: "mobile_1".to_string(),
+            owner: Some(0),
+            ..Default::default()
+        };
+        mobile_comm.register_mobile(ADDR.to_string(), mobile).await.unwrap();
+
+        assert!(mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), offer())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_failure_reported() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+
+        let mut mobile_comm =
+            MobileComm::new(db, FakeCameras { fail: true }).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        let failures = BlePublisher::new(512);
+        let mut subscriber = failures.get_subscriber().await;
+        mobile_comm
+            .sub_to_operation_failures(ADDR.to_string(), failures)
+            .await
+            .unwrap();
+
+        //the offer is acked before the camera is built
+        assert!(mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .is_ok());
+
+        let chunk: DataChunk =
+            subscriber.recv().await.unwrap().try_into().unwrap();
+        let failure: OperationFailed = chunk.d.as_slice().try_into().unwrap();
+        assert_eq!(failure.operation, FailedOperation::PipelineBuild);
+        assert_eq!(failure.camera.as_deref(), Some("back"));
+        assert_eq!(failure.code, FailureCode::BuildFailed);
+        assert_eq!(failure.detail, "No free device");
+    }
+
+    #[tokio::test]
+    async fn test_cameras_over_budget_refused() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+        db.expect_get_usage_stats()
+            .returning(|| Ok(UsageStatsSchema::default()));
+        db.expect_update_usage_stats().returning(|_| Ok(()));
+
+        let mut mobile_comm = MobileComm::new(db, NoCameras).unwrap();
+        let slots = mobile_comm.subscribe_camera_slots();
+        assert_eq!(*slots.borrow(), UNLIMITED_CAMERA_SLOTS);
+        mobile_comm.set_camera_budget(Some(2));
+        assert_eq!(*slots.borrow(), 2);
+
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        let failures = BlePublisher::new(512);
+        let mut subscriber = failures.get_subscriber().await;
+        mobile_comm
+            .sub_to_operation_failures(ADDR.to_string(), failures)
+            .await
+            .unwrap();
+        mobile_comm
+            .set_mobile_sdp_offer(
+                ADDR.to_string(),
+                camera_offer(&["back", "front", "wide"]),
+            )
+            .await
+            .unwrap();
+
+        let chunk: DataChunk =
+            subscriber.recv().await.unwrap().try_into().unwrap();
+        let failure: OperationFailed = chunk.d.as_slice().try_into().unwrap();
+        assert_eq!(failure.camera.as_deref(), Some("wide"));
+        assert_eq!(failure.code, FailureCode::Busy);
+        assert_eq!(*slots.borrow(), 0);
+
+        //a renegotiation reuses the slots of the mobile
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .unwrap();
+        assert_eq!(*slots.borrow(), 1);
+
+        mobile_comm.mobile_disconnected(ADDR.to_string()).await.unwrap();
+        assert_eq!(*slots.borrow(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_camera_remapped_to_device() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+
+        let mut mobile_comm =
+            MobileComm::new(db, FakeCameras { fail: false }).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        mobile_comm
+            .set_mobile_sdp_offer(
+                ADDR.to_string(),
+                camera_offer(&["back", "front"]),
+            )
+            .await
+            .unwrap();
+
+        let vdevices = mobile_comm.mobiles_connected[ADDR].vdevices.clone();
+        while vdevices.lock().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        let remap = CameraRemap {
+            mobile_id: "mobile_1".to_string(),
+            device: "back".to_string(),
+            camera: "front".to_string(),
+        };
+        mobile_comm.remap_camera(ADDR.to_string(), remap).await.unwrap();
+
+        //both devices keep streaming, with their cameras swapped
+        let vdevices = vdevices.lock().unwrap();
+        assert_eq!(vdevices["front"].get_sdp_answer(), "back");
+        assert_eq!(vdevices["back"].get_sdp_answer(), "front");
+    }
+
+    async fn next_handover(subscriber: &mut PubSubSubscriber) -> Handover {
+        let chunk: DataChunk =
+            subscriber.recv().await.unwrap().try_into().unwrap();
+        chunk.d.as_slice().try_into().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cameras_kept_until_handover_completed() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+        db.expect_get_usage_stats()
+            .returning(|| Ok(UsageStatsSchema::default()));
+        db.expect_update_usage_stats().times(1).returning(|_| Ok(()));
+
+        let mut mobile_comm =
+            MobileComm::new(db, FakeCameras { fail: false }).unwrap();
+        mobile_comm.set_host_group(Some("office".to_string()));
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .unwrap();
+        let handovers = BlePublisher::new(512);
+        let mut subscriber = handovers.get_subscriber().await;
+        mobile_comm
+            .sub_to_handovers(ADDR.to_string(), handovers)
+            .await
+            .unwrap();
+
+        let request = HandoverRequest { mobile_id: "mobile_1".to_string() };
+        mobile_comm.request_handover(ADDR.to_string(), request).await.unwrap();
+        let handover = next_handover(&mut subscriber).await;
+        assert_eq!(handover.stage, HandoverStage::Requested);
+
+        //the mobile leaves the room, the cameras keep streaming
+        mobile_comm.mobile_disconnected(ADDR.to_string()).await.unwrap();
+        assert!(mobile_comm.handovers["mobile_1"].retained.is_some());
+
+        let connected = Handover {
+            mobile_id: "mobile_1".to_string(),
+            token: "other".to_string(),
+            stage: HandoverStage::Connected,
+        };
+        assert!(mobile_comm
+            .complete_handover(ADDR.to_string(), connected.clone())
+            .await
+            .is_err());
+
+        let connected = Handover { token: handover.token, ..connected };
+        mobile_comm
+            .complete_handover(ADDR.to_string(), connected)
+            .await
+            .unwrap();
+        assert!(mobile_comm.handovers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_session_of_handover_reported() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+
+        let mut mobile_comm = MobileComm::new(db, StreamingCameras).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        let offer = MobileSdpOffer {
+            handover_token: Some("token-1".to_string()),
+            ..camera_offer(&["back"])
+        };
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), offer)
+            .await
+            .unwrap();
+        let handovers = BlePublisher::new(512);
+        let mut subscriber = handovers.get_subscriber().await;
+        mobile_comm
+            .sub_to_handovers(ADDR.to_string(), handovers)
+            .await
+            .unwrap();
+        let vdevices = mobile_comm.mobiles_connected[ADDR].vdevices.clone();
+        while vdevices.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        mobile_comm.check_sessions().await.unwrap();
+        let handover = next_handover(&mut subscriber).await;
+        assert_eq!(handover.token, "token-1");
+        assert_eq!(handover.stage, HandoverStage::Connected);
+
+        //reported once
+        let call = mobile_comm.mobiles_connected[ADDR].call.as_ref().unwrap();
+        assert!(call.handover_token.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handover_token_only_to_requester() {
+        const OTHER_ADDR: &str = "11:22:33:44:55:66";
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+
+        let mut mobile_comm =
+            MobileComm::new(db, FakeCameras { fail: false }).unwrap();
+        mobile_comm.set_host_group(Some("office".to_string()));
+
+        //no subscription before the session is bound by an offer
+        mobile_comm
+            .sub_to_ready_answer(OTHER_ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        assert!(mobile_comm
+            .sub_to_handovers(OTHER_ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .is_err());
+
+        let mut subscribers = Vec::new();
+        for (addr, mobile_id) in [(ADDR, "mobile_1"), (OTHER_ADDR, "mobile_2")]
+        {
+            mobile_comm
+                .sub_to_ready_answer(addr.to_string(), BlePublisher::new(512))
+                .await
+                .unwrap();
+            let offer = MobileSdpOffer {
+                mobile_id: mobile_id.to_string(),
+                ..camera_offer(&["back"])
+            };
+            mobile_comm
+                .set_mobile_sdp_offer(addr.to_string(), offer)
+                .await
+                .unwrap();
+            let handovers = BlePublisher::new(512);
+            subscribers.push(handovers.get_subscriber().await);
+            mobile_comm
+                .sub_to_handovers(addr.to_string(), handovers)
+                .await
+                .unwrap();
+        }
+
+        let request = HandoverRequest { mobile_id: "mobile_1".to_string() };
+        mobile_comm.request_handover(ADDR.to_string(), request).await.unwrap();
+        let handover = next_handover(&mut subscribers[0]).await;
+        assert_eq!(handover.mobile_id, "mobile_1");
+
+        //the other mobile never sees the token
+        assert!(timeout(Duration::from_millis(50), subscribers[1].recv())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_pairing_window_closes() {
+        let config =
+            AppConfig { pairing_window_secs: 60, ..Default::default() };
+        let clock = ManualClock::new(NaiveDate::default());
+        let pairing_mode = PairingMode::with_sources(
+            None,
+            watch::channel(config).1,
+            Arc::new(clock.clone()),
+            &SequentialIds::default(),
+        );
+        assert_eq!(pairing_mode.token(), "id-1");
+
+        clock.advance(Duration::from_secs(59));
+        assert!(pairing_mode.is_active());
+        clock.advance(Duration::from_secs(1));
+        assert!(!pairing_mode.is_active());
+    }
+
+    #[tokio::test]
+    async fn test_always_record_preference() {
+        let stored = Arc::new(Mutex::new(MobileSchema {
+            id: "mobile_1".to_string(),
+            ..Default::default()
+        }));
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        let read = stored.clone();
+        db.expect_get_mobile()
+            .returning(move |_| Ok(read.lock().unwrap().clone()));
+        let written = stored.clone();
+        db.expect_update_mobile().times(1).returning(move |mobile| {
+            *written.lock().unwrap() = mobile.clone();
+            Ok(())
+        });
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_update_host_settings().returning(|_| Ok(()));
+        //the first call ends with the second offer
+        db.expect_get_usage_stats()
+            .returning(|| Ok(UsageStatsSchema::default()));
+        db.expect_update_usage_stats().returning(|_| Ok(()));
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut mobile_comm =
+            MobileComm::new(db, CapturedCalls(calls.clone())).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+
+        //the session is bound to the mobile by its offer
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .unwrap();
+        while calls.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert!(!calls.lock().unwrap()[0].record);
+
+        let update = HostSettingsUpdate {
+            mobile_id: "mobile_1".to_string(),
+            always_record: Some(true),
+            ..Default::default()
+        };
+        mobile_comm
+            .update_host_settings(ADDR.to_string(), update)
+            .await
+            .unwrap();
+        assert!(stored.lock().unwrap().always_record);
+
+        //the next calls of the mobile are recorded
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .unwrap();
+        while calls.lock().unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert!(calls.lock().unwrap()[1].record);
+    }
+
+    #[tokio::test]
+    async fn test_host_settings_need_own_trusted_session() {
+        const OTHER_ADDR: &str = "11:22:33:44:55:66";
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema {
+                id: id.to_string(),
+                trusted: id == "mobile_1",
+                ..Default::default()
+            })
+        });
+        db.expect_get_host_settings()
+            .returning(|| Ok(HostSettingsSchema::default()));
+        db.expect_update_host_settings()
+            .withf(|settings| settings.ap_band == ApBand::Band5GHz)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut mobile_comm =
+            MobileComm::new(db, CapturedCalls(calls.clone())).unwrap();
+        for (addr, mobile_id) in [(ADDR, "mobile_1"), (OTHER_ADDR, "mobile_2")]
+        {
+            mobile_comm
+                .sub_to_ready_answer(addr.to_string(), BlePublisher::new(512))
+                .await
+                .unwrap();
+            let offer = MobileSdpOffer {
+                mobile_id: mobile_id.to_string(),
+                ..camera_offer(&["back"])
+            };
+            mobile_comm
+                .set_mobile_sdp_offer(addr.to_string(), offer)
+                .await
+                .unwrap();
+        }
+
+        let update = |mobile_id: &str| HostSettingsUpdate {
+            mobile_id: mobile_id.to_string(),
+            ap_band: Some(ApBand::Band5GHz),
+            ..Default::default()
+        };
+
+        //the second mobile sends the id of the first one
+        assert!(mobile_comm
+            .update_host_settings(OTHER_ADDR.to_string(), update("mobile_1"))
+            .await
+            .is_err());
+        //the second mobile is not trusted
+        assert!(mobile_comm
+            .update_host_settings(OTHER_ADDR.to_string(), update("mobile_2"))
+            .await
+            .is_err());
+        mobile_comm
+            .update_host_settings(ADDR.to_string(), update("mobile_1"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_host_settings_apply_to_calls() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_mobile().returning(|_| {
+            Ok(MobileSchema {
+                id: "mobile_1".to_string(),
+                ..Default::default()
+            })
+        });
+        db.expect_get_host_settings().returning(|| {
+            Ok(HostSettingsSchema {
+                latency_profile: LatencyProfile::Quality,
+                auto_record: true,
+                ..Default::default()
+            })
+        });
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut mobile_comm =
+            MobileComm::new(db, CapturedCalls(calls.clone())).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+
+        //the mobile doesn't record but the host records every call
+        mobile_comm
+            .set_mobile_sdp_offer(ADDR.to_string(), camera_offer(&["back"]))
+            .await
+            .unwrap();
+        while calls.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let options = calls.lock().unwrap()[0];
+        assert!(options.record);
+        assert_eq!(options.latency_profile, LatencyProfile::Quality);
+    }
+
+    //counts the ports held open
+    struct Firewalled(Arc<AtomicUsize>);
+
+    struct OpenPort(Arc<AtomicUsize>);
+
+    impl Drop for OpenPort {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl VDeviceBuilderOps for Firewalled {
+        async fn create_from(
+            &self, _mobile_name: String, _camera_offer: Vec<CameraSdp>,
+            _options: CallOptions, _on_ready: OnCameraReady,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn open_port(&self, _port: u16) -> Result<Option<PortRule>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(Box::new(OpenPort(self.0.clone()))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_link_test_port_open_while_it_runs() {
+        let mut db = MockAppDataStore::new();
+        db.expect_get_blocklist().returning(|| Ok(BlocklistSchema::default()));
+        db.expect_get_mobile().returning(|id| {
+            Ok(MobileSchema { id: id.to_string(), ..Default::default() })
+        });
+
+        let open_ports = Arc::new(AtomicUsize::new(0));
+        let mut mobile_comm =
+            MobileComm::new(db, Firewalled(open_ports.clone())).unwrap();
+        mobile_comm
+            .sub_to_ready_answer(ADDR.to_string(), BlePublisher::new(512))
+            .await
+            .unwrap();
+        let request = LinkTestRequest {
+            mobile_id: "mobile_1".to_string(),
+            duration_secs: 1,
+        };
+
+        //refused without the access point
+        assert!(mobile_comm
+            .run_link_test(ADDR.to_string(), request.clone())
+            .await
+            .is_err());
+        assert_eq!(open_ports.load(Ordering::SeqCst), 0);
+
+        mobile_comm.set_link_test_addr(Ipv4Addr::LOCALHOST);
+        mobile_comm
+            .run_link_test(ADDR.to_string(), request.clone())
+            .await
+            .unwrap();
+        assert_eq!(open_ports.load(Ordering::SeqCst), 1);
+
+        //the new test replaces the port of the previous one
+        mobile_comm.run_link_test(ADDR.to_string(), request).await.unwrap();
+        assert_eq!(open_ports.load(Ordering::SeqCst), 1);
+
+        mobile_comm.mobile_disconnected(ADDR.to_string()).await.unwrap();
+        assert_eq!(open_ports.load(Ordering::SeqCst), 0);
     }
 }
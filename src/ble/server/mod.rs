@@ -1,21 +1,36 @@
+pub mod authorization;
 pub mod mobile_buffer;
 pub mod mobile_comm;
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
-use mobile_buffer::MobileBufferMap;
+use mobile_buffer::{chunk_payload_len, MobileBufferMap};
 
 use super::{
     api::{CommBuffer, MAX_BUFFER_LEN},
-    comm_types::{DataChunk, HostProvInfo, MobileSdpAnswer, MobileSdpOffer},
+    comm_types::{
+        CameraRemap, DataChunk, Handover, HandoverRequest, HostDiagnostics,
+        HostInfoUpdated, HostPowerState, HostProvInfo, HostSettingsUpdate,
+        LinkTestReport, LinkTestRequest, MobileSdpAnswer, MobileSdpOffer,
+        MobileSdpReply, PrivacyState, SdpAnswerReady,
+    },
 };
 use crate::app_data::MobileSchema;
+use crate::clock::host_clock;
 use anyhow::anyhow;
 use async_trait::async_trait;
 use log::{debug, error, info};
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
 
 use crate::error::Result;
+use crate::metrics::{FailureReason, Metrics};
+use crate::supervisor::{catch_panic, RestartPolicy, Supervisor};
 
 use super::{
     api::{
@@ -39,11 +54,41 @@ pub trait CommDataService: Send + Sync + 'static {
 
     async fn get_host_info(&mut self, addr: String) -> Result<HostProvInfo>;
 
+    //host info changed since the last check, checked periodically
+    async fn refresh_host_info(&mut self) -> Result<bool>;
+
+    //diagnostics
+    async fn get_diagnostics(
+        &mut self, addr: String,
+    ) -> Result<HostDiagnostics>;
+
+    //access control, blocked mobiles are refused
+    async fn check_access(&mut self, addr: String) -> Result<()>;
+
+    //a request could not be decoded
+    async fn request_rejected(&mut self, addr: String) -> Result<()>;
+
+    //host administration
+    async fn update_host_settings(
+        &mut self, addr: String, update: HostSettingsUpdate,
+    ) -> Result<()>;
+
+    //link test before the call
+    async fn run_link_test(
+        &mut self, addr: String, request: LinkTestRequest,
+    ) -> Result<()>;
+
+    async fn get_link_test(&mut self, addr: String) -> Result<LinkTestReport>;
+
     //call establishment
     async fn set_mobile_sdp_offer(
         &mut self, addr: String, mobile_offer: MobileSdpOffer,
     ) -> Result<()>;
 
+    async fn set_mobile_sdp_reply(
+        &mut self, addr: String, mobile_reply: MobileSdpReply,
+    ) -> Result<()>;
+
     async fn sub_to_ready_answer(
         &mut self, addr: String, publisher: BlePublisher,
     ) -> Result<()>;
@@ -51,53 +96,316 @@ pub trait CommDataService: Send + Sync + 'static {
     async fn get_sdp_answer(&mut self, addr: String)
         -> Result<MobileSdpAnswer>;
 
+    async fn remap_camera(
+        &mut self, addr: String, remap: CameraRemap,
+    ) -> Result<()>;
+
+    async fn sdp_answer_ack(
+        &mut self, addr: String, ack: SdpAnswerReady,
+    ) -> Result<()>;
+
+    async fn sub_to_session_expiry(
+        &mut self, addr: String, publisher: BlePublisher,
+    ) -> Result<()>;
+
+    async fn sub_to_operation_failures(
+        &mut self, addr: String, publisher: BlePublisher,
+    ) -> Result<()>;
+
+    //handover of the calls between the hosts of a group
+    async fn request_handover(
+        &mut self, addr: String, request: HandoverRequest,
+    ) -> Result<()>;
+
+    async fn complete_handover(
+        &mut self, addr: String, handover: Handover,
+    ) -> Result<()>;
+
+    async fn sub_to_handovers(
+        &mut self, addr: String, publisher: BlePublisher,
+    ) -> Result<()>;
+
+    //privacy switch
+    async fn get_privacy_state(&mut self, addr: String)
+        -> Result<PrivacyState>;
+
+    async fn sub_to_privacy(
+        &mut self, addr: String, publisher: BlePublisher,
+    ) -> Result<()>;
+
+    //host power state
+    async fn get_power_state(&mut self, addr: String)
+        -> Result<HostPowerState>;
+
+    async fn sub_to_power_state(
+        &mut self, addr: String, publisher: BlePublisher,
+    ) -> Result<()>;
+
     //disconnected device
     async fn mobile_disconnected(&mut self, addr: String) -> Result<()>;
+
+    //time-limited sessions, checked periodically
+    async fn check_sessions(&mut self) -> Result<()>;
+
+    //shutdown, the calls stop before the mobiles are notified
+    async fn stop_calls(&mut self) -> Result<()>;
+
+    async fn notify_shutdown(&mut self) -> Result<()>;
+}
+
+/// Period of the time-limited sessions check
+const SESSION_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Shutdown stage run by the server task
+#[derive(Debug, Clone, Copy)]
+enum ServerShutdown {
+    StopCalls,
+    NotifyMobiles,
 }
 
+type ShutdownReq = (ServerShutdown, oneshot::Sender<Result<()>>);
+
 pub struct BleServer {
     ble_req: BleRequester,
-    _drop_tx: oneshot::Sender<()>,
+    shutdown_tx: mpsc::Sender<ShutdownReq>,
+    drop_tx: oneshot::Sender<()>,
+    //fails once the restarts are given up, none once joined
+    task: Option<JoinHandle<Result<()>>>,
 }
 
 impl BleServer {
+    /// Serves the requests with `comm_handler`, their sizes and failures
+    /// are recorded in `metrics`
     pub fn new(
         mut comm_handler: impl CommDataService, req_buffer_size: usize,
+        metrics: Metrics,
     ) -> Self {
         let (ble_tx, mut ble_rx) = mpsc::channel(req_buffer_size);
-        let (_drop_tx, mut _drop_rx) = oneshot::channel();
+        let (drop_tx, mut drop_rx) = oneshot::channel();
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<ShutdownReq>(1);
 
-        tokio::spawn(async move {
-            let mut ble_server_comm_handler = BleServerCommHandler::new();
+        let task = tokio::spawn(async move {
+            let mut ble_server_comm_handler =
+                BleServerCommHandler::new().with_metrics(metrics);
+            let mut session_check = tokio::time::interval(SESSION_CHECK_PERIOD);
+            //a panicking request doesn't drop the cameras of the handler
+            let mut supervisor =
+                Supervisor::new("BLE server", RestartPolicy::DEFAULT);
 
-            loop {
-                tokio::select! {
-                    _ = async {
-                         if let Some(comm) = ble_rx.recv().await {
-                            ble_server_comm_handler.handle_comm(&mut comm_handler, comm).await;
-                         }
-                    }  => {}
+            let stopped = loop {
+                let handled = tokio::select! {
+                    Some(comm) = ble_rx.recv() => {
+                        catch_panic(ble_server_comm_handler.handle_comm(&mut comm_handler, comm)).await
+                    }
+
+                    _ = session_check.tick() => {
+                        catch_panic(async {
+                            if let Err(e) = comm_handler.check_sessions().await {
+                                error!("Failed to check the sessions: {:?}", e);
+                            }
+                            if let Err(e) = ble_server_comm_handler.refresh_host_info(&mut comm_handler).await {
+                                error!("Failed to check the host info: {:?}", e);
+                            }
+                        }).await
+                    }
 
-                    _ = &mut _drop_rx => {
+                    Some((stage, done)) = shutdown_rx.recv() => {
+                        catch_panic(async {
+                            let stopped = match stage {
+                                ServerShutdown::StopCalls => comm_handler.stop_calls().await,
+                                ServerShutdown::NotifyMobiles => comm_handler.notify_shutdown().await,
+                            };
+                            let _ = done.send(stopped);
+                        }).await
+                    }
+
+                    _ = &mut drop_rx => {
                         info!("Ble Server task is stopping");
-                        break;
+                        break Ok(());
+                    }
+                };
+
+                if let Err(panic) = handled {
+                    if !supervisor.on_panic(&panic, Instant::now()) {
+                        break Err(anyhow!(
+                            "BLE server given up after repeated panics"
+                        ));
                     }
+                    ble_server_comm_handler.restart_requests();
                 }
-            }
+            };
+
+            //the database and the devices are released before the task is
+            //joined
+            drop(comm_handler);
+            stopped
         });
 
-        Self { ble_req: BleRequester::new(ble_tx), _drop_tx }
+        Self {
+            ble_req: BleRequester::new(ble_tx),
+            shutdown_tx,
+            drop_tx,
+            task: Some(task),
+        }
+    }
+
+    /// Waits for the server to stop serving the requests while it's still
+    /// owned, i.e. its restarts after panics are given up
+    pub async fn failed(&mut self) -> anyhow::Error {
+        let Some(task) = self.task.as_mut() else {
+            return std::future::pending().await;
+        };
+        let stopped = task.await;
+        self.task = None;
+
+        match stopped {
+            Ok(Err(e)) => e,
+            Ok(Ok(())) => anyhow!("BLE server stopped"),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Stops serving the requests, the handler is dropped once it returns
+    pub async fn stop(mut self) -> Result<()> {
+        drop(self.drop_tx);
+        match self.task.take() {
+            Some(task) => task.await?,
+            None => Ok(()),
+        }
     }
 
     pub fn get_requester(&self) -> BleRequester {
         self.ble_req.clone()
     }
+
+    /// Stops the calls of the mobiles, their devices are removed
+    pub async fn stop_calls(&self) -> Result<()> {
+        self.shutdown(ServerShutdown::StopCalls).await
+    }
+
+    /// Notifies the mobiles that the host is stopping
+    pub async fn notify_shutdown(&self) -> Result<()> {
+        self.shutdown(ServerShutdown::NotifyMobiles).await
+    }
+
+    async fn shutdown(&self, stage: ServerShutdown) -> Result<()> {
+        let (done_tx, done_rx) = oneshot::channel();
+        self.shutdown_tx
+            .send((stage, done_tx))
+            .await
+            .map_err(|_| anyhow!("BLE server already stopped"))?;
+        done_rx.await.map_err(|_| anyhow!("BLE server stopped"))?
+    }
+}
+
+//undecodable requests count as failures of the address
+async fn decode<T, E>(
+    comm_handler: &mut impl CommDataService, metrics: &Metrics, addr: &Address,
+    decoded: std::result::Result<T, E>,
+) -> Result<T>
+where
+    E: Into<anyhow::Error>,
+{
+    match decoded {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            metrics.record_failure(FailureReason::DeserializeError);
+            comm_handler.request_rejected(addr.clone()).await?;
+            Err(e.into())
+        }
+    }
+}
+
+/// Command decoded from the buffer assembled from the writes of a mobile
+#[derive(Debug)]
+pub enum DecodedCommand {
+    MobileDisconnected,
+    RegisterMobile(MobileSchema),
+    SdpOffer(MobileSdpOffer),
+    SdpReply(MobileSdpReply),
+    SdpAnswerAck(SdpAnswerReady),
+    UpdateHostSettings(HostSettingsUpdate),
+    RunLinkTest(LinkTestRequest),
+    RemapCamera(CameraRemap),
+    RequestHandover(HandoverRequest),
+    CompleteHandover(Handover),
+}
+
+impl DecodedCommand {
+    /// Decodes the payload of the command, the buffer comes from the mobile
+    /// and any content is an error at worst
+    pub fn decode(cmd_type: &CmdApi, buffer: CommBuffer) -> Result<Self> {
+        Ok(match cmd_type {
+            CmdApi::MobileDisconnected => Self::MobileDisconnected,
+            CmdApi::RegisterMobile => Self::RegisterMobile(buffer.try_into()?),
+            CmdApi::SdpOffer => Self::SdpOffer(buffer.try_into()?),
+            CmdApi::SdpReply => Self::SdpReply(buffer.try_into()?),
+            CmdApi::SdpAnswerAck => {
+                Self::SdpAnswerAck(buffer.as_slice().try_into()?)
+            }
+            CmdApi::UpdateHostSettings => {
+                Self::UpdateHostSettings(buffer.try_into()?)
+            }
+            CmdApi::RunLinkTest => Self::RunLinkTest(buffer.try_into()?),
+            CmdApi::RemapCamera => {
+                Self::RemapCamera(buffer.as_slice().try_into()?)
+            }
+            CmdApi::RequestHandover => {
+                Self::RequestHandover(buffer.as_slice().try_into()?)
+            }
+            CmdApi::CompleteHandover => {
+                Self::CompleteHandover(buffer.as_slice().try_into()?)
+            }
+        })
+    }
 }
 
 //data cache
+#[derive(Default)]
 struct ServerDataCache {
-    host_info: Option<Vec<u8>>,
+    //host info without pairing data, shared by the mobiles
+    host_info: Option<HostProvInfo>,
+    //host info with the clock of the host, kept per mobile until its read
+    //ends
+    host_info_reads: HashMap<Address, Vec<u8>>,
     sdp_answer: HashMap<Address, Option<Vec<u8>>>,
+    //diagnostics snapshot, kept per mobile until its read ends
+    diagnostics: HashMap<Address, Vec<u8>>,
+    //link test snapshot, kept per mobile until its read ends
+    link_test: HashMap<Address, Vec<u8>>,
+    //privacy state snapshot, kept per mobile until its read ends
+    privacy: HashMap<Address, Vec<u8>>,
+    //power state snapshot, kept per mobile until its read ends
+    power_state: HashMap<Address, Vec<u8>>,
+}
+
+impl ServerDataCache {
+    //drops the cached data of the query for the mobile
+    fn invalidate(&mut self, addr: &Address, query_type: &QueryApi) {
+        debug!("Cache of {:?} dropped for {}", query_type, addr);
+
+        match query_type {
+            QueryApi::HostInfo => {
+                self.host_info = None;
+                self.host_info_reads.remove(addr);
+            }
+            QueryApi::SdpAnswer => {
+                self.sdp_answer.remove(addr);
+            }
+            QueryApi::Diagnostics => {
+                self.diagnostics.remove(addr);
+            }
+            QueryApi::LinkTest => {
+                self.link_test.remove(addr);
+            }
+            QueryApi::Privacy => {
+                self.privacy.remove(addr);
+            }
+            QueryApi::PowerState => {
+                self.power_state.remove(addr);
+            }
+        }
+    }
 }
 
 //Handle the communication
@@ -106,6 +414,8 @@ struct BleServerCommHandler {
     server_data_cache: ServerDataCache,
     pubsub_topics_map: HashMap<PubSubTopic, BlePublisher>,
     chunk_len: usize,
+    host_info_revision: u32,
+    metrics: Metrics,
 }
 
 impl BleServerCommHandler {
@@ -123,13 +433,56 @@ impl BleServerCommHandler {
 
         Self {
             buffer_map: MobileBufferMap::new(chunk_len),
-            server_data_cache: ServerDataCache {
-                host_info: None,
-                sdp_answer: HashMap::new(),
-            },
+            server_data_cache: ServerDataCache::default(),
             pubsub_topics_map: HashMap::new(),
             chunk_len,
+            host_info_revision: 0,
+            metrics: Metrics::default(),
+        }
+    }
+
+    fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.buffer_map = self.buffer_map.with_metrics(metrics.clone());
+        self.metrics = metrics;
+        self
+    }
+
+    //the requests in progress are dropped after a panic, their buffers may
+    //be half written, the subscriptions are kept
+    fn restart_requests(&mut self) {
+        self.buffer_map = MobileBufferMap::new(self.chunk_len)
+            .with_metrics(self.metrics.clone());
+        self.server_data_cache = ServerDataCache::default();
+    }
+
+    //drops the cached host info and notifies the mobiles once it changed,
+    //the reads in progress keep their snapshot
+    async fn refresh_host_info(
+        &mut self, comm_handler: &mut impl CommDataService,
+    ) -> Result<()> {
+        if !comm_handler.refresh_host_info().await? {
+            return Ok(());
+        }
+
+        self.server_data_cache.host_info = None;
+        self.host_info_revision += 1;
+        info!("Host info updated, revision {}", self.host_info_revision);
+
+        let Some(publisher) =
+            self.pubsub_topics_map.get(&PubSubTopic::HostInfo)
+        else {
+            return Ok(());
+        };
+
+        let update: Vec<u8> =
+            HostInfoUpdated { revision: self.host_info_revision }.try_into()?;
+
+        //no mobile subscribed is not an error
+        if let Err(e) = publisher.publish(update).await {
+            debug!("Host info update not notified: {:?}", e);
         }
+
+        Ok(())
     }
 
     //handle query
@@ -139,20 +492,49 @@ impl BleServerCommHandler {
     ) -> Result<CommBuffer> {
         debug!("Query: {:?}", query.query_type);
 
+        //a read interrupted by a disconnect goes on from its snapshot
+        if let Some(chunk) = self.buffer_map.resume_read(&addr, &query)? {
+            return Ok(chunk);
+        }
+
+        //a read in progress keeps its snapshot so the chunks match
+        if query.force_refresh
+            && !self.buffer_map.is_reading(&addr, &query.query_type)
+        {
+            self.server_data_cache.invalidate(&addr, &query.query_type);
+        }
+
         //get the data requested
         let data = match query.query_type {
             QueryApi::HostInfo => {
-                if self.server_data_cache.host_info.is_none() {
-                    let host_info: Vec<u8> = comm_handler
-                        .get_host_info(addr.clone())
-                        .await?
-                        .try_into()?;
+                if !self.server_data_cache.host_info_reads.contains_key(&addr) {
+                    let host_info = match self.server_data_cache.host_info {
+                        Some(ref host_info) => host_info.clone(),
+                        None => {
+                            let host_info = comm_handler
+                                .get_host_info(addr.clone())
+                                .await?;
+                            //pairing data is only valid while the window is
+                            //open, so it is not shared
+                            if host_info.pairing_token.is_none() {
+                                self.server_data_cache.host_info =
+                                    Some(host_info.clone());
+                            }
+                            host_info
+                        }
+                    };
 
-                    self.server_data_cache.host_info = Some(host_info.clone());
+                    //the clock is read once per read, the chunks match
+                    let host_info: Vec<u8> =
+                        HostProvInfo { clock: host_clock(), ..host_info }
+                            .try_into()?;
+                    self.server_data_cache
+                        .host_info_reads
+                        .insert(addr.clone(), host_info);
                 }
                 self.server_data_cache
-                    .host_info
-                    .as_ref()
+                    .host_info_reads
+                    .get(&addr)
                     .ok_or(anyhow!("Host info not found"))?
             }
 
@@ -175,13 +557,134 @@ impl BleServerCommHandler {
                     .as_ref()
                     .ok_or(anyhow!("SDP answer not found"))?
             }
+
+            QueryApi::Diagnostics => {
+                if !self.server_data_cache.diagnostics.contains_key(&addr) {
+                    let diagnostics: Vec<u8> = comm_handler
+                        .get_diagnostics(addr.clone())
+                        .await?
+                        .try_into()?;
+
+                    self.server_data_cache
+                        .diagnostics
+                        .insert(addr.clone(), diagnostics);
+                }
+
+                self.server_data_cache
+                    .diagnostics
+                    .get(&addr)
+                    .ok_or(anyhow!("Diagnostics not found"))?
+            }
+
+            QueryApi::LinkTest => {
+                if !self.server_data_cache.link_test.contains_key(&addr) {
+                    let link_test: Vec<u8> = comm_handler
+                        .get_link_test(addr.clone())
+                        .await?
+                        .try_into()?;
+
+                    self.server_data_cache
+                        .link_test
+                        .insert(addr.clone(), link_test);
+                }
+
+                self.server_data_cache
+                    .link_test
+                    .get(&addr)
+                    .ok_or(anyhow!("Link test not found"))?
+            }
+
+            QueryApi::Privacy => {
+                if !self.server_data_cache.privacy.contains_key(&addr) {
+                    let privacy: Vec<u8> = comm_handler
+                        .get_privacy_state(addr.clone())
+                        .await?
+                        .try_into()?;
+
+                    self.server_data_cache
+                        .privacy
+                        .insert(addr.clone(), privacy);
+                }
+
+                self.server_data_cache
+                    .privacy
+                    .get(&addr)
+                    .ok_or(anyhow!("Privacy state not found"))?
+            }
+
+            QueryApi::PowerState => {
+                if !self.server_data_cache.power_state.contains_key(&addr) {
+                    let power_state: Vec<u8> = comm_handler
+                        .get_power_state(addr.clone())
+                        .await?
+                        .try_into()?;
+
+                    self.server_data_cache
+                        .power_state
+                        .insert(addr.clone(), power_state);
+                }
+
+                self.server_data_cache
+                    .power_state
+                    .get(&addr)
+                    .ok_or(anyhow!("Power state not found"))?
+            }
         };
 
         info!("Query data: {:?}", data);
         info!("Query request: {:?}", query);
 
+        //the data is measured once per transfer
+        if !self.buffer_map.is_reading(&addr, &query.query_type) {
+            self.metrics.observe_query(&query.query_type, data.len());
+        }
+
         //return the data
-        self.buffer_map.get_next_data_chunk(&addr, &query, &data)
+        let chunk = self.buffer_map.get_next_data_chunk(&addr, &query, &data);
+
+        //release the snapshot once the whole host info was read
+        if query.query_type == QueryApi::HostInfo
+            && !self.buffer_map.is_reading(&addr, &QueryApi::HostInfo)
+        {
+            self.server_data_cache.host_info_reads.remove(&addr);
+        }
+
+        //more cameras can be ready on the next read
+        if query.query_type == QueryApi::SdpAnswer
+            && !self.buffer_map.is_reading(&addr, &QueryApi::SdpAnswer)
+        {
+            self.server_data_cache.sdp_answer.remove(&addr);
+        }
+
+        //diagnostics are refreshed on every complete read
+        if query.query_type == QueryApi::Diagnostics
+            && !self.buffer_map.is_reading(&addr, &QueryApi::Diagnostics)
+        {
+            self.server_data_cache.diagnostics.remove(&addr);
+        }
+
+        //the link test progresses between reads
+        if query.query_type == QueryApi::LinkTest
+            && !self.buffer_map.is_reading(&addr, &QueryApi::LinkTest)
+        {
+            self.server_data_cache.link_test.remove(&addr);
+        }
+
+        //the privacy switch changes between reads
+        if query.query_type == QueryApi::Privacy
+            && !self.buffer_map.is_reading(&addr, &QueryApi::Privacy)
+        {
+            self.server_data_cache.privacy.remove(&addr);
+        }
+
+        //the battery drains between reads
+        if query.query_type == QueryApi::PowerState
+            && !self.buffer_map.is_reading(&addr, &QueryApi::PowerState)
+        {
+            self.server_data_cache.power_state.remove(&addr);
+        }
+
+        chunk
     }
 
     async fn handle_command(
@@ -190,27 +693,66 @@ impl BleServerCommHandler {
     ) -> Result<()> {
         debug!("Command: {:?}", cmd.cmd_type);
 
-        let Some(buffer) = self.buffer_map.get_complete_buffer(&addr, &cmd)?
-        else {
-            return Ok(());
+        //blocked mobiles can only disconnect
+        if cmd.cmd_type != CmdApi::MobileDisconnected {
+            comm_handler.check_access(addr.clone()).await?;
+        }
+
+        //commands without body don't go through the chunk assembly
+        let buffer = if cmd.cmd_type.has_payload() {
+            let buffer = self.buffer_map.get_complete_buffer(&addr, &cmd);
+            let Some(buffer) =
+                decode(comm_handler, &self.metrics, &addr, buffer).await?
+            else {
+                return Ok(());
+            };
+            buffer
+        } else {
+            Vec::new()
         };
 
-        match cmd.cmd_type {
-            CmdApi::MobileDisconnected => {
+        let command = DecodedCommand::decode(&cmd.cmd_type, buffer);
+        match decode(comm_handler, &self.metrics, &addr, command).await? {
+            DecodedCommand::MobileDisconnected => {
                 //clean up the device resources
                 self.buffer_map.remove_mobile(&addr);
                 self.server_data_cache.sdp_answer.remove(&addr);
+                self.server_data_cache.host_info_reads.remove(&addr);
+                self.server_data_cache.diagnostics.remove(&addr);
+                self.server_data_cache.link_test.remove(&addr);
+                self.server_data_cache.privacy.remove(&addr);
+                self.server_data_cache.power_state.remove(&addr);
                 comm_handler.mobile_disconnected(addr).await
             }
-            CmdApi::RegisterMobile => {
-                let mobile = buffer.try_into()?;
+            DecodedCommand::RegisterMobile(mobile) => {
                 comm_handler.register_mobile(addr, mobile).await
             }
-            CmdApi::SdpOffer => {
-                let mobile_offer = buffer.try_into()?;
+            DecodedCommand::SdpOffer(mobile_offer) => {
                 debug!("Mobile offer: {:?}", mobile_offer);
                 comm_handler.set_mobile_sdp_offer(addr, mobile_offer).await
             }
+            DecodedCommand::SdpReply(mobile_reply) => {
+                debug!("Mobile reply: {:?}", mobile_reply);
+                comm_handler.set_mobile_sdp_reply(addr, mobile_reply).await
+            }
+            DecodedCommand::SdpAnswerAck(ack) => {
+                comm_handler.sdp_answer_ack(addr, ack).await
+            }
+            DecodedCommand::UpdateHostSettings(update) => {
+                comm_handler.update_host_settings(addr, update).await
+            }
+            DecodedCommand::RunLinkTest(request) => {
+                comm_handler.run_link_test(addr, request).await
+            }
+            DecodedCommand::RemapCamera(remap) => {
+                comm_handler.remap_camera(addr, remap).await
+            }
+            DecodedCommand::RequestHandover(request) => {
+                comm_handler.request_handover(addr, request).await
+            }
+            DecodedCommand::CompleteHandover(handover) => {
+                comm_handler.complete_handover(addr, handover).await
+            }
         }
     }
 
@@ -220,10 +762,18 @@ impl BleServerCommHandler {
     ) -> Result<PubSubSubscriber> {
         let SubReq { topic, resp_buffer_len } = sub;
 
-        let publisher = self
-            .pubsub_topics_map
-            .entry(topic)
-            .or_insert(BlePublisher::new(resp_buffer_len - self.chunk_len));
+        comm_handler.check_access(addr.clone()).await?;
+
+        let payload_len = chunk_payload_len(resp_buffer_len, self.chunk_len)?;
+        let publisher = match topic {
+            //the tokens of a handover only reach the mobile that asked for it
+            PubSubTopic::Handover => BlePublisher::new(payload_len),
+            _ => self
+                .pubsub_topics_map
+                .entry(topic.clone())
+                .or_insert(BlePublisher::new(payload_len))
+                .clone(),
+        };
 
         match topic {
             PubSubTopic::SdpAnswerReady => {
@@ -231,10 +781,33 @@ impl BleServerCommHandler {
                     .sub_to_ready_answer(addr, publisher.clone())
                     .await?;
             }
+            PubSubTopic::SessionExpiry => {
+                comm_handler
+                    .sub_to_session_expiry(addr, publisher.clone())
+                    .await?;
+            }
+            PubSubTopic::OperationFailed => {
+                comm_handler
+                    .sub_to_operation_failures(addr, publisher.clone())
+                    .await?;
+            }
+            PubSubTopic::Handover => {
+                comm_handler.sub_to_handovers(addr, publisher.clone()).await?;
+            }
+            PubSubTopic::Privacy => {
+                comm_handler.sub_to_privacy(addr, publisher.clone()).await?;
+            }
+            PubSubTopic::PowerState => {
+                comm_handler
+                    .sub_to_power_state(addr, publisher.clone())
+                    .await?;
+            }
+            //published on the periodic host info check
+            PubSubTopic::HostInfo => {}
         };
 
-        //get the subscriber for this topic
-        Ok(publisher.get_subscriber().await)
+        //the chunks follow the MTU of this mobile, not of the first one
+        Ok(publisher.get_sized_subscriber(payload_len).await)
     }
 
     async fn handle_pub(
@@ -248,7 +821,13 @@ impl BleServerCommHandler {
         };
 
         match topic {
-            PubSubTopic::SdpAnswerReady => {}
+            PubSubTopic::SdpAnswerReady
+            | PubSubTopic::SessionExpiry
+            | PubSubTopic::Privacy
+            | PubSubTopic::PowerState
+            | PubSubTopic::HostInfo
+            | PubSubTopic::OperationFailed
+            | PubSubTopic::Handover => {}
         };
 
         publisher.publish(payload).await
@@ -295,3 +874,367 @@ impl BleServerCommHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::comm_types::msgpack_ser;
+    use crate::ble::requester::BleSubscriber;
+    use mockall::predicate::eq;
+
+    const ADDR: &str = "AA:BB:CC:DD:EE:FF";
+
+    #[tokio::test]
+    async fn test_server_fails_once_restarts_given_up() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler.expect_check_sessions().returning(|| Ok(()));
+        comm_handler.expect_refresh_host_info().returning(|| Ok(false));
+        comm_handler
+            .expect_mobile_disconnected()
+            .returning(|_| panic!("handler failed"));
+
+        let mut server = BleServer::new(comm_handler, 8, Metrics::default());
+        let requester = server.get_requester();
+
+        //the first panic and the restarts allowed
+        for _ in 0..=RestartPolicy::DEFAULT.max_restarts {
+            let disconnected = requester.cmd(
+                ADDR.to_string(),
+                CmdApi::MobileDisconnected,
+                vec![],
+            );
+            assert!(disconnected.await.is_err());
+        }
+
+        assert!(server.failed().await.to_string().contains("given up"));
+    }
+
+    #[tokio::test]
+    async fn test_stopped_server_releases_handler() {
+        //held by the handler, e.g. the database
+        let held = std::sync::Arc::new(());
+        let handler_held = held.clone();
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler.expect_check_sessions().returning(move || {
+            let _ = &handler_held;
+            Ok(())
+        });
+        comm_handler.expect_refresh_host_info().returning(|| Ok(false));
+
+        let server = BleServer::new(comm_handler, 8, Metrics::default());
+        server.stop().await.unwrap();
+        assert_eq!(std::sync::Arc::strong_count(&held), 1);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_without_payload() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler
+            .expect_mobile_disconnected()
+            .with(eq(ADDR.to_string()))
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut handler = BleServerCommHandler::new();
+        let cmd = CommandReq {
+            cmd_type: CmdApi::MobileDisconnected,
+            payload: vec![],
+        };
+
+        assert!(handler
+            .handle_command(&mut comm_handler, ADDR.to_string(), cmd)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_payload_rejected() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler.expect_check_access().returning(|_| Ok(()));
+        comm_handler
+            .expect_request_rejected()
+            .with(eq(ADDR.to_string()))
+            .times(2)
+            .returning(|_| Ok(()));
+
+        let mut handler = BleServerCommHandler::new();
+
+        for payload in [vec![], vec![0xc1]] {
+            let cmd = CommandReq { cmd_type: CmdApi::RegisterMobile, payload };
+            assert!(handler
+                .handle_command(&mut comm_handler, ADDR.to_string(), cmd)
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_random_writes_without_panic() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler.expect_check_access().returning(|_| Ok(()));
+        comm_handler.expect_request_rejected().returning(|_| Ok(()));
+        comm_handler.expect_register_mobile().returning(|_, _| Ok(()));
+        comm_handler.expect_set_mobile_sdp_offer().returning(|_, _| Ok(()));
+        comm_handler.expect_set_mobile_sdp_reply().returning(|_, _| Ok(()));
+        comm_handler.expect_sdp_answer_ack().returning(|_, _| Ok(()));
+        comm_handler.expect_update_host_settings().returning(|_, _| Ok(()));
+        comm_handler.expect_run_link_test().returning(|_, _| Ok(()));
+        comm_handler.expect_remap_camera().returning(|_, _| Ok(()));
+        comm_handler.expect_mobile_disconnected().returning(|_| Ok(()));
+        comm_handler.expect_request_handover().returning(|_, _| Ok(()));
+        comm_handler.expect_complete_handover().returning(|_, _| Ok(()));
+
+        let cmds = [
+            CmdApi::MobileDisconnected,
+            CmdApi::RegisterMobile,
+            CmdApi::SdpOffer,
+            CmdApi::SdpAnswerAck,
+            CmdApi::UpdateHostSettings,
+            CmdApi::RunLinkTest,
+            CmdApi::SdpReply,
+            CmdApi::RemapCamera,
+            CmdApi::RequestHandover,
+            CmdApi::CompleteHandover,
+        ];
+        let seed = msgpack_ser(&MobileSchema::default()).unwrap();
+
+        //xorshift, the same writes on every run
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut handler = BleServerCommHandler::new();
+        for _ in 0..5000 {
+            let cmd_type = cmds[next() as usize % cmds.len()].clone();
+            let mut bytes: Vec<u8> = match next() % 3 {
+                0 => seed.clone(),
+                _ => (0..next() % 64).map(|_| next() as u8).collect(),
+            };
+            for _ in 0..next() % 4 {
+                if !bytes.is_empty() {
+                    let at = next() as usize % bytes.len();
+                    bytes[at] = next() as u8;
+                }
+            }
+
+            //raw writes and well-formed chunks of random content
+            let payload = match next() % 2 {
+                0 => bytes,
+                _ => DataChunk { r: (next() % 3) as usize, d: bytes }
+                    .try_into()
+                    .unwrap(),
+            };
+            let cmd = CommandReq { cmd_type, payload };
+            let _ = handler
+                .handle_command(&mut comm_handler, ADDR.to_string(), cmd)
+                .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sdp_answer_read_again() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler
+            .expect_get_sdp_answer()
+            .with(eq(ADDR.to_string()))
+            .times(2)
+            .returning(|_| Ok(MobileSdpAnswer::default()));
+
+        let mut handler = BleServerCommHandler::new();
+
+        //every complete read gets the answers of the cameras ready by then
+        for _ in 0..2 {
+            let query = QueryReq {
+                query_type: QueryApi::SdpAnswer,
+                resp_buffer_len: 512,
+                force_refresh: false,
+                offset: None,
+            };
+            assert!(handler
+                .handle_query(&mut comm_handler, ADDR.to_string(), query)
+                .await
+                .is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_metrics() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler
+            .expect_get_sdp_answer()
+            .returning(|_| Ok(MobileSdpAnswer::default()));
+        comm_handler.expect_check_access().returning(|_| Ok(()));
+        comm_handler.expect_request_rejected().returning(|_| Ok(()));
+
+        let metrics = Metrics::default();
+        let mut handler =
+            BleServerCommHandler::new().with_metrics(metrics.clone());
+
+        for _ in 0..2 {
+            let query = QueryReq {
+                query_type: QueryApi::SdpAnswer,
+                resp_buffer_len: 512,
+                force_refresh: false,
+                offset: None,
+            };
+            handler
+                .handle_query(&mut comm_handler, ADDR.to_string(), query)
+                .await
+                .unwrap();
+        }
+        let cmd =
+            CommandReq { cmd_type: CmdApi::SdpOffer, payload: vec![0xc1] };
+        assert!(handler
+            .handle_command(&mut comm_handler, ADDR.to_string(), cmd)
+            .await
+            .is_err());
+
+        let requests = metrics.snapshot();
+        assert_eq!(requests.query_bytes["SdpAnswer"].count, 2);
+        //the answer fits in a single chunk
+        assert_eq!(requests.read_chunks.counts[0], 2);
+        assert_eq!(requests.failures.deserialize_error, 1);
+    }
+
+    #[tokio::test]
+    async fn test_host_info_update_drops_cache() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler
+            .expect_get_host_info()
+            .times(2)
+            .returning(|_| Ok(HostProvInfo::default()));
+        comm_handler.expect_check_access().returning(|_| Ok(()));
+        let mut changed = [false, true].into_iter();
+        comm_handler
+            .expect_refresh_host_info()
+            .times(2)
+            .returning(move || Ok(changed.next().unwrap()));
+
+        let mut handler = BleServerCommHandler::new();
+        let sub = SubReq { topic: PubSubTopic::HostInfo, resp_buffer_len: 512 };
+        let mut subscriber = handler
+            .handle_sub(&mut comm_handler, ADDR.to_string(), sub)
+            .await
+            .unwrap();
+
+        //the cached host info is only read again after an update
+        for _ in 0..2 {
+            handler.refresh_host_info(&mut comm_handler).await.unwrap();
+            let query = QueryReq {
+                query_type: QueryApi::HostInfo,
+                resp_buffer_len: 512,
+                force_refresh: false,
+                offset: None,
+            };
+            assert!(handler
+                .handle_query(&mut comm_handler, ADDR.to_string(), query)
+                .await
+                .is_ok());
+        }
+
+        let chunk: DataChunk =
+            subscriber.recv().await.unwrap().try_into().unwrap();
+        let update: HostInfoUpdated = chunk.d.as_slice().try_into().unwrap();
+        assert_eq!(update.revision, 1);
+    }
+
+    #[tokio::test]
+    async fn test_host_info_read_carries_clock() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler
+            .expect_get_host_info()
+            .times(1)
+            .returning(|_| Ok(HostProvInfo::default()));
+
+        let mut handler = BleServerCommHandler::new();
+
+        //the shared host info is stamped again on every read
+        for _ in 0..2 {
+            let query = QueryReq {
+                query_type: QueryApi::HostInfo,
+                resp_buffer_len: 512,
+                force_refresh: false,
+                offset: None,
+            };
+            let chunk: DataChunk = handler
+                .handle_query(&mut comm_handler, ADDR.to_string(), query)
+                .await
+                .unwrap()
+                .try_into()
+                .unwrap();
+            assert_eq!(chunk.r, 0);
+            let host_info: HostProvInfo = chunk.d.try_into().unwrap();
+            assert!(host_info.clock.is_some_and(|clock| clock.wall_ms > 0));
+        }
+        assert!(handler.server_data_cache.host_info_reads.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_topic_chunks_follow_each_subscriber() {
+        let mut comm_handler = MockCommDataService::new();
+        comm_handler.expect_check_access().returning(|_| Ok(()));
+        comm_handler.expect_sub_to_privacy().returning(|_, _| Ok(()));
+
+        //a large MTU phone subscribes first, then a small MTU one
+        let mut handler = BleServerCommHandler::new();
+        let mut subscribers = Vec::new();
+        for (addr, resp_buffer_len) in [(ADDR, 512), ("11:22:33:44:55:66", 64)]
+        {
+            let sub = SubReq { topic: PubSubTopic::Privacy, resp_buffer_len };
+            let subscriber = handler
+                .handle_sub(&mut comm_handler, addr.to_string(), sub)
+                .await
+                .unwrap();
+            subscribers.push((BleSubscriber::new(subscriber), resp_buffer_len));
+        }
+
+        let pub_req =
+            PubReq { topic: PubSubTopic::Privacy, payload: vec![7; 300] };
+        handler
+            .handle_pub(&mut comm_handler, ADDR.to_string(), pub_req)
+            .await
+            .unwrap();
+
+        for (mut subscriber, resp_buffer_len) in subscribers {
+            let message = subscriber
+                .recv_message(Duration::from_secs(1), |_| {})
+                .await
+                .unwrap();
+            assert_eq!(message.data, vec![7; 300]);
+            let payload_len =
+                chunk_payload_len(resp_buffer_len, handler.chunk_len).unwrap();
+            assert_eq!(message.chunks.len(), 300_usize.div_ceil(payload_len));
+        }
+    }
+
+    #[test]
+    fn test_cache_invalidate() {
+        let addr = "AA:BB:CC:DD:EE:FF".to_string();
+        let other = "11:22:33:44:55:66".to_string();
+        let mut cache = ServerDataCache {
+            host_info: Some(HostProvInfo::default()),
+            host_info_reads: HashMap::from([(addr.clone(), vec![2])]),
+            sdp_answer: HashMap::new(),
+            diagnostics: HashMap::from([
+                (addr.clone(), vec![3]),
+                (other.clone(), vec![4]),
+            ]),
+            link_test: HashMap::new(),
+            privacy: HashMap::new(),
+            power_state: HashMap::new(),
+        };
+
+        cache.invalidate(&addr, &QueryApi::HostInfo);
+        assert!(cache.host_info.is_none());
+        assert!(cache.host_info_reads.is_empty());
+
+        //only the cache of the mobile is dropped
+        cache.invalidate(&addr, &QueryApi::Diagnostics);
+        assert!(!cache.diagnostics.contains_key(&addr));
+        assert!(cache.diagnostics.contains_key(&other));
+    }
+}
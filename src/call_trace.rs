@@ -0,0 +1,406 @@
+//! # Call setup traces.
+//! The setup of a call goes from the offer of the mobile to the build of
+//! the device of every camera, the ICE connection and the first frame of
+//! the camera. Once every camera streams or failed, the stages are logged
+//! as a breakdown and exported to an OpenTelemetry collector over OTLP/HTTP
+//! if configured, to find where the setup time goes.
+
+use std::{
+    process::Stdio,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::config::TracingConfig;
+
+/// Times reached by the stream of a device once it is built
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamMilestones {
+    pub ice_connected: Option<Instant>,
+    pub first_frame: Option<Instant>,
+}
+
+/// Stage of the setup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub name: &'static str,
+    /// 16 hex digits
+    pub span_id: String,
+    pub parent_id: Option<String>,
+    pub camera: Option<String>,
+    pub start: Instant,
+    pub end: Instant,
+    pub failed: bool,
+}
+
+//setup of a camera of the call
+#[derive(Debug)]
+struct CameraSetup {
+    name: String,
+    built_at: Option<Instant>,
+    failed: bool,
+    milestones: StreamMilestones,
+}
+
+/// Setup of a call, from the offer to the first frame of every camera
+#[derive(Debug)]
+pub struct CallTrace {
+    mobile_id: String,
+    trace_id: String,
+    offer_at: Instant,
+    cameras: Vec<CameraSetup>,
+}
+
+impl CallTrace {
+    /// Trace `trace_id`, 32 hex digits, of the cameras offered by
+    /// `mobile_id` at `offer_at`
+    pub fn new(
+        mobile_id: String, trace_id: String, offer_at: Instant,
+        cameras: Vec<String>,
+    ) -> Self {
+        let cameras = cameras
+            .into_iter()
+            .map(|name| CameraSetup {
+                name,
+                built_at: None,
+                failed: false,
+                milestones: StreamMilestones::default(),
+            })
+            .collect();
+
+        Self { mobile_id, trace_id, offer_at, cameras }
+    }
+
+    pub fn offer_at(&self) -> Instant {
+        self.offer_at
+    }
+
+    /// The device of the camera was built, or failed to, at `at`
+    pub fn camera_built(&mut self, name: &str, at: Instant, built: bool) {
+        if let Some(camera) = self.camera_mut(name) {
+            camera.built_at = Some(at);
+            camera.failed = !built;
+        }
+    }
+
+    /// The cameras left pending failed at `at`
+    pub fn fail_pending(&mut self, at: Instant) {
+        for camera in self.cameras.iter_mut() {
+            if camera.built_at.is_none() {
+                camera.built_at = Some(at);
+                camera.failed = true;
+            }
+        }
+    }
+
+    pub fn update_milestones(
+        &mut self, name: &str, milestones: StreamMilestones,
+    ) {
+        if let Some(camera) = self.camera_mut(name) {
+            camera.milestones = milestones;
+        }
+    }
+
+    //the setups follow the cameras to their new devices
+    pub fn remap(&mut self, device: &str, camera: &str) {
+        for setup in self.cameras.iter_mut() {
+            if setup.name == device {
+                setup.name = camera.to_string();
+            } else if setup.name == camera {
+                setup.name = device.to_string();
+            }
+        }
+    }
+
+    /// Whether every camera streams or failed
+    pub fn is_complete(&self) -> bool {
+        self.cameras.iter().all(|camera| {
+            camera.failed || camera.milestones.first_frame.is_some()
+        })
+    }
+
+    /// Time from the offer to the first frame of the cameras that stream
+    pub fn first_frame_latencies(&self) -> Vec<(String, Duration)> {
+        self.cameras
+            .iter()
+            .filter_map(|camera| {
+                let first_frame = camera.milestones.first_frame?;
+                let latency =
+                    first_frame.saturating_duration_since(self.offer_at);
+                Some((camera.name.clone(), latency))
+            })
+            .collect()
+    }
+
+    fn camera_mut(&mut self, name: &str) -> Option<&mut CameraSetup> {
+        self.cameras.iter_mut().find(|camera| camera.name == name)
+    }
+
+    fn span_id(&self, index: usize) -> String {
+        format!("{}{:08x}", &self.trace_id[..8], index)
+    }
+
+    /// Stages reached by every camera under a `call_setup` span ending
+    /// with the last of them
+    pub fn spans(&self) -> Vec<Span> {
+        let root_id = self.span_id(0);
+        let mut spans = Vec::new();
+
+        for camera in self.cameras.iter() {
+            let stages = [
+                ("device_build", camera.built_at),
+                ("ice_connect", camera.milestones.ice_connected),
+                ("first_frame", camera.milestones.first_frame),
+            ];
+
+            let mut start = self.offer_at;
+            for (name, end) in stages {
+                let Some(end) = end else {
+                    break;
+                };
+                let failed = name == "device_build" && camera.failed;
+                let end = end.max(start);
+                spans.push(Span {
+                    name,
+                    span_id: self.span_id(spans.len() + 1),
+                    parent_id: Some(root_id.clone()),
+                    camera: Some(camera.name.clone()),
+                    start,
+                    end,
+                    failed,
+                });
+                if failed {
+                    break;
+                }
+                start = end;
+            }
+        }
+
+        let end = spans.iter().map(|span| span.end).max();
+        let root = Span {
+            name: "call_setup",
+            span_id: root_id,
+            parent_id: None,
+            camera: None,
+            start: self.offer_at,
+            end: end.unwrap_or(self.offer_at),
+            failed: spans.iter().any(|span| span.failed),
+        };
+
+        std::iter::once(root).chain(spans).collect()
+    }
+
+    /// Milliseconds of every stage, e.g. `front: device_build 850ms, ...`
+    pub fn breakdown(&self) -> String {
+        let spans = self.spans();
+        let mut cameras = Vec::new();
+
+        for camera in self.cameras.iter() {
+            let stages: Vec<String> = spans
+                .iter()
+                .filter(|span| span.camera.as_ref() == Some(&camera.name))
+                .map(|span| {
+                    let millis = (span.end - span.start).as_millis();
+                    let failed = if span.failed { " (failed)" } else { "" };
+                    format!("{} {}ms{}", span.name, millis, failed)
+                })
+                .collect();
+            let stages = if stages.is_empty() {
+                "pending".to_string()
+            } else {
+                stages.join(", ")
+            };
+            cameras.push(format!("{}: {}", camera.name, stages));
+        }
+
+        cameras.join("; ")
+    }
+
+    /// OTLP/HTTP JSON request of the trace, the instants are converted
+    /// with `now` read at `unix_now` since the epoch
+    pub fn otlp_request(
+        &self, service_name: &str, now: Instant, unix_now: Duration,
+    ) -> Value {
+        let unix_nanos = |at: Instant| {
+            let nanos = unix_now
+                .saturating_sub(now.saturating_duration_since(at))
+                .as_nanos();
+            nanos.to_string()
+        };
+        let attribute = |key: &str, value: &str| json!({"key": key, "value": {"stringValue": value}});
+
+        let spans: Vec<Value> = self
+            .spans()
+            .into_iter()
+            .map(|span| {
+                let mut attributes =
+                    vec![attribute("mobile.id", &self.mobile_id)];
+                if let Some(camera) = &span.camera {
+                    attributes.push(attribute("camera.name", camera));
+                }
+
+                //internal span, error status if failed
+                let mut otlp_span = json!({
+                    "traceId": self.trace_id,
+                    "spanId": span.span_id,
+                    "name": span.name,
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.end),
+                    "attributes": attributes,
+                    "status": {"code": if span.failed { 2 } else { 0 }},
+                });
+                if let Some(parent_id) = span.parent_id {
+                    otlp_span["parentSpanId"] = json!(parent_id);
+                }
+                otlp_span
+            })
+            .collect();
+
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME")},
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+}
+
+/// Logs the traces and exports them to the configured collector
+#[derive(Debug, Clone, Default)]
+pub struct TraceExporter {
+    config: TracingConfig,
+}
+
+impl TraceExporter {
+    pub fn new(config: TracingConfig) -> Self {
+        Self { config }
+    }
+
+    /// Logs the trace and sends it in the background, `now` is the time of
+    /// the clock of the trace
+    pub fn export(&self, trace: &CallTrace, now: Instant) {
+        info!(
+            "Call setup of {} traced: {}",
+            trace.mobile_id,
+            trace.breakdown()
+        );
+
+        if let Some(alert_ms) = self.config.first_frame_alert_ms {
+            for (camera, latency) in trace.first_frame_latencies() {
+                let latency_ms = latency.as_millis() as u64;
+                if latency_ms > alert_ms {
+                    warn!(
+                        "First frame of {} camera {} after {}ms, over the \
+                         {}ms alert",
+                        trace.mobile_id, camera, latency_ms, alert_ms
+                    );
+                }
+            }
+        }
+
+        let Some(endpoint) = self.config.otlp_endpoint.as_ref() else {
+            return;
+        };
+        let unix_now =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let request = trace
+            .otlp_request(&self.config.service_name, now, unix_now)
+            .to_string();
+        let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+
+        tokio::spawn(async move {
+            if let Err(e) = post(&url, request).await {
+                warn!("Call setup trace not exported: {:?}", e);
+            }
+        });
+    }
+}
+
+//the body goes through stdin, it's too long for the arguments
+async fn post(url: &str, body: String) -> crate::error::Result<()> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", "5"])
+        .args(["--header", "Content-Type: application/json"])
+        .args(["--data-binary", "@-"])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "OTLP collector request failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_setup_stages_traced() {
+        let offer_at = Instant::now();
+        let at = |millis| offer_at + Duration::from_millis(millis);
+        let mut trace = CallTrace::new(
+            "mobile_1".to_string(),
+            "0af7651916cd43dd8448eb211c80319c".to_string(),
+            offer_at,
+            vec!["front".to_string(), "back".to_string()],
+        );
+
+        trace.camera_built("front", at(800), true);
+        trace.camera_built("back", at(900), false);
+        trace.update_milestones(
+            "front",
+            StreamMilestones {
+                ice_connected: Some(at(3000)),
+                first_frame: None,
+            },
+        );
+        assert!(!trace.is_complete());
+
+        trace.update_milestones(
+            "front",
+            StreamMilestones {
+                ice_connected: Some(at(3000)),
+                first_frame: Some(at(3500)),
+            },
+        );
+        assert!(trace.is_complete());
+        assert_eq!(
+            trace.breakdown(),
+            "front: device_build 800ms, ice_connect 2200ms, first_frame \
+             500ms; back: device_build 900ms (failed)"
+        );
+
+        let request =
+            trace.otlp_request("wcd", at(4000), Duration::from_secs(100));
+        let spans = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["name"], "call_setup");
+        assert_eq!(spans[0]["startTimeUnixNano"], "96000000000");
+        assert_eq!(spans[0]["endTimeUnixNano"], "99500000000");
+        assert_eq!(spans[0]["status"]["code"], 2);
+        assert_eq!(spans[1]["parentSpanId"], spans[0]["spanId"]);
+        assert_eq!(spans[4]["name"], "device_build");
+        assert_eq!(spans[4]["status"]["code"], 2);
+    }
+}
@@ -0,0 +1,160 @@
+//! Definitions of the command line, also read by the build script to
+//! generate the shell completions and the man page.
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Mobile cameras as webcams over WebRTC")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Print the output of the subcommand as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Write the logs to this file besides stderr, rotated as configured
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Asks the running process to apply the config changes
+    Reload {
+        /// Apply the changes that restart the advertisement, the access
+        /// point or the virtual devices
+        #[arg(long)]
+        apply_disruptive: bool,
+    },
+    /// Manages the BLE addresses and mobile ids refused by the host
+    Blocklist {
+        #[command(subcommand)]
+        action: BlocklistAction,
+    },
+    /// Lets a registered mobile change the settings shared by every mobile
+    Trust {
+        mobile_id: String,
+        /// Take the trust back
+        #[arg(long)]
+        revoke: bool,
+    },
+    /// Shows the usage of the mobiles per day
+    Stats {
+        /// Totals per week instead of per day
+        #[arg(long)]
+        summary: bool,
+    },
+    /// Shows the runs of the scheduled self-test, fails if the latest one
+    /// failed
+    SelfTest,
+    /// Turns the virtual cameras off or on in the running process, bind
+    /// `privacy toggle` to a hotkey for a kill switch. Needs a host built
+    /// with the desktop feature.
+    Privacy {
+        #[command(subcommand)]
+        action: PrivacyAction,
+    },
+    /// Checks the host for the issues that keep the services from starting
+    Doctor {
+        /// Lift the soft blocks of the radios
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Lists the paths, sockets, modules, programs and capabilities used
+    /// with the current config
+    Audit {
+        /// Print an AppArmor profile of the host instead
+        #[arg(long)]
+        generate_apparmor_profile: bool,
+    },
+    /// Manages the virtual devices combining the cameras of several mobiles
+    Composite {
+        #[command(subcommand)]
+        action: CompositeAction,
+    },
+    /// Manages the denoise and sharpen presets of the cameras, applied to
+    /// the running cameras right away
+    Filters {
+        #[command(subcommand)]
+        action: FiltersAction,
+    },
+    /// Creates a static set of loopback devices, kept across reboots by
+    /// the options of the module, and leased by the host instead of adding
+    /// a device per camera
+    ProvisionDevices {
+        /// Devices to create
+        #[arg(long)]
+        count: u32,
+        /// Number of the first device, `/dev/video<first>`
+        #[arg(long, default_value_t = 10)]
+        first: u32,
+        /// Devices announcing all caps, the last ones, all of them if
+        /// `loopback.caps` is `all`
+        #[arg(long)]
+        all_caps: Option<u32>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BlocklistAction {
+    /// Lists the blocked entries
+    List,
+    /// Blocks a BLE address or a mobile id
+    Add { entry: String },
+    /// Unblocks a BLE address or a mobile id
+    Remove { entry: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CompositeAction {
+    /// Lists the composite devices of the config
+    List,
+    /// Adds a composite device to the config, or replaces it
+    Add {
+        /// Name of the virtual device
+        name: String,
+        /// Virtual device of a camera, e.g. "Pixel: back", repeated in the
+        /// order of the layout
+        #[arg(long = "source", required = true)]
+        sources: Vec<String>,
+        /// Arrangement of the cameras, the first one is the main camera of
+        /// the picture-in-picture
+        #[arg(long, default_value = "grid", value_parser = ["grid", "pip"])]
+        layout: String,
+    },
+    /// Removes a composite device from the config
+    Remove { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FiltersAction {
+    /// Lists the presets of the config
+    List,
+    /// Sets the presets of a virtual device, or the default ones, the
+    /// preset not given is kept
+    Set {
+        /// Virtual device, e.g. "Pixel: back", the devices without their
+        /// own presets if not given
+        #[arg(long)]
+        device: Option<String>,
+        #[arg(long, value_parser = ["off", "light", "strong"])]
+        denoise: Option<String>,
+        #[arg(long, value_parser = ["off", "light", "strong"])]
+        sharpen: Option<String>,
+    },
+    /// Removes the presets of a virtual device, it takes the default ones
+    Remove { device: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PrivacyAction {
+    /// Shows the "Camera disabled" frame on every virtual camera
+    On,
+    /// Shows the mobile cameras again
+    Off,
+    /// Switches between on and off
+    Toggle,
+    /// Shows whether the cameras are disabled
+    Status,
+}
@@ -0,0 +1,823 @@
+//! Command line interface. Without a subcommand the host services are run.
+//! With `--json` every subcommand prints a single JSON document, its fields
+//! are only added to, so scripts can rely on them.
+
+mod args;
+
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Serialize;
+
+pub use args::{
+    BlocklistAction, Cli, Command, CompositeAction, FiltersAction,
+    PrivacyAction,
+};
+
+use crate::{
+    app_data::{AppData, AutoBlock, DiskBasedDb, MobileUsage, SelfTestRun},
+    audit::{Access, Audit, AuditItem},
+    ble::{comm_types::HostCapabilities, server::mobile_comm::AppDataStore},
+    config::{AppConfig, CompositeConfig, FilterPresets, PostProcessingConfig},
+    error::Result,
+    live_config::request_disruptive_reload,
+    rfkill::{self, Radio},
+};
+
+//output of a subcommand, printed as text or as JSON
+trait CommandOutput: Serialize {
+    fn print_text(&self);
+
+    fn print(&self, json: bool) -> Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self)?);
+        } else {
+            self.print_text();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReloadOutput {
+    disruptive_requested: bool,
+}
+
+impl CommandOutput for ReloadOutput {
+    fn print_text(&self) {
+        if !self.disruptive_requested {
+            println!("Safe changes are applied when the config is saved");
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BlocklistOutput {
+    entries: Vec<String>,
+    automatic: Vec<AutoBlock>,
+}
+
+impl CommandOutput for BlocklistOutput {
+    fn print_text(&self) {
+        for entry in self.entries.iter() {
+            println!("{}", entry);
+        }
+        for blocked in self.automatic.iter() {
+            println!("{}\tautomatic since {}", blocked.entry, blocked.day);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BlocklistChange {
+    entry: String,
+    blocked: bool,
+}
+
+impl CommandOutput for BlocklistChange {
+    fn print_text(&self) {
+        if self.blocked {
+            println!("{} blocked", self.entry);
+        } else {
+            println!("{} unblocked", self.entry);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TrustChange {
+    mobile_id: String,
+    trusted: bool,
+}
+
+impl CommandOutput for TrustChange {
+    fn print_text(&self) {
+        if self.trusted {
+            println!("{} trusted", self.mobile_id);
+        } else {
+            println!("{} no longer trusted", self.mobile_id);
+        }
+    }
+}
+
+#[cfg(feature = "desktop")]
+#[derive(Debug, Serialize)]
+struct PrivacyOutput {
+    enabled: bool,
+}
+
+#[cfg(feature = "desktop")]
+impl CommandOutput for PrivacyOutput {
+    fn print_text(&self) {
+        if self.enabled {
+            println!("Cameras disabled");
+        } else {
+            println!("Cameras enabled");
+        }
+    }
+}
+
+#[cfg(feature = "webrtc")]
+#[derive(Debug, Serialize)]
+struct ProvisionOutput {
+    devices: Vec<ProvisionedDeviceOutput>,
+}
+
+#[cfg(feature = "webrtc")]
+#[derive(Debug, Serialize)]
+struct ProvisionedDeviceOutput {
+    path: String,
+    label: String,
+    caps: crate::config::CapsMode,
+    created: bool,
+}
+
+#[cfg(feature = "webrtc")]
+impl CommandOutput for ProvisionOutput {
+    fn print_text(&self) {
+        for device in self.devices.iter() {
+            let state = if device.created { "created" } else { "present" };
+            println!(
+                "{}\t{}\t{:?} caps\t{}",
+                device.path, device.label, device.caps, state
+            );
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DoctorOutput {
+    radios: Vec<Radio>,
+    //none in a build without the pipelines
+    video: Option<HostCapabilities>,
+}
+
+impl CommandOutput for DoctorOutput {
+    fn print_text(&self) {
+        if self.radios.is_empty() {
+            println!("No radio kill switches found");
+        }
+        for radio in self.radios.iter() {
+            let state = match (radio.hard_blocked, radio.soft_blocked) {
+                (true, _) => "blocked by a hardware switch",
+                (false, true) => "soft-blocked, run `doctor --fix`",
+                (false, false) => "ok",
+            };
+            println!("{} {} ({}): {}", radio.kind, radio.name, radio.id, state);
+        }
+
+        let Some(video) = &self.video else {
+            return;
+        };
+        let mut apis = Vec::new();
+        if video.vaapi {
+            apis.push("VA-API");
+        }
+        if video.vulkan {
+            apis.push("Vulkan");
+        }
+        if apis.is_empty() {
+            println!("Hardware video decoding: none, decoded by the CPU");
+        } else {
+            println!(
+                "Hardware video decoding: {} ({})",
+                apis.join(", "),
+                video.hw_codecs.join(", ")
+            );
+        }
+    }
+}
+
+impl CommandOutput for Audit {
+    fn print_text(&self) {
+        for path in self.paths.iter() {
+            let access = match path.access {
+                Access::Read => "r ",
+                Access::ReadWrite => "rw",
+            };
+            println!("path       {} {}  # {}", access, path.path, path.reason);
+        }
+        for socket in self.sockets.iter() {
+            let address = socket.address.as_deref().unwrap_or("any");
+            println!(
+                "socket     {} {} {}  # {}",
+                socket.domain, socket.kind, address, socket.reason
+            );
+        }
+        let items = [
+            ("module", &self.modules),
+            ("program", &self.programs),
+            ("capability", &self.capabilities),
+        ];
+        for (kind, list) in items {
+            for AuditItem { name, reason } in list.iter() {
+                println!("{:<10} {}  # {}", kind, name, reason);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AppArmorOutput {
+    profile: String,
+}
+
+impl CommandOutput for AppArmorOutput {
+    fn print_text(&self) {
+        print!("{}", self.profile);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompositesOutput {
+    composites: Vec<CompositeConfig>,
+}
+
+impl CommandOutput for CompositesOutput {
+    fn print_text(&self) {
+        for composite in self.composites.iter() {
+            println!(
+                "{} ({:?}, {}x{}): {}",
+                composite.name,
+                composite.layout,
+                composite.width,
+                composite.height,
+                composite.sources.join(", ")
+            );
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CompositeChange {
+    name: String,
+    added: bool,
+}
+
+impl CommandOutput for CompositeChange {
+    fn print_text(&self) {
+        let change = if self.added { "added" } else { "removed" };
+        println!(
+            "Composite {} {}, apply it with `reload --apply-disruptive`",
+            self.name, change
+        );
+    }
+}
+
+//e.g. "denoise light, sharpen off"
+fn presets_text(presets: &FilterPresets) -> String {
+    format!("denoise {:?}, sharpen {:?}", presets.denoise, presets.sharpen)
+        .to_lowercase()
+}
+
+#[derive(Debug, Serialize)]
+struct FiltersOutput {
+    post_processing: PostProcessingConfig,
+}
+
+impl CommandOutput for FiltersOutput {
+    fn print_text(&self) {
+        println!("Default: {}", presets_text(&self.post_processing.default));
+        for (device, presets) in self.post_processing.devices.iter() {
+            println!("{}: {}", device, presets_text(presets));
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FiltersChange {
+    /// None for the default presets
+    device: Option<String>,
+    /// None once the presets of the device are removed
+    presets: Option<FilterPresets>,
+}
+
+impl CommandOutput for FiltersChange {
+    fn print_text(&self) {
+        let device = self.device.as_deref().unwrap_or("the default");
+        match &self.presets {
+            Some(presets) => {
+                println!(
+                    "Filters of {} set to {}",
+                    device,
+                    presets_text(presets)
+                )
+            }
+            None => println!("Filters of {} removed", device),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MobileStats {
+    mobile_id: String,
+    name: String,
+    calls: u32,
+    streaming_secs: u64,
+    failures: u32,
+    /// Average time from the offer to the first frame of the cameras
+    first_frame_avg_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct PeriodStats {
+    /// `YYYY-MM-DD` day or `YYYY-Www` ISO week
+    period: String,
+    calls: u32,
+    streaming_secs: u64,
+    failures: u32,
+    first_frame_avg_ms: Option<u64>,
+    mobiles: Vec<MobileStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatsOutput {
+    periods: Vec<PeriodStats>,
+}
+
+impl CommandOutput for StatsOutput {
+    fn print_text(&self) {
+        for period in self.periods.iter() {
+            println!(
+                "{}  {}",
+                period.period,
+                format_usage(
+                    period.streaming_secs,
+                    period.calls,
+                    period.failures,
+                    period.first_frame_avg_ms
+                )
+            );
+
+            for mobile in period.mobiles.iter() {
+                println!(
+                    "  {}  {}",
+                    mobile.name,
+                    format_usage(
+                        mobile.streaming_secs,
+                        mobile.calls,
+                        mobile.failures,
+                        mobile.first_frame_avg_ms
+                    )
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SelfTestOutput {
+    runs: Vec<SelfTestRun>,
+}
+
+impl CommandOutput for SelfTestOutput {
+    fn print_text(&self) {
+        if self.runs.is_empty() {
+            println!("No self-test run, set self_test.schedule");
+        }
+        for run in self.runs.iter() {
+            let result = if run.passed() { "passed" } else { "failed" };
+            println!("{}  {}", run.at, result);
+            for step in run.steps.iter().filter(|step| !step.passed) {
+                println!("  {}: {}", step.name, step.detail);
+            }
+        }
+    }
+}
+
+/// Requests the running process to apply the config changes
+pub fn run_reload(apply_disruptive: bool, json: bool) -> Result<()> {
+    if apply_disruptive {
+        request_disruptive_reload()?;
+    }
+
+    ReloadOutput { disruptive_requested: apply_disruptive }.print(json)
+}
+
+/// Runs the blocklist action on the database at `db_path`
+pub fn run_blocklist(
+    db_path: &str, action: BlocklistAction, json: bool,
+) -> Result<()> {
+    //the database is locked while the host services run
+    let disk_db = DiskBasedDb::open_from(db_path)
+        .context("Failed to open the database, stop the host first")?;
+
+    let mut app_data = AppData::open(disk_db);
+    let mut blocklist = app_data.get_blocklist()?;
+
+    match action {
+        BlocklistAction::List => BlocklistOutput {
+            entries: blocklist.entries,
+            automatic: blocklist.automatic,
+        }
+        .print(json),
+        BlocklistAction::Add { entry } => {
+            if blocklist.add(&entry) {
+                app_data.update_blocklist(&blocklist)?;
+            }
+            BlocklistChange { entry, blocked: true }.print(json)
+        }
+        BlocklistAction::Remove { entry } => {
+            if !blocklist.remove(&entry) {
+                return Err(anyhow::anyhow!("{} is not blocked", entry));
+            }
+            app_data.update_blocklist(&blocklist)?;
+            BlocklistChange { entry, blocked: false }.print(json)
+        }
+    }
+}
+
+/// Sets whether the registered mobile `mobile_id` can change the settings
+/// of the whole host, on the database at `db_path`
+pub fn run_trust(
+    db_path: &str, mobile_id: String, revoke: bool, json: bool,
+) -> Result<()> {
+    let disk_db = DiskBasedDb::open_from(db_path)
+        .context("Failed to open the database, stop the host first")?;
+
+    let mut app_data = AppData::open(disk_db);
+    let mut mobile = app_data
+        .get_mobile(&mobile_id)
+        .with_context(|| format!("{} is not registered", mobile_id))?;
+    mobile.trusted = !revoke;
+    app_data.update_mobile(&mobile)?;
+
+    TrustChange { mobile_id, trusted: mobile.trusted }.print(json)
+}
+
+/// Checks the radio kill switches under `rfkill_root`, lifting the soft
+/// blocks of the WiFi and Bluetooth radios if `fix` is set
+pub fn run_doctor(rfkill_root: &str, fix: bool, json: bool) -> Result<()> {
+    let mut radios = rfkill::read_radios(Path::new(rfkill_root));
+
+    if fix {
+        for radio in radios.iter_mut().filter(|radio| {
+            radio.soft_blocked
+                && [rfkill::WLAN, rfkill::BLUETOOTH]
+                    .contains(&radio.kind.as_str())
+        }) {
+            rfkill::unblock(radio).with_context(|| {
+                format!("Failed to unblock {}, run as root", radio.name)
+            })?;
+        }
+    }
+
+    #[cfg(feature = "webrtc")]
+    let video = Some(crate::vdevice_builder::probe_decoders(false));
+    #[cfg(not(feature = "webrtc"))]
+    let video = None;
+
+    let output = DoctorOutput { radios, video };
+    output.print(json)?;
+
+    //scripts get a failure while a radio stays blocked
+    if output.radios.iter().any(Radio::is_blocked) {
+        return Err(anyhow::anyhow!("Blocked radios found"));
+    }
+
+    Ok(())
+}
+
+/// Prints the resources used by the host with the current config and the
+/// database at `db_path`, or their AppArmor profile
+pub fn run_audit(
+    db_path: &str, generate_apparmor_profile: bool, json: bool,
+) -> Result<()> {
+    let config = AppConfig::load()?;
+    let audit = Audit::for_config(
+        &config,
+        Path::new(db_path),
+        AppConfig::default_path().as_deref(),
+    );
+
+    if !generate_apparmor_profile {
+        return audit.print(json);
+    }
+
+    let binary = std::env::current_exe()
+        .context("Failed to find the path of the binary")?;
+    AppArmorOutput { profile: audit.apparmor_profile(&binary) }.print(json)
+}
+
+/// Runs the composite action on the config file
+pub fn run_composite(action: CompositeAction, json: bool) -> Result<()> {
+    let path = AppConfig::default_path()
+        .ok_or_else(|| anyhow::anyhow!("No config directory found"))?;
+    let mut composites = AppConfig::load_from(&path)?.composites;
+
+    let change = match action {
+        CompositeAction::List => {
+            return CompositesOutput { composites }.print(json);
+        }
+        CompositeAction::Add { name, sources, layout } => {
+            let layout = serde_json::from_value(serde_json::json!(layout))?;
+            composites.retain(|composite| composite.name != name);
+            composites.push(CompositeConfig {
+                name: name.clone(),
+                sources,
+                layout,
+                ..Default::default()
+            });
+            CompositeChange { name, added: true }
+        }
+        CompositeAction::Remove { name } => {
+            let count = composites.len();
+            composites.retain(|composite| composite.name != name);
+            if composites.len() == count {
+                return Err(anyhow::anyhow!("No composite named {}", name));
+            }
+            CompositeChange { name, added: false }
+        }
+    };
+
+    AppConfig::write_setting(
+        &path,
+        "composites",
+        serde_json::to_value(&composites)?,
+    )?;
+    change.print(json)
+}
+
+/// Runs the filters action on the config file, the running host applies
+/// the change on its own
+pub fn run_filters(action: FiltersAction, json: bool) -> Result<()> {
+    let path = AppConfig::default_path()
+        .ok_or_else(|| anyhow::anyhow!("No config directory found"))?;
+    let mut post_processing = AppConfig::load_from(&path)?.post_processing;
+
+    let change = match action {
+        FiltersAction::List => {
+            return FiltersOutput { post_processing }.print(json);
+        }
+        FiltersAction::Set { device, denoise, sharpen } => {
+            let presets = match &device {
+                Some(device) => post_processing.presets_for(device),
+                None => post_processing.default,
+            };
+            let preset = |preset: Option<String>, current| match preset {
+                Some(preset) => serde_json::from_value(preset.into()),
+                None => Ok(current),
+            };
+            let presets = FilterPresets {
+                denoise: preset(denoise, presets.denoise)?,
+                sharpen: preset(sharpen, presets.sharpen)?,
+            };
+
+            match &device {
+                Some(device) => {
+                    post_processing.devices.insert(device.clone(), presets);
+                }
+                None => post_processing.default = presets,
+            }
+            FiltersChange { device, presets: Some(presets) }
+        }
+        FiltersAction::Remove { device } => {
+            if post_processing.devices.remove(&device).is_none() {
+                return Err(anyhow::anyhow!("No filters set for {}", device));
+            }
+            FiltersChange { device: Some(device), presets: None }
+        }
+    };
+
+    AppConfig::write_setting(
+        &path,
+        "post_processing",
+        serde_json::to_value(&post_processing)?,
+    )?;
+    change.print(json)
+}
+
+/// Prints the usage stats of the database at `db_path`
+pub fn run_stats(db_path: &str, summary: bool, json: bool) -> Result<()> {
+    let disk_db = DiskBasedDb::open_from(db_path)
+        .context("Failed to open the database, stop the host first")?;
+
+    let app_data = AppData::open(disk_db);
+    let stats = app_data.get_usage_stats()?;
+
+    let periods = if summary {
+        stats.weekly()
+    } else {
+        stats.days.into_iter().map(|daily| (daily.day, daily.mobiles)).collect()
+    };
+
+    //the mobiles removed since then are shown by id
+    let name_of = |mobile_id: &str| {
+        app_data
+            .get_mobile(mobile_id)
+            .map_or(mobile_id.to_string(), |mobile| mobile.name)
+    };
+
+    StatsOutput {
+        periods: periods
+            .into_iter()
+            .map(|(period, mobiles)| period_stats(period, mobiles, name_of))
+            .collect(),
+    }
+    .print(json)
+}
+
+/// Prints the runs of the self-test, oldest first
+pub fn run_self_test(db_path: &str, json: bool) -> Result<()> {
+    let disk_db = DiskBasedDb::open_from(db_path)
+        .context("Failed to open the database, stop the host first")?;
+
+    let log = AppData::open(disk_db).get_self_tests()?;
+    let output = SelfTestOutput { runs: log.runs };
+    output.print(json)?;
+
+    //scripts get a failure until a run passes again
+    if output.runs.last().is_some_and(|run| !run.passed()) {
+        return Err(anyhow::anyhow!("The latest self-test failed"));
+    }
+
+    Ok(())
+}
+
+/// Runs the privacy action on the running process over the system bus
+#[cfg(feature = "desktop")]
+pub fn run_privacy(action: PrivacyAction, json: bool) -> Result<()> {
+    use crate::desktop_bus::{BUS_NAME, INTERFACE, OBJECT_PATH};
+    use dbus::blocking::Connection;
+    use std::time::Duration;
+
+    let conn = Connection::new_system()?;
+    let proxy = conn.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+    let call = |method: &str| -> Result<bool> {
+        let (enabled,): (bool,) = proxy
+            .method_call(INTERFACE, method, ())
+            .context("Failed to reach the host, is it running?")?;
+        Ok(enabled)
+    };
+
+    let enabled = match action {
+        PrivacyAction::On | PrivacyAction::Off => {
+            let enabled = matches!(action, PrivacyAction::On);
+            proxy
+                .method_call::<(), _, _, _>(INTERFACE, "SetPrivacy", (enabled,))
+                .context("Failed to reach the host, is it running?")?;
+            enabled
+        }
+        PrivacyAction::Toggle => call("TogglePrivacy")?,
+        PrivacyAction::Status => call("GetPrivacy")?,
+    };
+
+    PrivacyOutput { enabled }.print(json)
+}
+
+/// The privacy switch is served on the system bus by the desktop feature
+#[cfg(not(feature = "desktop"))]
+pub fn run_privacy(_action: PrivacyAction, _json: bool) -> Result<()> {
+    Err(anyhow::anyhow!(
+        "Host built without the desktop D-Bus service, the privacy switch \
+         needs the desktop feature"
+    ))
+}
+
+/// Creates the provisioned devices and writes their boot config
+#[cfg(feature = "webrtc")]
+pub fn run_provision_devices(
+    count: u32, first: u32, all_caps: Option<u32>, json: bool,
+) -> Result<()> {
+    use crate::{
+        config::CapsMode,
+        vdevice_builder::{provision, DeviceMapping},
+    };
+
+    let loopback = AppConfig::load()?.loopback;
+    let all_caps = all_caps.unwrap_or(match loopback.caps {
+        CapsMode::All => count,
+        CapsMode::Exclusive => 0,
+    });
+    let mapping = DeviceMapping::new(first, count, all_caps);
+    let created = provision(&mapping, &loopback)?;
+
+    let devices = mapping
+        .devices
+        .into_iter()
+        .map(|device| ProvisionedDeviceOutput {
+            created: created.contains(&device.number),
+            path: device.path,
+            label: device.label,
+            caps: device.caps,
+        })
+        .collect();
+    ProvisionOutput { devices }.print(json)
+}
+
+/// The loopback devices are managed by the webrtc feature
+#[cfg(not(feature = "webrtc"))]
+pub fn run_provision_devices(
+    _count: u32, _first: u32, _all_caps: Option<u32>, _json: bool,
+) -> Result<()> {
+    Err(anyhow::anyhow!("Host built without the virtual devices"))
+}
+
+fn period_stats(
+    period: String, mobiles: Vec<MobileUsage>, name_of: impl Fn(&str) -> String,
+) -> PeriodStats {
+    let first_frames: u32 =
+        mobiles.iter().map(|usage| usage.first_frames).sum();
+    let first_frame_ms: u64 =
+        mobiles.iter().map(|usage| usage.first_frame_ms).sum();
+
+    let mobiles: Vec<MobileStats> = mobiles
+        .into_iter()
+        .map(|usage| MobileStats {
+            name: name_of(&usage.mobile_id),
+            first_frame_avg_ms: average(
+                usage.first_frame_ms,
+                usage.first_frames,
+            ),
+            mobile_id: usage.mobile_id,
+            calls: usage.calls,
+            streaming_secs: usage.streaming_secs,
+            failures: usage.failures,
+        })
+        .collect();
+
+    PeriodStats {
+        period,
+        calls: mobiles.iter().map(|mobile| mobile.calls).sum(),
+        streaming_secs: mobiles
+            .iter()
+            .map(|mobile| mobile.streaming_secs)
+            .sum(),
+        failures: mobiles.iter().map(|mobile| mobile.failures).sum(),
+        first_frame_avg_ms: average(first_frame_ms, first_frames),
+        mobiles,
+    }
+}
+
+fn average(total: u64, count: u32) -> Option<u64> {
+    (count > 0).then(|| total / count as u64)
+}
+
+fn format_usage(
+    streaming_secs: u64, calls: u32, failures: u32,
+    first_frame_avg_ms: Option<u64>,
+) -> String {
+    let mut usage = format!(
+        "{:.1} h, {} calls, {} failed cameras",
+        streaming_secs as f64 / 3600.0,
+        calls,
+        failures
+    );
+    if let Some(first_frame_avg_ms) = first_frame_avg_ms {
+        usage.push_str(&format!(", first frame in {} ms", first_frame_avg_ms));
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_json_schema() {
+        let usage = MobileUsage {
+            mobile_id: "mobile_1".to_string(),
+            calls: 2,
+            streaming_secs: 5400,
+            failures: 1,
+            ..Default::default()
+        };
+        let output = StatsOutput {
+            periods: vec![period_stats(
+                "2026-W42".to_string(),
+                vec![usage.clone(), usage],
+                |_| "Pixel".to_string(),
+            )],
+        };
+
+        let json = serde_json::to_value(&output).unwrap();
+        let period = &json["periods"][0];
+        assert_eq!(period["period"], "2026-W42");
+        assert_eq!(period["calls"], 4);
+        assert_eq!(period["streaming_secs"], 10800);
+        assert_eq!(period["failures"], 2);
+        assert_eq!(period["mobiles"][1]["mobile_id"], "mobile_1");
+        assert_eq!(period["mobiles"][1]["name"], "Pixel");
+    }
+
+    #[test]
+    fn test_first_frame_average() {
+        let usage =
+            |mobile_id: &str, first_frames, first_frame_ms| MobileUsage {
+                mobile_id: mobile_id.to_string(),
+                calls: 1,
+                first_frames,
+                first_frame_ms,
+                ..Default::default()
+            };
+        let period = period_stats(
+            "2026-10-18".to_string(),
+            vec![usage("mobile_1", 2, 5000), usage("mobile_2", 0, 0)],
+            |_| "Pixel".to_string(),
+        );
+
+        assert_eq!(period.first_frame_avg_ms, Some(2500));
+        assert_eq!(period.mobiles[0].first_frame_avg_ms, Some(2500));
+        assert_eq!(period.mobiles[1].first_frame_avg_ms, None);
+    }
+}
@@ -0,0 +1,163 @@
+//! # Time and id sources.
+//! The pairing window, the guest sessions and the queued offers expire with
+//! the time, and the pairing tokens are random. They are read through these
+//! traits, so the tests simulate the time and get known ids instead of
+//! sleeping.
+//!
+//! The clock of the host is also given to the mobiles, a phone whose clock
+//! is off takes its offset instead of failing on the session expiries.
+
+use std::{
+    fs,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Local, NaiveDate, NaiveDateTime};
+use uuid::Uuid;
+
+use crate::ble::comm_types::HostClock;
+
+/// Time since boot, suspend included
+pub const UPTIME_PATH: &str = "/proc/uptime";
+
+/// Source of the time
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// Local day, the usage stats are kept per day
+    fn today(&self) -> NaiveDate;
+
+    /// Local date and time, the self-test runs on a local schedule
+    fn local_now(&self) -> NaiveDateTime;
+}
+
+/// Time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn today(&self) -> NaiveDate {
+        Local::now().date_naive()
+    }
+
+    fn local_now(&self) -> NaiveDateTime {
+        Local::now().naive_local()
+    }
+}
+
+/// Wall and monotonic clocks of the host, none if the uptime can't be read
+pub fn host_clock() -> Option<HostClock> {
+    let uptime = fs::read_to_string(UPTIME_PATH).ok()?;
+    let since_boot = parse_uptime(&uptime)?;
+    let wall = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+    Some(HostClock {
+        wall_ms: wall.as_millis() as u64,
+        monotonic_ms: since_boot.as_millis() as u64,
+    })
+}
+
+//seconds since boot, then the idle seconds of the cpus
+fn parse_uptime(uptime: &str) -> Option<Duration> {
+    let secs = uptime.split_whitespace().next()?.parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+/// Source of the random ids
+pub trait IdGenerator: Send + Sync + 'static {
+    fn new_id(&self) -> String;
+}
+
+/// Random v4 UUIDs
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn new_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+#[cfg(test)]
+pub use manual::{ManualClock, SequentialIds};
+
+#[cfg(test)]
+mod manual {
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Clock moved only by the test, the clones share the time
+    #[derive(Clone)]
+    pub struct ManualClock {
+        start: Instant,
+        start_day: NaiveDate,
+        advanced: Arc<Mutex<Duration>>,
+    }
+
+    impl ManualClock {
+        pub fn new(today: NaiveDate) -> Self {
+            Self {
+                start: Instant::now(),
+                start_day: today,
+                advanced: Arc::default(),
+            }
+        }
+
+        /// Moves the time forward, the day changes every 24 hours
+        pub fn advance(&self, by: Duration) {
+            *self.advanced.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.start + *self.advanced.lock().unwrap()
+        }
+
+        fn today(&self) -> NaiveDate {
+            let days = self.advanced.lock().unwrap().as_secs() / 86_400;
+            self.start_day + chrono::Days::new(days)
+        }
+
+        //the test starts at midnight of its first day
+        fn local_now(&self) -> NaiveDateTime {
+            let advanced = *self.advanced.lock().unwrap();
+            self.start_day.and_time(chrono::NaiveTime::MIN)
+                + chrono::TimeDelta::from_std(advanced).unwrap_or_default()
+        }
+    }
+
+    /// Ids `id-1`, `id-2`...
+    #[derive(Default)]
+    pub struct SequentialIds(AtomicU32);
+
+    impl IdGenerator for SequentialIds {
+        fn new_id(&self) -> String {
+            format!("id-{}", self.0.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_uptime() {
+        assert_eq!(
+            parse_uptime("3601.25 14000.50\n"),
+            Some(Duration::from_millis(3_601_250))
+        );
+        assert_eq!(parse_uptime(""), None);
+        assert_eq!(parse_uptime("-1 0"), None);
+    }
+}
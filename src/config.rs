@@ -0,0 +1,1065 @@
+//! # Host configuration.
+//! Optional JSON file in the user config directory, missing fields and a
+//! missing file fall back to the defaults.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use directories::ProjectDirs;
+use log::{info, LevelFilter};
+use serde::{Deserialize, Serialize};
+
+use crate::{ble::server::authorization::WorkingHours, error::Result};
+use anyhow::anyhow;
+
+const CONFIG_FILE_NAME: &str = "config.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AppConfig {
+    /// Log level, overridden by RUST_LOG
+    pub log_level: LevelFilter,
+    /// Log file written besides stderr, read when the process starts
+    pub log_file: Option<LogFileConfig>,
+    /// Seconds after startup in which the host shares the pairing data
+    pub pairing_window_secs: u64,
+    /// Expose the read-only diagnostics characteristic in the provisioner
+    pub diagnostics_enabled: bool,
+    /// Advertise with a resolvable private address instead of the adapter
+    /// static address, if the controller supports it
+    pub le_privacy: bool,
+    /// Group of hosts sharing the imported pairing data, announced in the
+    /// provisioning info and the advertisement
+    pub host_group: Option<String>,
+    /// Bluetooth adapters sharing the mobiles, the default adapter if empty
+    pub ble_adapters: Vec<String>,
+    /// What to do with an adapter that can't advertise
+    pub ble_advertising_fallback: AdvertisingFallback,
+    /// Scale down the pipelines output under host CPU pressure
+    pub cpu_pressure: CpuPressureConfig,
+    /// Publish the cameras over RTSP
+    pub rtsp: RtspConfig,
+    /// Extra output backends of the cameras
+    pub outputs: Vec<OutputConfig>,
+    /// Provider of the TURN credentials of the calls
+    pub turn: Option<TurnConfig>,
+    /// Built-in rewrites of the SDP of the calls, applied in order
+    pub sdp_mungers: Vec<SdpMungerConfig>,
+    /// Operation inside a container
+    pub container: ContainerConfig,
+    /// Limit the streams to the working hours
+    pub working_hours: Option<WorkingHours>,
+    /// Trusted mobile ids, the cameras of their last call are kept
+    /// prepared so the next stream starts instantly
+    pub warm_standby: Vec<String>,
+    /// Time-limited sessions of the guest mobiles
+    pub guest_sessions: GuestSessionsConfig,
+    /// Days of usage statistics kept in the database
+    pub stats_retention_days: u32,
+    /// Battery level and AC status shared with the mobiles
+    pub power: PowerConfig,
+    /// Ingress bandwidth caps of the calls, read when a call starts
+    pub bandwidth: BandwidthConfig,
+    /// Prefer the hardware video decoders working on the host, probed at
+    /// startup
+    pub hw_decoding: bool,
+    /// Cameras streaming at once on the host, the free slots are
+    /// advertised, unlimited if not set
+    pub max_cameras: Option<u32>,
+    /// Maximum format of the cameras, read when a call starts
+    pub video_limits: VideoLimitsConfig,
+    /// Denoise and sharpen stages of the cameras, applied live
+    pub post_processing: PostProcessingConfig,
+    /// Private directory of the generated configs and control sockets
+    pub runtime_dir: PathBuf,
+    /// Name resolved to the host by the access point DNS server, announced
+    /// in the provisioning info
+    pub local_hostname: Option<String>,
+    /// Answer the connectivity checks of the phones on the access point,
+    /// so they don't leave it for having no internet
+    pub suppress_captive_portal: bool,
+    /// Lift the soft rfkill blocks of the WiFi and Bluetooth radios at
+    /// startup instead of failing
+    pub rfkill_unblock: bool,
+    /// Previews of the cameras for the desktop front-ends, read when a
+    /// camera starts
+    pub thumbnails: ThumbnailsConfig,
+    /// Recordings of the mobiles set to always record, read when a call
+    /// starts
+    pub recording: RecordingConfig,
+    /// Scope the mobiles to the users of the seats on multi-user hosts
+    pub per_user: PerUserConfig,
+    /// Overrides of the loopback devices created on the host, read when a
+    /// camera starts
+    pub loopback: LoopbackConfig,
+    /// Restrict the inbound UDP of the access point to the media ports of
+    /// the calls, read when a call starts
+    pub session_firewall: bool,
+    /// Virtual devices combining the cameras of several mobiles
+    pub composites: Vec<CompositeConfig>,
+    /// Traces of the call setups
+    pub tracing: TracingConfig,
+    /// Scheduled self-test of the devices and pipelines
+    pub self_test: SelfTestConfig,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            log_level: LevelFilter::Error,
+            log_file: None,
+            pairing_window_secs: 300,
+            diagnostics_enabled: true,
+            le_privacy: false,
+            host_group: None,
+            ble_adapters: Vec::new(),
+            ble_advertising_fallback: AdvertisingFallback::default(),
+            cpu_pressure: CpuPressureConfig::default(),
+            rtsp: RtspConfig::default(),
+            outputs: Vec::new(),
+            turn: None,
+            sdp_mungers: Vec::new(),
+            container: ContainerConfig::default(),
+            working_hours: None,
+            warm_standby: Vec::new(),
+            guest_sessions: GuestSessionsConfig::default(),
+            stats_retention_days: 90,
+            power: PowerConfig::default(),
+            bandwidth: BandwidthConfig::default(),
+            hw_decoding: true,
+            max_cameras: None,
+            video_limits: VideoLimitsConfig::default(),
+            post_processing: PostProcessingConfig::default(),
+            runtime_dir: PathBuf::from("/run/webcam-direct"),
+            local_hostname: Some("host.webcamdirect".to_string()),
+            suppress_captive_portal: true,
+            rfkill_unblock: false,
+            thumbnails: ThumbnailsConfig::default(),
+            recording: RecordingConfig::default(),
+            per_user: PerUserConfig::default(),
+            loopback: LoopbackConfig::default(),
+            session_firewall: false,
+            composites: Vec::new(),
+            tracing: TracingConfig::default(),
+            self_test: SelfTestConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Size in MB after which the file is rotated
+    pub max_size_mb: u64,
+    /// Hours after which the file is rotated, only by size if not set
+    pub max_age_hours: Option<u64>,
+    /// Rotated files kept, `<path>.1` is the newest
+    pub keep: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/var/log/webcam-direct.log"),
+            max_size_mb: 10,
+            max_age_hours: None,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct GuestSessionsConfig {
+    /// Guest mobile ids, unregistered when their session expires so they
+    /// must pair again
+    pub mobiles: Vec<String>,
+    /// Seconds from the first offer of the guest until the session expires
+    pub max_session_secs: u64,
+    /// Seconds before the expiry the mobile is warned
+    pub warning_secs: u64,
+}
+
+impl Default for GuestSessionsConfig {
+    fn default() -> Self {
+        Self { mobiles: Vec::new(), max_session_secs: 3600, warning_secs: 300 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Cap of the cameras of a mobile together, unlimited if not set
+    pub per_mobile_kbps: Option<u32>,
+    /// Cap of every call together, unlimited if not set
+    pub total_kbps: Option<u32>,
+}
+
+/// Limits of the offered formats, in landscape, a portrait camera gets them
+/// turned. Unlimited if not set
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct VideoLimitsConfig {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_fps: Option<u32>,
+}
+
+/// Strength of a post-processing stage
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterPreset {
+    #[default]
+    Off,
+    Light,
+    Strong,
+}
+
+/// Post-processing stages of a camera
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(default)]
+pub struct FilterPresets {
+    pub denoise: FilterPreset,
+    pub sharpen: FilterPreset,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PostProcessingConfig {
+    /// Presets of the devices without their own
+    pub default: FilterPresets,
+    /// Presets by virtual device name, e.g. `Pixel: back`
+    pub devices: BTreeMap<String, FilterPresets>,
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl PostProcessingConfig {
+    /// Presets of the virtual device
+    pub fn presets_for(&self, vdevice_name: &str) -> FilterPresets {
+        self.devices.get(vdevice_name).copied().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PerUserConfig {
+    pub enabled: bool,
+    /// Seat of the users pairing the mobiles
+    pub seat: String,
+}
+
+impl Default for PerUserConfig {
+    fn default() -> Self {
+        Self { enabled: false, seat: "seat0".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ThumbnailsConfig {
+    pub enabled: bool,
+    /// Width of the JPEG, the height keeps the aspect ratio
+    pub width: u32,
+}
+
+impl Default for ThumbnailsConfig {
+    fn default() -> Self {
+        Self { enabled: true, width: 240 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub struct RecordingConfig {
+    /// Directory of the recordings, created on the first one
+    pub directory: PathBuf,
+    /// Name of the files, `{mobile}`, `{camera}`, `{date}` and `{time}` are
+    /// replaced when the camera starts
+    pub filename: String,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("/var/lib/webcam-direct/recordings"),
+            filename: "{mobile}-{camera}-{date}-{time}.mkv".to_string(),
+        }
+    }
+}
+
+/// Limits of the loopback devices, derived from the format of the camera
+/// when unset
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub struct LoopbackConfig {
+    /// Raise it if a consumer shows tearing
+    pub max_buffers: Option<u32>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Capabilities announced by the devices, also the `exclusive_caps`
+    /// option of the module loaded at startup
+    pub caps: CapsMode,
+    /// Capabilities by virtual device name, e.g. `Pixel: back`, for the
+    /// consumers needing the other mode
+    pub device_caps: BTreeMap<String, CapsMode>,
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl LoopbackConfig {
+    /// Capabilities the device of the virtual device must announce
+    pub fn caps_for(&self, vdevice_name: &str) -> CapsMode {
+        self.device_caps.get(vdevice_name).copied().unwrap_or(self.caps)
+    }
+}
+
+/// Capabilities announced by a loopback device
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CapsMode {
+    /// Output only until a producer writes, then capture only, some Chrome
+    /// versions only list such devices
+    #[default]
+    Exclusive,
+    /// Capture and output at all times, for the consumers confused by the
+    /// capabilities changing
+    All,
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl CapsMode {
+    /// Value of the `exclusive_caps` option of v4l2loopback
+    pub fn exclusive_caps(self) -> u8 {
+        match self {
+            CapsMode::Exclusive => 1,
+            CapsMode::All => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PowerConfig {
+    pub enabled: bool,
+    /// Seconds between reads of the power supplies
+    pub poll_secs: u64,
+    /// Scale down the pipelines output while the host runs on battery
+    pub reduce_quality_on_battery: bool,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self { enabled: true, poll_secs: 30, reduce_quality_on_battery: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CpuPressureConfig {
+    pub enabled: bool,
+    /// Host CPU utilization above which the quality is reduced
+    pub high_percent: f32,
+    /// Host CPU utilization below which the quality is restored
+    pub low_percent: f32,
+    /// Seconds between host CPU samples
+    pub sample_secs: u32,
+}
+
+impl Default for CpuPressureConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            high_percent: 85.0,
+            low_percent: 60.0,
+            sample_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RtspConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Virtual device names published over RTSP, all of them if empty
+    pub devices: Vec<String>,
+}
+
+impl Default for RtspConfig {
+    fn default() -> Self {
+        Self { enabled: false, port: 8554, devices: Vec::new() }
+    }
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl RtspConfig {
+    /// Whether the virtual device must be published over RTSP
+    pub fn is_enabled_for(&self, vdevice_name: &str) -> bool {
+        self.enabled && matches_device(&self.devices, vdevice_name)
+    }
+}
+
+/// Output backend of a camera
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutputBackend {
+    /// MPEG-TS over SRT, e.g. `srt://:7001` to listen or
+    /// `srt://mixer:7001` to push
+    Srt { uri: String },
+    /// NDI source, named after the virtual device if no name is given
+    Ndi {
+        #[serde(default)]
+        ndi_name: Option<String>,
+    },
+}
+
+impl OutputBackend {
+    /// Port of an SRT URI listening for the callers, e.g. `srt://:7001` or
+    /// `srt://0.0.0.0:7001?mode=listener`
+    pub fn srt_listener_port(&self) -> Option<&str> {
+        let OutputBackend::Srt { uri } = self else {
+            return None;
+        };
+        let rest = uri.strip_prefix("srt://")?;
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = authority.rsplit_once(':')?;
+
+        let listener = host.is_empty()
+            || query.split('&').any(|param| param == "mode=listener");
+        listener.then_some(port)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OutputConfig {
+    /// Virtual device names using the backend, all of them if empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub backend: OutputBackend,
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl OutputConfig {
+    /// Whether the virtual device must use this backend
+    pub fn is_enabled_for(&self, vdevice_name: &str) -> bool {
+        matches_device(&self.devices, vdevice_name)
+    }
+}
+
+/// Arrangement of the cameras of a composite device
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CompositeLayout {
+    /// Tiles of the same size
+    #[default]
+    Grid,
+    /// First camera full frame, the others as insets in the bottom corner
+    Pip,
+}
+
+/// Virtual device combining the cameras of several mobiles
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CompositeConfig {
+    /// Label of the virtual device
+    pub name: String,
+    /// Virtual device names of the cameras, e.g. `Pixel: back`, in the
+    /// order of the layout
+    pub sources: Vec<String>,
+    pub layout: CompositeLayout,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl Default for CompositeConfig {
+    fn default() -> Self {
+        Self {
+            name: "Composite".to_string(),
+            sources: Vec::new(),
+            layout: CompositeLayout::Grid,
+            width: 1280,
+            height: 720,
+            fps: 30,
+        }
+    }
+}
+
+/// Traces of the call setups, always logged as a breakdown of the stages
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TracingConfig {
+    /// Base URL of an OTLP/HTTP collector receiving the traces, e.g.
+    /// `http://localhost:4318`
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of the exported traces
+    pub service_name: String,
+    /// Milliseconds from the offer to the first frame of a camera above
+    /// which a warning is logged
+    pub first_frame_alert_ms: Option<u64>,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: "webcam-direct-linux".to_string(),
+            first_frame_alert_ms: None,
+        }
+    }
+}
+
+/// Self-test run while no camera streams
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SelfTestConfig {
+    /// Local times of the runs in the cron format, e.g. `30 3 * * *`,
+    /// disabled if not set
+    pub schedule: Option<String>,
+}
+
+/// Provider of ephemeral TURN credentials, requested at call setup
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TurnConfig {
+    /// REST endpoint answering in the coturn REST API format or with an
+    /// `ice_servers` list
+    pub url: String,
+    /// Environment variable with the bearer token of the endpoint, so no
+    /// secret is kept in the config file
+    #[serde(default)]
+    pub token_env: Option<String>,
+}
+
+/// Built-in SDP munger
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SdpMungerConfig {
+    /// Put H264 first in the video codecs of the offer
+    PreferH264,
+    /// Announce in the answer the maximum resolution the host receives
+    CapResolution { max_width: u32, max_height: u32 },
+}
+
+/// Use of a Bluetooth adapter whose controller can't advertise
+#[derive(
+    Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AdvertisingFallback {
+    /// Serve the GATT services without advertising, the mobiles find the
+    /// host with the pairing code
+    #[default]
+    GattOnly,
+    /// Fail the startup
+    Fail,
+}
+
+/// Whether the host manages the kernel modules and the loopback devices
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerMode {
+    /// Detected at startup
+    #[default]
+    Auto,
+    Host,
+    Container,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ContainerConfig {
+    pub mode: ContainerMode,
+    /// Loopback devices created on the host and passed to the container
+    pub devices: Vec<String>,
+}
+
+//an empty device list selects all the devices
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+fn matches_device(devices: &[String], vdevice_name: &str) -> bool {
+    devices.is_empty() || devices.iter().any(|name| name == vdevice_name)
+}
+
+impl AppConfig {
+    /// Path of the configuration file in the user config directory
+    pub fn default_path() -> Option<PathBuf> {
+        ProjectDirs::from("", "", "webcam-direct")
+            .map(|dirs| dirs.config_dir().join(CONFIG_FILE_NAME))
+    }
+
+    /// Loads the configuration from the default path
+    pub fn load() -> Result<Self> {
+        match Self::default_path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Loads the configuration from the given file, the defaults are used
+    /// if the file does not exist
+    pub fn load_from(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            info!("Config file {:?} not found, using defaults", path);
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let config: Self = serde_json::from_str(&content)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Rejects the settings that parse but can't work
+    pub fn validate(&self) -> Result<()> {
+        let pressure = &self.cpu_pressure;
+        if pressure.sample_secs == 0 {
+            return Err(anyhow!("cpu_pressure.sample_secs must be at least 1"));
+        }
+        //the quality would flip on every sample
+        if pressure.low_percent >= pressure.high_percent {
+            return Err(anyhow!(
+                "cpu_pressure.low_percent ({}) must be below high_percent ({})",
+                pressure.low_percent,
+                pressure.high_percent
+            ));
+        }
+
+        //a listener takes its port once, for a single device
+        let mut listeners = Vec::new();
+        for output in &self.outputs {
+            let Some(port) = output.backend.srt_listener_port() else {
+                continue;
+            };
+            if output.devices.len() != 1 {
+                return Err(anyhow!(
+                    "The SRT listener on port {} is shared by several \
+                     devices, list a single device in its output",
+                    port
+                ));
+            }
+            if listeners.contains(&port) {
+                return Err(anyhow!(
+                    "The SRT listener port {} is used by several outputs",
+                    port
+                ));
+            }
+            listeners.push(port);
+        }
+
+        Ok(())
+    }
+
+    /// Sets `key` in the config file at `path`, the other settings are
+    /// kept as written
+    pub fn write_setting(
+        path: &Path, key: &str, value: serde_json::Value,
+    ) -> Result<()> {
+        let mut settings = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(path)?)?
+        } else {
+            serde_json::Value::Object(Default::default())
+        };
+        let Some(object) = settings.as_object_mut() else {
+            return Err(anyhow!("Config file {:?} is not an object", path));
+        };
+        object.insert(key.to_string(), value);
+
+        //the file must still load
+        serde_json::from_value::<AppConfig>(settings.clone())?.validate()?;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&settings)?)?;
+
+        Ok(())
+    }
+
+    /// Settings changed in `other` that need the advertisement, the access
+    /// point or the video stack to be restarted
+    pub fn disruptive_changes(&self, other: &AppConfig) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+
+        if self.diagnostics_enabled != other.diagnostics_enabled {
+            changes.push("diagnostics_enabled");
+        }
+        if self.le_privacy != other.le_privacy {
+            changes.push("le_privacy");
+        }
+        if self.host_group != other.host_group {
+            changes.push("host_group");
+        }
+        if self.ble_adapters != other.ble_adapters {
+            changes.push("ble_adapters");
+        }
+        if self.ble_advertising_fallback != other.ble_advertising_fallback {
+            changes.push("ble_advertising_fallback");
+        }
+        if self.rtsp != other.rtsp {
+            changes.push("rtsp");
+        }
+        if self.outputs != other.outputs {
+            changes.push("outputs");
+        }
+        if self.container != other.container {
+            changes.push("container");
+        }
+        if self.working_hours != other.working_hours {
+            changes.push("working_hours");
+        }
+        if self.warm_standby != other.warm_standby {
+            changes.push("warm_standby");
+        }
+        if self.guest_sessions != other.guest_sessions {
+            changes.push("guest_sessions");
+        }
+        if self.stats_retention_days != other.stats_retention_days {
+            changes.push("stats_retention_days");
+        }
+        if self.power != other.power {
+            changes.push("power");
+        }
+        if self.hw_decoding != other.hw_decoding {
+            changes.push("hw_decoding");
+        }
+        if self.max_cameras != other.max_cameras {
+            changes.push("max_cameras");
+        }
+        if self.runtime_dir != other.runtime_dir {
+            changes.push("runtime_dir");
+        }
+        if self.local_hostname != other.local_hostname {
+            changes.push("local_hostname");
+        }
+        if self.suppress_captive_portal != other.suppress_captive_portal {
+            changes.push("suppress_captive_portal");
+        }
+        if self.rfkill_unblock != other.rfkill_unblock {
+            changes.push("rfkill_unblock");
+        }
+        if self.per_user != other.per_user {
+            changes.push("per_user");
+        }
+        if self.composites != other.composites {
+            changes.push("composites");
+        }
+        if self.tracing != other.tracing {
+            changes.push("tracing");
+        }
+        if self.self_test != other.self_test {
+            changes.push("self_test");
+        }
+
+        changes
+    }
+
+    /// This config with the settings of `other` that can be applied at
+    /// runtime
+    pub fn with_safe_changes(&self, other: &AppConfig) -> AppConfig {
+        AppConfig {
+            log_level: other.log_level,
+            pairing_window_secs: other.pairing_window_secs,
+            cpu_pressure: other.cpu_pressure.clone(),
+            turn: other.turn.clone(),
+            sdp_mungers: other.sdp_mungers.clone(),
+            bandwidth: other.bandwidth.clone(),
+            video_limits: other.video_limits.clone(),
+            post_processing: other.post_processing.clone(),
+            thumbnails: other.thumbnails.clone(),
+            recording: other.recording.clone(),
+            loopback: other.loopback.clone(),
+            session_firewall: other.session_firewall,
+            ..self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "wcd-config-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let path = Path::new("/nonexistent/webcam-direct/config.json");
+
+        assert_eq!(AppConfig::load_from(path).unwrap(), AppConfig::default());
+    }
+
+    #[test]
+    fn test_load_partial_file() {
+        let path = temp_config("partial", "{}");
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config, AppConfig::default());
+    }
+
+    #[test]
+    fn test_load_file() {
+        let path = temp_config("full", r#"{"diagnostics_enabled": false}"#);
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(!config.diagnostics_enabled);
+    }
+
+    #[test]
+    fn test_load_nested_partial_file() {
+        let path =
+            temp_config("nested", r#"{"cpu_pressure": {"high_percent": 90}}"#);
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.cpu_pressure.high_percent, 90.0);
+        assert_eq!(
+            config.cpu_pressure.low_percent,
+            CpuPressureConfig::default().low_percent
+        );
+    }
+
+    #[test]
+    fn test_rtsp_enabled_for_device() {
+        let mut rtsp = RtspConfig { enabled: true, ..Default::default() };
+        assert!(rtsp.is_enabled_for("Pixel: Back"));
+
+        rtsp.devices = vec!["Pixel: Front".to_string()];
+        assert!(!rtsp.is_enabled_for("Pixel: Back"));
+        assert!(rtsp.is_enabled_for("Pixel: Front"));
+
+        rtsp.enabled = false;
+        assert!(!rtsp.is_enabled_for("Pixel: Front"));
+    }
+
+    #[test]
+    fn test_load_post_processing() {
+        let path = temp_config(
+            "post-processing",
+            r#"{"post_processing": {
+                "default": {"denoise": "light"},
+                "devices": {"Pixel: back": {"sharpen": "strong"}}
+            }}"#,
+        );
+
+        let config = AppConfig::load_from(&path).unwrap().post_processing;
+        std::fs::remove_file(&path).unwrap();
+
+        let light = FilterPresets {
+            denoise: FilterPreset::Light,
+            sharpen: FilterPreset::Off,
+        };
+        assert_eq!(config.presets_for("iPhone: front"), light);
+        //a device with its own presets doesn't inherit the default ones
+        let strong = FilterPresets {
+            denoise: FilterPreset::Off,
+            sharpen: FilterPreset::Strong,
+        };
+        assert_eq!(config.presets_for("Pixel: back"), strong);
+    }
+
+    #[test]
+    fn test_load_outputs() {
+        let path = temp_config(
+            "outputs",
+            r#"{"outputs": [
+                {"backend": {"type": "srt", "uri": "srt://mixer:7001"}},
+                {"devices": ["Pixel: Back"], "backend": {"type": "ndi"}}
+            ]}"#,
+        );
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config.outputs[0].backend,
+            OutputBackend::Srt { uri: "srt://mixer:7001".to_string() }
+        );
+        assert!(config.outputs[0].is_enabled_for("Pixel: Front"));
+        assert_eq!(
+            config.outputs[1].backend,
+            OutputBackend::Ndi { ndi_name: None }
+        );
+        assert!(!config.outputs[1].is_enabled_for("Pixel: Front"));
+    }
+
+    #[test]
+    fn test_cpu_pressure_rejected() {
+        for (name, pressure) in [
+            ("cpu-zero", r#"{"cpu_pressure": {"sample_secs": 0}}"#),
+            (
+                "cpu-inverted",
+                r#"{"cpu_pressure": {"high_percent": 50, "low_percent": 60}}"#,
+            ),
+            (
+                "cpu-equal",
+                r#"{"cpu_pressure": {"high_percent": 70, "low_percent": 70}}"#,
+            ),
+        ] {
+            let path = temp_config(name, pressure);
+            let error = AppConfig::load_from(&path).unwrap_err();
+            std::fs::remove_file(&path).unwrap();
+            assert!(error.to_string().contains("cpu_pressure"));
+        }
+    }
+
+    #[test]
+    fn test_srt_listener_single_device() {
+        let shared = temp_config(
+            "srt-shared",
+            r#"{"outputs": [
+                {"backend": {"type": "srt", "uri": "srt://:7001"}}
+            ]}"#,
+        );
+        let error = AppConfig::load_from(&shared).unwrap_err();
+        std::fs::remove_file(&shared).unwrap();
+        assert!(error.to_string().contains("7001"));
+
+        let twice = temp_config(
+            "srt-twice",
+            r#"{"outputs": [
+                {"devices": ["Pixel: Back"], "backend":
+                    {"type": "srt", "uri": "srt://:7001"}},
+                {"devices": ["Pixel: Front"], "backend":
+                    {"type": "srt", "uri": "srt://0.0.0.0:7001?mode=listener"}}
+            ]}"#,
+        );
+        assert!(AppConfig::load_from(&twice).is_err());
+        std::fs::remove_file(&twice).unwrap();
+
+        let single = temp_config(
+            "srt-single",
+            r#"{"outputs": [
+                {"devices": ["Pixel: Back"], "backend":
+                    {"type": "srt", "uri": "srt://:7001"}},
+                {"devices": ["Pixel: Front"], "backend":
+                    {"type": "srt", "uri": "srt://:7002"}}
+            ]}"#,
+        );
+        AppConfig::load_from(&single).unwrap();
+        std::fs::remove_file(&single).unwrap();
+    }
+
+    #[test]
+    fn test_write_setting_keeps_file() {
+        let path = temp_config("write", r#"{"pairing_window_secs": 60}"#);
+
+        let composites = vec![CompositeConfig {
+            sources: vec!["Pixel: back".to_string()],
+            layout: CompositeLayout::Pip,
+            ..Default::default()
+        }];
+        AppConfig::write_setting(
+            &path,
+            "composites",
+            serde_json::to_value(&composites).unwrap(),
+        )
+        .unwrap();
+        assert!(AppConfig::write_setting(
+            &path,
+            "composites",
+            serde_json::json!("grid")
+        )
+        .is_err());
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //the defaults are not written out
+        assert!(!content.contains("log_level"));
+        assert_eq!(config.pairing_window_secs, 60);
+        assert_eq!(config.composites, composites);
+    }
+
+    #[test]
+    fn test_load_sdp_mungers() {
+        let path = temp_config(
+            "mungers",
+            r#"{"sdp_mungers": [
+                {"type": "prefer_h264"},
+                {"type": "cap_resolution", "max_width": 1280,
+                 "max_height": 720}
+            ]}"#,
+        );
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            config.sdp_mungers,
+            vec![
+                SdpMungerConfig::PreferH264,
+                SdpMungerConfig::CapResolution {
+                    max_width: 1280,
+                    max_height: 720
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_load_invalid_file() {
+        let path = temp_config("invalid", "not json");
+
+        let config = AppConfig::load_from(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        let path = temp_config("log", r#"{"log_level": "debug"}"#);
+
+        let config = AppConfig::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.log_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn test_safe_and_disruptive_changes() {
+        let current = AppConfig::default();
+        let new = AppConfig {
+            log_level: LevelFilter::Info,
+            pairing_window_secs: 60,
+            le_privacy: true,
+            ..AppConfig::default()
+        };
+
+        assert_eq!(current.disruptive_changes(&new), vec!["le_privacy"]);
+
+        let live = current.with_safe_changes(&new);
+        assert_eq!(live.log_level, LevelFilter::Info);
+        assert_eq!(live.pairing_window_secs, 60);
+        assert!(!live.le_privacy);
+        assert!(live.disruptive_changes(&current).is_empty());
+    }
+}
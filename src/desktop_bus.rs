@@ -0,0 +1,301 @@
+//! # Desktop D-Bus service.
+//! Serves the pairing payload on the system bus, so the desktop front-ends
+//! show a scannable pairing code without encoding it themselves, and the
+//! privacy switch for the front-ends, the CLI and the hotkeys. The failed
+//! self-tests are signaled, so the front-ends notify the user. The bus
+//! policy in `data/io.github.gamilr.WebcamDirect.conf` limits the callers,
+//! since the payload carries the pairing token and the AP credentials.
+
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use dbus::{
+    channel::{MatchingReceiver, Sender},
+    message::MatchRule,
+    Message, MethodErr,
+};
+use dbus_crossroads::Crossroads;
+use dbus_tokio::connection;
+use log::{error, info};
+use serde::Serialize;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    app_data::SelfTestRun,
+    ble::{
+        comm_types::{ApCredentials, HostProvInfo},
+        server::mobile_comm::PairingMode,
+    },
+    error::Result,
+    privacy_switch::PrivacySwitch,
+    thumbnails::Thumbnails,
+};
+
+pub const BUS_NAME: &str = "io.github.gamilr.WebcamDirect";
+pub const OBJECT_PATH: &str = "/io/github/gamilr/WebcamDirect";
+pub const INTERFACE: &str = "io.github.gamilr.WebcamDirect1";
+const PAIRING_CLOSED: &str = "io.github.gamilr.WebcamDirect1.PairingClosed";
+const NO_THUMBNAIL: &str = "io.github.gamilr.WebcamDirect1.NoThumbnail";
+
+/// Pixels per module of the rendered code
+const QR_SCALE: usize = 8;
+
+/// Light modules around the code, required by the scanners
+const QR_QUIET_ZONE: usize = 4;
+
+/// Pairing data encoded in the code, as JSON
+#[derive(Debug, Serialize)]
+pub struct PairingPayload<'a> {
+    pub host_id: &'a str,
+    pub host_name: &'a str,
+    pub pairing_token: &'a str,
+    pub ap_creds: Option<&'a ApCredentials>,
+}
+
+/// Pairing code of the host, only available while the window is open
+pub struct PairingCode {
+    host_id: String,
+    host_name: String,
+    pairing_mode: Arc<PairingMode>,
+}
+
+impl PairingCode {
+    pub fn new(
+        host_info: &HostProvInfo, pairing_mode: Arc<PairingMode>,
+    ) -> Self {
+        Self {
+            host_id: host_info.id.clone(),
+            host_name: host_info.name.clone(),
+            pairing_mode,
+        }
+    }
+
+    /// The payload and the code rendered as PNG
+    fn get(&self) -> Result<(String, Vec<u8>)> {
+        if !self.pairing_mode.is_active() {
+            return Err(anyhow!("The pairing window is closed"));
+        }
+
+        let payload = serde_json::to_string(&PairingPayload {
+            host_id: &self.host_id,
+            host_name: &self.host_name,
+            pairing_token: self.pairing_mode.token(),
+            ap_creds: self.pairing_mode.ap_creds(),
+        })?;
+        let png = render_png(&payload)?;
+
+        Ok((payload, png))
+    }
+}
+
+//state served by the bus object
+struct BusObject {
+    pairing_code: PairingCode,
+    privacy: PrivacySwitch,
+    thumbnails: Thumbnails,
+}
+
+/// Service on the system bus, the bus name is released on drop
+pub struct DesktopBus {
+    connection_task: JoinHandle<()>,
+    self_test_task: JoinHandle<()>,
+}
+
+impl DesktopBus {
+    pub async fn serve(
+        pairing_code: PairingCode, privacy: PrivacySwitch,
+        thumbnails: Thumbnails,
+        mut self_tests: watch::Receiver<Option<SelfTestRun>>,
+    ) -> Result<Self> {
+        let (resource, conn) = connection::new_system_sync()?;
+
+        let connection_task = tokio::spawn(async {
+            let e = resource.await;
+            error!("Lost the connection to the system bus: {:?}", e);
+        });
+
+        if let Err(e) = conn.request_name(BUS_NAME, false, true, true).await {
+            connection_task.abort();
+            return Err(e.into());
+        }
+
+        let mut cr = Crossroads::new();
+        let iface = cr.register(INTERFACE, |b| {
+            b.method(
+                "GetPairingCode",
+                (),
+                ("payload", "png"),
+                |_, object: &mut BusObject, (): ()| {
+                    object.pairing_code.get().map_err(|e| {
+                        MethodErr::from((PAIRING_CLOSED, e.to_string()))
+                    })
+                },
+            );
+            b.method(
+                "SetPrivacy",
+                ("enabled",),
+                (),
+                |_, object: &mut BusObject, (enabled,): (bool,)| {
+                    object
+                        .privacy
+                        .set(enabled)
+                        .map_err(|e| MethodErr::failed(&e))
+                },
+            );
+            b.method(
+                "GetPrivacy",
+                (),
+                ("enabled",),
+                |_, object: &mut BusObject, (): ()| {
+                    Ok((object.privacy.is_enabled(),))
+                },
+            );
+            b.method(
+                "TogglePrivacy",
+                (),
+                ("enabled",),
+                |_, object: &mut BusObject, (): ()| {
+                    let enabled = object
+                        .privacy
+                        .toggle()
+                        .map_err(|e| MethodErr::failed(&e))?;
+                    Ok((enabled,))
+                },
+            );
+            b.method(
+                "ListThumbnails",
+                (),
+                ("cameras",),
+                |_, object: &mut BusObject, (): ()| {
+                    Ok((object.thumbnails.cameras(),))
+                },
+            );
+            b.method(
+                "GetThumbnail",
+                ("camera",),
+                ("jpeg",),
+                |_, object: &mut BusObject, (camera,): (String,)| {
+                    object
+                        .thumbnails
+                        .get(&camera)
+                        .map(|jpeg| (jpeg,))
+                        .ok_or_else(|| {
+                            MethodErr::from((
+                                NO_THUMBNAIL,
+                                format!("No thumbnail of {}", camera),
+                            ))
+                        })
+                },
+            );
+        });
+        cr.insert(
+            OBJECT_PATH,
+            &[iface],
+            BusObject { pairing_code, privacy, thumbnails },
+        );
+
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                if let Err(()) = cr.handle_message(msg, conn) {
+                    error!("Failed to handle a D-Bus method call");
+                }
+                true
+            }),
+        );
+
+        //`SelfTestFailed(at, steps)` with the failed steps as JSON
+        let self_test_task = tokio::spawn(async move {
+            while self_tests.changed().await.is_ok() {
+                let Some(run) = self_tests.borrow_and_update().clone() else {
+                    continue;
+                };
+                if run.passed() {
+                    continue;
+                }
+
+                let failed: Vec<_> =
+                    run.steps.iter().filter(|step| !step.passed).collect();
+                let steps = serde_json::to_string(&failed).unwrap_or_default();
+                let sent = Message::new_signal(
+                    OBJECT_PATH,
+                    INTERFACE,
+                    "SelfTestFailed",
+                )
+                .ok()
+                .and_then(|signal| {
+                    conn.send(signal.append2(&run.at, steps)).ok()
+                });
+                if sent.is_none() {
+                    error!("Failed to signal the self-test failure");
+                }
+            }
+        });
+
+        info!("Serving {} on the system bus", BUS_NAME);
+
+        Ok(Self { connection_task, self_test_task })
+    }
+}
+
+impl Drop for DesktopBus {
+    fn drop(&mut self) {
+        self.connection_task.abort();
+        self.self_test_task.abort();
+    }
+}
+
+/// Grayscale PNG of the QR code of the payload
+pub fn render_png(payload: &str) -> Result<Vec<u8>> {
+    let code = qrcode::QrCode::new(payload.as_bytes())?;
+    let modules = code.width();
+    let colors = code.to_colors();
+
+    let side = (modules + 2 * QR_QUIET_ZONE) * QR_SCALE;
+    let mut pixels = vec![u8::MAX; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != qrcode::Color::Dark {
+            continue;
+        }
+
+        let x = (i % modules + QR_QUIET_ZONE) * QR_SCALE;
+        let y = (i / modules + QR_QUIET_ZONE) * QR_SCALE;
+        for row in y..y + QR_SCALE {
+            pixels[row * side + x..row * side + x + QR_SCALE].fill(0);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_png() {
+        let payload = serde_json::to_string(&PairingPayload {
+            host_id: "7c9e6679-7425-40de-944b-e07fc1f90ae7",
+            host_name: "desk-1",
+            pairing_token: "f47ac10b-58cc-4372-a567-0e02b2c3d479",
+            ap_creds: None,
+        })
+        .unwrap();
+        let png = render_png(&payload).unwrap();
+
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        let modules = qrcode::QrCode::new(payload.as_bytes()).unwrap().width();
+
+        assert_eq!(info.width as usize, (modules + 8) * QR_SCALE);
+        assert_eq!(info.width, info.height);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+    }
+}
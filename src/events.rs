@@ -0,0 +1,159 @@
+//! # Host events and commands.
+//! Frontends linking the library, e.g. egui or GTK GUIs running the host in
+//! their process, follow the mobiles and the cameras through a stream of
+//! events and drive the host through a handle, without the D-Bus service.
+//! The events are only added to, so the frontends keep building against
+//! newer versions.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::{broadcast, Notify};
+
+use crate::{
+    config::AppConfig, error::Result, live_config::ConfigWatcher,
+    privacy_switch::PrivacySwitch,
+};
+
+/// Events kept for a subscriber lagging behind, the older ones are skipped
+const CAPACITY: usize = 64;
+
+/// Change in the mobiles or the cameras served by the host
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[non_exhaustive]
+pub enum Event {
+    /// A registered mobile sent its offer
+    MobileConnected { mobile_id: String, name: String },
+    /// The mobile left, its cameras are stopped
+    MobileDisconnected { mobile_id: String },
+    /// The loopback device of a virtual device is ready, created on the
+    /// host or leased
+    DeviceCreated { name: String, device: String },
+    /// A camera of the mobile streams to its device
+    StreamStarted { mobile_id: String, camera: String },
+    /// An operation of the mobile failed, as reported to the mobile
+    Error { mobile_id: String, message: String },
+}
+
+/// Sends the events of the host to every subscriber
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self { sender: broadcast::channel(CAPACITY).0 }
+    }
+}
+
+impl EventBus {
+    /// Events from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Dropped while there is no subscriber
+    pub fn emit(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Commands of the running host, for the frontends
+#[derive(Clone)]
+pub struct HostHandle {
+    events: EventBus,
+    privacy: PrivacySwitch,
+    config_watcher: Arc<ConfigWatcher>,
+    restart: Arc<Notify>,
+}
+
+impl HostHandle {
+    pub fn new(
+        events: EventBus, privacy: PrivacySwitch,
+        config_watcher: Arc<ConfigWatcher>,
+    ) -> Self {
+        Self { events, privacy, config_watcher, restart: Arc::default() }
+    }
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Shows the "Camera disabled" frame on every virtual camera, or the
+    /// mobile cameras again
+    pub fn set_privacy(&self, enabled: bool) -> Result<()> {
+        self.privacy.set(enabled)
+    }
+
+    /// Flips the privacy switch and returns the new state
+    pub fn toggle_privacy(&self) -> Result<bool> {
+        self.privacy.toggle()
+    }
+
+    pub fn is_privacy_enabled(&self) -> bool {
+        self.privacy.is_enabled()
+    }
+
+    /// Applies the safe changes of the config right away, returns the
+    /// disruptive ones left for `apply_pending_config`
+    pub fn update_config(&self, config: AppConfig) -> Vec<&'static str> {
+        self.config_watcher.update(config)
+    }
+
+    /// Asks the host to restart its services with the pending config, like
+    /// `reload --apply-disruptive`
+    pub fn apply_pending_config(&self) {
+        self.restart.notify_one();
+    }
+
+    /// Waits for `apply_pending_config`, the request made meanwhile is kept
+    pub async fn restart_requested(&self) {
+        self.restart.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handle_commands_and_events() {
+        let (config_watcher, live_config) =
+            ConfigWatcher::new(AppConfig::default());
+        let events = EventBus::default();
+        //emitted without subscribers
+        events.emit(Event::MobileDisconnected { mobile_id: "m1".into() });
+
+        let handle = HostHandle::new(
+            events.clone(),
+            PrivacySwitch::new(),
+            config_watcher,
+        );
+        let mut subscriber = handle.subscribe();
+        let connected = Event::MobileConnected {
+            mobile_id: "m1".into(),
+            name: "Pixel".into(),
+        };
+        events.emit(connected.clone());
+        assert_eq!(subscriber.recv().await.unwrap(), connected);
+
+        assert!(handle.toggle_privacy().unwrap());
+        assert!(handle.is_privacy_enabled());
+
+        let config = AppConfig {
+            pairing_window_secs: 30,
+            ..live_config.borrow().clone()
+        };
+        assert!(handle.update_config(config).is_empty());
+        assert_eq!(live_config.borrow().pairing_window_secs, 30);
+
+        //requested before the host waits for it
+        handle.apply_pending_config();
+        handle.restart_requested().await;
+    }
+}
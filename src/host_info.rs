@@ -0,0 +1,104 @@
+//! # Host info changes.
+//! The host name is announced in the provisioning info. It's polled while
+//! the host runs, so a rename reaches the connected mobiles without them
+//! reconnecting or pairing again.
+
+use std::time::Duration;
+
+use log::{debug, info};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::app_data::HostInfo;
+
+/// Period of the host name check
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Name of the host, None if it's not valid UTF-8
+pub fn read_host_name() -> Option<String> {
+    hostname::get().ok()?.into_string().ok()
+}
+
+/// Polls the host name, stopped on drop
+pub struct HostInfoMonitor {
+    changes: watch::Sender<HostInfo>,
+    poll_task: JoinHandle<()>,
+}
+
+impl HostInfoMonitor {
+    /// Follows the name given by `read_name` every `period`, the connection
+    /// type is kept until the services restart
+    pub fn spawn(
+        host_info: HostInfo, period: Duration,
+        read_name: impl Fn() -> Option<String> + Send + 'static,
+    ) -> Self {
+        let (changes, _) = watch::channel(host_info);
+
+        let tx = changes.clone();
+        let poll_task = tokio::spawn(async move {
+            let mut poll = tokio::time::interval(period);
+            loop {
+                poll.tick().await;
+                let Some(name) = read_name() else {
+                    debug!("Host name not readable");
+                    continue;
+                };
+                tx.send_if_modified(|current| {
+                    if current.name == name {
+                        return false;
+                    }
+                    info!("Host renamed from {} to {}", current.name, name);
+                    current.name = name;
+                    true
+                });
+            }
+        });
+
+        Self { changes, poll_task }
+    }
+
+    /// Receiver of the host info changes
+    pub fn subscribe(&self) -> watch::Receiver<HostInfo> {
+        self.changes.subscribe()
+    }
+}
+
+impl Drop for HostInfoMonitor {
+    fn drop(&mut self) {
+        self.poll_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::app_data::ConnectionType;
+
+    #[tokio::test]
+    async fn test_rename_notified() {
+        let name = Arc::new(Mutex::new("MyPC".to_string()));
+        let host_info = HostInfo {
+            name: "MyPC".to_string(),
+            connection_type: ConnectionType::AP,
+        };
+
+        let read_name = name.clone();
+        let monitor = HostInfoMonitor::spawn(
+            host_info,
+            Duration::from_millis(5),
+            move || Some(read_name.lock().unwrap().clone()),
+        );
+        let mut changes = monitor.subscribe();
+
+        *name.lock().unwrap() = "Studio".to_string();
+        tokio::time::timeout(Duration::from_secs(1), changes.changed())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let host_info = changes.borrow_and_update().clone();
+        assert_eq!(host_info.name, "Studio");
+        assert_eq!(host_info.connection_type, ConnectionType::AP);
+    }
+}
@@ -0,0 +1,33 @@
+#[cfg(feature = "ap")]
+pub mod access_point_ctl;
+pub mod app_data;
+pub mod audit;
+pub mod ble;
+pub mod call_trace;
+pub mod cli;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "desktop")]
+pub mod desktop_bus;
+pub mod error;
+pub mod events;
+pub mod host_info;
+pub mod link_test;
+pub mod live_config;
+pub mod log_file;
+pub mod metrics;
+pub mod power_state;
+pub mod privacy_switch;
+pub mod retry;
+pub mod rfkill;
+pub mod self_test;
+pub mod shutdown;
+#[cfg(feature = "webrtc")]
+pub mod sleep_inhibitor;
+pub mod startup;
+pub mod supervisor;
+#[cfg(any(feature = "webrtc", feature = "desktop"))]
+pub mod thumbnails;
+pub mod user_sessions;
+#[cfg(feature = "webrtc")]
+pub mod vdevice_builder;
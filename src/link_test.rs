@@ -0,0 +1,289 @@
+//! # LAN link test.
+//! Requested over BLE before the call so the mobile can pick its initial
+//! resolution from measurements. The mobile sends numbered UDP packets
+//! stamped with its send time to the host, the host echoes them back, so
+//! the mobile can measure the round trip, and measures the received
+//! bandwidth, loss and jitter. The port is only opened on the access point
+//! address, only the source of the first packet is measured and echoed, and
+//! the echoes are capped so the host can't be used as a reflector.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use log::{debug, error, info};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    ble::comm_types::{LinkTestReport, LinkTestState},
+    error::Result,
+};
+
+/// Sequence number (u32) and send time in microseconds (u64), big endian
+pub const HEADER_LEN: usize = 12;
+
+/// Longest test a mobile can request
+pub const MAX_DURATION: Duration = Duration::from_secs(10);
+
+/// Echoed bytes per second, about 20 Mbit/s
+const MAX_ECHO_RATE: u64 = 2_500_000;
+
+/// Whether a packet of `len` bytes can be echoed after `echoed` bytes
+/// `elapsed` into the test, a tenth of a second is allowed upfront
+fn echo_allowed(echoed: u64, len: usize, elapsed: Duration) -> bool {
+    let budget =
+        MAX_ECHO_RATE * elapsed.as_millis() as u64 / 1000 + MAX_ECHO_RATE / 10;
+    echoed + len as u64 <= budget
+}
+
+/// Receive statistics of a test
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    packets: u64,
+    bytes: u64,
+    max_seq: Option<u32>,
+    first_arrival_us: Option<i64>,
+    last_arrival_us: i64,
+    last_transit_us: Option<i64>,
+    //interarrival jitter as in RFC 3550
+    jitter_us: f64,
+}
+
+impl LinkStats {
+    /// Accounts a packet received at `arrival_us`, false if it has no header
+    pub fn on_packet(&mut self, packet: &[u8], arrival_us: i64) -> bool {
+        if packet.len() < HEADER_LEN {
+            return false;
+        }
+
+        let seq =
+            u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]);
+        let mut sent_us = [0; 8];
+        sent_us.copy_from_slice(&packet[4..HEADER_LEN]);
+        let sent_us = u64::from_be_bytes(sent_us) as i64;
+
+        let transit_us = arrival_us - sent_us;
+        if let Some(last_transit_us) = self.last_transit_us {
+            let delta = (transit_us - last_transit_us).abs() as f64;
+            self.jitter_us += (delta - self.jitter_us) / 16.0;
+        }
+        self.last_transit_us = Some(transit_us);
+
+        self.packets += 1;
+        self.bytes += packet.len() as u64;
+        self.max_seq = Some(self.max_seq.map_or(seq, |max| max.max(seq)));
+        self.first_arrival_us.get_or_insert(arrival_us);
+        self.last_arrival_us = arrival_us;
+
+        true
+    }
+
+    pub fn report(&self, state: LinkTestState, port: u16) -> LinkTestReport {
+        //sequence numbers start at 0
+        let expected = self.max_seq.map_or(0, |max| max as u64 + 1);
+
+        let span_us =
+            self.last_arrival_us - self.first_arrival_us.unwrap_or_default();
+        let bandwidth_kbps = if span_us > 0 {
+            (self.bytes * 8 * 1000 / span_us as u64) as u32
+        } else {
+            0
+        };
+
+        LinkTestReport {
+            state,
+            port,
+            packets: self.packets,
+            lost: expected.saturating_sub(self.packets),
+            bandwidth_kbps,
+            jitter_ms: (self.jitter_us / 1000.0) as f32,
+        }
+    }
+}
+
+/// Keeps a port of the host open while it lives
+pub type PortRule = Box<dyn Send + Sync>;
+
+/// Running or finished link test of a mobile
+pub struct LinkTest {
+    report: watch::Receiver<LinkTestReport>,
+    task: JoinHandle<()>,
+    //the port stays open for the whole test
+    port_rule: Option<PortRule>,
+}
+
+impl LinkTest {
+    /// Opens a UDP port on `addr` and echoes the packets of the mobile for
+    /// the given duration
+    pub async fn start(addr: Ipv4Addr, duration: Duration) -> Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::from((addr, 0))).await?;
+        let port = socket.local_addr()?.port();
+        let duration = duration.min(MAX_DURATION);
+
+        let (report_tx, report) = watch::channel(
+            LinkStats::default().report(LinkTestState::Running, port),
+        );
+
+        info!("Link test on UDP port {} for {:?}", port, duration);
+
+        let task = tokio::spawn(async move {
+            let mut stats = LinkStats::default();
+            let mut buf = vec![0; 65536];
+            let mut mobile = None;
+            let mut echoed = 0;
+            let started_at = Instant::now();
+            let deadline = started_at + duration;
+
+            while let Ok(recv) =
+                timeout_at(deadline, socket.recv_from(&mut buf)).await
+            {
+                let (len, peer) = match recv {
+                    Ok(recv) => recv,
+                    Err(e) => {
+                        error!("Link test receive failed: {:?}", e);
+                        break;
+                    }
+                };
+
+                //the test belongs to the source of the first packet
+                if *mobile.get_or_insert(peer) != peer {
+                    debug!("Link test packet from another source {}", peer);
+                    continue;
+                }
+
+                let elapsed = started_at.elapsed();
+                if !stats.on_packet(&buf[..len], elapsed.as_micros() as i64) {
+                    debug!("Link test packet without header from {}", peer);
+                    continue;
+                }
+
+                if !echo_allowed(echoed, len, elapsed) {
+                    continue;
+                }
+                echoed += len as u64;
+                if let Err(e) = socket.send_to(&buf[..len], peer).await {
+                    debug!("Link test echo to {} failed: {:?}", peer, e);
+                }
+            }
+
+            let report = stats.report(LinkTestState::Done, port);
+            info!("Link test done: {:?}", report);
+            report_tx.send_replace(report);
+        });
+
+        Ok(Self { report, task, port_rule: None })
+    }
+
+    /// UDP port of the test
+    pub fn port(&self) -> u16 {
+        self.report.borrow().port
+    }
+
+    /// Keeps `port_rule` until the test is dropped
+    pub fn hold_port_rule(&mut self, port_rule: PortRule) {
+        self.port_rule = Some(port_rule);
+    }
+
+    pub fn report(&self) -> LinkTestReport {
+        self.report.borrow().clone()
+    }
+}
+
+impl Drop for LinkTest {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(seq: u32, sent_us: u64, len: usize) -> Vec<u8> {
+        let mut packet = vec![0; len];
+        packet[..4].copy_from_slice(&seq.to_be_bytes());
+        packet[4..HEADER_LEN].copy_from_slice(&sent_us.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_stats_bandwidth_and_loss() {
+        let mut stats = LinkStats::default();
+
+        //1000 bytes every 10ms, packet 2 lost
+        for seq in [0, 1, 3, 4] {
+            let at = seq as i64 * 10_000;
+            assert!(stats.on_packet(&packet(seq, at as u64, 1000), at));
+        }
+        assert!(!stats.on_packet(&[0; 4], 50_000));
+
+        let report = stats.report(LinkTestState::Done, 5000);
+        assert_eq!(report.packets, 4);
+        assert_eq!(report.lost, 1);
+        //4000 bytes in 40ms
+        assert_eq!(report.bandwidth_kbps, 800);
+        assert_eq!(report.jitter_ms, 0.0);
+    }
+
+    #[test]
+    fn test_stats_jitter() {
+        let mut stats = LinkStats::default();
+
+        stats.on_packet(&packet(0, 0, 100), 1_000);
+        //arrives 16ms late compared with the first packet
+        stats.on_packet(&packet(1, 10_000, 100), 27_000);
+
+        let report = stats.report(LinkTestState::Done, 5000);
+        assert_eq!(report.jitter_ms, 1.0);
+    }
+
+    #[test]
+    fn test_echo_rate_capped() {
+        //a tenth of a second upfront
+        assert!(echo_allowed(0, 250_000, Duration::ZERO));
+        assert!(!echo_allowed(250_000, 1, Duration::ZERO));
+        //then the rate
+        assert!(echo_allowed(250_000, 2_500_000, Duration::from_secs(1)));
+        assert!(!echo_allowed(2_750_000, 1, Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_link_test_echo() {
+        let link_test =
+            LinkTest::start(Ipv4Addr::LOCALHOST, Duration::from_millis(300))
+                .await
+                .unwrap();
+        let report = link_test.report();
+        assert_eq!(report.state, LinkTestState::Running);
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(("127.0.0.1", report.port)).await.unwrap();
+        socket.send(&packet(0, 0, 64)).await.unwrap();
+
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], packet(0, 0, 64).as_slice());
+
+        //another source is neither measured nor echoed
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        other.connect(("127.0.0.1", report.port)).await.unwrap();
+        other.send(&packet(1, 0, 64)).await.unwrap();
+        assert!(tokio::time::timeout(
+            Duration::from_millis(100),
+            other.recv(&mut buf)
+        )
+        .await
+        .is_err());
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let report = link_test.report();
+        assert_eq!(report.state, LinkTestState::Done);
+        assert_eq!(report.packets, 1);
+    }
+}
@@ -0,0 +1,252 @@
+//! # Live configuration.
+//! The config file is watched and the safe changes (log level, quality
+//! presets, pairing window) are applied right away. Changes that need the
+//! advertisement, the access point or the video stack to be restarted are
+//! kept pending until `webcam-direct-linux reload --apply-disruptive` asks
+//! the running process to apply them.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use env_logger::Target;
+use inotify::{Inotify, WatchMask};
+use log::{error, info, warn, LevelFilter};
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::{AppConfig, LogFileConfig},
+    error::Result,
+    log_file::{RotatingFile, StderrAndFile},
+};
+
+/// Pid of the running process, used to deliver the reload requests
+const PID_FILE: &str = "/run/webcam-direct.pid";
+
+/// Config in use, updated on the safe changes
+pub type LiveConfig = watch::Receiver<AppConfig>;
+
+/// Inits the logger, RUST_LOG takes precedence over the configured level.
+/// With a log file the records are written to stderr and to the file.
+pub fn init_logger(level: LevelFilter, log_file: Option<&LogFileConfig>) {
+    let from_env = std::env::var_os("RUST_LOG").is_some();
+
+    let mut builder = if from_env {
+        env_logger::Builder::from_default_env()
+    } else {
+        //everything passes the logger filter, the max level does the
+        //filtering
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(LevelFilter::Trace);
+        builder
+    };
+
+    if let Some(log_file) = log_file {
+        match RotatingFile::open(log_file) {
+            Ok(file) => {
+                builder.target(Target::Pipe(Box::new(StderrAndFile(file))));
+            }
+            //the logger is not up yet
+            Err(e) => eprintln!(
+                "Logging to stderr only, {:?} not opened: {:?}",
+                log_file.path, e
+            ),
+        }
+    }
+
+    builder.init();
+
+    if !from_env {
+        log::set_max_level(level);
+    }
+}
+
+fn apply_log_level(level: LevelFilter) {
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(level);
+    }
+}
+
+pub struct ConfigWatcher {
+    live: watch::Sender<AppConfig>,
+    //loaded config with changes that need a restart
+    pending: Mutex<Option<AppConfig>>,
+}
+
+impl ConfigWatcher {
+    pub fn new(config: AppConfig) -> (Arc<Self>, LiveConfig) {
+        let (live, live_config) = watch::channel(config);
+
+        (Arc::new(Self { live, pending: Mutex::new(None) }), live_config)
+    }
+
+    /// Applies the safe changes of the new config, returns the disruptive
+    /// changes left pending
+    pub fn update(&self, new: AppConfig) -> Vec<&'static str> {
+        let current = self.live.borrow().clone();
+
+        let live = current.with_safe_changes(&new);
+        if live != current {
+            apply_log_level(live.log_level);
+            self.live.send_replace(live);
+            info!("Configuration reloaded");
+        }
+
+        let changes = current.disruptive_changes(&new);
+        let pending = if changes.is_empty() {
+            None
+        } else {
+            warn!(
+                "Changes to {:?} need a restart, apply them with \
+                 `webcam-direct-linux reload --apply-disruptive`",
+                changes
+            );
+            Some(new)
+        };
+
+        if let Ok(mut current_pending) = self.pending.lock() {
+            *current_pending = pending;
+        }
+
+        changes
+    }
+
+    /// Makes the pending config the live one, false if nothing is pending
+    pub fn apply_pending(&self) -> bool {
+        let pending = self.pending.lock().ok().and_then(|mut p| p.take());
+
+        match pending {
+            Some(config) => {
+                apply_log_level(config.log_level);
+                self.live.send_replace(config);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Reloads the config every time the file is written. The parent
+    /// directory is watched since editors usually replace the file.
+    pub fn watch(self: Arc<Self>, path: PathBuf) -> Result<JoinHandle<()>> {
+        let dir = path
+            .parent()
+            .ok_or_else(|| anyhow!("Invalid config path {:?}", path))?;
+        let file_name: OsString = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid config path {:?}", path))?
+            .into();
+
+        let inotify = Inotify::init()?;
+        inotify.watches().add(
+            dir,
+            WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+        )?;
+
+        let mut events = inotify.into_event_stream([0; 1024])?;
+
+        info!("Watching {:?} for changes", path);
+
+        Ok(tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        error!("Config watch failed, error: {:?}", e);
+                        break;
+                    }
+                };
+
+                if event.name.as_deref() != Some(file_name.as_os_str()) {
+                    continue;
+                }
+
+                match AppConfig::load_from(&path) {
+                    Ok(config) => {
+                        self.update(config);
+                    }
+                    Err(e) => {
+                        warn!("Config not reloaded, error: {:?}", e);
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Pid file of the running process, removed on drop
+pub struct PidFile;
+
+impl PidFile {
+    pub fn create() -> Result<Self> {
+        std::fs::write(PID_FILE, std::process::id().to_string())?;
+        Ok(Self)
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(PID_FILE) {
+            error!("Failed to remove {}: {:?}", PID_FILE, e);
+        }
+    }
+}
+
+/// Asks the running process to apply the pending config changes
+pub fn request_disruptive_reload() -> Result<()> {
+    if !Path::new(PID_FILE).exists() {
+        return Err(anyhow!("webcam-direct-linux is not running"));
+    }
+
+    let pid = std::fs::read_to_string(PID_FILE)?;
+
+    let status = Command::new("kill").arg("-HUP").arg(pid.trim()).status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Failed to signal the process {}", pid.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_safe_changes() {
+        let (watcher, live_config) = ConfigWatcher::new(AppConfig::default());
+
+        let changes = watcher.update(AppConfig {
+            pairing_window_secs: 60,
+            ..AppConfig::default()
+        });
+
+        assert!(changes.is_empty());
+        assert_eq!(live_config.borrow().pairing_window_secs, 60);
+        assert!(!watcher.apply_pending());
+    }
+
+    #[test]
+    fn test_update_disruptive_changes() {
+        let (watcher, live_config) = ConfigWatcher::new(AppConfig::default());
+
+        let changes = watcher.update(AppConfig {
+            pairing_window_secs: 60,
+            le_privacy: true,
+            ..AppConfig::default()
+        });
+
+        assert_eq!(changes, vec!["le_privacy"]);
+        assert_eq!(live_config.borrow().pairing_window_secs, 60);
+        assert!(!live_config.borrow().le_privacy);
+
+        assert!(watcher.apply_pending());
+        assert!(live_config.borrow().le_privacy);
+        assert!(!watcher.apply_pending());
+    }
+}
@@ -0,0 +1,152 @@
+//! # Log file.
+//! The log records are written to stderr and to a file rotated by size and
+//! optionally by age, for setups without a journal to keep the logs of a
+//! detached host.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::config::LogFileConfig;
+
+/// File renamed to `<path>.1` once it is full or old, the older files
+/// shift up to `<path>.<keep>`
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = open_append(&config.path)?;
+
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_size_mb * 1024 * 1024,
+            max_age: config
+                .max_age_hours
+                .map(|hours| Duration::from_secs(hours * 3600)),
+            keep: config.keep,
+            size: file.metadata()?.len(),
+            file,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+
+        self.size + len as u64 > self.max_bytes
+            || self.max_age.is_some_and(|age| self.opened_at.elapsed() >= age)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        //without files to keep the log starts over
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Writes the log records to stderr and to the log file
+pub struct StderrAndFile(pub RotatingFile);
+
+impl Write for StderrAndFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        //a full disk must not hide the records on stderr
+        let _ = io::stderr().write_all(buf);
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _ = io::stderr().flush();
+        self.0.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = std::env::temp_dir()
+            .join(format!("wcd-log-{}", std::process::id()));
+        let config = LogFileConfig {
+            path: dir.join("webcam-direct.log"),
+            max_size_mb: 0,
+            max_age_hours: None,
+            keep: 2,
+        };
+
+        let mut file = RotatingFile::open(&config).unwrap();
+        for record in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(record.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert_eq!(read(&config.path), "fourth\n");
+        assert_eq!(read(&rotated_path(&config.path, 1)), "third\n");
+        assert_eq!(read(&rotated_path(&config.path, 2)), "second\n");
+        assert!(!rotated_path(&config.path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
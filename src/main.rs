@@ -1,136 +1,640 @@
-mod access_point_ctl;
-mod app_data;
-mod ble;
-mod error;
-mod vdevice_builder;
+#[cfg(feature = "ap")]
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::signal::{
+    self,
+    unix::{Signal, SignalKind},
+};
 
-use tokio::signal;
+use clap::Parser;
+use log::{error, info, warn};
+use tokio::io::AsyncBufReadExt;
 
-use access_point_ctl::{
+#[cfg(feature = "ap")]
+use webcam_direct_linux::access_point_ctl::{
+    captive_portal::{CaptivePortal, PROBE_HOSTS},
     dhcp_server::{DhcpIpRange, DnsmasqProc},
-    iw_link::{wdev_drv, IwLink},
+    iw_link::{
+        wdev_drv::{self, WirelessDriver},
+        IwLink,
+    },
+    link_stats::LinkMonitor,
     process_hdl::ProcessHdl,
     wifi_manager::{
-        FileHdl, HostapdProc, WifiCredentials, WifiManager, WpaCtl,
+        prepare_runtime_dir, FileHdl, HostapdProc, WifiCredentials,
+        WifiManager, WpaCtl,
     },
     AccessPointCtl, ApController,
 };
-use app_data::{AppData, ConnectionType, DiskBasedDb, HostInfo};
-use error::Result;
-
-use ble::{
+#[cfg(feature = "ap")]
+use webcam_direct_linux::app_data::ApBand;
+use webcam_direct_linux::app_data::{
+    AppData, ConnectionType, DiskBasedDb, HostInfo,
+};
+use webcam_direct_linux::audit::Audit;
+use webcam_direct_linux::call_trace::TraceExporter;
+use webcam_direct_linux::cli::{self, Cli, Command};
+use webcam_direct_linux::config::{AppConfig, LogFileConfig};
+use webcam_direct_linux::error::Result;
+use webcam_direct_linux::events::{EventBus, HostHandle};
+use webcam_direct_linux::host_info::{self, HostInfoMonitor};
+use webcam_direct_linux::live_config::{
+    init_logger, ConfigWatcher, LiveConfig, PidFile,
+};
+use webcam_direct_linux::metrics::Metrics;
+#[cfg(any(feature = "ap", feature = "ble"))]
+use webcam_direct_linux::retry;
+use webcam_direct_linux::rfkill;
+
+#[cfg(feature = "ble")]
+use tokio::sync::watch;
+#[cfg(feature = "ble")]
+use webcam_direct_linux::ble::comm_types::AdapterStatus;
+#[cfg(feature = "ble")]
+use webcam_direct_linux::ble::{
+    adapters::{self, AdapterPool},
     clients::{
         mobile_prop::MobilePropClient, provisioner::ProvisionerClient,
         sdp_exchanger::SdpExchangerClient,
     },
-    server::BleServer,
+    privacy::{BtMgmtCmd, LePrivacy},
 };
-use tokio::io::AsyncBufReadExt;
-
-use log::info;
-use vdevice_builder::VDeviceBuilder;
-
-use crate::ble::server::mobile_comm::{AppDataStore, MobileComm};
+use webcam_direct_linux::ble::{comm_types::ApCredentials, server::BleServer};
+
+use webcam_direct_linux::power_state::PowerMonitor;
+use webcam_direct_linux::privacy_switch::PrivacySwitch;
+use webcam_direct_linux::shutdown::{ShutdownSequence, ShutdownStage};
+#[cfg(all(feature = "webrtc", feature = "logind"))]
+use webcam_direct_linux::sleep_inhibitor::{Logind, SleepInhibitor};
+use webcam_direct_linux::startup::{StartupGates, StartupStage};
+#[cfg(any(feature = "webrtc", feature = "desktop"))]
+use webcam_direct_linux::thumbnails::Thumbnails;
+#[cfg(feature = "logind")]
+use webcam_direct_linux::user_sessions::{self, UserScope};
+#[cfg(feature = "webrtc")]
+use webcam_direct_linux::vdevice_builder::VDeviceBuilder;
+
+#[cfg(not(feature = "webrtc"))]
+use webcam_direct_linux::ble::server::mobile_comm::NoVDeviceBuilder;
+use webcam_direct_linux::ble::server::mobile_comm::{
+    AppDataStore, MobileComm, PairingMode,
+};
+#[cfg(feature = "desktop")]
+use webcam_direct_linux::desktop_bus::{DesktopBus, PairingCode};
+
+/// Directory of the in disk database
+const DB_PATH: &str = "/tmp";
+
+/// Access point and the services running on it, stopped on drop
+#[cfg(feature = "ap")]
+struct AccessPoint<C: AccessPointCtl> {
+    ctl: C,
+    _captive_portal: Option<CaptivePortal>,
+    link_monitor: LinkMonitor,
+    router_ip: Ipv4Addr,
+}
 
-fn setup_access_point() -> Result<impl AccessPointCtl> {
+#[cfg(feature = "ap")]
+async fn setup_access_point(
+    creds: &WifiCredentials, band: ApBand, config: &AppConfig,
+) -> Result<AccessPoint<impl AccessPointCtl>> {
     let if_name = "wcdirect0";
+    let runtime_dir = config.runtime_dir.as_path();
+
+    //the hostapd config holds the passphrase
+    prepare_runtime_dir(runtime_dir)?;
+
+    //only the radio of the phy the interface is created from
+    if let Some(phy) = wdev_drv::Nl80211Driver.get_ap_wiphy_indx()? {
+        rfkill::ensure_unblocked(
+            Path::new(rfkill::RFKILL_PATH),
+            rfkill::WLAN,
+            &[&format!("phy{}", phy)],
+            config.rfkill_unblock,
+        )?;
+    }
 
     //init the wireless interface handler---------
-    let link = IwLink::new(wdev_drv::Nl80211Driver, if_name)?;
+    let link = retry::retry_blocking(
+        "Creating the access point interface",
+        retry::RetryPolicy::BOOT,
+        || IwLink::new(wdev_drv::Nl80211Driver, if_name),
+    )?;
+    let link_monitor =
+        LinkMonitor::spawn(wdev_drv::Nl80211Driver, link.if_index(), if_name);
 
     //init the dhcp server---------
-    let dhcp_server_proc = DnsmasqProc::new(ProcessHdl::handler());
+    let mut dhcp_server_proc = DnsmasqProc::new(ProcessHdl::handler())
+        .with_local_name(config.local_hostname.clone());
+    if config.suppress_captive_portal {
+        dhcp_server_proc = dhcp_server_proc.with_redirected_hosts(PROBE_HOSTS);
+    }
 
     //wifi manager process
     let hostapd_proc = HostapdProc::new(
-        FileHdl::from_path("/tmp/hostapd.conf"),
+        FileHdl::temp_in(runtime_dir, "hostapd", "conf"),
         ProcessHdl::handler(),
-    );
-
-    let wpactrl = WpaCtl::new("/tmp/hostapd", if_name);
+    )
+    .with_band(band);
 
-    let creds = WifiCredentials {
-        ssid: "WebcamDirect".to_string(),
-        password: "12345678".to_string(),
-    };
+    let wpactrl = WpaCtl::new(runtime_dir.join("hostapd"), if_name)
+        .with_client_dir(runtime_dir);
 
-    let wifi_manager = WifiManager::new(&creds, hostapd_proc, wpactrl)?;
+    let wifi_manager = WifiManager::new(creds, hostapd_proc, wpactrl)?;
 
     let mut ap = ApController::new(link, dhcp_server_proc, wifi_manager);
 
-    ap.start_dhcp_server(DhcpIpRange::new("193.168.3.5", "193.168.3.150")?)?;
+    let ip_range = DhcpIpRange::new("193.168.3.5", "193.168.3.150")?;
+    let router_ip = ip_range.get_router_ip().parse()?;
+    ap.start_dhcp_server(ip_range)?;
 
     ap.start_wifi()?;
 
+    //the phones stay on the access point without internet
+    let captive_portal = if config.suppress_captive_portal {
+        CaptivePortal::spawn(router_ip)
+            .await
+            .inspect_err(|e| warn!("Connectivity checks not answered: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
+
     //init Access Point manager------
-    Ok(ap)
+    Ok(AccessPoint {
+        ctl: ap,
+        _captive_portal: captive_portal,
+        link_monitor,
+        router_ip,
+    })
+}
+
+#[cfg(feature = "ble")]
+async fn setup_ble_adapters(
+    config: &AppConfig,
+) -> Result<(Vec<bluer::Adapter>, Vec<AdapterStatus>)> {
+    let session = bluer::Session::new().await?;
+
+    let adapters = if config.ble_adapters.is_empty() {
+        vec![session.default_adapter().await?]
+    } else {
+        config
+            .ble_adapters
+            .iter()
+            .map(|name| session.adapter(name))
+            .collect::<bluer::Result<Vec<_>>>()?
+    };
+
+    let names: Vec<&str> = adapters.iter().map(|a| a.name()).collect();
+    rfkill::ensure_unblocked(
+        Path::new(rfkill::RFKILL_PATH),
+        rfkill::BLUETOOTH,
+        &names,
+        config.rfkill_unblock,
+    )?;
+
+    for adapter in adapters.iter() {
+        //privacy must be set while the adapter is powered off
+        if config.le_privacy {
+            if let Err(e) = LePrivacy::new(BtMgmtCmd, adapter.name())
+                .and_then(|privacy| privacy.enable())
+            {
+                warn!("LE privacy not enabled, error: {:?}", e);
+            }
+        }
+
+        retry::retry("Powering the adapter", retry::RetryPolicy::BOOT, || {
+            adapter.set_powered(true)
+        })
+        .await?;
+    }
+
+    let mut statuses = Vec::new();
+    for adapter in adapters.iter() {
+        statuses.push(AdapterStatus {
+            name: adapter.name().to_string(),
+            mode: adapters::probe_mode(
+                adapter,
+                config.ble_advertising_fallback,
+            )
+            .await?,
+        });
+    }
+
+    Ok((adapters, statuses))
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Reload { apply_disruptive }) => {
+            return cli::run_reload(apply_disruptive, cli.json);
+        }
+        Some(Command::Blocklist { action }) => {
+            return cli::run_blocklist(DB_PATH, action, cli.json);
+        }
+        Some(Command::Trust { mobile_id, revoke }) => {
+            return cli::run_trust(DB_PATH, mobile_id, revoke, cli.json);
+        }
+        Some(Command::Stats { summary }) => {
+            return cli::run_stats(DB_PATH, summary, cli.json);
+        }
+        Some(Command::SelfTest) => {
+            return cli::run_self_test(DB_PATH, cli.json);
+        }
+        Some(Command::Privacy { action }) => {
+            return cli::run_privacy(action, cli.json);
+        }
+        Some(Command::Doctor { fix }) => {
+            return cli::run_doctor(rfkill::RFKILL_PATH, fix, cli.json);
+        }
+        Some(Command::Audit { generate_apparmor_profile }) => {
+            return cli::run_audit(
+                DB_PATH,
+                generate_apparmor_profile,
+                cli.json,
+            );
+        }
+        Some(Command::Composite { action }) => {
+            return cli::run_composite(action, cli.json);
+        }
+        Some(Command::Filters { action }) => {
+            return cli::run_filters(action, cli.json);
+        }
+        Some(Command::ProvisionDevices { count, first, all_caps }) => {
+            return cli::run_provision_devices(
+                count, first, all_caps, cli.json,
+            );
+        }
+        None => {}
+    }
+
+    let config = AppConfig::load()?;
+
+    //the path given on the command line keeps the configured rotation
+    let log_file = match cli.log_file {
+        Some(path) => Some(LogFileConfig {
+            path,
+            ..config.log_file.clone().unwrap_or_default()
+        }),
+        None => config.log_file.clone(),
+    };
+    init_logger(config.log_level, log_file.as_ref());
 
     info!("Starting webcam direct");
 
+    //the policy of locked-down hosts must allow these
+    Audit::for_config(
+        &config,
+        Path::new(DB_PATH),
+        AppConfig::default_path().as_deref(),
+    )
+    .log();
+
+    let _pid_file = PidFile::create()
+        .inspect_err(|e| warn!("Reload requests disabled, error: {:?}", e))
+        .ok();
+
+    let (config_watcher, live_config) = ConfigWatcher::new(config);
+
+    if let Some(path) = AppConfig::default_path() {
+        if let Err(e) = config_watcher.clone().watch(path) {
+            warn!("Config changes require a restart, error: {:?}", e);
+        }
+    }
+
+    let mut hangup = signal::unix::signal(SignalKind::hangup())?;
+
+    //the cameras stay disabled across the restarts
+    let privacy = PrivacySwitch::new();
+    //subscribers follow the host across the restarts too
+    let handle = HostHandle::new(
+        EventBus::default(),
+        privacy.clone(),
+        config_watcher.clone(),
+    );
+
+    while run(
+        live_config.clone(),
+        &config_watcher,
+        &mut hangup,
+        &privacy,
+        &handle,
+    )
+    .await?
+    {
+        info!("Restarting with the new configuration");
+    }
+
+    info!("webcam direct process stopped");
+
+    Ok(())
+}
+
+/// Runs the host services until the process is stopped, returns true if
+/// they must be restarted to apply the pending config changes
+async fn run(
+    live_config: LiveConfig, config_watcher: &Arc<ConfigWatcher>,
+    hangup: &mut Signal, privacy: &PrivacySwitch, handle: &HostHandle,
+) -> Result<bool> {
+    #[cfg_attr(
+        not(any(feature = "ble", feature = "webrtc")),
+        allow(unused_variables)
+    )]
+    let config = live_config.borrow().clone();
+
+    let mut startup = StartupGates::new();
+
+    //init the in disk database
+    let disk_db = DiskBasedDb::open_from(DB_PATH)?;
+    startup.reach(StartupStage::Storage)?;
+
     //get host name
     let mut host_info = HostInfo {
         name: "MyPC".to_string(),
         connection_type: ConnectionType::WLAN,
     };
 
-    if let Ok(host_name) = hostname::get()?.into_string() {
+    if let Some(host_name) = host_info::read_host_name() {
         host_info.name = host_name;
     }
 
-    let ap_controller_rc = setup_access_point();
-    if ap_controller_rc.is_ok() {
+    let mut app_data = AppData::new(disk_db, host_info.clone())?;
+    startup.reach(StartupStage::Identity)?;
+
+    #[cfg(feature = "ap")]
+    let ap_creds = WifiCredentials {
+        ssid: "WebcamDirect".to_string(),
+        password: "12345678".to_string(),
+    };
+
+    //the band set by the mobiles applies from the next start of the AP
+    #[cfg(feature = "ap")]
+    let ap_band = app_data.get_host_settings()?.ap_band;
+
+    #[cfg(feature = "ap")]
+    let ap_controller_rc =
+        setup_access_point(&ap_creds, ap_band, &config).await;
+
+    //the AP credentials are shared while pairing only if the access point
+    //is up
+    #[cfg(feature = "ap")]
+    let pairing_ap_creds =
+        ap_controller_rc.as_ref().ok().map(|_| ApCredentials {
+            ssid: ap_creds.ssid.clone(),
+            password: ap_creds.password.clone(),
+        });
+    #[cfg(not(feature = "ap"))]
+    let pairing_ap_creds: Option<ApCredentials> = None;
+
+    //the host info is only added once, it's kept up to date from here on
+    if pairing_ap_creds.is_some() {
         host_info.connection_type = ConnectionType::AP;
     }
+    app_data.update_host_info(&host_info)?;
+    startup.reach(StartupStage::NetworkMode)?;
 
-    let session = bluer::Session::new().await?;
+    let host_info_monitor = HostInfoMonitor::spawn(
+        host_info,
+        host_info::POLL_INTERVAL,
+        host_info::read_host_name,
+    );
 
-    let adapter = session.default_adapter().await?;
+    //the provisioning info is final from here on
+    let host_prov_info = app_data.get_host_prov_info()?;
 
-    adapter.set_powered(true).await?;
+    //battery level and AC status of laptops
+    let power_monitor = config
+        .power
+        .enabled
+        .then(|| PowerMonitor::spawn(config.power.poll_secs));
+
+    //previews of the cameras for the desktop front-ends
+    #[cfg(any(feature = "webrtc", feature = "desktop"))]
+    let thumbnails = Thumbnails::default();
+
+    #[cfg(feature = "webrtc")]
+    let mut vdev_builder =
+        VDeviceBuilder::new(live_config.clone(), privacy.clone()).await?;
+    #[cfg(feature = "webrtc")]
+    vdev_builder.share_thumbnails(thumbnails.clone());
+    #[cfg(feature = "webrtc")]
+    vdev_builder.publish_events(handle.events().clone());
+    #[cfg(feature = "webrtc")]
+    if let Some(monitor) = power_monitor
+        .as_ref()
+        .filter(|_| config.power.reduce_quality_on_battery)
+    {
+        vdev_builder.reduce_quality_on_battery(monitor.subscribe());
+    }
+    #[cfg(all(feature = "webrtc", feature = "logind"))]
+    vdev_builder.inhibit_sleep(SleepInhibitor::new(Logind));
+    #[cfg(feature = "webrtc")]
+    let capabilities = vdev_builder.capabilities().clone();
+    #[cfg(feature = "webrtc")]
+    let vdev_builder_released = vdev_builder.released();
+    #[cfg(not(feature = "webrtc"))]
+    let vdev_builder = NoVDeviceBuilder;
+
+    let mut mobile_comm = MobileComm::new(app_data, vdev_builder)?;
+    mobile_comm.set_events(handle.events().clone());
+    #[cfg(feature = "webrtc")]
+    mobile_comm.set_host_capabilities(capabilities);
+
+    if let Some(working_hours) = config.working_hours.clone() {
+        mobile_comm.set_authorization_policy(working_hours);
+    }
 
-    //init the in disk database
-    let config_path = "/tmp";
+    //prepare the last cameras of the trusted mobiles
+    mobile_comm.set_host_group(config.host_group.clone());
 
-    let disk_db = DiskBasedDb::open_from(config_path)?;
+    //the name resolves only on the access point
+    if pairing_ap_creds.is_some() {
+        mobile_comm.set_local_hostname(config.local_hostname.clone());
+    }
 
-    let app_data = AppData::new(disk_db, host_info.clone())?;
+    mobile_comm.enable_warm_standby(config.warm_standby.clone());
+    mobile_comm.prepare_warm_standby().await;
 
-    let host_prov_info = app_data.get_host_prov_info()?;
+    mobile_comm.enable_guest_sessions(config.guest_sessions.clone());
 
-    let mobile_comm = MobileComm::new(app_data, VDeviceBuilder::new().await?)?;
+    mobile_comm.set_stats_retention(config.stats_retention_days);
 
-    let ble_server = BleServer::new(mobile_comm, 512);
+    //the free slots are advertised by the call service
+    mobile_comm.set_camera_budget(config.max_cameras);
+    #[cfg(feature = "ble")]
+    let camera_slots = mobile_comm.subscribe_camera_slots();
 
-    let _provisioner = ProvisionerClient::new(
-        adapter.clone(),
-        ble_server.get_requester(),
-        host_prov_info.name.clone(),
-    );
+    mobile_comm.set_trace_exporter(TraceExporter::new(config.tracing.clone()));
 
-    let _mobile_prop_client =
-        MobilePropClient::new(adapter.clone(), ble_server.get_requester());
+    //breakage from kernel or GStreamer upgrades is found while idle
+    if let Some(schedule) = config.self_test.schedule.as_ref() {
+        match schedule.parse() {
+            Ok(schedule) => mobile_comm.enable_self_test(schedule),
+            Err(e) => warn!("Self-test disabled: {:?}", e),
+        }
+    }
 
-    let _sdp_exchanger = SdpExchangerClient::new(
-        adapter.clone(),
-        ble_server.get_requester(),
-        host_prov_info.name.clone(),
-        host_prov_info.id,
-    );
+    mobile_comm.set_privacy_switch(privacy.clone());
+
+    //the mobiles of a user are not served in the sessions of the others
+    if config.per_user.enabled {
+        #[cfg(feature = "logind")]
+        mobile_comm.set_user_scope(UserScope::new(
+            user_sessions::Logind::default(),
+            config.per_user.seat.clone(),
+        ));
+        #[cfg(not(feature = "logind"))]
+        warn!("Per-user mobiles require the logind feature");
+    }
 
-    info!("Press any key or Ctrl-C to stop the process");
+    if let Some(monitor) = power_monitor.as_ref() {
+        mobile_comm.set_power_state(monitor.subscribe());
+    }
+
+    //renames are notified to the connected mobiles
+    mobile_comm.set_host_info_changes(host_info_monitor.subscribe());
 
-    tokio::select! {
-      _ = signal::ctrl_c() => {
-        info!("Received Ctrl-C, shutting down.");
-      }
+    #[cfg(feature = "ap")]
+    if let Ok(ap) = ap_controller_rc.as_ref() {
+        mobile_comm.set_ap_link_stats(ap.link_monitor.subscribe());
+        mobile_comm.set_link_test_addr(ap.router_ip);
     }
 
-    info!("webcam direct process stopped");
+    //shared by the BLE server and the mobile communication
+    let metrics = Metrics::default();
+    mobile_comm.set_metrics(metrics.clone());
+
+    //modes of the Bluetooth adapters, reported in the diagnostics
+    #[cfg(feature = "ble")]
+    let ble_status = watch::Sender::new(Vec::new());
+    #[cfg(feature = "ble")]
+    mobile_comm.set_ble_adapters(ble_status.subscribe());
+
+    //open the pairing window
+    let pairing_mode =
+        Arc::new(PairingMode::new(pairing_ap_creds, live_config));
+    mobile_comm.enable_pairing_mode(pairing_mode.clone());
+
+    //the pairing code for the desktop front-ends
+    #[cfg(feature = "desktop")]
+    let _desktop_bus = DesktopBus::serve(
+        PairingCode::new(&host_prov_info, pairing_mode),
+        privacy.clone(),
+        thumbnails,
+        mobile_comm.subscribe_self_tests(),
+    )
+    .await
+    .inspect_err(|e| warn!("No desktop D-Bus service: {:?}", e))
+    .ok();
+
+    let mut ble_server = BleServer::new(mobile_comm, 512, metrics);
+
+    //advertise only once the provisioning info is final
+    startup.require(StartupStage::NetworkMode)?;
+    #[cfg(feature = "ble")]
+    let (adapters, statuses) = setup_ble_adapters(&config).await?;
+
+    //the clients run on every adapter, the advertisement rotates among the
+    //ones that can advertise
+    #[cfg(feature = "ble")]
+    let adapter_pool = AdapterPool::new(statuses, ble_status);
+
+    #[cfg(feature = "ble")]
+    let clients = adapters
+        .iter()
+        .map(|adapter| {
+            (
+                ProvisionerClient::new(
+                    adapter.clone(),
+                    adapter_pool.clone(),
+                    ble_server.get_requester(),
+                    host_prov_info.name.clone(),
+                    config.host_group.clone(),
+                    config.diagnostics_enabled,
+                ),
+                MobilePropClient::new(
+                    adapter.clone(),
+                    adapter_pool.clone(),
+                    ble_server.get_requester(),
+                ),
+                SdpExchangerClient::new(
+                    adapter.clone(),
+                    adapter_pool.clone(),
+                    ble_server.get_requester(),
+                    host_prov_info.name.clone(),
+                    host_prov_info.id.clone(),
+                    camera_slots.clone(),
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    #[cfg(feature = "ble")]
+    startup.reach(StartupStage::Ble)?;
+
+    #[cfg(not(feature = "ble"))]
+    warn!(
+        "Built without BLE support, {} can't be discovered by the mobiles",
+        host_prov_info.name
+    );
 
-    Ok(())
+    info!("Press any key or Ctrl-C to stop the process");
+
+    let restart = loop {
+        tokio::select! {
+          _ = signal::ctrl_c() => {
+            info!("Received Ctrl-C, shutting down.");
+            break Ok(false);
+          }
+          _ = hangup.recv() => {
+            if config_watcher.apply_pending() {
+                break Ok(true);
+            }
+            info!("No config changes pending");
+          }
+          _ = handle.restart_requested() => {
+            if config_watcher.apply_pending() {
+                break Ok(true);
+            }
+            info!("No config changes pending");
+          }
+          //without the server the mobiles can't be served, the process
+          //fails so its supervisor restarts it
+          e = ble_server.failed() => {
+            error!("Shutting down: {:?}", e);
+            break Err(e);
+          }
+        }
+    };
+
+    //the calls stop while their transports are still up
+    let mut shutdown = ShutdownSequence::new();
+    shutdown.run(ShutdownStage::Streams, ble_server.stop_calls()).await?;
+    shutdown.run(ShutdownStage::Mobiles, ble_server.notify_shutdown()).await?;
+    #[cfg(feature = "ble")]
+    shutdown
+        .run(ShutdownStage::Gatt, async move {
+            drop(clients);
+            Ok(())
+        })
+        .await?;
+    #[cfg(feature = "ap")]
+    shutdown
+        .run(ShutdownStage::AccessPoint, async move {
+            if let Ok(mut ap) = ap_controller_rc {
+                ap.ctl.stop_wifi()?;
+            }
+            Ok(())
+        })
+        .await?;
+
+    //the next run opens the database and loads the modules again, a camera
+    //still being built holds the builder until it's done
+    ble_server.stop().await?;
+    #[cfg(feature = "webrtc")]
+    vdev_builder_released.await;
+
+    restart
 }
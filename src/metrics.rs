@@ -0,0 +1,128 @@
+//! # Request metrics.
+//! Distributions of the sizes of the requests served to the mobiles and the
+//! reasons of the failed ones, kept since the host started. The counters
+//! alone don't tell a large host info from a long MTU-limited transfer, the
+//! histograms are read in the diagnostics of the long-running installs.
+
+use std::sync::{Arc, Mutex};
+
+use crate::ble::{
+    api::QueryApi,
+    comm_types::{Histogram, RequestMetrics},
+};
+
+/// Inclusive upper bounds of the query data buckets, in bytes
+const QUERY_BYTES_BOUNDS: [u64; 7] =
+    [64, 256, 1024, 4096, 16384, 65536, 262144];
+
+/// Inclusive upper bounds of the chunks per transfer buckets
+const CHUNKS_BOUNDS: [u64; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+
+/// Reason of a failed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    DeserializeError,
+    NotRegistered,
+    PipelineFailed,
+}
+
+/// Direction of a chunked transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    Read,
+    Write,
+}
+
+/// Metrics of the process, shared by the BLE server and the mobile
+/// communication
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    requests: Arc<Mutex<RequestMetrics>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: Arc::new(Mutex::new(RequestMetrics {
+                read_chunks: Histogram::new(&CHUNKS_BOUNDS),
+                write_chunks: Histogram::new(&CHUNKS_BOUNDS),
+                ..Default::default()
+            })),
+        }
+    }
+}
+
+impl Metrics {
+    /// Bytes of the data of a read, once per transfer
+    pub fn observe_query(&self, query_type: &QueryApi, bytes: usize) {
+        self.update(|requests| {
+            requests
+                .query_bytes
+                .entry(format!("{:?}", query_type))
+                .or_insert_with(|| Histogram::new(&QUERY_BYTES_BOUNDS))
+                .observe(bytes as u64)
+        });
+    }
+
+    /// Chunks of a complete transfer
+    pub fn observe_chunks(&self, transfer: Transfer, chunks: usize) {
+        self.update(|requests| match transfer {
+            Transfer::Read => requests.read_chunks.observe(chunks as u64),
+            Transfer::Write => requests.write_chunks.observe(chunks as u64),
+        });
+    }
+
+    pub fn record_failure(&self, reason: FailureReason) {
+        self.update(|requests| {
+            let failures = &mut requests.failures;
+            match reason {
+                FailureReason::DeserializeError => {
+                    failures.deserialize_error += 1
+                }
+                FailureReason::NotRegistered => failures.not_registered += 1,
+                FailureReason::PipelineFailed => failures.pipeline_failed += 1,
+            }
+        });
+    }
+
+    pub fn snapshot(&self) -> RequestMetrics {
+        self.requests
+            .lock()
+            .map(|requests| requests.clone())
+            .unwrap_or_default()
+    }
+
+    //a poisoned lock loses the observation, not the request
+    fn update(&self, observe: impl FnOnce(&mut RequestMetrics)) {
+        if let Ok(mut requests) = self.requests.lock() {
+            observe(&mut requests);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histograms_and_failures() {
+        let metrics = Metrics::default();
+        metrics.observe_query(&QueryApi::HostInfo, 100);
+        metrics.observe_query(&QueryApi::HostInfo, 300_000);
+        metrics.observe_chunks(Transfer::Read, 3);
+        metrics.observe_chunks(Transfer::Write, 1);
+        metrics.record_failure(FailureReason::NotRegistered);
+        metrics.record_failure(FailureReason::NotRegistered);
+
+        //clones share the metrics
+        let requests = metrics.clone().snapshot();
+        let host_info = &requests.query_bytes["HostInfo"];
+        assert_eq!(host_info.counts, vec![0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(host_info.count, 2);
+        assert_eq!(host_info.sum, 300_100);
+        assert_eq!(requests.read_chunks.counts[2], 1);
+        assert_eq!(requests.write_chunks.counts[0], 1);
+        assert_eq!(requests.failures.not_registered, 2);
+        assert_eq!(requests.failures.deserialize_error, 0);
+    }
+}
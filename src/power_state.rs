@@ -0,0 +1,173 @@
+//! # Host power state.
+//! The battery level and the AC status of laptops are read from the power
+//! supply class of sysfs and polled, so the mobiles can warn before the
+//! host sleeps or dies and the pipelines can lower the quality on battery.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::{debug, info};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::ble::comm_types::HostPowerState;
+
+/// Power supplies of the host
+pub const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Reads the power state from the power supplies under `root`, hosts
+/// without battery report AC online
+pub fn read_power_state(root: &Path) -> HostPowerState {
+    let mut state = HostPowerState { ac_online: false, ..Default::default() };
+    let mut has_mains = false;
+
+    let Ok(supplies) = fs::read_dir(root) else {
+        return HostPowerState { ac_online: true, ..Default::default() };
+    };
+
+    for supply in supplies.flatten().map(|entry| entry.path()) {
+        match read_attr(&supply, "type").as_deref() {
+            //USB-C chargers show as USB supplies, e.g. of the ucsi driver
+            Some("Mains") | Some("USB")
+                if read_attr(&supply, "scope").as_deref() != Some("Device") =>
+            {
+                has_mains = true;
+                state.ac_online |=
+                    read_attr(&supply, "online").as_deref() == Some("1");
+            }
+            //the peripherals, such as a mouse, have their own scope
+            Some("Battery")
+                if read_attr(&supply, "scope").as_deref() != Some("Device") =>
+            {
+                let capacity = read_attr(&supply, "capacity")
+                    .and_then(|capacity| capacity.parse::<u8>().ok());
+                state.battery_percent = state.battery_percent.max(capacity);
+            }
+            _ => {}
+        }
+    }
+
+    //without a mains or USB supply only the hosts without battery are on AC
+    if !has_mains {
+        state.ac_online = state.battery_percent.is_none();
+    }
+
+    state
+}
+
+fn read_attr(supply: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(supply.join(attr))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// Polls the power state, stopped on drop
+pub struct PowerMonitor {
+    changes: watch::Sender<HostPowerState>,
+    poll_task: JoinHandle<()>,
+}
+
+impl PowerMonitor {
+    pub fn spawn(poll_secs: u64) -> Self {
+        let root = PathBuf::from(POWER_SUPPLY_PATH);
+        let (changes, _) = watch::channel(read_power_state(&root));
+        info!("Host power state: {:?}", *changes.borrow());
+
+        let tx = changes.clone();
+        let poll_task = tokio::spawn(async move {
+            let mut poll =
+                tokio::time::interval(Duration::from_secs(poll_secs.max(1)));
+            loop {
+                poll.tick().await;
+                let state = read_power_state(&root);
+                tx.send_if_modified(|current| {
+                    if *current == state {
+                        return false;
+                    }
+                    debug!("Host power state changed: {:?}", state);
+                    *current = state;
+                    true
+                });
+            }
+        });
+
+        Self { changes, poll_task }
+    }
+
+    /// Receiver of the power state changes
+    pub fn subscribe(&self) -> watch::Receiver<HostPowerState> {
+        self.changes.subscribe()
+    }
+}
+
+impl Drop for PowerMonitor {
+    fn drop(&mut self) {
+        self.poll_task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supply(root: &Path, name: &str, attrs: &[(&str, &str)]) {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (attr, value) in attrs {
+            fs::write(dir.join(attr), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_laptop_on_battery() {
+        let root = std::env::temp_dir()
+            .join(format!("wcd-power-{}", std::process::id()));
+        supply(&root, "AC", &[("type", "Mains"), ("online", "0")]);
+        supply(&root, "BAT0", &[("type", "Battery"), ("capacity", "42")]);
+        supply(
+            &root,
+            "hid-mouse",
+            &[("type", "Battery"), ("scope", "Device"), ("capacity", "90")],
+        );
+
+        let state = read_power_state(&root);
+        assert_eq!(
+            state,
+            HostPowerState {
+                ac_online: false,
+                battery_percent: Some(42),
+                ..Default::default()
+            }
+        );
+        assert!(state.on_battery());
+
+        fs::remove_dir_all(&root).unwrap();
+
+        //a desktop without power supplies
+        assert!(!read_power_state(&root).on_battery());
+    }
+
+    #[test]
+    fn test_read_laptop_on_usb_c() {
+        let root = std::env::temp_dir()
+            .join(format!("wcd-power-usb-{}", std::process::id()));
+        supply(&root, "BAT0", &[("type", "Battery"), ("capacity", "42")]);
+        supply(
+            &root,
+            "ucsi-source-psy-USBC000:001",
+            &[("type", "USB"), ("online", "0")],
+        );
+        assert!(read_power_state(&root).on_battery());
+
+        supply(
+            &root,
+            "ucsi-source-psy-USBC000:002",
+            &[("type", "USB"), ("online", "1")],
+        );
+        assert!(!read_power_state(&root).on_battery());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+//! # Privacy kill switch.
+//! While enabled every virtual camera shows a "Camera disabled" frame
+//! instead of the mobile camera. The switch is created once per process, so
+//! the state holds across calls, reconnections and restarts of the host
+//! until it is disabled again.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use anyhow::anyhow;
+use log::info;
+use tokio::sync::watch;
+
+use crate::error::Result;
+
+/// Applies the state to an output, returns false once the output is gone
+pub type PrivacyOutput = Box<dyn Fn(bool) -> bool + Send>;
+
+struct SwitchState {
+    enabled: bool,
+    outputs: Vec<PrivacyOutput>,
+}
+
+/// Global switch shared by the pipelines, the mobiles and the controls
+#[derive(Clone)]
+pub struct PrivacySwitch {
+    state: Arc<Mutex<SwitchState>>,
+    changes: watch::Sender<bool>,
+}
+
+impl PrivacySwitch {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SwitchState {
+                enabled: false,
+                outputs: Vec::new(),
+            })),
+            changes: watch::channel(false).0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self.changes.borrow()
+    }
+
+    /// Registers an output, the current state is applied right away
+    pub fn add_output(&self, output: PrivacyOutput) -> Result<()> {
+        let mut state = self.lock()?;
+        if output(state.enabled) {
+            state.outputs.push(output);
+        }
+
+        Ok(())
+    }
+
+    /// Applies the state to every output at once, the outputs registered
+    /// meanwhile wait for the lock so none of them misses the change
+    pub fn set(&self, enabled: bool) -> Result<()> {
+        let mut state = self.lock()?;
+        if state.enabled != enabled {
+            self.apply(&mut state, enabled);
+        }
+
+        Ok(())
+    }
+
+    /// Flips the state and returns the new one
+    pub fn toggle(&self) -> Result<bool> {
+        let mut state = self.lock()?;
+        let enabled = !state.enabled;
+        self.apply(&mut state, enabled);
+
+        Ok(enabled)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, SwitchState>> {
+        self.state.lock().map_err(|_| anyhow!("Privacy switch lock poisoned"))
+    }
+
+    fn apply(&self, state: &mut SwitchState, enabled: bool) {
+        state.enabled = enabled;
+        state.outputs.retain(|output| output(enabled));
+        self.changes.send_replace(enabled);
+
+        info!(
+            "Privacy switch {}",
+            if enabled { "enabled" } else { "disabled" }
+        );
+    }
+
+    /// Receiver of the state changes
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.changes.subscribe()
+    }
+}
+
+impl Default for PrivacySwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PrivacySwitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivacySwitch")
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_outputs_follow_the_switch() {
+        let privacy = PrivacySwitch::new();
+        privacy.set(true).unwrap();
+
+        //a late output starts disabled
+        let camera = Arc::new(AtomicBool::new(false));
+        let state = camera.clone();
+        privacy
+            .add_output(Box::new(move |enabled| {
+                state.store(enabled, Ordering::SeqCst);
+                Arc::strong_count(&state) > 1
+            }))
+            .unwrap();
+        assert!(camera.load(Ordering::SeqCst));
+
+        let changes = privacy.subscribe();
+        assert!(!privacy.toggle().unwrap());
+        assert!(!camera.load(Ordering::SeqCst));
+        assert!(!*changes.borrow());
+
+        //a gone output is dropped on the next change
+        drop(camera);
+        privacy.set(true).unwrap();
+        assert!(privacy.state.lock().unwrap().outputs.is_empty());
+        assert!(privacy.is_enabled());
+    }
+}
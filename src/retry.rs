@@ -0,0 +1,146 @@
+//! # Retries of the setup operations.
+//! Module loads, interface creation, hostapd and the advertisements fail
+//! transiently while the system boots, e.g. until rfkill or udev settle.
+//! They are retried with a jittered exponential backoff, so the hosts
+//! started together don't retry in lockstep.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Debug,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use log::warn;
+
+/// Attempts and delays of a retried operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Covers the boot races, about 15 seconds in total
+    pub const BOOT: Self = Self {
+        max_attempts: 6,
+        initial_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(8),
+    };
+
+    /// Delay before the retry of the failed `attempt`, from 1. Between half
+    /// and the whole exponential delay, as picked by `jitter` in [0, 1]
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exp = self
+            .initial_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max_delay);
+
+        exp.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Runs `op` until it succeeds or the attempts run out, the last error is
+/// returned
+pub async fn retry<T, E: Debug, F, Fut>(
+    what: &str, policy: RetryPolicy, mut op: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt, jitter());
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                    what, attempt, policy.max_attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// `retry` for the blocking operations, the thread sleeps between attempts
+#[cfg_attr(not(feature = "ap"), allow(dead_code))]
+pub fn retry_blocking<T, E: Debug>(
+    what: &str, policy: RetryPolicy, mut op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt, jitter());
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                    what, attempt, policy.max_attempts, delay, e
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: RetryPolicy = RetryPolicy {
+        max_attempts: 3,
+        initial_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(2),
+    };
+
+    #[test]
+    fn test_backoff_delays() {
+        let policy = RetryPolicy::BOOT;
+        assert_eq!(policy.delay(1, 1.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_secs(2));
+        assert_eq!(policy.delay(3, 0.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(10, 1.0), Duration::from_secs(8));
+
+        let jitter = jitter();
+        assert!((0.0..1.0).contains(&jitter));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success_or_attempts_out() {
+        let mut calls = 0;
+        let result = retry("flaky", FAST, || {
+            calls += 1;
+            let ok = calls == 2;
+            async move {
+                if ok {
+                    Ok(calls)
+                } else {
+                    Err("busy")
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(2));
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry_blocking("down", FAST, || {
+            calls += 1;
+            Err(calls)
+        });
+        assert_eq!(result, Err(3));
+    }
+}
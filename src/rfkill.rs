@@ -0,0 +1,177 @@
+//! # Radio kill switches.
+//! A soft-blocked WiFi or Bluetooth radio makes the interface creation or
+//! the adapter power on fail with errors that don't name the cause. The
+//! rfkill class of sysfs is checked first, the soft blocks are lifted if
+//! configured, and the blocked radios are reported by name.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use log::info;
+use serde::Serialize;
+
+use crate::error::Result;
+
+/// Kill switches of the host radios
+pub const RFKILL_PATH: &str = "/sys/class/rfkill";
+
+/// Radio types of the rfkill class used by the host
+pub const WLAN: &str = "wlan";
+pub const BLUETOOTH: &str = "bluetooth";
+
+/// Kill switch state of a radio
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Radio {
+    /// Entry of the switch, e.g. rfkill0
+    pub id: String,
+    /// Radio type, e.g. wlan or bluetooth
+    pub kind: String,
+    /// Device of the radio, e.g. phy0 or hci0
+    pub name: String,
+    pub soft_blocked: bool,
+    /// Blocked by a hardware switch, can't be lifted by software
+    pub hard_blocked: bool,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Radio {
+    pub fn is_blocked(&self) -> bool {
+        self.soft_blocked || self.hard_blocked
+    }
+}
+
+/// Reads the kill switches under `root`, sorted by entry
+pub fn read_radios(root: &Path) -> Vec<Radio> {
+    let Ok(entries) = fs::read_dir(root) else {
+        return Vec::new();
+    };
+
+    let mut radios: Vec<Radio> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter_map(|path| {
+            Some(Radio {
+                id: path.file_name()?.to_string_lossy().to_string(),
+                kind: read_attr(&path, "type")?,
+                name: read_attr(&path, "name").unwrap_or_default(),
+                soft_blocked: read_attr(&path, "soft").as_deref() == Some("1"),
+                hard_blocked: read_attr(&path, "hard").as_deref() == Some("1"),
+                path,
+            })
+        })
+        .collect();
+    radios.sort_by(|a, b| a.id.cmp(&b.id));
+
+    radios
+}
+
+fn read_attr(radio: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(radio.join(attr))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// Lifts the soft block of the radio
+pub fn unblock(radio: &mut Radio) -> Result<()> {
+    fs::write(radio.path.join("soft"), "0")?;
+    radio.soft_blocked = false;
+    info!("Unblocked the {} radio {}", radio.kind, radio.name);
+
+    Ok(())
+}
+
+/// Fails naming the blocked radios of `kind` among the devices used, e.g.
+/// phy0 or hci0, the soft blocks are lifted first if `auto_unblock` is set.
+/// The other radios of the host are left as they are.
+pub fn ensure_unblocked(
+    root: &Path, kind: &str, devices: &[&str], auto_unblock: bool,
+) -> Result<()> {
+    let used = read_radios(root)
+        .into_iter()
+        .filter(|r| r.kind == kind && devices.contains(&r.name.as_str()));
+    for mut radio in used {
+        if radio.hard_blocked {
+            return Err(anyhow!(
+                "The {} radio {} ({}) is blocked by a hardware switch",
+                kind,
+                radio.name,
+                radio.id
+            ));
+        }
+        if radio.soft_blocked {
+            if !auto_unblock {
+                return Err(anyhow!(
+                    "The {} radio {} ({}) is soft-blocked, run `rfkill \
+                     unblock {}` or enable rfkill_unblock",
+                    kind,
+                    radio.name,
+                    radio.id,
+                    kind
+                ));
+            }
+            unblock(&mut radio)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn switch(root: &Path, id: &str, kind: &str, soft: &str, hard: &str) {
+        let dir = root.join(id);
+        fs::create_dir_all(&dir).unwrap();
+        for (attr, value) in
+            [("type", kind), ("name", id), ("soft", soft), ("hard", hard)]
+        {
+            fs::write(dir.join(attr), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_soft_block_lifted_if_enabled() {
+        let root = std::env::temp_dir()
+            .join(format!("wcd-rfkill-{}", std::process::id()));
+        switch(&root, "rfkill0", WLAN, "1", "0");
+        switch(&root, "rfkill1", BLUETOOTH, "0", "1");
+
+        let error =
+            ensure_unblocked(&root, WLAN, &["rfkill0"], false).unwrap_err();
+        assert!(error.to_string().contains("rfkill0"));
+        assert!(read_radios(&root)[0].is_blocked());
+
+        ensure_unblocked(&root, WLAN, &["rfkill0"], true).unwrap();
+        assert!(!read_radios(&root)[0].is_blocked());
+
+        //a hardware switch can't be lifted
+        let blocked = ensure_unblocked(&root, BLUETOOTH, &["rfkill1"], true);
+        assert!(blocked.is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_only_used_radio_checked() {
+        let root = std::env::temp_dir()
+            .join(format!("wcd-rfkill-used-{}", std::process::id()));
+        switch(&root, "rfkill0", BLUETOOTH, "0", "0");
+        switch(&root, "rfkill1", BLUETOOTH, "0", "1");
+        switch(&root, "rfkill2", WLAN, "1", "0");
+
+        //a blocked radio of another device doesn't matter
+        ensure_unblocked(&root, BLUETOOTH, &["rfkill0"], false).unwrap();
+        assert!(
+            ensure_unblocked(&root, BLUETOOTH, &["rfkill1"], false).is_err()
+        );
+        ensure_unblocked(&root, WLAN, &["rfkill0"], true).unwrap();
+        assert!(read_radios(&root)[2].soft_blocked);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! # Scheduled self-test.
+//! At the times of a cron-like schedule, once no camera streams, the host
+//! checks that the kernel modules are loaded, that a virtual device can be
+//! created and that a test pipeline plays into it. The runs are kept in the
+//! database and a failure is logged and signaled to the desktop front-ends,
+//! so a kernel or GStreamer upgrade breaking the cameras is found before the
+//! next call.
+
+use std::{future::Future, str::FromStr};
+
+use anyhow::anyhow;
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use log::warn;
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+use crate::{app_data::SelfTestRun, error::Result};
+
+/// Schedule of the self-test, the five fields `minute hour day-of-month
+/// month day-of-week` of cron with `*`, lists, ranges and `*/n` steps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    //bit per allowed value
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    //as in cron, either day field matches if both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(anyhow!(
+                "The schedule `{}` needs 5 fields: minute hour day-of-month \
+                 month day-of-week",
+                spec
+            ));
+        };
+
+        //Sunday is either 0 or 7
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits & !(1 << 7)) | 1;
+        }
+
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// Whether the minute of `at` is scheduled
+    pub fn matches(&self, at: NaiveDateTime) -> bool {
+        let is_set = |bits: u64, value: u32| bits & (1 << value) != 0;
+        let day = is_set(self.days, at.day());
+        let weekday =
+            is_set(self.weekdays, at.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        day && is_set(self.minutes, at.minute())
+            && is_set(self.hours, at.hour())
+            && is_set(self.months, at.month())
+    }
+}
+
+//comma separated values, ranges and steps between `min` and `max`
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let invalid = || anyhow!("Invalid schedule field `{}`", field);
+    let number = |value: &str| value.parse::<u32>().map_err(|_| invalid());
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, number(step)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            //`n/step` goes from n to the max
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+/// Runs of the self-test, due once per scheduled minute and started when
+/// the host is idle
+pub struct SelfTestScheduler {
+    schedule: Schedule,
+    last_slot: Option<NaiveDateTime>,
+    pending: bool,
+    running: Option<oneshot::Receiver<SelfTestRun>>,
+}
+
+impl SelfTestScheduler {
+    pub fn new(schedule: Schedule) -> Self {
+        Self { schedule, last_slot: None, pending: false, running: None }
+    }
+
+    /// Whether a run is to start at `now`, a run due while the host is
+    /// busy waits until it is `idle`
+    pub fn is_due(&mut self, now: NaiveDateTime, idle: bool) -> bool {
+        let slot = now.with_second(0).and_then(|now| now.with_nanosecond(0));
+        if self.schedule.matches(now) && self.last_slot != slot {
+            self.last_slot = slot;
+            self.pending = true;
+        }
+
+        if !self.pending || !idle || self.running.is_some() {
+            return false;
+        }
+        self.pending = false;
+        true
+    }
+
+    /// Runs `run` in the background, its result is taken with
+    /// `take_finished`
+    pub fn start(
+        &mut self, run: impl Future<Output = SelfTestRun> + Send + 'static,
+    ) {
+        let (tx, rx) = oneshot::channel();
+        self.running = Some(rx);
+        tokio::spawn(async move {
+            let _ = tx.send(run.await);
+        });
+    }
+
+    /// The run started if it finished
+    pub fn take_finished(&mut self) -> Option<SelfTestRun> {
+        let result = self.running.as_mut()?.try_recv();
+        match result {
+            Ok(run) => {
+                self.running = None;
+                Some(run)
+            }
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Closed) => {
+                warn!("Self-test stopped without a result");
+                self.running = None;
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        //2024-06-03 is a Monday
+        NaiveDate::from_ymd_opt(2024, 6, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_schedule_fields() {
+        let nightly: Schedule = "30 3 * * 1-5".parse().unwrap();
+        assert!(nightly.matches(at(3, 3, 30)));
+        assert!(!nightly.matches(at(3, 3, 31)));
+        //Sunday
+        assert!(!nightly.matches(at(2, 3, 30)));
+
+        let steps: Schedule = "*/15 0,12 * * 7".parse().unwrap();
+        assert!(steps.matches(at(2, 12, 45)));
+        assert!(!steps.matches(at(2, 13, 45)));
+
+        //either the day of the month or the weekday
+        let either: Schedule = "0 0 1 * 1".parse().unwrap();
+        assert!(either.matches(at(1, 0, 0)));
+        assert!(either.matches(at(3, 0, 0)));
+        assert!(!either.matches(at(4, 0, 0)));
+
+        assert!("0 3 * *".parse::<Schedule>().is_err());
+        assert!("60 3 * * *".parse::<Schedule>().is_err());
+        assert!("*/0 3 * * *".parse::<Schedule>().is_err());
+    }
+}
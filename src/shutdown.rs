@@ -0,0 +1,124 @@
+//! # Shutdown ordering.
+//! The calls run over the access point and their signaling over BLE, so the
+//! host services are stopped in the reverse order of the startup: the
+//! pipelines stop and their loopback devices are removed while the DTLS
+//! sessions are still reachable, the mobiles are told the host is going
+//! away, then the GATT services and the access point go down. Every stage
+//! is bounded by its timeout, a stuck stage doesn't keep the next ones from
+//! running.
+
+use std::{fmt, future::Future, time::Duration};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use tokio::time::{timeout, Instant};
+
+use crate::error::Result;
+
+/// Shutdown stages, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownStage {
+    /// The pipelines are stopped and the loopback devices removed
+    Streams,
+    /// The mobiles are notified of the shutdown
+    Mobiles,
+    /// The GATT services and the advertisement are removed
+    Gatt,
+    /// The access point goes down
+    AccessPoint,
+}
+
+impl ShutdownStage {
+    /// Longest wait for the stage before going on with the next one
+    pub fn timeout(self) -> Duration {
+        match self {
+            Self::Streams => Duration::from_secs(5),
+            Self::Mobiles => Duration::from_secs(2),
+            Self::Gatt => Duration::from_secs(3),
+            Self::AccessPoint => Duration::from_secs(5),
+        }
+    }
+}
+
+impl fmt::Display for ShutdownStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Streams => "streams",
+            Self::Mobiles => "mobiles",
+            Self::Gatt => "GATT",
+            Self::AccessPoint => "access point",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Stages of the shutdown of a run, a stage without service is skipped
+pub struct ShutdownSequence {
+    stage: Option<ShutdownStage>,
+    started: Instant,
+}
+
+impl ShutdownSequence {
+    pub fn new() -> Self {
+        Self { stage: None, started: Instant::now() }
+    }
+
+    /// Runs `stage`, which must come after the stages run so far. A stage
+    /// failing or timing out is logged and the shutdown goes on.
+    pub async fn run(
+        &mut self, stage: ShutdownStage, work: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        if self.stage.is_some_and(|current| current >= stage) {
+            return Err(anyhow!(
+                "Shutdown stage {} run after {}",
+                stage,
+                self.stage.map_or("none".to_string(), |s| s.to_string())
+            ));
+        }
+        self.stage = Some(stage);
+
+        let stage_started = Instant::now();
+        match timeout(stage.timeout(), work).await {
+            Ok(Ok(())) => info!(
+                "Shutdown: {} stopped in {:?}, {:?} in total",
+                stage,
+                stage_started.elapsed(),
+                self.started.elapsed()
+            ),
+            Ok(Err(e)) => warn!("Shutdown: {} failed: {:?}", stage, e),
+            Err(_) => warn!(
+                "Shutdown: {} not stopped after {:?}, going on",
+                stage,
+                stage.timeout()
+            ),
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for ShutdownSequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stages_run_in_order() {
+        let mut shutdown = ShutdownSequence::new();
+
+        //a failed stage doesn't stop the shutdown
+        let failed = async { Err(anyhow!("Pipeline stuck")) };
+        assert!(shutdown.run(ShutdownStage::Streams, failed).await.is_ok());
+
+        //the access point can't go down before the GATT services
+        let stopped = || async { Ok(()) };
+        shutdown.run(ShutdownStage::AccessPoint, stopped()).await.unwrap();
+        assert!(shutdown.run(ShutdownStage::Gatt, stopped()).await.is_err());
+        assert!(shutdown.run(ShutdownStage::Streams, stopped()).await.is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! # Sleep inhibitor.
+//! The host takes a logind inhibitor lock when the first stream starts and
+//! releases it when the last one stops, so the machine doesn't suspend or
+//! go idle mid-meeting because the user stopped touching the keyboard.
+
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+
+use crate::error::Result;
+
+#[cfg(test)]
+use mockall::automock;
+
+/// Inhibitor lock, released on drop
+pub type InhibitLock = Box<dyn Send>;
+
+/// Trait to take the inhibitor lock of the system
+#[cfg_attr(test, automock)]
+pub trait InhibitOps: Send + Sync + 'static {
+    fn inhibit(&self) -> Result<InhibitLock>;
+}
+
+struct InhibitorState {
+    streams: usize,
+    lock: Option<InhibitLock>,
+}
+
+/// Counts the active streams, shared by the pipelines
+#[derive(Clone)]
+pub struct SleepInhibitor {
+    ops: Arc<dyn InhibitOps>,
+    state: Arc<Mutex<InhibitorState>>,
+}
+
+impl SleepInhibitor {
+    pub fn new(ops: impl InhibitOps) -> Self {
+        Self {
+            ops: Arc::new(ops),
+            state: Arc::new(Mutex::new(InhibitorState {
+                streams: 0,
+                lock: None,
+            })),
+        }
+    }
+
+    /// Counts a new stream until the guard is dropped
+    pub fn stream_started(&self) -> StreamGuard {
+        //a poisoned state leaves the sleep as it is
+        if let Ok(mut state) = self.state.lock() {
+            state.streams += 1;
+
+            //a failed lock is taken again by the next stream
+            if state.lock.is_none() {
+                match self.ops.inhibit() {
+                    Ok(lock) => {
+                        info!("Sleep inhibited while streaming");
+                        state.lock = Some(lock);
+                    }
+                    Err(e) => warn!("Failed to inhibit the sleep: {:?}", e),
+                }
+            }
+        }
+
+        StreamGuard(self.clone())
+    }
+}
+
+impl std::fmt::Debug for SleepInhibitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let streams = self.state.lock().map(|state| state.streams).ok();
+        f.debug_struct("SleepInhibitor").field("streams", &streams).finish()
+    }
+}
+
+/// Active stream, the lock is released with the last one
+#[derive(Debug)]
+pub struct StreamGuard(SleepInhibitor);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.0.state.lock() else {
+            return;
+        };
+        state.streams = state.streams.saturating_sub(1);
+
+        if state.streams == 0 && state.lock.take().is_some() {
+            info!("Sleep allowed again, no stream left");
+        }
+    }
+}
+
+/// Inhibitor lock of logind, held as the file descriptor it returns
+#[cfg(feature = "logind")]
+pub struct Logind;
+
+#[cfg(feature = "logind")]
+impl InhibitOps for Logind {
+    fn inhibit(&self) -> Result<InhibitLock> {
+        use dbus::{arg::OwnedFd, blocking::Connection};
+        use std::time::Duration;
+
+        let conn = Connection::new_system()?;
+        let proxy = conn.with_proxy(
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            Duration::from_secs(5),
+        );
+        let (lock,): (OwnedFd,) = proxy.method_call(
+            "org.freedesktop.login1.Manager",
+            "Inhibit",
+            (
+                "sleep:idle",
+                "webcam-direct",
+                "Streaming a mobile camera",
+                "block",
+            ),
+        )?;
+
+        Ok(Box::new(lock))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_held_while_streaming() {
+        let mut ops = MockInhibitOps::new();
+        ops.expect_inhibit().times(2).returning(|| Ok(Box::new(())));
+        let inhibitor = SleepInhibitor::new(ops);
+
+        let first = inhibitor.stream_started();
+        let second = inhibitor.stream_started();
+        drop(first);
+        assert!(inhibitor.state.lock().unwrap().lock.is_some());
+
+        drop(second);
+        assert!(inhibitor.state.lock().unwrap().lock.is_none());
+
+        //the next stream takes the lock again
+        let _third = inhibitor.stream_started();
+        assert!(inhibitor.state.lock().unwrap().lock.is_some());
+    }
+}
@@ -0,0 +1,127 @@
+//! # Startup ordering.
+//! The provisioning info advertised over BLE is built from the storage, the
+//! host identity and the network mode. The host services are started
+//! through ordered readiness gates, so the mobiles never read the info
+//! before it is final.
+
+use std::{fmt, time::Instant};
+
+use anyhow::anyhow;
+use log::info;
+
+use crate::error::Result;
+
+/// Startup stages, in the order they are reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StartupStage {
+    Starting,
+    /// The database is open
+    Storage,
+    /// The host info is stored
+    Identity,
+    /// The access point is up or the host stays on the WLAN
+    NetworkMode,
+    /// The provisioning info is final and advertised
+    Ble,
+}
+
+impl StartupStage {
+    fn next(self) -> Option<Self> {
+        match self {
+            Self::Starting => Some(Self::Storage),
+            Self::Storage => Some(Self::Identity),
+            Self::Identity => Some(Self::NetworkMode),
+            Self::NetworkMode => Some(Self::Ble),
+            Self::Ble => None,
+        }
+    }
+}
+
+impl fmt::Display for StartupStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Starting => "starting",
+            Self::Storage => "storage",
+            Self::Identity => "identity",
+            Self::NetworkMode => "network mode",
+            Self::Ble => "BLE",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Readiness gates of the host services, one per run
+pub struct StartupGates {
+    stage: StartupStage,
+    started: Instant,
+}
+
+impl StartupGates {
+    pub fn new() -> Self {
+        Self { stage: StartupStage::Starting, started: Instant::now() }
+    }
+
+    /// Opens the gate of `stage`, which must follow the current one
+    pub fn reach(&mut self, stage: StartupStage) -> Result<()> {
+        if self.stage.next() != Some(stage) {
+            return Err(anyhow!(
+                "Startup stage {} reached before {}",
+                stage,
+                self.stage.next().map_or("none".to_string(), |s| s.to_string())
+            ));
+        }
+
+        info!(
+            "Startup: {} -> {} ready after {:?}",
+            self.stage,
+            stage,
+            self.started.elapsed()
+        );
+        self.stage = stage;
+
+        Ok(())
+    }
+
+    /// Fails unless the gate of `stage` is open
+    pub fn require(&self, stage: StartupStage) -> Result<()> {
+        if self.stage < stage {
+            return Err(anyhow!(
+                "Startup stage {} required, only {} is ready",
+                stage,
+                self.stage
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for StartupGates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stages_reached_in_order() {
+        let mut gates = StartupGates::new();
+        assert!(gates.require(StartupStage::Storage).is_err());
+
+        //the network mode can't be resolved before the identity
+        gates.reach(StartupStage::Storage).unwrap();
+        assert!(gates.reach(StartupStage::NetworkMode).is_err());
+        assert!(gates.require(StartupStage::Identity).is_err());
+
+        gates.reach(StartupStage::Identity).unwrap();
+        gates.reach(StartupStage::NetworkMode).unwrap();
+        assert!(gates.require(StartupStage::Identity).is_ok());
+        assert!(gates.require(StartupStage::Ble).is_err());
+
+        gates.reach(StartupStage::Ble).unwrap();
+        assert!(gates.reach(StartupStage::Ble).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! # Panic isolation.
+//! A panic in a request of the BLE server, a task of the access point or a
+//! pipeline is caught where it happens and logged with its component, so
+//! the other components and the running cameras go on. The failed component
+//! is restarted until it panics too often in a row.
+
+use std::{
+    any::Any,
+    future::Future,
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use log::error;
+use tokio::task::JoinHandle;
+
+/// Restarts of a component allowed within a window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub window: Duration,
+    /// Wait before a restart
+    pub delay: Duration,
+}
+
+impl RestartPolicy {
+    /// A few restarts a minute, a component panicking in a loop is given up
+    pub const DEFAULT: Self = Self {
+        max_restarts: 3,
+        window: Duration::from_secs(60),
+        delay: Duration::from_secs(1),
+    };
+}
+
+/// Panics of a component within the window of its policy
+#[derive(Debug)]
+pub struct Supervisor {
+    name: &'static str,
+    policy: RestartPolicy,
+    panics: Vec<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(name: &'static str, policy: RestartPolicy) -> Self {
+        Self { name, policy, panics: Vec::new() }
+    }
+
+    /// Logs the panic at `now`, returns whether the component can be
+    /// restarted
+    pub fn on_panic(&mut self, panic: &str, now: Instant) -> bool {
+        let window = self.policy.window;
+        self.panics.retain(|at| now.saturating_duration_since(*at) < window);
+        self.panics.push(now);
+
+        let panics = self.panics.len() as u32;
+        if panics <= self.policy.max_restarts {
+            error!(
+                "{} panicked, restarting it ({}/{}): {}",
+                self.name, panics, self.policy.max_restarts, panic
+            );
+            return true;
+        }
+
+        error!(
+            "{} panicked {} times within {:?}, given up: {}",
+            self.name, panics, window, panic
+        );
+        false
+    }
+}
+
+/// Message of a panic payload
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Runs `body`, a panic is logged with the component and None returned.
+/// For the callbacks called from C, a panic can't unwind out of them
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub fn isolate<R>(component: &str, body: impl FnOnce() -> R) -> Option<R> {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => Some(value),
+        Err(payload) => {
+            error!("{} panicked: {}", component, panic_message(&*payload));
+            None
+        }
+    }
+}
+
+/// Runs `future`, a panic is returned as its message
+pub async fn catch_panic<F: Future>(future: F) -> Result<F::Output, String> {
+    AssertUnwindSafe(future)
+        .catch_unwind()
+        .await
+        .map_err(|payload| panic_message(&*payload))
+}
+
+/// Runs the task made by `start` in the background, it's made again when it
+/// panics until `policy` gives it up
+#[cfg_attr(not(feature = "ap"), allow(dead_code))]
+pub fn spawn_supervised<F, Fut>(
+    name: &'static str, policy: RestartPolicy, mut start: F,
+) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut supervisor = Supervisor::new(name, policy);
+        while let Err(panic) = catch_panic(start()).await {
+            if !supervisor.on_panic(&panic, Instant::now()) {
+                return;
+            }
+            tokio::time::sleep(policy.delay).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_panicking_task_restarted_until_given_up() {
+        let policy = RestartPolicy {
+            max_restarts: 2,
+            window: Duration::from_secs(60),
+            delay: Duration::ZERO,
+        };
+        let starts = Arc::new(AtomicU32::new(0));
+
+        let task_starts = starts.clone();
+        spawn_supervised("test task", policy, move || {
+            let starts = task_starts.clone();
+            async move {
+                starts.fetch_add(1, Ordering::SeqCst);
+                panic!("task failed");
+            }
+        })
+        .await
+        .unwrap();
+
+        //the first run and two restarts
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+
+        let caught = catch_panic(async { panic!("request failed") }).await;
+        assert_eq!(caught, Err::<(), _>("request failed".to_string()));
+    }
+}
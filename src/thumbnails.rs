@@ -0,0 +1,95 @@
+//! # Camera thumbnails.
+//! Every active camera keeps its latest frame as a small JPEG, taken once
+//! per second, so the admins see which mobile camera feeds which device
+//! without opening it. The frames are taken after the privacy switch, the
+//! disabled cameras show the placeholder.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Latest thumbnail of every active camera, shared by the pipelines and the
+/// desktop front-ends
+#[derive(Clone, Default)]
+pub struct Thumbnails {
+    frames: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl Thumbnails {
+    /// Names of the cameras with a thumbnail, sorted
+    #[cfg_attr(not(feature = "desktop"), allow(dead_code))]
+    pub fn cameras(&self) -> Vec<String> {
+        //a poisoned map shows no thumbnail
+        let Ok(frames) = self.frames.lock() else {
+            return Vec::new();
+        };
+        let mut cameras: Vec<String> = frames.keys().cloned().collect();
+        cameras.sort();
+        cameras
+    }
+
+    /// Latest JPEG of the camera
+    #[cfg_attr(not(feature = "desktop"), allow(dead_code))]
+    pub fn get(&self, camera: &str) -> Option<Vec<u8>> {
+        self.frames.lock().ok()?.get(camera).cloned()
+    }
+
+    /// Slot of the camera, its thumbnail is removed once the slot is dropped
+    #[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+    pub fn slot(&self, camera: &str) -> ThumbnailSlot {
+        ThumbnailSlot { thumbnails: self.clone(), camera: camera.to_string() }
+    }
+}
+
+impl fmt::Debug for Thumbnails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Thumbnails").field("cameras", &self.cameras()).finish()
+    }
+}
+
+/// Thumbnail of a camera, held by its pipeline
+#[derive(Debug)]
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+pub struct ThumbnailSlot {
+    thumbnails: Thumbnails,
+    camera: String,
+}
+
+#[cfg_attr(not(feature = "webrtc"), allow(dead_code))]
+impl ThumbnailSlot {
+    pub fn set(&self, jpeg: Vec<u8>) {
+        if let Ok(mut frames) = self.thumbnails.frames.lock() {
+            frames.insert(self.camera.clone(), jpeg);
+        }
+    }
+}
+
+impl Drop for ThumbnailSlot {
+    fn drop(&mut self) {
+        if let Ok(mut frames) = self.thumbnails.frames.lock() {
+            frames.remove(&self.camera);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail_removed_with_camera() {
+        let thumbnails = Thumbnails::default();
+        let back = thumbnails.slot("back");
+        let front = thumbnails.slot("front");
+
+        back.set(vec![0xff, 0xd8]);
+        front.set(vec![0xff, 0xd8, 0xff]);
+        assert_eq!(thumbnails.cameras(), vec!["back", "front"]);
+
+        drop(front);
+        assert_eq!(thumbnails.cameras(), vec!["back"]);
+        assert_eq!(thumbnails.get("back"), Some(vec![0xff, 0xd8]));
+    }
+}
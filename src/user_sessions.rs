@@ -0,0 +1,225 @@
+//! # User sessions.
+//! On multi-user hosts every mobile belongs to the user of the active
+//! session on the pairing seat when it registered. Its cameras are created
+//! only while that user has an active session on a seat, and their device
+//! nodes are restricted to that user, so the phones of one user are neither
+//! seen nor driven from the session of another. The sessions are read over
+//! blocking D-Bus calls, made off the async threads.
+
+use std::sync::Arc;
+#[cfg(feature = "logind")]
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use log::warn;
+
+use crate::{app_data::MobileSchema, error::Result};
+
+#[cfg(test)]
+use mockall::automock;
+
+/// User id of the system
+pub type Uid = u32;
+
+/// Trait to read the sessions of the system
+#[cfg_attr(test, automock)]
+pub trait SessionOps: Send + Sync + 'static {
+    /// User of the active session of the seat, None if nobody uses it
+    fn seat_user(&self, seat: &str) -> Result<Option<Uid>>;
+
+    /// Users with an active session on any seat
+    fn active_users(&self) -> Result<Vec<Uid>>;
+}
+
+//id, user id, user name, seat and object path of a logind session
+#[cfg(feature = "logind")]
+type SessionEntry = (String, Uid, String, String, dbus::Path<'static>);
+
+/// Scopes the mobiles to the users of the seats
+pub struct UserScope {
+    ops: Arc<dyn SessionOps>,
+    seat: String,
+}
+
+impl UserScope {
+    /// The mobiles registered now belong to the user of `seat`
+    #[cfg_attr(not(feature = "logind"), allow(dead_code))]
+    pub fn new(ops: impl SessionOps, seat: String) -> Self {
+        Self { ops: Arc::new(ops), seat }
+    }
+
+    /// Owner of the mobiles registered now, None if the seat is free
+    pub async fn pairing_owner(&self) -> Option<Uid> {
+        let ops = self.ops.clone();
+        let seat = self.seat.clone();
+        let owner = tokio::task::spawn_blocking(move || ops.seat_user(&seat))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|owner| owner);
+        match owner {
+            Ok(owner) => owner,
+            Err(e) => {
+                warn!("No user of {}, mobile not scoped: {:?}", self.seat, e);
+                None
+            }
+        }
+    }
+
+    /// Whether the cameras of the mobile can run, the mobiles without an
+    /// owner are shared by every user
+    pub async fn authorize(&self, mobile: &MobileSchema) -> Result<()> {
+        let Some(owner) = mobile.owner else {
+            return Ok(());
+        };
+
+        let ops = self.ops.clone();
+        let users =
+            tokio::task::spawn_blocking(move || ops.active_users()).await??;
+        if users.contains(&owner) {
+            return Ok(());
+        }
+
+        Err(anyhow!(
+            "Mobile {} belongs to user {}, who has no active session",
+            mobile.id,
+            owner
+        ))
+    }
+}
+
+/// Sessions of logind, over a system bus connection kept between the calls
+#[cfg(feature = "logind")]
+#[derive(Default)]
+pub struct Logind {
+    conn: Mutex<Option<dbus::blocking::Connection>>,
+}
+
+#[cfg(feature = "logind")]
+impl Logind {
+    fn with_conn<T>(
+        &self, f: impl FnOnce(&dbus::blocking::Connection) -> Result<T>,
+    ) -> Result<T> {
+        let mut kept = self
+            .conn
+            .lock()
+            .map_err(|_| anyhow!("Logind connection lock poisoned"))?;
+        let conn = match kept.take() {
+            Some(conn) => conn,
+            None => dbus::blocking::Connection::new_system()?,
+        };
+        let result = f(&conn);
+        //a broken connection is opened again by the next call
+        if result.is_ok() {
+            *kept = Some(conn);
+        }
+        result
+    }
+}
+
+#[cfg(feature = "logind")]
+impl SessionOps for Logind {
+    fn seat_user(&self, seat: &str) -> Result<Option<Uid>> {
+        self.with_conn(|conn| seat_user(conn, seat))
+    }
+
+    fn active_users(&self) -> Result<Vec<Uid>> {
+        self.with_conn(active_users)
+    }
+}
+
+#[cfg(feature = "logind")]
+fn seat_user(
+    conn: &dbus::blocking::Connection, seat: &str,
+) -> Result<Option<Uid>> {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use std::time::Duration;
+
+    let manager = conn.with_proxy(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        Duration::from_secs(5),
+    );
+    let (seat_path,): (dbus::Path,) = manager.method_call(
+        "org.freedesktop.login1.Manager",
+        "GetSeat",
+        (seat,),
+    )?;
+
+    let (session_id, session_path): (String, dbus::Path) = conn
+        .with_proxy("org.freedesktop.login1", seat_path, Duration::from_secs(5))
+        .get("org.freedesktop.login1.Seat", "ActiveSession")?;
+    if session_id.is_empty() {
+        return Ok(None);
+    }
+
+    let (uid, _): (Uid, dbus::Path) = conn
+        .with_proxy(
+            "org.freedesktop.login1",
+            session_path,
+            Duration::from_secs(5),
+        )
+        .get("org.freedesktop.login1.Session", "User")?;
+
+    Ok(Some(uid))
+}
+
+#[cfg(feature = "logind")]
+fn active_users(conn: &dbus::blocking::Connection) -> Result<Vec<Uid>> {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use std::time::Duration;
+
+    let manager = conn.with_proxy(
+        "org.freedesktop.login1",
+        "/org/freedesktop/login1",
+        Duration::from_secs(5),
+    );
+    let (sessions,): (Vec<SessionEntry>,) = manager.method_call(
+        "org.freedesktop.login1.Manager",
+        "ListSessions",
+        (),
+    )?;
+
+    //remote sessions have no seat and don't show the cameras
+    let mut users = Vec::new();
+    for (_, uid, _, seat, path) in sessions {
+        if seat.is_empty() || users.contains(&uid) {
+            continue;
+        }
+
+        let active: bool = conn
+            .with_proxy("org.freedesktop.login1", path, Duration::from_secs(5))
+            .get("org.freedesktop.login1.Session", "Active")?;
+        if active {
+            users.push(uid);
+        }
+    }
+
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mobile_scoped_to_owner() {
+        let mut ops = MockSessionOps::new();
+        ops.expect_seat_user().returning(|_| Ok(Some(1000)));
+        ops.expect_active_users().returning(|| Ok(vec![1001]));
+        let scope = UserScope::new(ops, "seat0".to_string());
+
+        let mut mobile = MobileSchema {
+            id: "mobile_1".to_string(),
+            owner: scope.pairing_owner().await,
+            ..Default::default()
+        };
+        assert!(scope.authorize(&mobile).await.is_err());
+
+        //paired before the scoping, shared
+        mobile.owner = None;
+        assert!(scope.authorize(&mobile).await.is_ok());
+
+        mobile.owner = Some(1001);
+        assert!(scope.authorize(&mobile).await.is_ok());
+    }
+}
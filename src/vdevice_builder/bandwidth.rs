@@ -0,0 +1,120 @@
+//! # Ingress bandwidth caps.
+//! The caps are announced to the mobile in the answer as a quality hint,
+//! and enforced on the received RTP with token buckets, one per mobile
+//! shared by its cameras and one shared by every call, so the webcam
+//! traffic can't saturate an uplink shared with the access point.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Burst admitted over the rate, in seconds of traffic
+const BURST_SECS: f64 = 0.5;
+
+/// Bytes per second refilled up to the burst
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(kbps: u32) -> Self {
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Self {
+            bytes_per_sec,
+            tokens: bytes_per_sec * BURST_SECS,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Changes the rate, the tokens left are kept up to the new burst
+    pub fn set_kbps(&mut self, kbps: u32) {
+        self.refill(Instant::now());
+        self.bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        self.tokens = self.tokens.min(self.burst());
+    }
+
+    fn burst(&self) -> f64 {
+        self.bytes_per_sec * BURST_SECS
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.bytes_per_sec).min(self.burst());
+        self.refilled_at = now;
+    }
+}
+
+/// Buckets a received packet must fit in, no bucket admits everything
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthPolicer {
+    buckets: Vec<Arc<Mutex<TokenBucket>>>,
+}
+
+impl BandwidthPolicer {
+    pub fn new(buckets: Vec<Arc<Mutex<TokenBucket>>>) -> Self {
+        Self { buckets }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.buckets.is_empty()
+    }
+
+    /// Takes the bytes from every bucket, a packet over any cap is dropped
+    /// without consuming the others
+    pub fn admit(&self, bytes: usize, now: Instant) -> bool {
+        let Ok(mut buckets) = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.lock())
+            .collect::<Result<Vec<_>, _>>()
+        else {
+            return true;
+        };
+
+        for bucket in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+        if buckets.iter().any(|bucket| bucket.tokens < bytes as f64) {
+            return false;
+        }
+        for bucket in buckets.iter_mut() {
+            bucket.tokens -= bytes as f64;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_admit_within_every_cap() {
+        //62500 and 125000 bytes per second, half a second of burst
+        let mobile = Arc::new(Mutex::new(TokenBucket::new(500)));
+        let total = Arc::new(Mutex::new(TokenBucket::new(1000)));
+        let first = BandwidthPolicer::new(vec![mobile, total.clone()]);
+        let second = BandwidthPolicer::new(vec![total]);
+        let start = Instant::now();
+
+        assert!(first.admit(30000, start));
+        //over the burst of the mobile, the total is not consumed
+        assert!(!first.admit(2000, start));
+        assert!(second.admit(32500, start));
+        assert!(!second.admit(1, start));
+
+        //refilled at the rate of each bucket
+        let later = start + Duration::from_millis(100);
+        assert!(second.admit(12500, later));
+        assert!(!first.admit(6500, later));
+
+        assert!(BandwidthPolicer::default().admit(usize::MAX, later));
+    }
+}
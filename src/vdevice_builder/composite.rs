@@ -0,0 +1,214 @@
+//! Composite devices.
+//! A composite combines the cameras of several mobiles into a single
+//! virtual device, in a grid or as a picture-in-picture. Every source camera
+//! feeds an `intervideosink` channel besides its own device, the composite
+//! pipeline reads the channels and shows black for the cameras that aren't
+//! streaming.
+
+use std::fmt::Write;
+
+use super::container::{DeviceLease, DevicePool};
+use super::device_conflict::check_device;
+use super::rtsp_output::rtsp_path;
+use super::vdevice::{device_config, V4l2Device};
+use crate::{
+    ble::comm_types::VideoProp,
+    config::{CompositeConfig, CompositeLayout, LoopbackConfig},
+    error::Result,
+};
+use anyhow::{anyhow, Context};
+use gst::prelude::*;
+use log::{error, info};
+
+/// Area of a camera in the composite frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Areas of `count` cameras in a frame of `width` by `height`, in the order
+/// of the sources
+pub fn tiles(
+    layout: CompositeLayout, count: u32, width: u32, height: u32,
+) -> Vec<Tile> {
+    match layout {
+        CompositeLayout::Grid => {
+            let cols =
+                (1..=count).find(|cols| cols * cols >= count).unwrap_or(1);
+            let rows = count.div_ceil(cols).max(1);
+            let (tile_width, tile_height) = (width / cols, height / rows);
+
+            (0..count)
+                .map(|index| Tile {
+                    x: index % cols * tile_width,
+                    y: index / cols * tile_height,
+                    width: tile_width,
+                    height: tile_height,
+                })
+                .collect()
+        }
+        CompositeLayout::Pip => {
+            //the insets go from the bottom right corner to the left
+            let (inset_width, inset_height) = (width / 4, height / 4);
+            let margin = width / 40;
+            let main = Tile { x: 0, y: 0, width, height };
+
+            std::iter::once(main)
+                .chain((1..count).map(|index| Tile {
+                    x: width.saturating_sub(index * (inset_width + margin)),
+                    y: height.saturating_sub(inset_height + margin),
+                    width: inset_width,
+                    height: inset_height,
+                }))
+                .collect()
+        }
+    }
+}
+
+/// intervideo channel fed by a source camera of the composites
+pub fn channel(vdevice_name: &str) -> String {
+    format!("composite-{}", rtsp_path(vdevice_name))
+}
+
+//the sources are drawn in order, the first one at the bottom
+fn launch_description(config: &CompositeConfig, device: &str) -> String {
+    let tiles = tiles(
+        config.layout,
+        config.sources.len() as u32,
+        config.width,
+        config.height,
+    );
+
+    let mut description = "compositor name=mix background=black".to_string();
+    for (index, tile) in tiles.iter().enumerate() {
+        let _ = write!(
+            description,
+            " sink_{index}::xpos={} sink_{index}::ypos={} \
+             sink_{index}::width={} sink_{index}::height={} \
+             sink_{index}::zorder={index} \
+             sink_{index}::sizing-policy=keep-aspect-ratio",
+            tile.x, tile.y, tile.width, tile.height
+        );
+    }
+    let _ = write!(
+        description,
+        " ! videoconvert ! video/x-raw,width={},height={},framerate={}/1 \
+         ! v4l2sink device={} sync=false",
+        config.width, config.height, config.fps, device
+    );
+
+    for (index, source) in config.sources.iter().enumerate() {
+        let _ = write!(
+            description,
+            " intervideosrc channel={} ! videoconvert ! queue ! mix.sink_{}",
+            channel(source),
+            index
+        );
+    }
+
+    description
+}
+
+/// Virtual device running the composite of its sources
+pub struct CompositeDevice {
+    sources: Vec<String>,
+    pipeline: gst::Element,
+    //released once the pipeline is stopped
+    _device_lease: Option<DeviceLease>,
+    _v4l2_device: Option<V4l2Device>,
+}
+
+impl CompositeDevice {
+    /// Starts the composite on a device of `device_pool` in a container,
+    /// on a new loopback device otherwise
+    pub async fn new(
+        config: &CompositeConfig, device_pool: Option<&DevicePool>,
+        loopback: &LoopbackConfig,
+    ) -> Result<Self> {
+        if config.sources.is_empty() {
+            return Err(anyhow!("Composite {} has no sources", config.name));
+        }
+
+        let (device, device_lease, v4l2_device) = match device_pool {
+            Some(pool) => {
+                let lease = pool.lease(check_device).with_context(|| {
+                    format!("No free device for composite {}", config.name)
+                })?;
+                (lease.path().to_string(), Some(lease), None)
+            }
+            None => {
+                let video_prop = VideoProp {
+                    resolution: (config.width, config.height),
+                    fps: config.fps,
+                };
+                let device_config =
+                    device_config(&config.name, &video_prop, loopback);
+                let v4l2_device = V4l2Device::new(device_config).await?;
+                let path = v4l2_device.path.to_string_lossy().to_string();
+                (path, None, Some(v4l2_device))
+            }
+        };
+
+        gst::init()?;
+        let pipeline =
+            gst::parse::launch(&launch_description(config, &device))?;
+
+        let name = config.name.clone();
+        if let Some(bus) = pipeline.bus() {
+            bus.set_sync_handler(move |_, msg| {
+                if let gst::MessageView::Error(err) = msg.view() {
+                    error!("Composite {} failed: {}", name, err.error());
+                }
+                gst::BusSyncReply::Drop
+            });
+        }
+        pipeline.set_state(gst::State::Playing)?;
+
+        info!("Composite {} available at {}", config.name, device);
+
+        Ok(Self {
+            sources: config.sources.clone(),
+            pipeline,
+            _device_lease: device_lease,
+            _v4l2_device: v4l2_device,
+        })
+    }
+
+    /// Whether the camera of the virtual device is shown in the composite
+    pub fn has_source(&self, vdevice_name: &str) -> bool {
+        self.sources.iter().any(|source| source == vdevice_name)
+    }
+}
+
+impl Drop for CompositeDevice {
+    fn drop(&mut self) {
+        if let Err(e) = self.pipeline.set_state(gst::State::Null) {
+            error!("Failed to stop the composite: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiles_of_layouts() {
+        let grid = tiles(CompositeLayout::Grid, 3, 1280, 720);
+        assert_eq!(
+            grid,
+            vec![
+                Tile { x: 0, y: 0, width: 640, height: 360 },
+                Tile { x: 640, y: 0, width: 640, height: 360 },
+                Tile { x: 0, y: 360, width: 640, height: 360 },
+            ]
+        );
+
+        let pip = tiles(CompositeLayout::Pip, 2, 1280, 720);
+        assert_eq!(pip[0], Tile { x: 0, y: 0, width: 1280, height: 720 });
+        assert_eq!(pip[1], Tile { x: 928, y: 508, width: 320, height: 180 });
+    }
+}
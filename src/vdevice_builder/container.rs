@@ -0,0 +1,287 @@
+//! Container support.
+//! Inside a container the kernel modules can't be managed and the loopback
+//! devices can't be created, they must be created on the host and passed
+//! through to the container, e.g.:
+//!
+//! ```sh
+//! sudo modprobe v4l2loopback video_nr=10,11 exclusive_caps=1
+//! docker run --device /dev/video10 --device /dev/video11 ...
+//! ```
+
+use std::{
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::error::Result;
+use anyhow::anyhow;
+
+//capability bits, see linux/capability.h
+const CAP_SYS_MODULE: u32 = 16;
+const CAP_MKNOD: u32 = 27;
+
+//major number of the video4linux devices
+const VIDEO_MAJOR: u32 = 81;
+
+/// Effective capabilities from the content of /proc/<pid>/status
+pub fn parse_cap_eff(status: &str) -> Option<u64> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+}
+
+fn has_capability(cap_eff: u64, cap: u32) -> bool {
+    cap_eff & (1 << cap) != 0
+}
+
+//cgroup paths of the common container runtimes
+const CONTAINER_CGROUPS: [&str; 5] =
+    ["/docker", "/lxc", "/kubepods", "/containerd", "/libpod"];
+
+/// Whether the environment of pid 1, NUL separated, names a container
+/// runtime with `container=`, as systemd, podman and lxc set it
+pub fn has_container_env(environ: &[u8]) -> bool {
+    environ
+        .split(|byte| *byte == 0)
+        .any(|var| var.starts_with(b"container=") && var.len() > 10)
+}
+
+/// Whether the content of /proc/<pid>/cgroup is under a container runtime
+pub fn has_container_cgroup(cgroup: &str) -> bool {
+    cgroup.lines().filter_map(|line| line.splitn(3, ':').nth(2)).any(|path| {
+        CONTAINER_CGROUPS.iter().any(|runtime| path.contains(runtime))
+    })
+}
+
+/// Whether the process runs in a container, from the markers of the
+/// runtimes: their files, the environment of pid 1 and the cgroup
+pub fn is_containerized() -> bool {
+    if Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || Path::new("/run/systemd/container").exists()
+    {
+        return true;
+    }
+
+    //only readable as root, an unprivileged host run skips it
+    if fs::read("/proc/1/environ").is_ok_and(|env| has_container_env(&env)) {
+        return true;
+    }
+
+    fs::read_to_string("/proc/self/cgroup")
+        .is_ok_and(|cgroup| has_container_cgroup(&cgroup))
+}
+
+/// Capabilities missing to load the modules and create the device nodes on
+/// the host, empty if unknown
+pub fn missing_capabilities() -> Vec<&'static str> {
+    let Some(cap_eff) = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| parse_cap_eff(&status))
+    else {
+        return Vec::new();
+    };
+
+    [(CAP_SYS_MODULE, "CAP_SYS_MODULE"), (CAP_MKNOD, "CAP_MKNOD")]
+        .into_iter()
+        .filter(|(cap, _)| !has_capability(cap_eff, *cap))
+        .map(|(_, name)| name)
+        .collect()
+}
+
+/// Major number of a device id, glibc encoding
+pub fn dev_major(rdev: u64) -> u32 {
+    (((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff)) as u32
+}
+
+/// Checks a device passed to the container is a video device the process
+/// can read and write
+pub fn verify_device(path: &str) -> Result<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow!(
+                "{} not found, create it on the host with `modprobe \
+                 v4l2loopback video_nr=<N> exclusive_caps=1` and pass it \
+                 with `--device {}`",
+                path,
+                path
+            ));
+        }
+        Err(e) => return Err(anyhow!("Failed to stat {}: {:?}", path, e)),
+    };
+
+    if !metadata.file_type().is_char_device()
+        || dev_major(metadata.rdev()) != VIDEO_MAJOR
+    {
+        return Err(anyhow!(
+            "{} is not a video device, pass the host device with \
+             `--device {}` instead of mounting it",
+            path,
+            path
+        ));
+    }
+
+    if let Err(e) = OpenOptions::new().read(true).write(true).open(path) {
+        return Err(anyhow!(
+            "No read/write access to {} ({}), allow it in the device cgroup \
+             with `--device-cgroup-rule 'c {}:* rmw'` and add the user to \
+             the video group",
+            path,
+            e,
+            VIDEO_MAJOR
+        ));
+    }
+
+    Ok(())
+}
+
+/// Pre-created devices, leased one per camera
+#[derive(Debug, Clone)]
+pub struct DevicePool {
+    free: Arc<Mutex<Vec<String>>>,
+}
+
+impl DevicePool {
+    pub fn new(devices: Vec<String>) -> Self {
+        //leased from the end, keep the configured order
+        let free = devices.into_iter().rev().collect();
+        Self { free: Arc::new(Mutex::new(free)) }
+    }
+
+    /// Takes the first free device passing `check`, e.g. a device another
+    /// process doesn't write to. Fails with the error of the first device
+    /// checked if none passes.
+    pub fn lease(
+        &self, check: impl Fn(&str) -> Result<()>,
+    ) -> Result<DeviceLease> {
+        let mut free = self
+            .free
+            .lock()
+            .map_err(|_| anyhow!("Device pool lock poisoned"))?;
+        if free.is_empty() {
+            return Err(anyhow!(
+                "No free device, pass more devices to the container"
+            ));
+        }
+
+        let mut first_error = None;
+        for index in (0..free.len()).rev() {
+            match check(&free[index]) {
+                Ok(()) => {
+                    let path = free.remove(index);
+                    return Ok(DeviceLease { path, free: self.free.clone() });
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| anyhow!("No device checked")))
+    }
+}
+
+/// Device in use by a camera, returned to the pool on drop
+#[derive(Debug)]
+pub struct DeviceLease {
+    path: String,
+    free: Arc<Mutex<Vec<String>>>,
+}
+
+impl DeviceLease {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for DeviceLease {
+    fn drop(&mut self) {
+        if let Ok(mut free) = self.free.lock() {
+            free.push(self.path.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cap_eff() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\n\
+                      CapEff:\t00000000a80425fb\nCapBnd:\t00000000a80425fb\n";
+
+        let cap_eff = parse_cap_eff(status).unwrap();
+
+        //docker default set keeps mknod but drops sys_module
+        assert!(has_capability(cap_eff, CAP_MKNOD));
+        assert!(!has_capability(cap_eff, CAP_SYS_MODULE));
+        assert_eq!(parse_cap_eff("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn test_container_markers() {
+        assert!(has_container_env(b"HOME=/\0container=podman\0TERM=xterm\0"));
+        assert!(!has_container_env(b"HOME=/\0TERM=linux\0"));
+        assert!(!has_container_env(b"container=\0"));
+
+        assert!(has_container_cgroup("0::/docker/3f2a9c\n"));
+        assert!(has_container_cgroup(
+            "12:pids:/kubepods/besteffort/pod1\n0::/\n"
+        ));
+        //a host session, whatever its capabilities
+        assert!(!has_container_cgroup(
+            "0::/user.slice/user-1000.slice/session-2.scope\n"
+        ));
+    }
+
+    #[test]
+    fn test_dev_major() {
+        //makedev(81, 10) and makedev(259, 3)
+        assert_eq!(dev_major(0x510a), 81);
+        assert_eq!(dev_major(0x10303), 259);
+    }
+
+    #[test]
+    fn test_verify_missing_device() {
+        let err = verify_device("/dev/nonexistent-video").unwrap_err();
+
+        assert!(err.to_string().contains("--device /dev/nonexistent-video"));
+    }
+
+    #[test]
+    fn test_device_pool_lease() {
+        let pool = DevicePool::new(vec![
+            "/dev/video10".to_string(),
+            "/dev/video11".to_string(),
+        ]);
+
+        let any = |_: &str| Ok(());
+        let first = pool.lease(any).unwrap();
+        let second = pool.lease(any).unwrap();
+        assert_eq!(first.path(), "/dev/video10");
+        assert_eq!(second.path(), "/dev/video11");
+        assert!(pool.lease(any).is_err());
+
+        drop(first);
+        assert_eq!(pool.lease(any).unwrap().path(), "/dev/video10");
+
+        //a device in conflict is skipped
+        let pool = DevicePool::new(vec![
+            "/dev/video10".to_string(),
+            "/dev/video11".to_string(),
+        ]);
+        let conflict = |path: &str| match path {
+            "/dev/video10" => Err(anyhow!("{} in use", path)),
+            _ => Ok(()),
+        };
+        let lease = pool.lease(conflict).unwrap();
+        assert_eq!(lease.path(), "/dev/video11");
+        let err = pool.lease(conflict).unwrap_err();
+        assert_eq!(err.to_string(), "/dev/video10 in use");
+    }
+}
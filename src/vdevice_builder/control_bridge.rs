@@ -0,0 +1,200 @@
+//! Bridge between the standard V4L2 image controls of the loopback device and
+//! the videobalance element of the pipeline, so the image can be tweaked from
+//! the camera settings of the consuming app.
+//!
+//! v4l2loopback only exposes its own controls, e.g. `keep_format` or
+//! `timeout`, none of the image controls, so with it the bridge stays
+//! disabled and nothing is polled. It applies to loopback drivers that
+//! expose the standard image controls.
+
+use gst::{glib, prelude::*};
+use log::{debug, info, warn};
+use std::time::Duration;
+use v4l::{control::Value, Device};
+
+/// Interval to poll the device controls
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// V4L2 control mapped to a videobalance property
+#[derive(Debug, PartialEq)]
+pub struct BalanceControl {
+    /// V4L2 control id
+    pub cid: u32,
+    /// videobalance property name
+    pub property: &'static str,
+    /// videobalance property range and neutral value
+    pub min: f64,
+    pub neutral: f64,
+    pub max: f64,
+}
+
+pub const BALANCE_CONTROLS: [BalanceControl; 4] = [
+    //V4L2_CID_BRIGHTNESS
+    BalanceControl {
+        cid: 0x0098_0900,
+        property: "brightness",
+        min: -1.0,
+        neutral: 0.0,
+        max: 1.0,
+    },
+    //V4L2_CID_CONTRAST
+    BalanceControl {
+        cid: 0x0098_0901,
+        property: "contrast",
+        min: 0.0,
+        neutral: 1.0,
+        max: 2.0,
+    },
+    //V4L2_CID_SATURATION
+    BalanceControl {
+        cid: 0x0098_0902,
+        property: "saturation",
+        min: 0.0,
+        neutral: 1.0,
+        max: 2.0,
+    },
+    //V4L2_CID_HUE
+    BalanceControl {
+        cid: 0x0098_0903,
+        property: "hue",
+        min: -1.0,
+        neutral: 0.0,
+        max: 1.0,
+    },
+];
+
+/// Maps a V4L2 control value to the videobalance range, the control default
+/// maps to the neutral value so untouched controls leave the image as is.
+pub fn map_control(
+    control: &BalanceControl, value: i64, min: i64, default: i64, max: i64,
+) -> f64 {
+    let value = value.clamp(min, max);
+
+    if value < default && default > min {
+        let ratio = (default - value) as f64 / (default - min) as f64;
+        control.neutral - ratio * (control.neutral - control.min)
+    } else if value > default && max > default {
+        let ratio = (value - default) as f64 / (max - default) as f64;
+        control.neutral + ratio * (control.max - control.neutral)
+    } else {
+        control.neutral
+    }
+}
+
+/// Control of the device being watched
+struct WatchedControl {
+    control: &'static BalanceControl,
+    min: i64,
+    default: i64,
+    max: i64,
+    last: Option<i64>,
+}
+
+/// Polls the image controls of the device and applies the changes to the
+/// videobalance element, returns `None` if the device exposes none of them.
+pub fn watch_controls(
+    vdevice: &str, videobalance: gst::Element,
+) -> Option<glib::SourceId> {
+    let device = match Device::with_path(vdevice) {
+        Ok(device) => device,
+        Err(e) => {
+            warn!("Failed to open {} to watch controls: {:?}", vdevice, e);
+            return None;
+        }
+    };
+
+    let descriptions = match device.query_controls() {
+        Ok(descriptions) => descriptions,
+        Err(e) => {
+            warn!("Failed to query controls of {}: {:?}", vdevice, e);
+            return None;
+        }
+    };
+
+    let mut watched: Vec<WatchedControl> = BALANCE_CONTROLS
+        .iter()
+        .filter_map(|control| {
+            let desc = descriptions.iter().find(|d| d.id == control.cid)?;
+            Some(WatchedControl {
+                control,
+                min: desc.minimum,
+                default: desc.default,
+                max: desc.maximum,
+                last: None,
+            })
+        })
+        .collect();
+
+    if watched.is_empty() {
+        //the case of v4l2loopback, no timer is left running
+        info!("{} exposes no image controls, control bridge disabled", vdevice);
+        return None;
+    }
+
+    info!(
+        "Bridging controls {:?} of {}",
+        watched.iter().map(|w| w.control.property).collect::<Vec<_>>(),
+        vdevice
+    );
+
+    Some(glib::timeout_add(POLL_INTERVAL, move || {
+        for watched in watched.iter_mut() {
+            let Ok(ctrl) = device.control(watched.control.cid) else {
+                continue;
+            };
+
+            let Value::Integer(value) = ctrl.value else {
+                continue;
+            };
+
+            if watched.last == Some(value) {
+                continue;
+            }
+
+            let mapped = map_control(
+                watched.control,
+                value,
+                watched.min,
+                watched.default,
+                watched.max,
+            );
+
+            debug!("Control {} set to {}", watched.control.property, mapped);
+            videobalance.set_property(watched.control.property, mapped);
+            watched.last = Some(value);
+        }
+
+        glib::ControlFlow::Continue
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_control_default_is_neutral() {
+        for control in BALANCE_CONTROLS.iter() {
+            assert_eq!(map_control(control, 128, 0, 128, 255), control.neutral);
+        }
+    }
+
+    #[test]
+    fn test_map_control_range() {
+        let contrast = &BALANCE_CONTROLS[1];
+
+        assert_eq!(map_control(contrast, 0, 0, 50, 100), 0.0);
+        assert_eq!(map_control(contrast, 25, 0, 50, 100), 0.5);
+        assert_eq!(map_control(contrast, 100, 0, 50, 100), 2.0);
+        //out of range values are clamped
+        assert_eq!(map_control(contrast, 500, 0, 50, 100), 2.0);
+    }
+
+    #[test]
+    fn test_map_control_asymmetric_default() {
+        let brightness = &BALANCE_CONTROLS[0];
+
+        assert_eq!(map_control(brightness, -64, -64, 0, 192), -1.0);
+        assert_eq!(map_control(brightness, 96, -64, 0, 192), 0.5);
+    }
+}
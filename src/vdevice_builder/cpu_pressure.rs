@@ -0,0 +1,94 @@
+//! Quality level of the pipelines driven by the host CPU utilization.
+//! The quality is reduced above the high threshold and restored once the
+//! utilization goes below the low threshold, the gap avoids flapping.
+
+use crate::config::CpuPressureConfig;
+use log::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QualityLevel {
+    /// Output at the resolution sent by the mobile
+    Full,
+    /// Output scaled down to lower the host load
+    Reduced,
+}
+
+#[derive(Debug)]
+pub struct CpuPressure {
+    high_percent: f32,
+    low_percent: f32,
+    level: QualityLevel,
+}
+
+impl CpuPressure {
+    pub fn new(config: &CpuPressureConfig) -> Self {
+        Self {
+            high_percent: config.high_percent,
+            low_percent: config.low_percent,
+            level: QualityLevel::Full,
+        }
+    }
+
+    pub fn level(&self) -> QualityLevel {
+        self.level
+    }
+
+    /// Updates the pressure with a new host CPU sample, returns the new
+    /// quality level only when it changes
+    pub fn update(&mut self, host_cpu_percent: f32) -> Option<QualityLevel> {
+        let level = match self.level {
+            QualityLevel::Full if host_cpu_percent > self.high_percent => {
+                QualityLevel::Reduced
+            }
+            QualityLevel::Reduced if host_cpu_percent < self.low_percent => {
+                QualityLevel::Full
+            }
+            level => level,
+        };
+
+        if level == self.level {
+            return None;
+        }
+
+        info!(
+            "Host CPU at {:.1}%, changing quality to {:?}",
+            host_cpu_percent, level
+        );
+        self.level = level;
+
+        Some(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressure() -> CpuPressure {
+        CpuPressure::new(&CpuPressureConfig {
+            enabled: true,
+            high_percent: 80.0,
+            low_percent: 50.0,
+            sample_secs: 5,
+        })
+    }
+
+    #[test]
+    fn test_reduce_above_high_threshold() {
+        let mut pressure = pressure();
+
+        assert_eq!(pressure.update(70.0), None);
+        assert_eq!(pressure.update(85.0), Some(QualityLevel::Reduced));
+        assert_eq!(pressure.update(90.0), None);
+    }
+
+    #[test]
+    fn test_restore_below_low_threshold() {
+        let mut pressure = pressure();
+
+        assert_eq!(pressure.update(85.0), Some(QualityLevel::Reduced));
+        //between the thresholds the level is kept
+        assert_eq!(pressure.update(60.0), None);
+        assert_eq!(pressure.update(40.0), Some(QualityLevel::Full));
+    }
+}
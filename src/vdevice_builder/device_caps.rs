@@ -0,0 +1,64 @@
+//! Capabilities mode of the loopback devices.
+//! With `exclusive_caps=1` a free device only offers output, and capture
+//! once a producer writes to it, with `exclusive_caps=0` it offers both at
+//! all times. Some Chrome versions only list the first kind, other
+//! consumers fail on it, so a camera is leased a device of the mode its
+//! virtual device needs. The devices added at runtime always have exclusive
+//! caps, the other mode needs the devices created by the module options.
+
+use anyhow::anyhow;
+use v4l::{capability::Flags, Device};
+
+use crate::{config::CapsMode, error::Result};
+
+/// Mode of a free device from its capabilities, none if a producer writes
+/// to it
+pub fn caps_mode(capture: bool, output: bool) -> Option<CapsMode> {
+    match (capture, output) {
+        (false, true) => Some(CapsMode::Exclusive),
+        (true, true) => Some(CapsMode::All),
+        _ => None,
+    }
+}
+
+/// Fails if the free `device` announces the capabilities of the other mode,
+/// a device in use is left to the conflict check
+pub fn check_caps(device: &str, mode: CapsMode) -> Result<()> {
+    let caps = Device::with_path(device)?.query_caps()?.capabilities;
+    match caps_mode(
+        caps.contains(Flags::VIDEO_CAPTURE),
+        caps.contains(Flags::VIDEO_OUTPUT),
+    ) {
+        Some(announced) if announced != mode => Err(anyhow!(
+            "{} announces {:?} caps, {:?} caps needed",
+            device,
+            announced,
+            mode
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LoopbackConfig;
+
+    #[test]
+    fn test_caps_mode() {
+        assert_eq!(caps_mode(false, true), Some(CapsMode::Exclusive));
+        assert_eq!(caps_mode(true, true), Some(CapsMode::All));
+        //exclusive caps with a producer writing
+        assert_eq!(caps_mode(true, false), None);
+
+        let loopback: LoopbackConfig =
+            serde_json::from_value(serde_json::json!({
+                "caps": "all",
+                "device_caps": { "Pixel: back": "exclusive" }
+            }))
+            .unwrap();
+        assert_eq!(loopback.caps_for("Pixel: back"), CapsMode::Exclusive);
+        assert_eq!(loopback.caps_for("Pixel: front"), CapsMode::All);
+        assert_eq!(loopback.caps.exclusive_caps(), 0);
+    }
+}
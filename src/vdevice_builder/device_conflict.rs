@@ -0,0 +1,187 @@
+//! Conflicting camera software.
+//! The devices passed to a container may also be chosen by another
+//! producer on the host, e.g. the virtual camera of OBS or droidcam, and
+//! both producers writing to a device interleave their frames. A device
+//! another process writes to is skipped when leasing one, and reported with
+//! the names of the processes if no other device is free.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{ble::comm_types::DeviceConflict, error::Result};
+use v4l::{capability::Flags, Device};
+
+//access mode bits of the open flags, see fcntl.h
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+
+/// Process having a device open
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceHolder {
+    pub pid: u32,
+    pub name: String,
+    /// Opened write-only, as a producer
+    pub writes: bool,
+}
+
+/// Processes other than this one having `device` open, the processes of
+/// other users are only visible with the privileges to read their fds
+pub fn device_holders(proc_dir: &Path, device: &str) -> Vec<DeviceHolder> {
+    let device = fs::canonicalize(device).unwrap_or(PathBuf::from(device));
+    let Ok(entries) = fs::read_dir(proc_dir) else {
+        return Vec::new();
+    };
+
+    let mut holders = Vec::new();
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse().ok())
+        else {
+            continue;
+        };
+        if pid == std::process::id() {
+            continue;
+        }
+
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        let device_fds: Vec<_> = fds
+            .flatten()
+            .filter(|fd| fs::read_link(fd.path()).is_ok_and(|t| t == device))
+            .map(|fd| fd.file_name())
+            .collect();
+        if device_fds.is_empty() {
+            continue;
+        }
+
+        let writes = device_fds.iter().any(|fd| {
+            let fdinfo = entry.path().join("fdinfo").join(fd);
+            fs::read_to_string(fdinfo).is_ok_and(|info| is_write_only(&info))
+        });
+        let name = fs::read_to_string(entry.path().join("comm"))
+            .map(|comm| comm.trim().to_string())
+            .unwrap_or_else(|_| format!("pid {}", pid));
+        holders.push(DeviceHolder { pid, name, writes });
+    }
+
+    holders.sort_by_key(|holder| holder.pid);
+    holders
+}
+
+//the open flags of /proc/<pid>/fdinfo/<fd> are in octal
+fn is_write_only(fdinfo: &str) -> bool {
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| flags & O_ACCMODE == O_WRONLY)
+}
+
+/// With `exclusive_caps=1` a loopback device only offers capture once a
+/// producer writes to it
+fn has_producer(device: &str) -> bool {
+    Device::with_path(device).and_then(|device| device.query_caps()).is_ok_and(
+        |caps| {
+            caps.capabilities.contains(Flags::VIDEO_CAPTURE)
+                && !caps.capabilities.contains(Flags::VIDEO_OUTPUT)
+        },
+    )
+}
+
+/// The conflict of `device` if another process produces on it, a producer
+/// opening the device for reading too is one of its holders
+fn find_conflict(
+    device: &str, holders: &[DeviceHolder], has_producer: bool,
+) -> Result<()> {
+    let writes = holders.iter().any(|holder| holder.writes);
+    if !writes && !has_producer {
+        return Ok(());
+    }
+
+    let mut names: Vec<&str> = holders
+        .iter()
+        .filter(|holder| holder.writes || !writes)
+        .map(|holder| holder.name.as_str())
+        .collect();
+    names.sort();
+    names.dedup();
+    Err(DeviceConflict {
+        device: device.to_string(),
+        holders: match names.is_empty() {
+            true => "a process not visible to the host".to_string(),
+            false => names.join(", "),
+        },
+    }
+    .into())
+}
+
+/// Fails if another process writes to `device`
+pub fn check_device(device: &str) -> Result<()> {
+    find_conflict(
+        device,
+        &device_holders(Path::new("/proc"), device),
+        has_producer(device),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn process(
+        proc_dir: &Path, pid: u32, name: &str, target: &Path, flags: &str,
+    ) {
+        let dir = proc_dir.join(pid.to_string());
+        fs::create_dir_all(dir.join("fd")).unwrap();
+        fs::create_dir_all(dir.join("fdinfo")).unwrap();
+        fs::write(dir.join("comm"), format!("{}\n", name)).unwrap();
+        symlink(target, dir.join("fd").join("7")).unwrap();
+        fs::write(
+            dir.join("fdinfo").join("7"),
+            format!("pos:\t0\nflags:\t{}\nmnt_id:\t25\n", flags),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_conflicting_producer_found() {
+        let root = std::env::temp_dir()
+            .join(format!("wcd-device-conflict-{}", std::process::id()));
+        let device = root.join("video10");
+        let proc_dir = root.join("proc");
+        fs::create_dir_all(&proc_dir).unwrap();
+        fs::write(&device, "").unwrap();
+        let other = root.join("video11");
+        fs::write(&other, "").unwrap();
+
+        process(&proc_dir, 4242, "obs", &device, "0100001");
+        process(&proc_dir, 4343, "zoom", &device, "0100002");
+        process(&proc_dir, 4444, "droidcam", &other, "0100001");
+        //this process is not a conflict
+        process(&proc_dir, std::process::id(), "wcd", &device, "0100001");
+
+        let path = device.to_str().unwrap();
+        let holders = device_holders(&proc_dir, path);
+        assert_eq!(
+            holders,
+            vec![
+                DeviceHolder { pid: 4242, name: "obs".into(), writes: true },
+                DeviceHolder { pid: 4343, name: "zoom".into(), writes: false },
+            ]
+        );
+
+        let err = find_conflict(path, &holders, false).unwrap_err();
+        let conflict = err.downcast_ref::<DeviceConflict>().unwrap();
+        assert_eq!(conflict.holders, "obs");
+
+        //a reader alone is a consumer, unless the device has a producer
+        assert!(find_conflict(path, &holders[1..], false).is_ok());
+        let err = find_conflict(path, &holders[1..], true).unwrap_err();
+        assert!(err.to_string().contains("in use by zoom"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
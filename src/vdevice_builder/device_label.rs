@@ -0,0 +1,222 @@
+//! Labels of the loopback devices.
+//! The names of the phones often carry emoji and accented letters, which
+//! some v4l2 consumers truncate or garble. The label of a device is the name
+//! transliterated to ASCII as in the language of the host, without the
+//! emoji, and with a `(2)` suffix while another device has the same label.
+//! The original name is kept everywhere else, e.g. in the stream stats.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+/// Bytes of the card name of a v4l2 device, without the final NUL
+const MAX_LABEL_LEN: usize = 31;
+
+//label of a name left without any letter
+const DEFAULT_LABEL: &str = "Camera";
+
+/// Language of the host from the locale, e.g. `de` for `de_DE.UTF-8`
+pub fn language_from_env() -> String {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .and_then(|locale| {
+            locale.split(['_', '.', '@']).next().map(str::to_lowercase)
+        })
+        .unwrap_or_default()
+}
+
+//the umlauts are spelled out in German, the Nordic letters in Danish and
+//Norwegian
+fn transliterate(c: char, language: &str) -> Option<&'static str> {
+    let german = language == "de";
+    let nordic = matches!(language, "da" | "nb" | "nn" | "no");
+
+    Some(match c {
+        'Ä' if german => "Ae",
+        'Ö' if german => "Oe",
+        'Ü' if german => "Ue",
+        'ä' if german => "ae",
+        'ö' if german => "oe",
+        'ü' if german => "ue",
+        'Å' if nordic => "Aa",
+        'Ø' if nordic => "Oe",
+        'å' if nordic => "aa",
+        'ø' if nordic => "oe",
+        'À'..='Å' | 'Ā' | 'Ă' | 'Ą' => "A",
+        'à'..='å' | 'ā' | 'ă' | 'ą' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' | 'Ć' | 'Č' => "C",
+        'ç' | 'ć' | 'č' => "c",
+        'Ð' | 'Ď' | 'Đ' => "D",
+        'ð' | 'ď' | 'đ' => "d",
+        'È'..='Ë' | 'Ē' | 'Ė' | 'Ę' | 'Ě' => "E",
+        'è'..='ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+        'Ğ' => "G",
+        'ğ' => "g",
+        'Ì'..='Ï' | 'Ī' | 'Į' | 'İ' => "I",
+        'ì'..='ï' | 'ī' | 'į' | 'ı' => "i",
+        'Ł' => "L",
+        'ł' => "l",
+        'Ñ' | 'Ń' | 'Ň' => "N",
+        'ñ' | 'ń' | 'ň' => "n",
+        'Ò'..='Ö' | 'Ø' | 'Ō' | 'Ő' => "O",
+        'ò'..='ö' | 'ø' | 'ō' | 'ő' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ř' => "R",
+        'ř' => "r",
+        'Ś' | 'Š' | 'Ş' => "S",
+        'ś' | 'š' | 'ş' => "s",
+        'ß' => "ss",
+        'Ť' | 'Ţ' => "T",
+        'ť' | 'ţ' => "t",
+        'Þ' => "Th",
+        'þ' => "th",
+        'Ù'..='Ü' | 'Ū' | 'Ů' | 'Ű' | 'Ų' => "U",
+        'ù'..='ü' | 'ū' | 'ů' | 'ű' | 'ų' => "u",
+        'Ý' | 'Ÿ' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Ź' | 'Ż' | 'Ž' => "Z",
+        'ź' | 'ż' | 'ž' => "z",
+        '‘' | '’' => "'",
+        '“' | '”' => "\"",
+        '–' | '—' => "-",
+        '…' => "...",
+        _ => return None,
+    })
+}
+
+/// Label of `name` in `language`, the letters of other scripts are kept,
+/// the emoji and the symbols dropped
+pub fn normalize(name: &str, language: &str) -> String {
+    let mut label = String::new();
+    //the space before a dropped emoji goes with it, e.g. `Pixel 📱: back`
+    let mut dropped = false;
+    for c in name.chars() {
+        if c.is_whitespace() || c.is_control() {
+            label.push(' ');
+            continue;
+        }
+
+        if dropped && c.is_ascii_punctuation() {
+            label.truncate(label.trim_end().len());
+        }
+        if let Some(ascii) = transliterate(c, language) {
+            label.push_str(ascii);
+        } else if c.is_ascii() || c.is_alphanumeric() {
+            label.push(c);
+        } else {
+            dropped = true;
+            continue;
+        }
+        dropped = false;
+    }
+
+    let label = label.split_whitespace().collect::<Vec<_>>().join(" ");
+    if label.chars().any(char::is_alphanumeric) {
+        label
+    } else {
+        DEFAULT_LABEL.to_string()
+    }
+}
+
+//`label` cut to `max_len` bytes on a character boundary
+fn truncate(label: &str, max_len: usize) -> &str {
+    let mut end = label.len().min(max_len);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].trim_end()
+}
+
+/// Labels of the devices in use
+#[derive(Debug, Clone)]
+pub struct DeviceLabels {
+    language: String,
+    in_use: Arc<Mutex<HashSet<String>>>,
+}
+
+impl DeviceLabels {
+    pub fn new(language: String) -> Self {
+        Self { language, in_use: Arc::default() }
+    }
+
+    /// Label of the device of `name`, suffixed with the first free number
+    /// while the label is in use
+    pub fn claim(&self, name: &str) -> LabelClaim {
+        let base = normalize(name, &self.language);
+        let mut label = truncate(&base, MAX_LABEL_LEN).to_string();
+        let Ok(mut in_use) = self.in_use.lock() else {
+            return LabelClaim { label, in_use: Arc::default() };
+        };
+
+        let mut number = 2;
+        while in_use.contains(&label) {
+            let suffix = format!(" ({})", number);
+            let base = truncate(&base, MAX_LABEL_LEN - suffix.len());
+            label = format!("{}{}", base, suffix);
+            number += 1;
+        }
+        in_use.insert(label.clone());
+
+        LabelClaim { label, in_use: self.in_use.clone() }
+    }
+}
+
+/// Label of a device, free again on drop
+#[derive(Debug)]
+pub struct LabelClaim {
+    label: String,
+    in_use: Arc<Mutex<HashSet<String>>>,
+}
+
+impl LabelClaim {
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+impl Drop for LabelClaim {
+    fn drop(&mut self) {
+        if let Ok(mut in_use) = self.in_use.lock() {
+            in_use.remove(&self.label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels_normalized_and_unique() {
+        assert_eq!(normalize("Zoë's 📱 Pixel: back", ""), "Zoe's Pixel: back");
+        assert_eq!(
+            normalize("Jürgen’s Handy: front", "de"),
+            "Juergen's Handy: front"
+        );
+        assert_eq!(normalize("Søren: back", "da"), "Soeren: back");
+        assert_eq!(normalize("Дима: front", ""), "Дима: front");
+        assert_eq!(normalize("🔥🔥", ""), "Camera");
+
+        let labels = DeviceLabels::new(String::new());
+        let first = labels.claim("iPhone 😀: back");
+        let second = labels.claim("iPhone: back");
+        assert_eq!(first.label(), "iPhone: back");
+        assert_eq!(second.label(), "iPhone: back (2)");
+
+        //the suffix fits in the card name
+        let long = labels.claim("A very long phone name of someone");
+        let long_2 = labels.claim("A very long phone name of someone");
+        assert_eq!(long.label(), "A very long phone name of someo");
+        assert_eq!(long_2.label(), "A very long phone name of s (2)");
+
+        //free again once the device is gone
+        drop(first);
+        assert_eq!(labels.claim("iPhone: back").label(), "iPhone: back");
+    }
+}
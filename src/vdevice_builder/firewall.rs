@@ -0,0 +1,259 @@
+//! # Session firewall.
+//! Hosts serving the hotspot often leave their firewall open on its
+//! interface. With `session_firewall` set, the inbound UDP of the access
+//! point is restricted, besides DHCP and DNS, to the ICE ports of the calls
+//! in progress from the addresses of their mobile, and to the ports of the
+//! link tests. The ports of a camera are closed once it stops, the table is
+//! removed with the builder.
+
+use std::{
+    io::Write,
+    net::IpAddr,
+    process::{Command, Stdio},
+};
+
+use anyhow::anyhow;
+use log::{debug, error, info, warn};
+
+use crate::error::Result;
+
+/// Table of the rules, apart from the ones of the host
+const TABLE: &str = "webcam_direct";
+
+/// Interface of the access point
+const INTERFACE: &str = "wcdirect0";
+
+/// Host ports and mobile addresses of the media of a camera
+#[derive(Debug, Default, PartialEq)]
+pub struct MediaEndpoints {
+    pub ports: Vec<u16>,
+    pub addresses: Vec<IpAddr>,
+}
+
+//`a=candidate:<foundation> <component> <transport> <priority> <address>
+//<port> typ <type>`, the udp host candidates only
+fn host_candidates(sdp: &str) -> impl Iterator<Item = (&str, u16)> {
+    sdp.lines().filter_map(|line| {
+        let fields: Vec<&str> = line
+            .trim()
+            .strip_prefix("a=candidate:")?
+            .split_whitespace()
+            .collect();
+        match fields.as_slice() {
+            [_, _, transport, _, address, port, "typ", "host", ..]
+                if transport.eq_ignore_ascii_case("udp") =>
+            {
+                Some((*address, port.parse().ok()?))
+            }
+            _ => None,
+        }
+    })
+}
+
+impl MediaEndpoints {
+    /// Ports of the local description and addresses of the remote one, a
+    /// mobile hiding its addresses behind mDNS names has none
+    pub fn from_sdp(local_sdp: &str, remote_sdp: &str) -> Self {
+        let mut endpoints = Self::default();
+        for (_, port) in host_candidates(local_sdp) {
+            if !endpoints.ports.contains(&port) {
+                endpoints.ports.push(port);
+            }
+        }
+        for (address, _) in host_candidates(remote_sdp) {
+            let Ok(address) = address.parse() else {
+                continue;
+            };
+            if !endpoints.addresses.contains(&address) {
+                endpoints.addresses.push(address);
+            }
+        }
+        endpoints
+    }
+
+    //set elements of the endpoints, by set name
+    fn elements(&self) -> Vec<(&'static str, String)> {
+        if self.addresses.is_empty() {
+            return self
+                .ports
+                .iter()
+                .map(|port| ("media_ports", port.to_string()))
+                .collect();
+        }
+
+        let mut elements = Vec::new();
+        for address in self.addresses.iter() {
+            let set = match address {
+                IpAddr::V4(_) => "media4",
+                IpAddr::V6(_) => "media6",
+            };
+            for port in self.ports.iter() {
+                elements.push((set, format!("{} . {}", address, port)));
+            }
+        }
+        elements
+    }
+}
+
+/// Rules of the access point, the previous table is replaced
+fn ruleset() -> String {
+    format!(
+        "table inet {table}\n\
+         delete table inet {table}\n\
+         table inet {table} {{\n\
+         \tset media4 {{ type ipv4_addr . inet_service; }}\n\
+         \tset media6 {{ type ipv6_addr . inet_service; }}\n\
+         \tset media_ports {{ type inet_service; }}\n\
+         \tchain input {{\n\
+         \t\ttype filter hook input priority filter; policy accept;\n\
+         \t\tiifname != \"{interface}\" accept\n\
+         \t\tmeta l4proto != udp accept\n\
+         \t\tct state established,related accept\n\
+         \t\tudp dport {{ 53, 67 }} accept\n\
+         \t\tip saddr . udp dport @media4 accept\n\
+         \t\tip6 saddr . udp dport @media6 accept\n\
+         \t\tudp dport @media_ports accept\n\
+         \t\tdrop\n\
+         \t}}\n\
+         }}\n",
+        table = TABLE,
+        interface = INTERFACE,
+    )
+}
+
+//the element commands of `verb`, `add` or `delete`
+fn element_script(verb: &str, elements: &[(&str, String)]) -> String {
+    elements
+        .iter()
+        .map(|(set, element)| {
+            format!(
+                "{} element inet {} {} {{ {} }}\n",
+                verb, TABLE, set, element
+            )
+        })
+        .collect()
+}
+
+fn nft(script: &str) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes())?;
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Table of the access point rules, removed on drop
+#[derive(Debug)]
+pub struct SessionFirewall;
+
+impl SessionFirewall {
+    pub fn install() -> Result<Self> {
+        nft(&ruleset())?;
+        info!("Inbound UDP of {} restricted to the calls", INTERFACE);
+        Ok(Self)
+    }
+
+    /// Opens the ports of `endpoints` until the returned rule is dropped
+    pub fn allow(&self, endpoints: &MediaEndpoints) -> Result<MediaRule> {
+        let elements = endpoints.elements();
+        if elements.is_empty() {
+            return Err(anyhow!("No host candidate in the call"));
+        }
+        if endpoints.addresses.is_empty() {
+            warn!("Mobile address unknown, media ports open to any address");
+        }
+
+        nft(&element_script("add", &elements))?;
+        debug!("Media ports open: {:?}", elements);
+        Ok(MediaRule { script: element_script("delete", &elements) })
+    }
+
+    /// Opens `port` to any address of the access point until the returned
+    /// rule is dropped, for the traffic before the call
+    pub fn allow_port(&self, port: u16) -> Result<MediaRule> {
+        let elements =
+            MediaEndpoints { ports: vec![port], addresses: Vec::new() }
+                .elements();
+        nft(&element_script("add", &elements))?;
+        debug!("Port {} open", port);
+        Ok(MediaRule { script: element_script("delete", &elements) })
+    }
+}
+
+impl Drop for SessionFirewall {
+    fn drop(&mut self) {
+        if let Err(e) = nft(&format!("delete table inet {}\n", TABLE)) {
+            error!("Failed to remove the session firewall: {:?}", e);
+        }
+    }
+}
+
+/// Ports of a camera, closed on drop
+#[derive(Debug)]
+pub struct MediaRule {
+    script: String,
+}
+
+impl Drop for MediaRule {
+    fn drop(&mut self) {
+        //gone with the table if the firewall was removed first
+        if let Err(e) = nft(&self.script) {
+            debug!("Media ports not closed: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: &str = "v=0\r\n\
+        a=candidate:1 1 UDP 2015363327 192.168.4.1 40000 typ host\r\n\
+        a=candidate:2 1 TCP 1015021823 192.168.4.1 9 typ host\r\n\
+        a=candidate:3 1 UDP 1678051839 203.0.113.5 40000 typ srflx raddr \
+        192.168.4.1 rport 40000\r\n\
+        a=candidate:4 1 UDP 2015363583 192.168.4.1 40002 typ host\r\n";
+
+    #[test]
+    fn test_endpoints_from_candidates() {
+        let remote = "a=candidate:1 1 udp 2122260223 192.168.4.23 51000 typ \
+                      host\r\na=candidate:2 1 udp 2122260223 \
+                      8f1c.local 51002 typ host\r\n";
+
+        let endpoints = MediaEndpoints::from_sdp(LOCAL, remote);
+        assert_eq!(endpoints.ports, vec![40000, 40002]);
+        assert_eq!(endpoints.addresses, vec![IpAddr::from([192, 168, 4, 23])]);
+        assert_eq!(
+            element_script("add", &endpoints.elements()),
+            "add element inet webcam_direct media4 { 192.168.4.23 . 40000 }\n\
+             add element inet webcam_direct media4 { 192.168.4.23 . 40002 }\n"
+        );
+    }
+
+    #[test]
+    fn test_mdns_mobile_matched_by_port() {
+        let remote = "a=candidate:1 1 udp 2122260223 8f1c.local 51000 typ host";
+
+        let endpoints = MediaEndpoints::from_sdp(LOCAL, remote);
+        assert!(endpoints.addresses.is_empty());
+        assert_eq!(
+            endpoints.elements(),
+            vec![
+                ("media_ports", "40000".to_string()),
+                ("media_ports", "40002".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! # Hardware video decoding.
+//! The VA-API and Vulkan decoders of GStreamer are registered when their
+//! plugin is installed, but they still fail on a host whose driver lacks
+//! the codec. Every known decoder decodes a test frame once at startup and
+//! the working ones are announced in the host info. The ones whose output
+//! is in system memory are ranked above the software decoders so decodebin
+//! picks them without per-machine settings.
+
+use gst::prelude::*;
+use log::{info, warn};
+
+use crate::ble::comm_types::HostCapabilities;
+
+const VAAPI: &str = "vaapi";
+const VULKAN: &str = "vulkan";
+
+/// Known hardware decoders by API and codec, the `va` plugin first, then
+/// the older `vaapi` one
+const DECODERS: [(&str, &str, &str); 5] = [
+    (VAAPI, "H264", "vah264dec"),
+    (VAAPI, "VP8", "vavp8dec"),
+    (VAAPI, "H264", "vaapih264dec"),
+    (VAAPI, "VP8", "vaapivp8dec"),
+    (VULKAN, "H264", "vulkanh264dec"),
+];
+
+/// Seconds a decoder gets to decode the test frame
+const PROBE_TIMEOUT_SECS: u64 = 5;
+
+/// Software encoders of the test frame by codec, the first one installed
+/// is used
+const ENCODERS: [(&str, &str); 3] = [
+    ("H264", "x264enc tune=zerolatency ! h264parse"),
+    ("H264", "openh264enc ! h264parse"),
+    ("VP8", "vp8enc deadline=1"),
+];
+
+//the vulkan decoders output GPU images, which decodebin would hand to the
+//pipeline as is
+fn download(api: &str) -> Option<&'static str> {
+    (api == VULKAN).then_some("vulkandownload")
+}
+
+/// Capabilities from the decoders that `decodes`, with the working
+/// decoders
+fn from_decoders(
+    decodes: impl Fn(&str, &str, &str) -> bool,
+) -> (HostCapabilities, Vec<&'static str>) {
+    let mut capabilities = HostCapabilities::default();
+    let mut working = Vec::new();
+    for (api, codec, decoder) in DECODERS {
+        if !decodes(api, codec, decoder) {
+            continue;
+        }
+        match api {
+            VAAPI => capabilities.vaapi = true,
+            _ => capabilities.vulkan = true,
+        }
+        if !capabilities.hw_codecs.iter().any(|known| known == codec) {
+            capabilities.hw_codecs.push(codec.to_string());
+        }
+        working.push(decoder);
+    }
+    (capabilities, working)
+}
+
+/// Working decoders linked by decodebin straight to the pipelines, the
+/// ones ranked
+fn preferred(working: &[&'static str]) -> Vec<&'static str> {
+    DECODERS
+        .iter()
+        .filter(|(api, _, decoder)| {
+            download(api).is_none() && working.contains(decoder)
+        })
+        .map(|(_, _, decoder)| *decoder)
+        .collect()
+}
+
+//a single frame from the encoder, decoded to system memory
+fn probe_launch(encoder: &str, api: &str, decoder: &str) -> String {
+    let download = download(api).map(|download| format!("{} ! ", download));
+    format!(
+        "videotestsrc num-buffers=1 ! \
+        video/x-raw,format=I420,width=64,height=64,framerate=30/1 ! \
+        {} ! {} ! {}video/x-raw ! fakesink name=sink",
+        encoder,
+        decoder,
+        download.unwrap_or_default()
+    )
+}
+
+//the decoder is only proven once a decoded frame reaches the sink
+fn decodes(api: &str, codec: &str, decoder: &str) -> bool {
+    if gst::ElementFactory::find(decoder).is_none() {
+        return false;
+    }
+    let encoder = ENCODERS.iter().find(|(of, encoder)| {
+        *of == codec
+            && encoder
+                .split(' ')
+                .next()
+                .and_then(gst::ElementFactory::find)
+                .is_some()
+    });
+    let Some((_, encoder)) = encoder else {
+        warn!("No {} encoder to probe {}", codec, decoder);
+        return false;
+    };
+
+    let launch = probe_launch(encoder, api, decoder);
+    let Ok(Ok(pipeline)) = gst::parse::launch(&launch)
+        .map(|element| element.downcast::<gst::Pipeline>())
+    else {
+        return false;
+    };
+    let Some(bus) = pipeline.bus() else {
+        return false;
+    };
+
+    let decoded = pipeline.set_state(gst::State::Playing).is_ok()
+        && bus
+            .timed_pop_filtered(
+                gst::ClockTime::from_seconds(PROBE_TIMEOUT_SECS),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            )
+            .is_some_and(|msg| msg.type_() == gst::MessageType::Eos)
+        && pipeline.by_name("sink").is_some_and(|sink| {
+            sink.property::<Option<gst::Sample>>("last-sample").is_some()
+        });
+    let _ = pipeline.set_state(gst::State::Null);
+    decoded
+}
+
+/// Probes the known decoders, the working ones in system memory are
+/// preferred by the pipelines if `prefer` is set
+pub fn probe_decoders(prefer: bool) -> HostCapabilities {
+    if let Err(e) = gst::init() {
+        warn!("Hardware decoders not probed: {:?}", e);
+        return HostCapabilities::default();
+    }
+
+    let (capabilities, working) = from_decoders(decodes);
+    if working.is_empty() {
+        info!("No hardware video decoder, the cameras are decoded by the CPU");
+        return capabilities;
+    }
+
+    info!("Hardware video decoders: {}", working.join(", "));
+    if prefer {
+        for decoder in preferred(&working) {
+            if let Some(factory) = gst::ElementFactory::find(decoder) {
+                factory.set_rank(gst::Rank::PRIMARY + 1);
+            }
+        }
+    }
+    capabilities
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_of_working_decoders() {
+        let (capabilities, working) = from_decoders(|_, _, decoder| {
+            ["vulkanh264dec", "vavp8dec", "vah264dec"].contains(&decoder)
+        });
+
+        assert!(capabilities.vaapi && capabilities.vulkan);
+        assert_eq!(capabilities.hw_codecs, vec!["H264", "VP8"]);
+        assert_eq!(working, vec!["vah264dec", "vavp8dec", "vulkanh264dec"]);
+
+        let (capabilities, working) = from_decoders(|_, _, _| false);
+        assert_eq!(capabilities, HostCapabilities::default());
+        assert!(working.is_empty());
+    }
+
+    #[test]
+    fn test_preferred_decoders_link_to_system_memory() {
+        let (_, working) = from_decoders(|_, _, _| true);
+
+        //the vulkan decoders work but decodebin can't link their output
+        assert_eq!(
+            preferred(&working),
+            vec!["vah264dec", "vavp8dec", "vaapih264dec", "vaapivp8dec"]
+        );
+        for (api, codec, decoder) in DECODERS {
+            let (_, encoder) =
+                ENCODERS.iter().find(|(of, _)| *of == codec).unwrap();
+            let launch = probe_launch(encoder, api, decoder);
+            let linked = format!("{} ! video/x-raw", decoder);
+            assert_eq!(
+                launch.contains(&linked),
+                preferred(&working).contains(&decoder)
+            );
+        }
+        assert!(probe_launch("x264enc", VULKAN, "vulkanh264dec")
+            .contains("vulkanh264dec ! vulkandownload ! video/x-raw"));
+    }
+}
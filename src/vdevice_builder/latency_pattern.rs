@@ -0,0 +1,200 @@
+//! # Latency test pattern.
+//! In a latency test the mobile sends frames filled by a grid of 8x6 black
+//! and white cells, white is 1. The rows are read from the top, the cells
+//! of a row from the left, the first cell being the most significant bit:
+//!
+//! - row 0: the finder byte 0xAA
+//! - rows 1-4: the time of the capture, milliseconds of the host wall clock
+//!   modulo 2^32, big-endian. The mobile takes it from the clock of the host
+//!   info.
+//! - row 5: the XOR of the four timestamp bytes and 0x5A
+//!
+//! The host samples the output of the pipeline scaled down to a few pixels
+//! per cell, so the decoding stays cheap at every frame.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ble::comm_types::LatencyStats;
+
+/// Cells of a row, the bits of a byte
+pub const COLUMNS: usize = 8;
+
+/// Rows of the pattern
+pub const ROWS: usize = 6;
+
+/// First row of every pattern
+const FINDER: u8 = 0xAA;
+
+/// Mixed into the checksum, so a uniform frame doesn't pass it
+const CHECKSUM_SEED: u8 = 0x5A;
+
+/// Difference between the black and white cells, out of 255
+const MIN_CONTRAST: u32 = 64;
+
+/// Latencies above are taken as a mobile clock off, not as samples
+const MAX_LATENCY_MS: u32 = 10_000;
+
+/// Rows of the pattern with the timestamp
+pub fn encode(timestamp: u32) -> [u8; ROWS] {
+    let [a, b, c, d] = timestamp.to_be_bytes();
+    [FINDER, a, b, c, d, a ^ b ^ c ^ d ^ CHECKSUM_SEED]
+}
+
+/// Timestamp of a frame in GRAY8, none if the frame isn't a pattern
+pub fn decode(gray: &[u8], width: usize, height: usize) -> Option<u32> {
+    if width < COLUMNS || height < ROWS || gray.len() < width * height {
+        return None;
+    }
+    //rows padded by the video frame alignment
+    let stride = gray.len() / height;
+
+    //mean of the inner half of every cell, the edges blur in the scaling
+    let (cell_width, cell_height) = (width / COLUMNS, height / ROWS);
+    let mut means = [0u32; COLUMNS * ROWS];
+    for (cell, mean) in means.iter_mut().enumerate() {
+        let (row, column) = (cell / COLUMNS, cell % COLUMNS);
+        let top = row * cell_height + cell_height / 4;
+        let left = column * cell_width + cell_width / 4;
+        let (rows, columns) =
+            ((cell_height / 2).max(1), (cell_width / 2).max(1));
+
+        let sum: u32 = (top..top + rows)
+            .flat_map(|y| {
+                let start = y * stride + left;
+                &gray[start..start + columns]
+            })
+            .map(|pixel| *pixel as u32)
+            .sum();
+        *mean = sum / (rows * columns) as u32;
+    }
+
+    let (min, max) = means.iter().fold((u32::MAX, 0), |(min, max), mean| {
+        (min.min(*mean), max.max(*mean))
+    });
+    if max - min < MIN_CONTRAST {
+        return None;
+    }
+    let threshold = (min + max) / 2;
+
+    let mut rows = [0u8; ROWS];
+    for (row, byte) in rows.iter_mut().enumerate() {
+        *byte = means[row * COLUMNS..(row + 1) * COLUMNS]
+            .iter()
+            .fold(0, |byte, mean| byte << 1 | (*mean > threshold) as u8);
+    }
+
+    let timestamp = u32::from_be_bytes([rows[1], rows[2], rows[3], rows[4]]);
+    (encode(timestamp) == rows).then_some(timestamp)
+}
+
+/// Host wall clock in the form of the pattern timestamps
+pub fn now_ms() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u32)
+        .unwrap_or_default()
+}
+
+/// Latency of the decoded frames of a camera
+#[derive(Debug, Default)]
+pub struct LatencyMeter {
+    //a pattern repeated in several frames is measured once
+    last_timestamp: Option<u32>,
+    total_ms: u64,
+    stats: Option<LatencyStats>,
+}
+
+impl LatencyMeter {
+    /// Measures the frame with the timestamp decoded at `now_ms`
+    pub fn record(&mut self, timestamp: u32, now_ms: u32) {
+        if self.last_timestamp.replace(timestamp) == Some(timestamp) {
+            return;
+        }
+
+        //the timestamps wrap every 49 days
+        let latency = now_ms.wrapping_sub(timestamp);
+        if latency > MAX_LATENCY_MS {
+            return;
+        }
+
+        let stats = self.stats.get_or_insert(LatencyStats {
+            min_ms: latency,
+            max_ms: latency,
+            ..Default::default()
+        });
+        self.total_ms += latency as u64;
+        stats.samples += 1;
+        stats.last_ms = latency;
+        stats.min_ms = stats.min_ms.min(latency);
+        stats.max_ms = stats.max_ms.max(latency);
+        stats.avg_ms = self.total_ms as f32 / stats.samples as f32;
+    }
+
+    /// Latency so far, none until a frame is measured
+    pub fn stats(&self) -> Option<LatencyStats> {
+        self.stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //draws the pattern the way the mobile does, with the given levels
+    fn render(
+        timestamp: u32, width: usize, height: usize, black: u8, white: u8,
+    ) -> Vec<u8> {
+        let rows = encode(timestamp);
+        (0..width * height)
+            .map(|pixel| {
+                let row = (pixel / width) * ROWS / height;
+                let column = (pixel % width) * COLUMNS / width;
+                let bit = rows[row] >> (COLUMNS - 1 - column) & 1;
+                if bit == 1 {
+                    white
+                } else {
+                    black
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decode_pattern() {
+        let timestamp = 0xDEAD_BEEF;
+        let frame = render(timestamp, 64, 48, 30, 220);
+        assert_eq!(decode(&frame, 64, 48), Some(timestamp));
+
+        //a low contrast camera is read from the midpoint of its levels
+        let frame = render(timestamp, 64, 48, 90, 170);
+        assert_eq!(decode(&frame, 64, 48), Some(timestamp));
+
+        //a uniform frame or a wrong checksum isn't a pattern
+        assert_eq!(decode(&[128; 64 * 48], 64, 48), None);
+        let mut frame = render(timestamp, 64, 48, 30, 220);
+        //the checksum row inverted
+        for pixel in frame.iter_mut().skip(64 * 40) {
+            *pixel = 255 - *pixel;
+        }
+        assert_eq!(decode(&frame, 64, 48), None);
+    }
+
+    #[test]
+    fn test_latency_meter() {
+        let mut meter = LatencyMeter::default();
+        assert_eq!(meter.stats(), None);
+
+        meter.record(1000, 1080);
+        //the same pattern in the next frame
+        meter.record(1000, 1110);
+        //across the wrap of the timestamps
+        meter.record(u32::MAX - 9, 30);
+        //a mobile clock off by a minute
+        meter.record(5000, 65_000);
+
+        let stats = meter.stats().unwrap();
+        assert_eq!(stats.samples, 2);
+        assert_eq!((stats.last_ms, stats.min_ms, stats.max_ms), (40, 40, 80));
+        assert_eq!(stats.avg_ms, 60.0);
+    }
+}
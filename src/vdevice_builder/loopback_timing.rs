@@ -0,0 +1,138 @@
+//! Frame size and interval advertised by the loopback device.
+//! The consuming apps pick their mode from the sizes and intervals the
+//! device enumerates, and v4l2loopback enumerates the format and the time
+//! per frame set by its writer. They follow the caps of the live stream, so
+//! an app asking for 30 fps doesn't pace a camera sending 15.
+
+use log::{info, warn};
+
+use crate::{ble::comm_types::VideoProp, error::Result};
+
+/// Size and rate of the frames of the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamTiming {
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second as a fraction
+    pub fps: (u32, u32),
+}
+
+impl StreamTiming {
+    /// Timing announced by the mobile in its offer
+    pub fn from_video_prop(video_prop: &VideoProp) -> Self {
+        let (width, height) = video_prop.resolution;
+        Self { width, height, fps: (video_prop.fps.max(1), 1) }
+    }
+
+    /// Timing with the fields of the caps of the stream, the missing ones
+    /// and a variable rate keep the previous values
+    pub fn with_caps(
+        self, width: Option<i32>, height: Option<i32>,
+        framerate: Option<(i32, i32)>,
+    ) -> Self {
+        let positive =
+            |value: i32| u32::try_from(value).ok().filter(|v| *v > 0);
+        Self {
+            width: width.and_then(positive).unwrap_or(self.width),
+            height: height.and_then(positive).unwrap_or(self.height),
+            //webrtc decoders report 0/1 for a variable rate
+            fps: framerate
+                .and_then(|(num, den)| positive(num).zip(positive(den)))
+                .unwrap_or(self.fps),
+        }
+    }
+
+    /// Time per frame, the form of the V4L2 frame intervals
+    pub fn interval(&self) -> (u32, u32) {
+        (self.fps.1, self.fps.0)
+    }
+}
+
+/// Sets the timing on the loopback device once it changes
+pub struct LoopbackTiming<F> {
+    latest: StreamTiming,
+    advertised: Option<StreamTiming>,
+    apply: F,
+}
+
+impl<F> LoopbackTiming<F>
+where
+    F: FnMut(&StreamTiming) -> Result<()>,
+{
+    /// Nothing is advertised until the first update
+    pub fn new(offered: StreamTiming, apply: F) -> Self {
+        Self { latest: offered, advertised: None, apply }
+    }
+
+    /// Timing of the last update
+    pub fn latest(&self) -> StreamTiming {
+        self.latest
+    }
+
+    /// Advertises the timing, a failure is retried with the next change
+    pub fn update(&mut self, timing: StreamTiming) {
+        self.latest = timing;
+        if self.advertised == Some(timing) {
+            return;
+        }
+
+        match (self.apply)(&timing) {
+            Ok(()) => {
+                info!("Loopback device advertises {:?}", timing);
+                self.advertised = Some(timing);
+            }
+            Err(e) => {
+                warn!("Failed to advertise {:?}: {:?}", timing, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_timing_follows_caps() {
+        let offered = StreamTiming::from_video_prop(&VideoProp {
+            resolution: (1280, 720),
+            fps: 30,
+        });
+        assert_eq!(offered.interval(), (1, 30));
+
+        //scaled down with a variable rate
+        let timing = offered.with_caps(Some(640), Some(360), Some((0, 1)));
+        assert_eq!(timing, StreamTiming { width: 640, height: 360, ..offered });
+
+        let timing = timing.with_caps(None, None, Some((30000, 1001)));
+        assert_eq!(timing.interval(), (1001, 30000));
+    }
+
+    #[test]
+    fn test_timing_advertised_once_changed() {
+        let timing = StreamTiming { width: 1280, height: 720, fps: (30, 1) };
+        let mut applied = Vec::new();
+        let mut fail = true;
+        {
+            let mut loopback =
+                LoopbackTiming::new(timing, |timing: &StreamTiming| {
+                    applied.push(*timing);
+                    if std::mem::take(&mut fail) {
+                        return Err(anyhow!("Device busy"));
+                    }
+                    Ok(())
+                });
+
+            loopback.update(timing);
+            //retried after the failure, then kept
+            loopback.update(timing);
+            loopback.update(timing);
+            loopback.update(StreamTiming { fps: (15, 1), ..timing });
+            assert_eq!(loopback.latest().fps, (15, 1));
+        }
+
+        assert_eq!(applied.len(), 3);
+        assert_eq!(applied[2].fps, (15, 1));
+    }
+}
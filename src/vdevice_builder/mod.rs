@@ -1,16 +1,75 @@
-use crate::ble::server::mobile_comm::VDeviceMap;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::app_data::{LastCamera, SelfTestStep};
+use crate::ble::comm_types::HostCapabilities;
+use crate::ble::server::mobile_comm::{CallOptions, OnCameraReady, VDeviceOps};
 use crate::ble::{
-    comm_types::CameraSdp, server::mobile_comm::VDeviceBuilderOps,
+    comm_types::{CameraSdp, HostPowerState, VideoProp},
+    server::mobile_comm::VDeviceBuilderOps,
+};
+use crate::config::{
+    CapsMode, ContainerMode, OutputConfig, RtspConfig, VideoLimitsConfig,
 };
 use crate::error::Result;
+use crate::events::{Event, EventBus};
+use crate::link_test::PortRule;
+use crate::live_config::LiveConfig;
+use crate::privacy_switch::PrivacySwitch;
+use crate::sleep_inhibitor::SleepInhibitor;
+use crate::thumbnails::Thumbnails;
+use anyhow::anyhow;
 use async_trait::async_trait;
-use log::error;
+use chrono::Local;
+use container::{
+    is_containerized, missing_capabilities, verify_device, DevicePool,
+};
+use log::{error, info, warn};
+use rtsp_output::RtspServer;
 use system_utils::{load_kmodule, unload_kmodule, update_dir_permissions};
+use tokio::sync::watch;
+mod bandwidth;
+mod composite;
+mod container;
+mod control_bridge;
+mod cpu_pressure;
+mod device_caps;
+mod device_conflict;
+mod device_label;
+mod firewall;
+mod hw_video;
+mod latency_pattern;
+mod loopback_timing;
+mod output_backend;
+mod output_format;
+mod post_processing;
+mod provisioned;
+mod recording;
+mod rtsp_output;
+mod sdp_munger;
+mod self_test;
+mod stream_stats;
 mod system_utils;
+mod turn;
 mod vdevice;
+mod video_limits;
 mod webrtc_pipeline;
 
-pub use vdevice::VDevice;
+use bandwidth::{BandwidthPolicer, TokenBucket};
+use composite::CompositeDevice;
+use device_caps::check_caps;
+use device_conflict::check_device;
+use device_label::DeviceLabels;
+use firewall::SessionFirewall;
+pub use hw_video::probe_decoders;
+use post_processing::DeviceFilters;
+pub use provisioned::{provision, DeviceMapping, MAPPING_PATH};
+pub use sdp_munger::SdpMunger;
+use sdp_munger::SdpMungers;
+pub use vdevice::{PreparedVDevice, VDevice};
+use webrtc_pipeline::{CallSettings, PipelineSettings};
 
 use system_utils::is_kmodule_loaded;
 
@@ -18,26 +77,452 @@ pub struct VDeviceBuilder {
     //flags to set up the system at beginning and tear down at the end
     is_v4l2loopback_loaded: bool,
     is_videodev_loaded: bool,
+
+    //settings applied to every pipeline, read when the camera starts
+    live_config: LiveConfig,
+
+    //RTSP output of the cameras
+    rtsp_config: RtspConfig,
+    rtsp_server: Option<RtspServer>,
+
+    //extra output backends
+    outputs: Vec<OutputConfig>,
+
+    //devices passed to the container, None if running on the host
+    device_pool: Option<DevicePool>,
+
+    //warm standby devices waiting for the offer, by device name
+    standby: Mutex<HashMap<String, PreparedVDevice>>,
+
+    //SDP mungers of the integrator, applied after the configured ones
+    sdp_mungers: SdpMungers,
+
+    //privacy switch of the process, outlives the builder
+    privacy: PrivacySwitch,
+
+    //power state of the host, the output is reduced on battery if set
+    battery_saver: Option<watch::Receiver<HostPowerState>>,
+
+    //keeps the host awake while any camera streams
+    sleep_inhibitor: Option<SleepInhibitor>,
+
+    //ingress cap shared by every call
+    total_bandwidth: Arc<Mutex<TokenBucket>>,
+
+    //devices combining the cameras of several mobiles
+    composites: Vec<CompositeDevice>,
+
+    //previews of the cameras, shown by the desktop front-ends
+    thumbnails: Option<Thumbnails>,
+
+    //labels of the devices created on the host
+    labels: DeviceLabels,
+
+    //devices of the cameras, for the library frontends
+    events: EventBus,
+
+    //rules of the access point, installed by the first call needing them
+    firewall: Mutex<Option<Arc<SessionFirewall>>>,
+
+    //hardware decoders found at startup, announced in the host info
+    capabilities: HostCapabilities,
+
+    //dropped after the modules are unloaded, so a restart loads them again
+    released: watch::Sender<()>,
+}
+
+//the provisioned devices still present, in their order
+fn provisioned_devices(mapping: &DeviceMapping) -> Result<Vec<String>> {
+    let devices: Vec<String> = mapping
+        .paths()
+        .into_iter()
+        .filter(|device| match verify_device(device) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Provisioned device skipped: {:#}", e);
+                false
+            }
+        })
+        .collect();
+    if devices.is_empty() {
+        return Err(anyhow!(
+            "None of the provisioned devices is available, run \
+             `webcam-direct-linux provision-devices` again"
+        ));
+    }
+    Ok(devices)
 }
 
 impl VDeviceBuilder {
-    pub async fn new() -> Result<Self> {
+    pub async fn new(
+        live_config: LiveConfig, privacy: PrivacySwitch,
+    ) -> Result<Self> {
+        let config = live_config.borrow().clone();
+        let container_config = config.container;
+        let rtsp_config = config.rtsp;
+        let in_container = match container_config.mode {
+            ContainerMode::Auto => is_containerized(),
+            ContainerMode::Host => false,
+            ContainerMode::Container => true,
+        };
+
         let mut is_v4l2loopback_loaded = false;
         let mut is_videodev_loaded = false;
-        //check for videodev module
-        if !is_kmodule_loaded("/proc/modules", "videodev").await? {
-            is_videodev_loaded = true;
-            load_kmodule("videodev", None).await?;
-            update_dir_permissions("/dev/v4l2loopback", "o+r").await?;
+        let mut device_pool = None;
+        if in_container {
+            //the modules and the devices are managed by the host
+            info!("Running in a container, using the pre-created devices");
+            if container_config.devices.is_empty() {
+                return Err(anyhow!(
+                    "No devices configured for the container, create them \
+                     on the host with `modprobe v4l2loopback \
+                     video_nr=<N> exclusive_caps=1`, pass them with \
+                     `--device /dev/video<N>` and list them in \
+                     container.devices"
+                ));
+            }
+
+            for device in container_config.devices.iter() {
+                verify_device(device)?;
+                //skipped while in conflict, the others are leased first
+                if let Err(e) = check_device(device) {
+                    warn!("{:#}", e);
+                }
+            }
+
+            device_pool = Some(DevicePool::new(container_config.devices));
+        } else {
+            let missing = missing_capabilities();
+            if !missing.is_empty() {
+                warn!(
+                    "Missing {}, loading the modules and creating the \
+                     devices may fail: run as root, or set container.mode \
+                     to container and pass pre-created devices",
+                    missing.join(", ")
+                );
+            }
+
+            //check for videodev module
+            if !is_kmodule_loaded("/proc/modules", "videodev").await? {
+                is_videodev_loaded = true;
+                load_kmodule("videodev", None).await?;
+                update_dir_permissions("/dev/v4l2loopback", "o+r").await?;
+            }
+
+            let provisioned = DeviceMapping::load(Path::new(MAPPING_PATH))?;
+            if let Some(mapping) = provisioned {
+                //the options of modprobe.d create the devices, kept loaded
+                info!("Using the devices provisioned in {}", MAPPING_PATH);
+                if !is_kmodule_loaded("/proc/modules", "v4l2loopback").await? {
+                    load_kmodule("v4l2loopback", None).await?;
+                }
+                device_pool =
+                    Some(DevicePool::new(provisioned_devices(&mapping)?));
+            } else if !is_kmodule_loaded("/proc/modules", "v4l2loopback")
+                .await?
+            {
+                is_v4l2loopback_loaded = true;
+                let exclusive_caps = format!(
+                    "exclusive_caps={}",
+                    config.loopback.caps.exclusive_caps()
+                );
+                load_kmodule("v4l2loopback", Some(&[&exclusive_caps])).await?;
+            }
         }
 
-        //check for v4l2loopback module
-        if !is_kmodule_loaded("/proc/modules", "v4l2loopback").await? {
-            is_v4l2loopback_loaded = true;
-            load_kmodule("v4l2loopback", Some(&["exclusive_caps=1"])).await?;
+        //decodebin picks the hardware decoders working on this host
+        let prefer = config.hw_decoding;
+        let capabilities =
+            tokio::task::spawn_blocking(move || probe_decoders(prefer)).await?;
+
+        let mut composites = Vec::new();
+        for composite in config.composites.iter() {
+            match CompositeDevice::new(
+                composite,
+                device_pool.as_ref(),
+                &config.loopback,
+            )
+            .await
+            {
+                Ok(device) => composites.push(device),
+                Err(e) => {
+                    warn!("Composite {} disabled: {:?}", composite.name, e)
+                }
+            }
         }
 
-        Ok(Self { is_v4l2loopback_loaded, is_videodev_loaded })
+        let rtsp_server = if rtsp_config.enabled {
+            match RtspServer::new(rtsp_config.port) {
+                Ok(server) => Some(server),
+                Err(e) => {
+                    warn!("RTSP output disabled, error: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            is_v4l2loopback_loaded,
+            is_videodev_loaded,
+            live_config,
+            rtsp_config,
+            rtsp_server,
+            outputs: config.outputs,
+            device_pool,
+            standby: Mutex::new(HashMap::new()),
+            sdp_mungers: Vec::new(),
+            privacy,
+            battery_saver: None,
+            sleep_inhibitor: None,
+            total_bandwidth: Arc::new(Mutex::new(TokenBucket::new(
+                config.bandwidth.total_kbps.unwrap_or_default(),
+            ))),
+            thumbnails: None,
+            composites,
+            labels: DeviceLabels::new(device_label::language_from_env()),
+            events: EventBus::default(),
+            firewall: Mutex::new(None),
+            capabilities,
+            released: watch::channel(()).0,
+        })
+    }
+
+    /// Scales down the output of the cameras while the host is on battery
+    pub fn reduce_quality_on_battery(
+        &mut self, power_state: watch::Receiver<HostPowerState>,
+    ) {
+        self.battery_saver = Some(power_state);
+    }
+
+    /// Keeps the latest frame of every camera in `thumbnails`
+    pub fn share_thumbnails(&mut self, thumbnails: Thumbnails) {
+        self.thumbnails = Some(thumbnails);
+    }
+
+    /// Hardware video decoding of the host
+    pub fn capabilities(&self) -> &HostCapabilities {
+        &self.capabilities
+    }
+
+    /// Resolves once the builder is dropped and its modules unloaded
+    pub fn released(&self) -> impl Future<Output = ()> {
+        let mut released = self.released.subscribe();
+        async move { while released.changed().await.is_ok() {} }
+    }
+
+    /// Emits the devices of the cameras on `events`
+    pub fn publish_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    /// Inhibits the sleep of the host while any camera streams
+    #[cfg_attr(not(feature = "logind"), allow(dead_code))]
+    pub fn inhibit_sleep(&mut self, inhibitor: SleepInhibitor) {
+        self.sleep_inhibitor = Some(inhibitor);
+    }
+
+    /// Adds a munger of the SDP offer and answer of every call
+    pub fn add_sdp_munger(&mut self, munger: Arc<dyn SdpMunger>) {
+        self.sdp_mungers.push(munger);
+    }
+
+    async fn prepare_vdevice(
+        &self, vdevice_name: String, video_prop: VideoProp,
+    ) -> Result<PreparedVDevice> {
+        let loopback = self.live_config.borrow().loopback.clone();
+        let caps = loopback.caps_for(&vdevice_name);
+        //the devices of a container are labeled by the host
+        let (device_lease, label) = match &self.device_pool {
+            Some(pool) => {
+                let lease = pool.lease(|device| {
+                    check_caps(device, caps)?;
+                    check_device(device)
+                })?;
+                (Some(lease), None)
+            }
+            None => {
+                if caps != CapsMode::Exclusive {
+                    warn!(
+                        "{} announces exclusive caps, the devices added at \
+                         runtime can't announce all caps, provision them",
+                        vdevice_name
+                    );
+                }
+                (None, Some(self.labels.claim(&vdevice_name)))
+            }
+        };
+        let rtsp_mount = self
+            .rtsp_server
+            .as_ref()
+            .filter(|_| self.rtsp_config.is_enabled_for(&vdevice_name))
+            .map(|server| server.add_camera(&vdevice_name));
+        let thumbnails = self.live_config.borrow().thumbnails.clone();
+        let settings = PipelineSettings {
+            outputs: self
+                .outputs
+                .iter()
+                .filter(|output| output.is_enabled_for(&vdevice_name))
+                .map(|output| output.backend.for_device(&vdevice_name))
+                .collect(),
+            cpu_pressure: self.live_config.borrow().cpu_pressure.clone(),
+            privacy: self.privacy.clone(),
+            battery_saver: self.battery_saver.clone(),
+            sleep_inhibitor: self.sleep_inhibitor.clone(),
+            thumbnail: self
+                .thumbnails
+                .as_ref()
+                .filter(|_| thumbnails.enabled)
+                .map(|shared| Arc::new(shared.slot(&vdevice_name))),
+            thumbnail_width: thumbnails.width,
+            composite_channel: self
+                .composites
+                .iter()
+                .any(|composite| composite.has_source(&vdevice_name))
+                .then(|| composite::channel(&vdevice_name)),
+            filters: Some(DeviceFilters::new(
+                self.live_config.clone(),
+                &vdevice_name,
+            )),
+            ..Default::default()
+        };
+
+        let prepared = VDevice::prepare(
+            vdevice_name.clone(),
+            video_prop,
+            settings,
+            rtsp_mount,
+            device_lease,
+            label,
+            &loopback,
+        )
+        .await?;
+        self.events.emit(Event::DeviceCreated {
+            name: vdevice_name,
+            device: prepared.device_path(),
+        });
+
+        Ok(prepared)
+    }
+
+    //requested once per call, the call goes on without TURN on failure
+    async fn turn_servers(&self) -> Vec<String> {
+        let turn = self.live_config.borrow().turn.clone();
+        let Some(turn) = turn else {
+            return Vec::new();
+        };
+
+        match turn::fetch_turn_servers(&turn).await {
+            Ok(turn_servers) => turn_servers,
+            Err(e) => {
+                warn!("No TURN credentials for the call, error: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    //read per call, the table is removed once the setting is off and the
+    //calls opening ports ended
+    fn session_firewall(&self) -> Option<Arc<SessionFirewall>> {
+        let enabled = self.live_config.borrow().session_firewall;
+        let mut firewall = self.firewall.lock().ok()?;
+        if !enabled {
+            firewall.take();
+            return None;
+        }
+
+        if firewall.is_none() {
+            match SessionFirewall::install() {
+                Ok(installed) => *firewall = Some(Arc::new(installed)),
+                Err(e) => {
+                    warn!("Media ports of the call not restricted: {:?}", e);
+                }
+            }
+        }
+        firewall.clone()
+    }
+
+    //the configured mungers and caps are read per call, so changes apply
+    //live
+    async fn call_settings(
+        &self, options: CallOptions, cameras: usize,
+    ) -> CallSettings {
+        let (mut sdp_mungers, caps, limits): (SdpMungers, _, _) = {
+            let config = self.live_config.borrow();
+            (
+                config
+                    .sdp_mungers
+                    .iter()
+                    .map(sdp_munger::from_config)
+                    .collect(),
+                config.bandwidth.clone(),
+                config.video_limits.clone(),
+            )
+        };
+        sdp_mungers.extend(self.sdp_mungers.iter().cloned());
+
+        //the hints keep the mobile from sending more than the host decodes
+        sdp_mungers.extend(video_limits::hints(&limits));
+
+        //the cameras of the mobile share its cap
+        let mut buckets = Vec::new();
+        if let Some(kbps) = caps.per_mobile_kbps {
+            buckets.push(Arc::new(Mutex::new(TokenBucket::new(kbps))));
+        }
+        if let Some(kbps) = caps.total_kbps {
+            if let Ok(mut total) = self.total_bandwidth.lock() {
+                total.set_kbps(kbps);
+            }
+            buckets.push(self.total_bandwidth.clone());
+        }
+
+        //the hint keeps the encoders of the mobile under the caps
+        if let Some(kbps) =
+            caps.per_mobile_kbps.into_iter().chain(caps.total_kbps).min()
+        {
+            let kbps = kbps / cameras.max(1) as u32;
+            sdp_mungers.push(Arc::new(sdp_munger::MaxBitrate { kbps }));
+        }
+
+        CallSettings {
+            turn_servers: self.turn_servers().await,
+            sdp_mungers,
+            negotiation: options.negotiation,
+            bandwidth: BandwidthPolicer::new(buckets),
+            latency_test: options.latency_test,
+            jitter_ms: options.latency_profile.jitter_ms(),
+            record_to: None,
+            firewall: self.session_firewall(),
+            owner: options.owner,
+        }
+    }
+
+    //the pipeline scales down what the mobile sends over the limits
+    fn clamp_format(
+        &self, camera_name: &str, video_prop: VideoProp,
+        limits: &VideoLimitsConfig,
+    ) -> VideoProp {
+        let clamped = video_limits::clamp(&video_prop, limits);
+        if clamped != video_prop {
+            info!(
+                "Format of camera {} clamped from {:?} to {:?}",
+                camera_name, video_prop, clamped
+            );
+        }
+        clamped
+    }
+
+    //the standby device is only usable if the camera format did not change,
+    //otherwise it is dropped to release its device
+    fn take_standby(
+        &self, vdevice_name: &str, video_prop: &VideoProp,
+    ) -> Option<PreparedVDevice> {
+        self.standby
+            .lock()
+            .ok()?
+            .remove(vdevice_name)
+            .filter(|prepared| prepared.video_prop() == video_prop)
     }
 }
 
@@ -45,30 +530,162 @@ impl VDeviceBuilder {
 impl VDeviceBuilderOps for VDeviceBuilder {
     async fn create_from(
         &self, mobile_name: String, camera_offer_list: Vec<CameraSdp>,
-    ) -> Result<VDeviceMap> {
-        let mut device_map = VDeviceMap::new();
+        options: CallOptions, on_ready: OnCameraReady,
+    ) -> Result<()> {
+        let call_settings =
+            self.call_settings(options, camera_offer_list.len()).await;
+        let limits = self.live_config.borrow().video_limits.clone();
+        let recording =
+            options.record.then(|| self.live_config.borrow().recording.clone());
 
-        for camera_offer in camera_offer_list {
+        for mut camera_offer in camera_offer_list {
+            camera_offer.format = self.clamp_format(
+                &camera_offer.name,
+                camera_offer.format,
+                &limits,
+            );
             let vdevice_name =
                 format!("{}: {}", &mobile_name, &camera_offer.name);
             let camera_name = camera_offer.name.clone();
-            let vdevice = match VDevice::new(vdevice_name, camera_offer).await {
-                Ok(vdevice) => vdevice,
-                Err(e) => {
-                    error!("Failed to create virtual device for camera {} error: {:?}", &camera_name, e);
-                    continue;
+            let standby =
+                self.take_standby(&vdevice_name, &camera_offer.format);
+            let prepared = match standby {
+                Some(prepared) => {
+                    //only the webrtc transport is left to connect
+                    info!("Using the warm standby of {}", &vdevice_name);
+                    Ok(prepared)
+                }
+                None => {
+                    self.prepare_vdevice(vdevice_name, camera_offer.format)
+                        .await
                 }
             };
+            //each camera is recorded to its own file
+            let record_to = recording.as_ref().and_then(|recording| {
+                recording
+                    .file_for(
+                        &mobile_name,
+                        &camera_name,
+                        Local::now().naive_local(),
+                    )
+                    .inspect_err(|e| {
+                        error!("Camera {} not recorded: {:?}", &camera_name, e)
+                    })
+                    .ok()
+            });
+            let vdevice = match prepared {
+                Ok(prepared) => {
+                    prepared
+                        .connect(
+                            &camera_offer.sdp,
+                            CallSettings { record_to, ..call_settings.clone() },
+                        )
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &vdevice {
+                error!(
+                    "Failed to create virtual device for camera {} error: {:?}",
+                    &camera_name, e
+                );
+            }
+
+            let vdevice =
+                vdevice.map(|vdevice| Box::new(vdevice) as Box<dyn VDeviceOps>);
+            if !on_ready(camera_name, vdevice) {
+                info!("Call of {} ended, stop creating devices", &mobile_name);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn prepare_standby(
+        &self, mobile_name: String, cameras: Vec<LastCamera>,
+    ) -> Result<()> {
+        let limits = self.live_config.borrow().video_limits.clone();
+        for mut camera in cameras {
+            camera.format =
+                self.clamp_format(&camera.name, camera.format, &limits);
+            let vdevice_name = format!("{}: {}", &mobile_name, &camera.name);
+            if self
+                .standby
+                .lock()
+                .map_err(|_| anyhow!("Standby lock poisoned"))?
+                .contains_key(&vdevice_name)
+            {
+                continue;
+            }
+
+            match self
+                .prepare_vdevice(vdevice_name.clone(), camera.format)
+                .await
+            {
+                Ok(prepared) => {
+                    info!("Warm standby ready for {}", &vdevice_name);
+                    self.standby
+                        .lock()
+                        .map_err(|_| anyhow!("Standby lock poisoned"))?
+                        .insert(vdevice_name, prepared);
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to prepare the standby of {} error: {:?}",
+                        &vdevice_name, e
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-            device_map.insert(camera_name, vdevice);
+    async fn self_test(&self) -> Result<Vec<SelfTestStep>> {
+        let modules = self_test::check_modules().await;
+        let mut steps = vec![self_test::step("modules", &modules)];
+        if modules.is_err() {
+            return Ok(steps);
         }
 
-        Ok(device_map)
+        let loopback = self.live_config.borrow().loopback.clone();
+        let device =
+            self_test::create_device(self.device_pool.as_ref(), &loopback)
+                .await;
+        steps.push(self_test::step("device", &device));
+        let Ok(device) = device else {
+            return Ok(steps);
+        };
+
+        let played = self_test::play_test_pattern(device.path()).await;
+        steps.push(self_test::step("pipeline", &played));
+
+        Ok(steps)
+    }
+
+    fn release_standby(&self) {
+        if let Ok(mut standby) = self.standby.lock() {
+            standby.clear();
+        }
+    }
+
+    fn open_port(&self, port: u16) -> Result<Option<PortRule>> {
+        let Some(firewall) = self.session_firewall() else {
+            return Ok(None);
+        };
+        Ok(Some(Box::new(firewall.allow_port(port)?)))
     }
 }
 
 impl Drop for VDeviceBuilder {
     fn drop(&mut self) {
+        //the standby pipelines stop before the modules are unloaded
+        if let Ok(standby) = self.standby.get_mut() {
+            standby.clear();
+        }
+        self.composites.clear();
+
         //unload the modules
         if self.is_v4l2loopback_loaded
             && unload_kmodule("v4l2loopback").is_err()
@@ -0,0 +1,74 @@
+//! Extra output backends of a camera, fed from the pipeline output tee so
+//! the feed can reach hardware mixers and streaming tools without a
+//! loopback hop.
+
+use crate::{config::OutputBackend, error::Result};
+use anyhow::anyhow;
+use gst::ElementFactory;
+
+impl OutputBackend {
+    /// Backend for the virtual device, unnamed NDI sources take the device
+    /// name
+    pub fn for_device(&self, vdevice_name: &str) -> Self {
+        match self {
+            OutputBackend::Ndi { ndi_name: None } => {
+                OutputBackend::Ndi { ndi_name: Some(vdevice_name.to_string()) }
+            }
+            backend => backend.clone(),
+        }
+    }
+
+    /// Elements of the output branch, from the raw video input to the sink
+    pub fn build_elements(&self) -> Result<Vec<gst::Element>> {
+        match self {
+            OutputBackend::Srt { uri } => Ok(vec![
+                ElementFactory::make("videoconvert").build()?,
+                ElementFactory::make("x264enc")
+                    .property_from_str("tune", "zerolatency")
+                    .property_from_str("speed-preset", "ultrafast")
+                    .build()?,
+                ElementFactory::make("mpegtsmux").build()?,
+                ElementFactory::make("srtsink")
+                    .property("uri", uri)
+                    .property("wait-for-connection", false)
+                    .build()?,
+            ]),
+            OutputBackend::Ndi { ndi_name } => {
+                //the NDI plugin is not part of the GStreamer distribution
+                if ElementFactory::find("ndisink").is_none() {
+                    return Err(anyhow!("NDI plugin not available"));
+                }
+
+                let mut ndisink = ElementFactory::make("ndisink");
+                if let Some(ndi_name) = ndi_name {
+                    ndisink = ndisink.property("ndi-name", ndi_name);
+                }
+
+                Ok(vec![
+                    ElementFactory::make("videoconvert").build()?,
+                    ndisink.build()?,
+                ])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_for_device_names_ndi_source() {
+        let unnamed = OutputBackend::Ndi { ndi_name: None };
+        assert_eq!(
+            unnamed.for_device("Pixel: Back"),
+            OutputBackend::Ndi { ndi_name: Some("Pixel: Back".to_string()) }
+        );
+
+        let named = OutputBackend::Ndi { ndi_name: Some("Cam".to_string()) };
+        assert_eq!(named.for_device("Pixel: Back"), named);
+
+        let srt = OutputBackend::Srt { uri: "srt://:7001".to_string() };
+        assert_eq!(srt.for_device("Pixel: Back"), srt);
+    }
+}
@@ -0,0 +1,87 @@
+//! Pixel format negotiation with the loopback device.
+//! Some consuming apps reject NV12 from loopback devices, so the formats are
+//! tried in order until the device keeps one of them.
+
+use crate::error::Result;
+use anyhow::anyhow;
+use log::{info, warn};
+
+/// Pixel format written to the loopback device
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// V4L2 fourcc
+    pub fourcc: &'static [u8; 4],
+    /// GStreamer raw video format name
+    pub gst_format: &'static str,
+}
+
+/// Formats tried on the loopback device, in order of preference
+pub const OUTPUT_FORMATS: [OutputFormat; 3] = [
+    OutputFormat { fourcc: b"NV12", gst_format: "NV12" },
+    OutputFormat { fourcc: b"YUYV", gst_format: "YUY2" },
+    OutputFormat { fourcc: b"RGB3", gst_format: "RGB" },
+];
+
+/// Picks the first format accepted by `try_format`, which returns whether
+/// the device kept the format after setting it.
+pub fn negotiate<F>(mut try_format: F) -> Result<&'static OutputFormat>
+where
+    F: FnMut(&OutputFormat) -> Result<bool>,
+{
+    for format in OUTPUT_FORMATS.iter() {
+        match try_format(format) {
+            Ok(true) => {
+                info!("Loopback output format picked: {}", format.gst_format);
+                return Ok(format);
+            }
+            Ok(false) => {
+                warn!("Loopback device rejected format {}", format.gst_format);
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to set format {} on loopback device: {:?}",
+                    format.gst_format, e
+                );
+            }
+        }
+    }
+
+    Err(anyhow!(
+        "Loopback device rejected all the formats: {:?}",
+        OUTPUT_FORMATS.iter().map(|f| f.gst_format).collect::<Vec<_>>()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_first_format() {
+        let format = negotiate(|_| Ok(true)).unwrap();
+
+        assert_eq!(format.gst_format, "NV12");
+    }
+
+    #[test]
+    fn test_negotiate_fallback() {
+        let mut tried = vec![];
+        let format = negotiate(|format| {
+            tried.push(format.gst_format);
+            match format.gst_format {
+                "NV12" => Ok(false),
+                "YUY2" => Err(anyhow!("device busy")),
+                _ => Ok(true),
+            }
+        })
+        .unwrap();
+
+        assert_eq!(format.gst_format, "RGB");
+        assert_eq!(tried, vec!["NV12", "YUY2", "RGB"]);
+    }
+
+    #[test]
+    fn test_negotiate_all_rejected() {
+        assert!(negotiate(|_| Ok(false)).is_err());
+    }
+}
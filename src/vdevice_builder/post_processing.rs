@@ -0,0 +1,224 @@
+//! # Post-processing.
+//! Optional denoise and sharpen stages cleaning up the low-light footage of
+//! the phones before it reaches the virtual camera. A stage that is off is
+//! bypassed and its filter gets no frames, so the presets of a device are
+//! switched live without rebuilding the pipeline.
+
+use anyhow::anyhow;
+use gst::{prelude::*, ElementFactory, Pipeline};
+use log::{info, warn};
+
+use crate::{
+    config::{FilterPreset, FilterPresets},
+    error::Result,
+    live_config::LiveConfig,
+};
+
+/// Presets of a virtual device, following the live config
+#[derive(Debug, Clone)]
+pub struct DeviceFilters {
+    live_config: LiveConfig,
+    vdevice_name: String,
+}
+
+impl DeviceFilters {
+    pub fn new(live_config: LiveConfig, vdevice_name: &str) -> Self {
+        Self { live_config, vdevice_name: vdevice_name.to_string() }
+    }
+
+    pub fn presets(&self) -> FilterPresets {
+        self.live_config
+            .borrow()
+            .post_processing
+            .presets_for(&self.vdevice_name)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Filter {
+    Denoise,
+    Sharpen,
+}
+
+impl Filter {
+    fn build(self) -> Result<gst::Element> {
+        let factory = match self {
+            Filter::Denoise => "videomedian",
+            Filter::Sharpen => "frei0r-filter-sharpness",
+        };
+        Ok(ElementFactory::make(factory).build()?)
+    }
+
+    fn preset(self, presets: FilterPresets) -> FilterPreset {
+        match self {
+            Filter::Denoise => presets.denoise,
+            Filter::Sharpen => presets.sharpen,
+        }
+    }
+
+    //the strength of an enabled filter
+    fn configure(self, filter: &gst::Element, preset: FilterPreset) {
+        let strong = preset == FilterPreset::Strong;
+        match self {
+            Filter::Denoise => {
+                filter.set_property_from_str(
+                    "filtersize",
+                    if strong { "9" } else { "5" },
+                );
+                //the light preset leaves the colors alone
+                filter.set_property("lum-only", !strong);
+            }
+            Filter::Sharpen => {
+                filter.set_property("amount", if strong { 0.6 } else { 0.3 });
+                filter.set_property("size", if strong { 0.5 } else { 0.3 });
+            }
+        }
+    }
+}
+
+//frames split between the filter and the bypass, the valve drops the
+//frames of the filter while it's off
+struct FilterStage {
+    filter: Filter,
+    element: gst::Element,
+    valve: gst::Element,
+    selector: gst::Element,
+    bypass_pad: gst::Pad,
+    filter_pad: gst::Pad,
+}
+
+impl FilterStage {
+    fn add(
+        pipeline: &Pipeline, filter: Filter, src: &gst::Element,
+    ) -> Result<Self> {
+        let element = filter.build()?;
+        let tee = ElementFactory::make("tee").build()?;
+        let valve = ElementFactory::make("valve").build()?;
+        //the filters take their own formats
+        let convert_in = ElementFactory::make("videoconvert").build()?;
+        let convert_out = ElementFactory::make("videoconvert").build()?;
+        //the bypass and the filter are fed by the same thread
+        let selector = ElementFactory::make("input-selector")
+            .property("sync-streams", false)
+            .build()?;
+
+        pipeline.add_many([
+            &tee,
+            &valve,
+            &convert_in,
+            &element,
+            &convert_out,
+            &selector,
+        ])?;
+        src.link(&tee)?;
+        tee.link(&selector)?;
+        gst::Element::link_many([
+            &tee,
+            &valve,
+            &convert_in,
+            &element,
+            &convert_out,
+            &selector,
+        ])?;
+
+        let peer = |element: &gst::Element| {
+            element
+                .static_pad("src")
+                .and_then(|pad| pad.peer())
+                .ok_or(anyhow!("{:?} stage not linked", filter))
+        };
+        let filter_pad = peer(&convert_out)?;
+        let bypass_pad = selector
+            .sink_pads()
+            .into_iter()
+            .find(|pad| *pad != filter_pad)
+            .ok_or(anyhow!("{:?} stage not linked", filter))?;
+
+        Ok(Self { filter, element, valve, selector, bypass_pad, filter_pad })
+    }
+
+    fn apply(&self, presets: FilterPresets) {
+        let preset = self.filter.preset(presets);
+        let enabled = preset != FilterPreset::Off;
+        if enabled {
+            self.filter.configure(&self.element, preset);
+        }
+
+        self.valve.set_property("drop", !enabled);
+        let pad = if enabled { &self.filter_pad } else { &self.bypass_pad };
+        self.selector.set_property("active-pad", pad);
+    }
+}
+
+/// Denoise and sharpen stages of a pipeline
+pub struct PostProcessing {
+    stages: Vec<FilterStage>,
+    presets: FilterPresets,
+}
+
+impl PostProcessing {
+    /// Links the stages between `src` and `sink`, a stage whose filter is
+    /// not installed is left out
+    pub fn add(
+        pipeline: &Pipeline, src: &gst::Element, sink: &gst::Element,
+        presets: FilterPresets,
+    ) -> Result<Self> {
+        let mut stages = Vec::new();
+        let mut last = src.clone();
+        for filter in [Filter::Denoise, Filter::Sharpen] {
+            match FilterStage::add(pipeline, filter, &last) {
+                Ok(stage) => {
+                    last = stage.selector.clone();
+                    stages.push(stage);
+                }
+                Err(e) => warn!("No {:?} stage: {:?}", filter, e),
+            }
+        }
+        last.link(sink)?;
+
+        let post_processing = Self { stages, presets };
+        for stage in post_processing.stages.iter() {
+            stage.apply(presets);
+        }
+        Ok(post_processing)
+    }
+
+    /// Switches the stages to the presets if they changed
+    pub fn apply(&mut self, presets: FilterPresets) {
+        if presets == self.presets {
+            return;
+        }
+
+        info!("Setting post-processing to {:?}", presets);
+        for stage in self.stages.iter() {
+            stage.apply(presets);
+        }
+        self.presets = presets;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use tokio::sync::watch;
+
+    #[test]
+    fn test_device_filters_follow_live_config() {
+        let (live, live_config) = watch::channel(AppConfig::default());
+        let filters = DeviceFilters::new(live_config, "Pixel: back");
+        assert_eq!(filters.presets(), FilterPresets::default());
+
+        let strong = FilterPresets {
+            denoise: FilterPreset::Strong,
+            sharpen: FilterPreset::Light,
+        };
+        live.send_modify(|config| {
+            config
+                .post_processing
+                .devices
+                .insert("Pixel: back".to_string(), strong);
+        });
+        assert_eq!(filters.presets(), strong);
+    }
+}
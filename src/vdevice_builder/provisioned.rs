@@ -0,0 +1,188 @@
+//! Static set of loopback devices.
+//! Some users rather have the devices created at boot by the module options
+//! than added and deleted with every camera. `provision-devices` writes the
+//! options of v4l2loopback and the mapping of the devices, and creates them
+//! right away. The host then leases the mapped devices, in their order, and
+//! leaves the module alone.
+
+use std::{fs, path::Path, process::Command};
+
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use v4l2loopback::add_device;
+
+use super::vdevice::static_device_config;
+use crate::{
+    config::{CapsMode, LoopbackConfig},
+    error::Result,
+};
+
+/// Options of v4l2loopback, read by modprobe at boot
+pub const MODPROBE_PATH: &str = "/etc/modprobe.d/webcam-direct.conf";
+
+/// Loads v4l2loopback at boot
+pub const MODULES_LOAD_PATH: &str = "/etc/modules-load.d/webcam-direct.conf";
+
+/// Devices leased by the host
+pub const MAPPING_PATH: &str = "/etc/webcam-direct/devices.json";
+
+/// Present while v4l2loopback is loaded
+const MODULE_PATH: &str = "/sys/module/v4l2loopback";
+
+/// Labels of the devices, numbered from 1
+const LABEL_PREFIX: &str = "Webcam Direct";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProvisionedDevice {
+    /// Number of the device, `/dev/video<number>`
+    pub number: u32,
+    pub path: String,
+    /// Name shown by the video apps
+    pub label: String,
+    #[serde(default)]
+    pub caps: CapsMode,
+}
+
+/// Devices provisioned on the host
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct DeviceMapping {
+    pub devices: Vec<ProvisionedDevice>,
+}
+
+impl DeviceMapping {
+    /// `count` devices numbered from `first`, the last `all_caps` ones
+    /// announce all caps
+    pub fn new(first: u32, count: u32, all_caps: u32) -> Self {
+        Self {
+            devices: (0..count)
+                .map(|index| ProvisionedDevice {
+                    number: first + index,
+                    path: format!("/dev/video{}", first + index),
+                    label: format!("{} {}", LABEL_PREFIX, index + 1),
+                    caps: match index + all_caps >= count {
+                        true => CapsMode::All,
+                        false => CapsMode::Exclusive,
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// Mapping written by `provision-devices`, none if the devices aren't
+    /// provisioned
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)?;
+        let mapping = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid device mapping {:?}", path))?;
+        Ok(Some(mapping))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.devices.iter().map(|device| device.path.clone()).collect()
+    }
+
+    /// Line of modprobe.d creating the devices when the module loads
+    pub fn modprobe_options(&self) -> String {
+        let join = |field: &dyn Fn(&ProvisionedDevice) -> String| {
+            self.devices.iter().map(field).collect::<Vec<_>>().join(",")
+        };
+
+        format!(
+            "options v4l2loopback devices={} video_nr={} card_label={} \
+             exclusive_caps={}\n",
+            self.devices.len(),
+            join(&|device| device.number.to_string()),
+            join(&|device| format!("\"{}\"", device.label)),
+            join(&|device| device.caps.exclusive_caps().to_string()),
+        )
+    }
+}
+
+/// Writes the boot config and the mapping of the devices, then creates the
+/// missing ones. Returns the numbers of the created devices.
+pub fn provision(
+    mapping: &DeviceMapping, loopback: &LoopbackConfig,
+) -> Result<Vec<u32>> {
+    if mapping.devices.is_empty() {
+        return Err(anyhow!("No device to provision"));
+    }
+
+    let write = |path: &str, content: String| {
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write {}, run as root", path))
+    };
+    write(MODPROBE_PATH, mapping.modprobe_options())?;
+    write(MODULES_LOAD_PATH, "v4l2loopback\n".to_string())?;
+    mapping.save(Path::new(MAPPING_PATH))?;
+
+    //loaded with the new options, the devices are created by the module
+    if !Path::new(MODULE_PATH).exists() {
+        let status = Command::new("modprobe").arg("v4l2loopback").status()?;
+        if !status.success() {
+            return Err(anyhow!("Failed to load v4l2loopback, install it"));
+        }
+    }
+
+    //a device in use keeps its options until the next boot
+    let mut created = Vec::new();
+    for device in mapping.devices.iter() {
+        if Path::new(&device.path).exists() {
+            continue;
+        }
+        if device.caps != CapsMode::Exclusive {
+            warn!("{} announces all caps after a reboot", device.path);
+        }
+        add_device(
+            Some(device.number),
+            static_device_config(&device.label, loopback),
+        )
+        .map_err(|e| anyhow!("Failed to create {}: {:?}", device.path, e))?;
+        info!("Provisioned {} as {}", device.path, device.label);
+        created.push(device.number);
+    }
+
+    Ok(created)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modprobe_options() {
+        let mapping = DeviceMapping::new(10, 2, 1);
+        assert_eq!(mapping.paths(), vec!["/dev/video10", "/dev/video11"]);
+        assert_eq!(
+            mapping.modprobe_options(),
+            "options v4l2loopback devices=2 video_nr=10,11 \
+             card_label=\"Webcam Direct 1\",\"Webcam Direct 2\" \
+             exclusive_caps=1,0\n"
+        );
+    }
+
+    #[test]
+    fn test_mapping_saved_and_loaded() {
+        let path = std::env::temp_dir()
+            .join(format!("wcd-devices-{}", std::process::id()))
+            .join("devices.json");
+        assert_eq!(DeviceMapping::load(&path).unwrap(), None);
+
+        let mapping = DeviceMapping::new(20, 3, 0);
+        mapping.save(&path).unwrap();
+        assert_eq!(DeviceMapping::load(&path).unwrap(), Some(mapping));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Recordings of the cameras of the mobiles set to always record. The
+//! output of the camera is encoded to a Matroska file from the start of the
+//! stream, the file is closed with the pipeline once the mobile
+//! disconnects. A Matroska file cut without its index still plays.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDateTime;
+use gst::ElementFactory;
+
+use crate::{config::RecordingConfig, error::Result};
+
+impl RecordingConfig {
+    /// Unused file recording the camera of the mobile from `started_at`,
+    /// the directory is created if missing
+    pub fn file_for(
+        &self, mobile: &str, camera: &str, started_at: NaiveDateTime,
+    ) -> Result<PathBuf> {
+        fs::create_dir_all(&self.directory)?;
+
+        let path =
+            self.directory.join(self.file_name(mobile, camera, started_at));
+        //a camera started twice in the same second keeps both recordings
+        let stem = path.with_extension("");
+        let extension = path.extension().map(|ext| ext.to_owned());
+        let mut unused = path.clone();
+        for index in 1.. {
+            if !unused.exists() {
+                break;
+            }
+            unused = PathBuf::from(format!("{}-{}", stem.display(), index));
+            if let Some(extension) = &extension {
+                unused.set_extension(extension);
+            }
+        }
+
+        Ok(unused)
+    }
+
+    //the names given by the mobile can't leave the directory
+    fn file_name(
+        &self, mobile: &str, camera: &str, started_at: NaiveDateTime,
+    ) -> String {
+        self.filename
+            .replace("{mobile}", &sanitize(mobile))
+            .replace("{camera}", &sanitize(camera))
+            .replace("{date}", &started_at.format("%Y-%m-%d").to_string())
+            .replace("{time}", &started_at.format("%H%M%S").to_string())
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+/// Elements of the recording branch, from the raw video input to the file
+pub fn build_elements(path: &Path) -> Result<Vec<gst::Element>> {
+    Ok(vec![
+        ElementFactory::make("videoconvert").build()?,
+        ElementFactory::make("x264enc")
+            .property_from_str("tune", "zerolatency")
+            .property_from_str("speed-preset", "veryfast")
+            .build()?,
+        ElementFactory::make("h264parse").build()?,
+        ElementFactory::make("matroskamux").build()?,
+        ElementFactory::make("filesink")
+            .property("location", path.to_string_lossy().as_ref())
+            .property("async", false)
+            .build()?,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_recording_file_name() {
+        let started_at = NaiveDate::from_ymd_opt(2026, 3, 9)
+            .and_then(|date| date.and_hms_opt(8, 5, 30))
+            .unwrap();
+        let config = RecordingConfig {
+            directory: std::env::temp_dir()
+                .join(format!("wcd-recordings-{}", std::process::id())),
+            ..Default::default()
+        };
+
+        let path = config.file_for("Pixel 8", "Back", started_at).unwrap();
+        assert_eq!(
+            path,
+            config.directory.join("Pixel_8-Back-2026-03-09-080530.mkv")
+        );
+
+        //the names can't escape the directory, and a started file is kept
+        let started =
+            config.directory.join("____etc-Back-2026-03-09-080530.mkv");
+        fs::write(started, []).unwrap();
+        let path = config.file_for("/../etc", "Back", started_at).unwrap();
+        assert_eq!(
+            path,
+            config.directory.join("____etc-Back-2026-03-09-080530-1.mkv")
+        );
+
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! RTSP output of the incoming cameras.
+//! Every camera enabled in the config gets an RTSP URL, so consumers that
+//! can't open a v4l2 device (VLC, NVRs) can read the stream directly. The
+//! pipeline sends the decoded frames to an `intervideosink` channel that the
+//! RTSP media encodes on demand.
+
+use crate::error::Result;
+use anyhow::anyhow;
+use gst::glib::{self, MainLoop};
+use gst_rtsp_server::{
+    prelude::*, RTSPMediaFactory, RTSPMountPoints, RTSPServer,
+};
+use log::{error, info};
+use std::thread;
+
+/// RTSP server shared by all the virtual devices
+pub struct RtspServer {
+    mounts: RTSPMountPoints,
+    mainloop: MainLoop,
+    server_thread: Option<thread::JoinHandle<()>>,
+    port: u16,
+}
+
+impl RtspServer {
+    pub fn new(port: u16) -> Result<Self> {
+        gst::init()?;
+
+        let server = RTSPServer::new();
+        server.set_service(&port.to_string());
+
+        let mounts = server
+            .mount_points()
+            .ok_or_else(|| anyhow!("Failed to get RTSP mount points"))?;
+
+        //the server runs in its own context, the pipelines own the default
+        let context = glib::MainContext::new();
+        let mainloop = MainLoop::new(Some(&context), false);
+        let _source = server.attach(Some(&context))?;
+
+        let mainloop_clone = mainloop.clone();
+        let server_thread = thread::Builder::new()
+            .name("wcd-rtsp".to_string())
+            .spawn(move || {
+                let _server = server;
+                mainloop_clone.run();
+            })?;
+
+        info!("RTSP server listening on port {}", port);
+
+        Ok(Self { mounts, mainloop, server_thread: Some(server_thread), port })
+    }
+
+    /// Publishes the camera under a path derived from the device name, the
+    /// camera is unpublished when the returned mount is dropped
+    pub fn add_camera(&self, vdevice_name: &str) -> RtspMount {
+        let channel = rtsp_path(vdevice_name);
+        let path = format!("/{}", channel);
+
+        let factory = RTSPMediaFactory::new();
+        factory.set_launch(&format!(
+            "( intervideosrc channel={} ! videoconvert ! \
+             x264enc tune=zerolatency speed-preset=ultrafast ! \
+             rtph264pay name=pay0 pt=96 )",
+            channel
+        ));
+        factory.set_shared(true);
+
+        self.mounts.add_factory(&path, factory);
+
+        info!(
+            "Camera {} available at rtsp://<host>:{}{}",
+            vdevice_name, self.port, path
+        );
+
+        RtspMount { mounts: self.mounts.clone(), path, channel }
+    }
+}
+
+impl Drop for RtspServer {
+    fn drop(&mut self) {
+        self.mainloop.quit();
+        if let Some(handle) = self.server_thread.take() {
+            if let Err(e) = handle.join() {
+                error!("Failed to join RTSP server thread: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Camera published in the RTSP server
+#[derive(Debug)]
+pub struct RtspMount {
+    mounts: RTSPMountPoints,
+    path: String,
+    channel: String,
+}
+
+impl RtspMount {
+    /// intervideo channel the pipeline must feed
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+}
+
+impl Drop for RtspMount {
+    fn drop(&mut self) {
+        self.mounts.remove_factory(&self.path);
+    }
+}
+
+/// Builds an URL friendly path from the device name
+pub fn rtsp_path(vdevice_name: &str) -> String {
+    let mut path = String::new();
+
+    for c in vdevice_name.chars() {
+        if c.is_ascii_alphanumeric() {
+            path.push(c.to_ascii_lowercase());
+        } else if !path.is_empty() && !path.ends_with('-') {
+            path.push('-');
+        }
+    }
+
+    path.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtsp_path() {
+        assert_eq!(rtsp_path("Pixel 7: Back"), "pixel-7-back");
+        assert_eq!(rtsp_path("  My phone!: Front "), "my-phone-front");
+    }
+}
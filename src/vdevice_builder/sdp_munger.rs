@@ -0,0 +1,250 @@
+//! # SDP munging.
+//! Hooks rewriting the offer of the mobile before it is applied and the
+//! host answer before it is returned, e.g. to force codecs, strip
+//! candidates or add bandwidth lines. Built-in mungers are selected in the
+//! config, integrators add their own with `VDeviceBuilder::add_sdp_munger`.
+
+use std::sync::Arc;
+
+use crate::config::SdpMungerConfig;
+
+/// Rewrites the SDP of a call, the default keeps it unchanged
+pub trait SdpMunger: Send + Sync + std::fmt::Debug {
+    /// SDP of the mobile, its offer or its answer to the host offer, before
+    /// it is applied to the pipeline
+    fn munge_offer(&self, offer: String) -> String {
+        offer
+    }
+
+    /// SDP of the host, its answer or its offer, before it is returned to
+    /// the mobile
+    fn munge_answer(&self, answer: String) -> String {
+        answer
+    }
+}
+
+/// Mungers of a call, applied in order
+pub type SdpMungers = Vec<Arc<dyn SdpMunger>>;
+
+pub fn from_config(config: &SdpMungerConfig) -> Arc<dyn SdpMunger> {
+    match config {
+        SdpMungerConfig::PreferH264 => Arc::new(PreferH264),
+        SdpMungerConfig::CapResolution { max_width, max_height } => {
+            Arc::new(CapResolution {
+                max_width: *max_width,
+                max_height: *max_height,
+            })
+        }
+    }
+}
+
+pub fn munge_offer(mungers: &[Arc<dyn SdpMunger>], offer: String) -> String {
+    mungers.iter().fold(offer, |sdp, munger| munger.munge_offer(sdp))
+}
+
+pub fn munge_answer(mungers: &[Arc<dyn SdpMunger>], answer: String) -> String {
+    mungers.iter().fold(answer, |sdp, munger| munger.munge_answer(sdp))
+}
+
+/// Puts the H264 payload types first in the video sections of the offer,
+/// so the answer picks H264 when the mobile offers it
+#[derive(Debug)]
+pub struct PreferH264;
+
+impl SdpMunger for PreferH264 {
+    fn munge_offer(&self, offer: String) -> String {
+        let mut sections = split_sections(&offer);
+
+        for section in sections.iter_mut().filter(|s| is_video(s)) {
+            let h264_types: Vec<String> = section
+                .iter()
+                .filter_map(|line| line.strip_prefix("a=rtpmap:"))
+                .filter_map(|rtpmap| rtpmap.split_once(' '))
+                .filter(|(_, codec)| codec.to_uppercase().starts_with("H264/"))
+                .map(|(payload_type, _)| payload_type.to_string())
+                .collect();
+
+            //m=video <port> <proto> <payload types...>
+            let fields: Vec<String> =
+                section[0].split(' ').map(str::to_string).collect();
+            if fields.len() <= 3 {
+                continue;
+            }
+            let (preferred, others): (Vec<String>, Vec<String>) = fields[3..]
+                .iter()
+                .cloned()
+                .partition(|payload_type| h264_types.contains(payload_type));
+
+            section[0] =
+                [&fields[..3], &preferred[..], &others[..]].concat().join(" ");
+        }
+
+        join_sections(sections)
+    }
+}
+
+/// Announces in the answer the maximum resolution the host receives
+#[derive(Debug)]
+pub struct CapResolution {
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl SdpMunger for CapResolution {
+    fn munge_answer(&self, answer: String) -> String {
+        let mut sections = split_sections(&answer);
+
+        for section in sections.iter_mut().filter(|s| is_video(s)) {
+            section.retain(|line| !line.starts_with("a=imageattr:"));
+            section.push(format!(
+                "a=imageattr:* recv [x=[1:{}],y=[1:{}]]",
+                self.max_width, self.max_height
+            ));
+        }
+
+        join_sections(sections)
+    }
+}
+
+/// Announces in the answer the maximum bitrate the host receives per video
+/// stream, the mobile encoder keeps under it
+#[derive(Debug)]
+pub struct MaxBitrate {
+    pub kbps: u32,
+}
+
+impl SdpMunger for MaxBitrate {
+    fn munge_answer(&self, answer: String) -> String {
+        let mut sections = split_sections(&answer);
+
+        for section in sections.iter_mut().filter(|s| is_video(s)) {
+            section.retain(|line| !line.starts_with("b=AS:"));
+            //the bandwidth goes after the title and the connection lines
+            let at = section
+                .iter()
+                .skip(1)
+                .position(|line| {
+                    !line.starts_with("i=") && !line.starts_with("c=")
+                })
+                .map_or(section.len(), |position| position + 1);
+            section.insert(at, format!("b=AS:{}", self.kbps));
+        }
+
+        join_sections(sections)
+    }
+}
+
+/// Announces in the answer the maximum frame rate the host receives
+#[derive(Debug)]
+pub struct MaxFramerate {
+    pub fps: u32,
+}
+
+impl SdpMunger for MaxFramerate {
+    fn munge_answer(&self, answer: String) -> String {
+        let mut sections = split_sections(&answer);
+
+        for section in sections.iter_mut().filter(|s| is_video(s)) {
+            section.retain(|line| !line.starts_with("a=framerate:"));
+            section.push(format!("a=framerate:{}", self.fps));
+        }
+
+        join_sections(sections)
+    }
+}
+
+//session lines first, then one entry per media section
+fn split_sections(sdp: &str) -> Vec<Vec<String>> {
+    let mut sections = vec![Vec::new()];
+
+    for line in sdp.lines().filter(|line| !line.is_empty()) {
+        if line.starts_with("m=") {
+            sections.push(Vec::new());
+        }
+        if let Some(section) = sections.last_mut() {
+            section.push(line.to_string());
+        }
+    }
+
+    sections
+}
+
+fn join_sections(sections: Vec<Vec<String>>) -> String {
+    sections.concat().into_iter().map(|line| line + "\r\n").collect()
+}
+
+fn is_video(section: &[String]) -> bool {
+    section.first().is_some_and(|line| line.starts_with("m=video "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 4611733054762223410 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 97 102\r\n\
+        a=mid:0\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtpmap:97 rtx/90000\r\n\
+        a=rtpmap:102 H264/90000\r\n";
+
+    #[test]
+    fn test_prefer_h264() {
+        let offer = PreferH264.munge_offer(OFFER.to_string());
+
+        assert!(offer.contains("\r\nm=video 9 UDP/TLS/RTP/SAVPF 102 96 97\r\n"));
+        assert_eq!(offer.len(), OFFER.len());
+        assert_eq!(PreferH264.munge_answer(OFFER.to_string()), OFFER);
+    }
+
+    #[test]
+    fn test_prefer_h264_without_h264() {
+        let offer = OFFER.replace("H264", "VP9");
+
+        assert_eq!(PreferH264.munge_offer(offer.clone()), offer);
+    }
+
+    #[test]
+    fn test_cap_resolution() {
+        let munger = CapResolution { max_width: 1280, max_height: 720 };
+        let answer = munger.munge_answer(
+            OFFER.to_string() + "a=imageattr:* recv [x=[1:1920],y=[1:1080]]\n",
+        );
+
+        assert_eq!(
+            answer,
+            OFFER.to_string() + "a=imageattr:* recv [x=[1:1280],y=[1:720]]\r\n"
+        );
+    }
+
+    #[test]
+    fn test_max_bitrate() {
+        let answer = MaxBitrate { kbps: 800 }.munge_answer(
+            OFFER
+                .replace("a=mid:0", "c=IN IP4 0.0.0.0\r\nb=AS:2000\r\na=mid:0"),
+        );
+
+        assert!(answer.contains(
+            "RTP/SAVPF 96 97 102\r\nc=IN IP4 0.0.0.0\r\nb=AS:800\r\na=mid:0"
+        ));
+        assert!(!answer.contains("b=AS:2000"));
+    }
+
+    #[test]
+    fn test_mungers_in_order() {
+        let mungers = vec![
+            from_config(&SdpMungerConfig::PreferH264),
+            from_config(&SdpMungerConfig::CapResolution {
+                max_width: 640,
+                max_height: 480,
+            }),
+        ];
+
+        assert!(munge_offer(&mungers, OFFER.to_string()).contains(" 102 96 97"));
+        assert!(munge_answer(&mungers, OFFER.to_string())
+            .ends_with("a=imageattr:* recv [x=[1:640],y=[1:480]]\r\n"));
+    }
+}
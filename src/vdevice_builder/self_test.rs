@@ -0,0 +1,112 @@
+//! Self-test of the devices.
+//! The steps a call goes through without a mobile: the kernel modules, the
+//! creation of a loopback device, or the lease of one in a container, and a
+//! test pattern played into it.
+
+use std::time::Duration;
+
+use super::container::{verify_device, DeviceLease, DevicePool};
+use super::device_conflict::check_device;
+use super::system_utils::is_kmodule_loaded;
+use super::vdevice::{device_config, V4l2Device};
+use crate::{
+    app_data::SelfTestStep, ble::comm_types::VideoProp, config::LoopbackConfig,
+    error::Result,
+};
+use anyhow::anyhow;
+use gst::prelude::*;
+use tokio::task;
+
+//the test pattern, one second of video
+const TEST_PROP: VideoProp = VideoProp { resolution: (640, 480), fps: 30 };
+
+const PIPELINE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Device written by the test pipeline, released on drop
+pub(super) enum TestDevice {
+    Leased(DeviceLease),
+    Created(V4l2Device),
+}
+
+impl TestDevice {
+    pub(super) fn path(&self) -> String {
+        match self {
+            Self::Leased(lease) => lease.path().to_string(),
+            Self::Created(device) => device.path.to_string_lossy().to_string(),
+        }
+    }
+}
+
+pub(super) fn step(name: &str, result: &Result<impl Sized>) -> SelfTestStep {
+    SelfTestStep {
+        name: name.to_string(),
+        passed: result.is_ok(),
+        detail: match result {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        },
+    }
+}
+
+/// The modules are shared with the host in a container too
+pub(super) async fn check_modules() -> Result<()> {
+    for module in ["videodev", "v4l2loopback"] {
+        if !is_kmodule_loaded("/proc/modules", module).await? {
+            return Err(anyhow!("Module {} is not loaded", module));
+        }
+    }
+    Ok(())
+}
+
+/// A free device of the pool in a container, a new one on the host
+pub(super) async fn create_device(
+    device_pool: Option<&DevicePool>, loopback: &LoopbackConfig,
+) -> Result<TestDevice> {
+    match device_pool {
+        Some(pool) => {
+            let lease = pool.lease(check_device)?;
+            verify_device(lease.path())?;
+            Ok(TestDevice::Leased(lease))
+        }
+        None => {
+            let config = device_config("Self-test", &TEST_PROP, loopback);
+            Ok(TestDevice::Created(V4l2Device::new(config).await?))
+        }
+    }
+}
+
+/// Plays the test pattern into `device` until its end
+pub(super) async fn play_test_pattern(device: String) -> Result<()> {
+    task::spawn_blocking(move || {
+        gst::init()?;
+        let (width, height) = TEST_PROP.resolution;
+        let pipeline = gst::parse::launch(&format!(
+            "videotestsrc num-buffers={} ! video/x-raw,width={},height={},\
+             framerate={}/1 ! videoconvert ! v4l2sink device={} sync=false",
+            TEST_PROP.fps, width, height, TEST_PROP.fps, device
+        ))?;
+        let bus = pipeline
+            .bus()
+            .ok_or_else(|| anyhow!("Test pipeline without a bus"))?;
+
+        pipeline.set_state(gst::State::Playing)?;
+        let msg = bus.timed_pop_filtered(
+            gst::ClockTime::from_seconds(PIPELINE_TIMEOUT.as_secs()),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        );
+        let played = match msg.as_ref().map(|msg| msg.view()) {
+            Some(gst::MessageView::Eos(_)) => Ok(()),
+            Some(gst::MessageView::Error(err)) => {
+                Err(anyhow!("Test pipeline failed: {}", err.error()))
+            }
+            _ => Err(anyhow!(
+                "Test pipeline not done after {:?}",
+                PIPELINE_TIMEOUT
+            )),
+        };
+        pipeline.set_state(gst::State::Null)?;
+
+        played
+    })
+    .await?
+}
@@ -0,0 +1,358 @@
+//! CPU usage attributable to a pipeline.
+//! The streaming threads of a pipeline register themselves when they start,
+//! their usage is then sampled from `/proc/self/task/<tid>/stat`. The GPU
+//! usage of the hardware decoders is sampled for the whole process from the
+//! engine times of its DRM clients in `/proc/self/fdinfo`, the decoders of
+//! the pipelines share them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{ble::comm_types::StreamStats, error::Result};
+use anyhow::anyhow;
+use log::error;
+
+/// Clock ticks per second used by /proc (USER_HZ), fixed by the kernel ABI
+const CLOCK_TICKS_PER_SEC: f32 = 100.0;
+
+/// Parses the user plus system time in clock ticks from a /proc stat line.
+/// The command name can contain spaces, so the fields are taken after the
+/// last parenthesis.
+pub fn parse_stat_ticks(stat: &str) -> Result<u64> {
+    let fields: Vec<&str> = stat
+        .rfind(')')
+        .map(|pos| stat[pos + 1..].split_whitespace().collect())
+        .ok_or_else(|| anyhow!("Invalid stat line"))?;
+
+    //utime and stime are the fields 14 and 15, the first two are the pid
+    //and the command name
+    let ticks = |idx: usize| -> Result<u64> {
+        fields
+            .get(idx)
+            .ok_or_else(|| anyhow!("Missing stat field {}", idx + 3))?
+            .parse::<u64>()
+            .map_err(|e| anyhow!("Invalid stat field {}: {:?}", idx + 3, e))
+    };
+
+    Ok(ticks(11)? + ticks(12)?)
+}
+
+fn read_stat_ticks(path: &str) -> Option<u64> {
+    let stat = std::fs::read_to_string(path).ok()?;
+    parse_stat_ticks(&stat).ok()
+}
+
+/// Kernel thread id of the calling thread.
+fn current_tid() -> Option<u32> {
+    //the link has the form <pid>/task/<tid>
+    let link = std::fs::read_link("/proc/thread-self").ok()?;
+    link.file_name()?.to_str()?.parse().ok()
+}
+
+/// Threads running a pipeline.
+#[derive(Debug, Default, Clone)]
+pub struct PipelineThreads(Arc<Mutex<HashSet<u32>>>);
+
+impl PipelineThreads {
+    /// Registers the calling thread as part of the pipeline.
+    pub fn register_current(&self) {
+        let Some(tid) = current_tid() else {
+            error!("Failed to get the thread id");
+            return;
+        };
+
+        if let Ok(mut tids) = self.0.lock() {
+            tids.insert(tid);
+        }
+    }
+
+    /// Removes the calling thread from the pipeline.
+    pub fn unregister_current(&self) {
+        let Some(tid) = current_tid() else {
+            return;
+        };
+
+        if let Ok(mut tids) = self.0.lock() {
+            tids.remove(&tid);
+        }
+    }
+
+    fn tids(&self) -> Vec<u32> {
+        self.0
+            .lock()
+            .map(|tids| tids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Parses the busy and total clock ticks of all the CPUs from /proc/stat.
+pub fn parse_host_cpu_ticks(stat: &str) -> Result<(u64, u64)> {
+    let line = stat
+        .lines()
+        .find(|line| line.starts_with("cpu "))
+        .ok_or_else(|| anyhow!("Missing cpu line"))?;
+
+    let ticks = line
+        .split_whitespace()
+        .skip(1)
+        .map(|field| field.parse::<u64>())
+        .collect::<std::result::Result<Vec<u64>, _>>()
+        .map_err(|e| anyhow!("Invalid cpu line: {:?}", e))?;
+
+    if ticks.len() < 5 {
+        return Err(anyhow!("Invalid cpu line: {}", line));
+    }
+
+    //idle and iowait are the 4th and 5th fields
+    let total = ticks.iter().sum::<u64>();
+    let idle = ticks[3] + ticks[4];
+
+    Ok((total - idle, total))
+}
+
+/// Parses the client id and the busy time of every engine, in nanoseconds,
+/// from the fdinfo of a DRM file descriptor. Other descriptors return None.
+pub fn parse_drm_fdinfo(fdinfo: &str) -> Option<(String, Vec<(String, u64)>)> {
+    let mut client = None;
+    let mut engines = Vec::new();
+    for line in fdinfo.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        if key == "drm-client-id" {
+            client = Some(value.trim().to_string());
+        } else if let Some(engine) = key.strip_prefix("drm-engine-") {
+            let Some(ns) = value.trim().strip_suffix(" ns") else {
+                continue;
+            };
+            if let Ok(ns) = ns.trim().parse() {
+                engines.push((engine.to_string(), ns));
+            }
+        }
+    }
+
+    Some((client?, engines))
+}
+
+//busy time by engine of the DRM clients of the process, a client opened
+//through several descriptors is counted once
+fn read_gpu_engines() -> HashMap<String, u64> {
+    let mut clients = HashSet::new();
+    let mut engines = HashMap::new();
+    let Ok(entries) = std::fs::read_dir("/proc/self/fdinfo") else {
+        return engines;
+    };
+
+    for entry in entries.flatten() {
+        let Ok(fdinfo) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let Some((client, busy)) = parse_drm_fdinfo(&fdinfo) else {
+            continue;
+        };
+        if !clients.insert(client) {
+            continue;
+        }
+        for (engine, ns) in busy {
+            *engines.entry(engine).or_insert(0) += ns;
+        }
+    }
+
+    engines
+}
+
+/// Utilization of the busiest engine in percent between two samples of
+/// the engine times, None without any engine
+pub fn busiest_engine_percent(
+    last: &HashMap<String, u64>, now: &HashMap<String, u64>, elapsed_secs: f32,
+) -> Option<f32> {
+    now.iter()
+        .filter_map(|(engine, ns)| {
+            let busy = ns.saturating_sub(*last.get(engine)?);
+            Some(busy as f32 / 1e9 / elapsed_secs * 100.0)
+        })
+        .reduce(f32::max)
+        .map(|percent| percent.min(100.0))
+}
+
+/// Computes the utilization of all the host CPUs since the previous sample.
+#[derive(Debug, Default)]
+pub struct HostCpuSampler {
+    last: Option<(u64, u64)>,
+}
+
+impl HostCpuSampler {
+    /// Samples the utilization in percent, 100 means all CPUs busy. The
+    /// first call only sets the starting point and returns `None`.
+    pub fn sample(&mut self) -> Option<f32> {
+        let stat = std::fs::read_to_string("/proc/stat").ok()?;
+        let (busy, total) = parse_host_cpu_ticks(&stat).ok()?;
+
+        let percent = self.last.and_then(|(last_busy, last_total)| {
+            let total = total.saturating_sub(last_total);
+            (total > 0).then(|| {
+                busy.saturating_sub(last_busy) as f32 / total as f32 * 100.0
+            })
+        });
+
+        self.last = Some((busy, total));
+
+        percent
+    }
+}
+
+#[derive(Debug)]
+struct CpuSample {
+    at: Instant,
+    pipeline_ticks: u64,
+    process_ticks: u64,
+    gpu_engines: HashMap<String, u64>,
+}
+
+/// Computes the CPU usage of the pipeline threads since the previous sample.
+#[derive(Debug)]
+pub struct CpuSampler {
+    threads: PipelineThreads,
+    last: Option<CpuSample>,
+}
+
+impl CpuSampler {
+    pub fn new(threads: PipelineThreads) -> Self {
+        Self { threads, last: None }
+    }
+
+    /// Samples the usage, the first call only sets the starting point and
+    /// reports zero usage.
+    pub fn sample(&mut self) -> StreamStats {
+        let tids = self.threads.tids();
+
+        let pipeline_ticks = tids
+            .iter()
+            .filter_map(|tid| {
+                read_stat_ticks(&format!("/proc/self/task/{}/stat", tid))
+            })
+            .sum();
+
+        let process_ticks = read_stat_ticks("/proc/self/stat").unwrap_or(0);
+
+        let now = CpuSample {
+            at: Instant::now(),
+            pipeline_ticks,
+            process_ticks,
+            gpu_engines: read_gpu_engines(),
+        };
+
+        let mut stats =
+            StreamStats { threads: tids.len(), ..Default::default() };
+
+        if let Some(last) = &self.last {
+            let elapsed = now.at.duration_since(last.at).as_secs_f32();
+            if elapsed > 0.0 {
+                let percent = |ticks: u64| {
+                    ticks as f32 / CLOCK_TICKS_PER_SEC / elapsed * 100.0
+                };
+
+                //threads that exited take their ticks with them
+                stats.cpu_percent = percent(
+                    now.pipeline_ticks.saturating_sub(last.pipeline_ticks),
+                );
+                stats.process_cpu_percent = percent(
+                    now.process_ticks.saturating_sub(last.process_ticks),
+                );
+                stats.process_gpu_percent = busiest_engine_percent(
+                    &last.gpu_engines,
+                    &now.gpu_engines,
+                    elapsed,
+                );
+            }
+        }
+
+        self.last = Some(now);
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat_ticks() {
+        let stat = "1234 (queue0:src) S 1 1234 1234 0 -1 4194560 100 0 0 0 \
+                    250 50 0 0 20 0 1 0 100 0 0";
+
+        assert_eq!(parse_stat_ticks(stat).unwrap(), 300);
+    }
+
+    #[test]
+    fn test_parse_stat_ticks_name_with_spaces() {
+        let stat = "1234 (my (odd) name) R 1 1234 1234 0 -1 4194560 100 0 0 0 \
+                    7 3 0 0 20 0 1 0 100 0 0";
+
+        assert_eq!(parse_stat_ticks(stat).unwrap(), 10);
+    }
+
+    #[test]
+    fn test_parse_stat_ticks_invalid() {
+        assert!(parse_stat_ticks("1234 (short) S 1").is_err());
+        assert!(parse_stat_ticks("no parenthesis").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_cpu_ticks() {
+        let stat = "cpu  100 10 40 800 50 0 0 0 0 0\n\
+                    cpu0 50 5 20 400 25 0 0 0 0 0\n";
+
+        assert_eq!(parse_host_cpu_ticks(stat).unwrap(), (150, 1000));
+        assert!(parse_host_cpu_ticks("intr 1 2 3").is_err());
+    }
+
+    #[test]
+    fn test_parse_drm_fdinfo() {
+        let fdinfo = "pos:\t0\nflags:\t02100002\ndrm-driver:\ti915\n\
+                      drm-client-id:\t42\ndrm-engine-render:\t2500 ns\n\
+                      drm-engine-video:\t900000 ns\n\
+                      drm-engine-capacity-video:\t2\n";
+
+        let (client, engines) = parse_drm_fdinfo(fdinfo).unwrap();
+        assert_eq!(client, "42");
+        assert_eq!(
+            engines,
+            vec![("render".to_string(), 2500), ("video".to_string(), 900000)]
+        );
+        assert!(parse_drm_fdinfo("pos:\t0\nflags:\t02\n").is_none());
+    }
+
+    #[test]
+    fn test_busiest_engine_percent() {
+        let last = HashMap::from([
+            ("render".to_string(), 0),
+            ("video".to_string(), 1_000_000_000),
+        ]);
+        let now = HashMap::from([
+            ("render".to_string(), 100_000_000),
+            ("video".to_string(), 1_500_000_000),
+        ]);
+
+        assert_eq!(busiest_engine_percent(&last, &now, 1.0), Some(50.0));
+        assert_eq!(busiest_engine_percent(&last, &HashMap::new(), 1.0), None);
+    }
+
+    #[test]
+    fn test_sample_current_thread() {
+        let threads = PipelineThreads::default();
+        threads.register_current();
+
+        let mut sampler = CpuSampler::new(threads.clone());
+        let first = sampler.sample();
+        assert_eq!(first.threads, 1);
+        assert_eq!(first.cpu_percent, 0.0);
+
+        threads.unregister_current();
+        assert_eq!(sampler.sample().threads, 0);
+    }
+}
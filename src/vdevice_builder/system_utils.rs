@@ -1,15 +1,25 @@
-use std::path::Path;
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use tokio::io::{AsyncBufReadExt, BufReader};
 
 use crate::error::Result;
+use crate::retry::{retry, RetryPolicy};
 use anyhow::anyhow;
-use log::error;
+use log::{debug, error};
 use tokio::{fs::File, process::Command};
 
-//utility function to load a kernel module
+//utility function to load a kernel module, retried while udev settles
 pub async fn load_kmodule(
     module_name: &str, args: Option<&[&str]>,
 ) -> Result<()> {
+    retry(&format!("Loading module {}", module_name), RetryPolicy::BOOT, || {
+        modprobe(module_name, args)
+    })
+    .await
+}
+
+async fn modprobe(module_name: &str, args: Option<&[&str]>) -> Result<()> {
     let mut cmd = Command::new("modprobe"); //.arg(module_name).status().await?
                                             //add argument if any
     let cmd = match args {
@@ -45,6 +55,47 @@ where
     }
 }
 
+/// Device node readable by a single user, its owner and mode are given
+/// back on drop
+#[derive(Debug)]
+pub struct NodeOwner {
+    path: PathBuf,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+}
+
+impl NodeOwner {
+    /// Restricts the node at `path` to the user `uid`
+    pub fn restrict(path: &Path, uid: u32) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        chown(path, Some(uid), None)?;
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode() & 0o7777,
+        })
+    }
+}
+
+impl Drop for NodeOwner {
+    fn drop(&mut self) {
+        //the nodes added per camera are already removed
+        let restored = chown(&self.path, Some(self.uid), Some(self.gid))
+            .and_then(|_| {
+                fs::set_permissions(
+                    &self.path,
+                    Permissions::from_mode(self.mode),
+                )
+            });
+        if let Err(e) = restored {
+            debug!("Owner of {:?} not restored: {:?}", self.path, e);
+        }
+    }
+}
+
 //utility function to unload a kernel module
 //turn into aync when async_drop is available
 pub fn unload_kmodule(module_name: &str) -> Result<()> {
@@ -81,3 +132,24 @@ where
 
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_restricted_to_owner() {
+        let path = std::env::temp_dir()
+            .join(format!("node-owner-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o664)).unwrap();
+        let uid = fs::metadata(&path).unwrap().uid();
+
+        let owner = NodeOwner::restrict(&path, uid).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+
+        drop(owner);
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o664);
+        fs::remove_file(&path).unwrap();
+    }
+}
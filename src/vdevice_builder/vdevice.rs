@@ -1,13 +1,40 @@
-use std::path::PathBuf;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use super::webrtc_pipeline::WebrtcPipeline;
-use crate::{ble::comm_types::CameraSdp, error::Result};
+use super::container::DeviceLease;
+use super::device_label::LabelClaim;
+use super::firewall::{MediaEndpoints, MediaRule, SessionFirewall};
+use super::rtsp_output::RtspMount;
+use super::system_utils::NodeOwner;
+use super::webrtc_pipeline::{
+    CallSettings, PipelineSettings, PreparedPipeline, WebrtcPipeline,
+};
+use crate::{
+    ble::{
+        comm_types::{Negotiation, StreamStats, VideoProp},
+        server::mobile_comm::VDeviceOps,
+    },
+    call_trace::StreamMilestones,
+    config::LoopbackConfig,
+    error::Result,
+};
 use anyhow::anyhow;
-use log::error;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 use tokio::task;
 use v4l2loopback::{add_device, delete_device, DeviceConfig};
 
+//frame sizes accepted by v4l2loopback
+const MIN_WIDTH: u32 = 48;
+const MIN_HEIGHT: u32 = 32;
+const MAX_SIZE: u32 = 8192;
+
+//buffers of a device, the queue holds about 100ms of video
+const MIN_BUFFERS: u32 = 4;
+const MAX_BUFFERS: u32 = 32;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Sdp {
     #[serde(rename = "type")]
@@ -16,24 +43,53 @@ struct Sdp {
 }
 
 #[derive(Debug)]
-struct V4l2Device {
+pub(super) struct V4l2Device {
     pub num: u32,
     pub path: PathBuf,
     pub name: String,
 }
 
+/// Limits of the loopback device of a camera, from the half resolution
+/// output under pressure up to the camera format in both orientations
+pub(super) fn device_config(
+    name: &str, video_prop: &VideoProp, overrides: &LoopbackConfig,
+) -> DeviceConfig {
+    let (width, height) = video_prop.resolution;
+    let long_side = width.max(height).min(MAX_SIZE);
+    let short_side = (width.min(height) / 2) & !1;
+
+    DeviceConfig {
+        min_width: overrides.min_width.unwrap_or(short_side.max(MIN_WIDTH)),
+        max_width: overrides.max_width.unwrap_or(long_side.max(MIN_WIDTH)),
+        min_height: overrides.min_height.unwrap_or(short_side.max(MIN_HEIGHT)),
+        max_height: overrides.max_height.unwrap_or(long_side.max(MIN_HEIGHT)),
+        max_buffers: overrides.max_buffers.unwrap_or(
+            video_prop.fps.div_ceil(10).clamp(MIN_BUFFERS, MAX_BUFFERS),
+        ),
+        max_openers: 9,
+        label: name.to_string(),
+    }
+}
+
+/// Limits of a provisioned device, any camera format fits in them
+pub(super) fn static_device_config(
+    label: &str, overrides: &LoopbackConfig,
+) -> DeviceConfig {
+    DeviceConfig {
+        min_width: overrides.min_width.unwrap_or(MIN_WIDTH),
+        max_width: overrides.max_width.unwrap_or(MAX_SIZE),
+        min_height: overrides.min_height.unwrap_or(MIN_HEIGHT),
+        max_height: overrides.max_height.unwrap_or(MAX_SIZE),
+        //enough for 60 fps
+        max_buffers: overrides.max_buffers.unwrap_or(MIN_BUFFERS * 2),
+        max_openers: 9,
+        label: label.to_string(),
+    }
+}
+
 impl V4l2Device {
-    async fn new(name: String) -> Result<Self> {
-        let config = DeviceConfig {
-            min_width: 100,
-            max_width: 4000,
-            min_height: 100,
-            max_height: 4000,
-            max_buffers: 2,
-            max_openers: 9,
-            label: name.clone(),
-            ..Default::default()
-        };
+    pub(super) async fn new(config: DeviceConfig) -> Result<Self> {
+        let name = config.label.clone();
 
         //create the device in a blocking task
         let name_clone = name.clone();
@@ -71,33 +127,206 @@ impl Drop for V4l2Device {
 
 #[derive(Debug)]
 pub struct VDevice {
-    //_v4l2_device: V4l2Device,
+    name: String,
     webrtc_pipeline: WebrtcPipeline,
+    //closed once the pipeline is gone
+    media_rule: Mutex<Option<MediaRule>>,
+    //opens the media ports once both descriptions are known
+    firewall: Option<Arc<SessionFirewall>>,
+    //dropped after the pipeline stops feeding it
+    _rtsp_mount: Option<RtspMount>,
+    //given back before the device returns to the pool
+    _node_owner: Option<NodeOwner>,
+    //returned to the pool once the pipeline is gone
+    _device_lease: Option<DeviceLease>,
+    //removed once the pipeline is gone, created on the host only
+    _v4l2_device: Option<V4l2Device>,
+    //free again once the device is removed
+    label: Option<LabelClaim>,
 }
 
 impl VDevice {
-    pub async fn new(name: String, camera_offer: CameraSdp) -> Result<Self> {
-        //get he resolution from the camera offer
-        let res_width = camera_offer.format.resolution.0;
-        let res_height = camera_offer.format.resolution.1;
-
-        //        let v4l2_device = V4l2Device::new(name.clone()).await?;
+    /// Builds the device pipeline up to the webrtc transport, the offer is
+    /// given later with `PreparedVDevice::connect`
+    pub async fn prepare(
+        name: String, video_prop: VideoProp, mut settings: PipelineSettings,
+        rtsp_mount: Option<RtspMount>, device_lease: Option<DeviceLease>,
+        label: Option<LabelClaim>, loopback: &LoopbackConfig,
+    ) -> Result<PreparedVDevice> {
+        //the devices of a container are created by the host
+        let (device_path_clone, v4l2_device) = match &device_lease {
+            Some(lease) => (lease.path().to_string(), None),
+            None => {
+                let label = label.as_ref().map_or(name.as_str(), |l| l.label());
+                if label != name {
+                    info!("Device of {} labeled {}", name, label);
+                }
+                let config = device_config(label, &video_prop, loopback);
+                let device = V4l2Device::new(config).await?;
+                (device.path.to_string_lossy().to_string(), Some(device))
+            }
+        };
 
         //create the pipeline in a blocking task
-        let sdp_offer: Sdp = serde_json::from_str(&camera_offer.sdp)?;
-        let video_prop = camera_offer.format.clone();
+        let format = video_prop.clone();
+        settings.rtsp_channel =
+            rtsp_mount.as_ref().map(|mount| mount.channel().to_string());
+        let pipeline = task::spawn_blocking(move || {
+            PreparedPipeline::new(device_path_clone, format, settings)
+        })
+        .await??;
+
+        Ok(PreparedVDevice {
+            name,
+            video_prop,
+            pipeline,
+            rtsp_mount,
+            device_lease,
+            v4l2_device,
+            label,
+        })
+    }
+}
+
+/// Virtual device waiting for the camera offer
+#[derive(Debug)]
+pub struct PreparedVDevice {
+    name: String,
+    video_prop: VideoProp,
+    pipeline: PreparedPipeline,
+    rtsp_mount: Option<RtspMount>,
+    device_lease: Option<DeviceLease>,
+    v4l2_device: Option<V4l2Device>,
+    label: Option<LabelClaim>,
+}
+
+impl PreparedVDevice {
+    pub fn video_prop(&self) -> &VideoProp {
+        &self.video_prop
+    }
+
+    /// Path of the loopback device fed by the camera
+    pub fn device_path(&self) -> String {
+        match (&self.device_lease, &self.v4l2_device) {
+            (Some(lease), _) => lease.path().to_string(),
+            (None, Some(device)) => device.path.to_string_lossy().to_string(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Connects the webrtc transport with the camera sdp offer, the offer
+    /// is ignored when the host negotiates
+    pub async fn connect(
+        self, sdp: &str, call_settings: CallSettings,
+    ) -> Result<VDevice> {
+        let sdp_offer = match call_settings.negotiation {
+            Negotiation::MobileOffer => {
+                Some(serde_json::from_str::<Sdp>(sdp)?.sdp)
+            }
+            Negotiation::HostOffer => None,
+        };
+
+        //only the owner of the mobile sees its cameras
+        let node_owner = call_settings
+            .owner
+            .map(|uid| NodeOwner::restrict(Path::new(&self.device_path()), uid))
+            .transpose()?;
 
-        //       let device_path_clone = v4l2_device.path.to_string_lossy().to_string();
-        let device_path_clone = "/dev/video0".to_string();
+        let remote_sdp = sdp_offer.clone();
+        let firewall = call_settings.firewall.clone();
+        let pipeline = self.pipeline;
         let webrtc_pipeline = task::spawn_blocking(move || {
-            WebrtcPipeline::new(device_path_clone, sdp_offer.sdp, video_prop)
+            pipeline.connect(sdp_offer, call_settings)
         })
         .await??;
 
-        Ok(Self { /*_v4l2_device: v4l2_device,*/ webrtc_pipeline })
+        let vdevice = VDevice {
+            name: self.name,
+            webrtc_pipeline,
+            media_rule: Mutex::new(None),
+            firewall,
+            _rtsp_mount: self.rtsp_mount,
+            _node_owner: node_owner,
+            _device_lease: self.device_lease,
+            _v4l2_device: self.v4l2_device,
+            label: self.label,
+        };
+        //the host offer waits for the answer of the mobile
+        if let Some(remote_sdp) = remote_sdp {
+            vdevice.open_media_ports(&remote_sdp)?;
+        }
+
+        Ok(vdevice)
+    }
+}
+
+impl VDevice {
+    //the ports of the local description, from the mobile addresses of the
+    //remote one
+    fn open_media_ports(&self, remote_sdp: &str) -> Result<()> {
+        let Some(firewall) = self.firewall.as_ref() else {
+            return Ok(());
+        };
+        let endpoints = MediaEndpoints::from_sdp(
+            &self.webrtc_pipeline.get_sdp_answer(),
+            remote_sdp,
+        );
+        let rule = firewall.allow(&endpoints)?;
+        if let Ok(mut media_rule) = self.media_rule.lock() {
+            *media_rule = Some(rule);
+        }
+        Ok(())
     }
+}
 
-    pub fn get_sdp_answer(&self) -> String {
+impl VDeviceOps for VDevice {
+    fn get_sdp_answer(&self) -> String {
         self.webrtc_pipeline.get_sdp_answer()
     }
+
+    fn set_remote_answer(&self, sdp: &str) -> Result<()> {
+        let sdp_answer: Sdp = serde_json::from_str(sdp)?;
+        self.open_media_ports(&sdp_answer.sdp)?;
+        self.webrtc_pipeline.set_remote_answer(sdp_answer.sdp)
+    }
+
+    fn stream_stats(&self) -> StreamStats {
+        StreamStats {
+            name: self.name.clone(),
+            device_label: self
+                .label
+                .as_ref()
+                .map(|label| label.label().to_string()),
+            ..self.webrtc_pipeline.stream_stats()
+        }
+    }
+
+    fn take_failure(&self) -> Option<String> {
+        self.webrtc_pipeline.take_failure()
+    }
+
+    fn milestones(&self) -> StreamMilestones {
+        self.webrtc_pipeline.milestones()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_config_from_format() {
+        let video_prop = VideoProp { resolution: (1920, 1080), fps: 60 };
+
+        let config = device_config("cam", &video_prop, &Default::default());
+        assert_eq!((config.min_width, config.max_width), (540, 1920));
+        assert_eq!((config.min_height, config.max_height), (540, 1920));
+        assert_eq!(config.max_buffers, 6);
+
+        let overrides =
+            LoopbackConfig { max_buffers: Some(8), ..Default::default() };
+        let config = device_config("cam", &video_prop, &overrides);
+        assert_eq!(config.max_buffers, 8);
+        assert_eq!(config.label, "cam");
+    }
 }
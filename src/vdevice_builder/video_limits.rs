@@ -0,0 +1,84 @@
+//! # Video limits.
+//! A small host can't decode whatever an enthusiastic phone offers, e.g.
+//! 4K at 60 fps. The offered formats are clamped to the configured limits
+//! before the devices and the pipelines are built, and the limits are
+//! announced to the mobile in the answer so it encodes within them.
+
+use std::sync::Arc;
+
+use super::sdp_munger::{CapResolution, MaxFramerate, SdpMunger};
+use crate::{ble::comm_types::VideoProp, config::VideoLimitsConfig};
+
+/// `video_prop` scaled down within the limits keeping its aspect ratio,
+/// the limits are turned for a portrait camera
+pub fn clamp(video_prop: &VideoProp, limits: &VideoLimitsConfig) -> VideoProp {
+    let (width, height) = video_prop.resolution;
+    let (max_width, max_height) = if width >= height {
+        (limits.max_width, limits.max_height)
+    } else {
+        (limits.max_height, limits.max_width)
+    };
+
+    let scale = [(max_width, width), (max_height, height)]
+        .into_iter()
+        .filter_map(|(max, size)| Some(max? as f64 / size.max(1) as f64))
+        .fold(1.0, f64::min);
+    let resolution = if scale < 1.0 {
+        //the encoders and the converters want even sizes
+        let scaled = |size: u32| ((size as f64 * scale) as u32 & !1).max(2);
+        (scaled(width), scaled(height))
+    } else {
+        video_prop.resolution
+    };
+
+    VideoProp {
+        resolution,
+        fps: limits
+            .max_fps
+            .map_or(video_prop.fps, |max| video_prop.fps.min(max)),
+    }
+}
+
+/// Mungers announcing the limits in the answer
+pub fn hints(limits: &VideoLimitsConfig) -> Vec<Arc<dyn SdpMunger>> {
+    let mut hints: Vec<Arc<dyn SdpMunger>> = Vec::new();
+    if let (Some(max_width), Some(max_height)) =
+        (limits.max_width, limits.max_height)
+    {
+        hints.push(Arc::new(CapResolution { max_width, max_height }));
+    }
+    if let Some(fps) = limits.max_fps {
+        hints.push(Arc::new(MaxFramerate { fps }));
+    }
+    hints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats_clamped() {
+        let limits = VideoLimitsConfig {
+            max_width: Some(1920),
+            max_height: Some(1080),
+            max_fps: Some(30),
+        };
+        let uhd = VideoProp { resolution: (3840, 2160), fps: 60 };
+        assert_eq!(
+            clamp(&uhd, &limits),
+            VideoProp { resolution: (1920, 1080), fps: 30 }
+        );
+
+        //portrait, and a 4:3 format limited by the height
+        let portrait = VideoProp { resolution: (2160, 3840), fps: 24 };
+        assert_eq!(clamp(&portrait, &limits).resolution, (1080, 1920));
+        let four_thirds = VideoProp { resolution: (2048, 1536), fps: 30 };
+        assert_eq!(clamp(&four_thirds, &limits).resolution, (1440, 1080));
+
+        let small = VideoProp { resolution: (1280, 720), fps: 30 };
+        assert_eq!(clamp(&small, &limits), small);
+        assert_eq!(clamp(&uhd, &VideoLimitsConfig::default()), uhd);
+        assert_eq!(hints(&limits).len(), 2);
+    }
+}
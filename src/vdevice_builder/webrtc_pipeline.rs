@@ -1,8 +1,44 @@
-use crate::{ble::comm_types::VideoProp, error::Result};
+use super::bandwidth::BandwidthPolicer;
+use super::control_bridge;
+use super::cpu_pressure::{CpuPressure, QualityLevel};
+use super::firewall::SessionFirewall;
+use super::latency_pattern::{self, LatencyMeter};
+use super::loopback_timing::{LoopbackTiming, StreamTiming};
+use super::output_format;
+use super::post_processing::{DeviceFilters, PostProcessing};
+use super::recording;
+use super::sdp_munger::{self, SdpMungers};
+use super::stream_stats::{CpuSampler, HostCpuSampler, PipelineThreads};
+use crate::{
+    ble::comm_types::{HostPowerState, Negotiation, StreamStats, VideoProp},
+    call_trace::StreamMilestones,
+    config::{CpuPressureConfig, OutputBackend},
+    error::Result,
+    privacy_switch::PrivacySwitch,
+    sleep_inhibitor::{SleepInhibitor, StreamGuard},
+    supervisor::{isolate, panic_message},
+    thumbnails::ThumbnailSlot,
+};
 use anyhow::anyhow;
 use gst_webrtc::WebRTCBundlePolicy;
-use std::{fs::OpenOptions, io::Write, sync::mpsc, thread};
-use v4l::{video::Output, Device, FourCC};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+use tokio::sync::watch;
+use v4l::{
+    video::{output::Parameters, Output},
+    Device, FourCC,
+};
 
 use gst::{
     glib::{self, MainLoop},
@@ -12,77 +48,334 @@ use gst::{
 
 use log::{debug, error, info};
 
+/// Host settings applied to every pipeline
+#[derive(Debug, Clone, Default)]
+pub struct PipelineSettings {
+    pub cpu_pressure: CpuPressureConfig,
+    /// intervideo channel feeding the RTSP server, if enabled for the device
+    pub rtsp_channel: Option<String>,
+    /// intervideo channel feeding the composites showing the device
+    pub composite_channel: Option<String>,
+    /// Extra output backends enabled for the device
+    pub outputs: Vec<OutputBackend>,
+    /// Replaces the camera by a placeholder while enabled
+    pub privacy: PrivacySwitch,
+    /// Power state of the host, the output is scaled down on battery if set
+    pub battery_saver: Option<watch::Receiver<HostPowerState>>,
+    /// Keeps the host awake while the pipeline streams
+    pub sleep_inhibitor: Option<SleepInhibitor>,
+    /// Keeps the latest output frame as a JPEG, if set
+    pub thumbnail: Option<Arc<ThumbnailSlot>>,
+    /// Width of the thumbnail
+    pub thumbnail_width: u32,
+    /// Denoise and sharpen presets of the device, if set
+    pub filters: Option<DeviceFilters>,
+}
+
+/// Settings of a single call, given with the offer
+#[derive(Clone, Default)]
+pub struct CallSettings {
+    /// TURN servers in the webrtcbin form, the credentials are only valid
+    /// for a while
+    pub turn_servers: Vec<String>,
+    pub sdp_mungers: SdpMungers,
+    pub negotiation: Negotiation,
+    /// Caps of the received RTP, shared with the other cameras
+    pub bandwidth: BandwidthPolicer,
+    /// The cameras send the latency test pattern
+    pub latency_test: bool,
+    /// Milliseconds the received video is buffered for
+    pub jitter_ms: u32,
+    /// File recording the camera, if the mobile always records
+    pub record_to: Option<PathBuf>,
+    /// Opens the media ports of the call on the access point
+    pub firewall: Option<Arc<SessionFirewall>>,
+    /// User the device is restricted to, the owner of the mobile
+    pub owner: Option<u32>,
+}
+
+/// Frames per second of the thumbnails
+const THUMBNAIL_FPS: i32 = 1;
+
+/// JPEG quality of the thumbnails, 0-100
+const THUMBNAIL_QUALITY: i32 = 60;
+
+/// Seconds between checks of the post-processing presets
+const FILTERS_CHECK_SECS: u32 = 1;
+
+/// Size the frames are scaled to for the latency pattern, 8 pixels a cell
+const LATENCY_WIDTH: usize = latency_pattern::COLUMNS * 8;
+const LATENCY_HEIGHT: usize = latency_pattern::ROWS * 8;
+
+//offer of the mobile, None when the host offers
 #[derive(Debug)]
-pub struct WebrtcPipeline {
+struct CallOffer {
+    sdp_offer: Option<String>,
+    turn_servers: Vec<String>,
+    bandwidth: BandwidthPolicer,
+    latency_test: bool,
+    jitter_ms: u32,
+    record_to: Option<PathBuf>,
+}
+
+//codecs of the host offer, decoded by decodebin
+const HOST_OFFER_CAPS: &str = "application/x-rtp,media=video,\
+    encoding-name=VP8,payload=96,clock-rate=90000;\
+    application/x-rtp,media=video,encoding-name=H264,payload=102,\
+    clock-rate=90000,packetization-mode=(string)1,\
+    profile-level-id=(string)42e01f";
+
+//thread running the pipeline main loop, stopped on drop
+#[derive(Debug)]
+struct PipelineThread {
     mainloop: MainLoop,
-    pipeline_thread: Option<thread::JoinHandle<Result<()>>>,
-    sdp_answer: String,
+    handle: Option<thread::JoinHandle<Result<()>>>,
 }
 
-impl WebrtcPipeline {
+impl Drop for PipelineThread {
+    fn drop(&mut self) {
+        info!("Dropping WebrtcPipeline");
+        self.mainloop.quit();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Failed to join pipeline thread: {:?}", e);
+            }
+        }
+    }
+}
+
+//first error posted on the bus, until it is taken
+type PipelineFailure = Arc<Mutex<Option<String>>>;
+
+//times of the ICE connection and of the first frame of the camera
+type PipelineMilestones = Arc<Mutex<StreamMilestones>>;
+
+//latency decoded from the test pattern, in a latency test
+type PipelineLatency = Arc<Mutex<LatencyMeter>>;
+
+//written by the pipeline thread, read through the pipeline
+#[derive(Debug, Default, Clone)]
+struct PipelineShared {
+    threads: PipelineThreads,
+    failure: PipelineFailure,
+    milestones: PipelineMilestones,
+    latency: PipelineLatency,
+}
+
+/// Pipeline built up to the webrtc transport, waiting for the offer
+#[derive(Debug)]
+pub struct PreparedPipeline {
+    //dropped before the thread is joined, so a pipeline discarded before
+    //the offer stops
+    offer_tx: mpsc::Sender<CallOffer>,
+    answer_rx: mpsc::Receiver<(String, gst::Element)>,
+    threads: PipelineThreads,
+    thread: PipelineThread,
+    sleep_inhibitor: Option<SleepInhibitor>,
+    failure: PipelineFailure,
+    milestones: PipelineMilestones,
+    latency: PipelineLatency,
+}
+
+impl PreparedPipeline {
     pub fn new(
-        vdevice: String, sdp_offer: String, video_prop: VideoProp,
+        vdevice: String, video_prop: VideoProp, settings: PipelineSettings,
     ) -> Result<Self> {
         let mainloop = glib::MainLoop::new(None, false);
 
-        let (tx, rx) = mpsc::channel();
+        let (tx, answer_rx) = mpsc::channel();
+        let (offer_tx, offer_rx) = mpsc::channel();
 
         let mainloop_clone = mainloop.clone();
 
+        let shared = PipelineShared::default();
+        let shared_clone = shared.clone();
+
+        let sleep_inhibitor = settings.sleep_inhibitor.clone();
+
         info!("Creating pipeline thread");
 
-        let pipeline_thread = thread::spawn(move || {
-            match create_pipeline(
-                mainloop_clone,
-                vdevice,
-                sdp_offer,
-                tx,
-                video_prop,
-            ) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    error!("Failed to create pipeline: {:?}", e);
-                    Err(e)
+        //a panic stops only this camera, it's reported as its failure
+        let failure = shared.failure.clone();
+        let pipeline_thread = thread::Builder::new()
+            .name("wcd-pipeline".to_string())
+            .spawn(move || {
+                let created = catch_unwind(AssertUnwindSafe(|| {
+                    create_pipeline(
+                        mainloop_clone,
+                        vdevice,
+                        offer_rx,
+                        tx,
+                        video_prop,
+                        shared_clone,
+                        settings,
+                    )
+                }))
+                .unwrap_or_else(|payload| {
+                    let panic = panic_message(&*payload);
+                    if let Ok(mut failure) = failure.lock() {
+                        failure.get_or_insert_with(|| {
+                            format!("Pipeline panicked: {}", panic)
+                        });
+                    }
+                    Err(anyhow!("Pipeline panicked: {}", panic))
+                });
+
+                match created {
+                    Ok(_) => Ok(()),
+                    Err(e) => {
+                        error!("Failed to create pipeline: {:?}", e);
+                        Err(e)
+                    }
                 }
-            }
-        });
+            })?;
+
+        Ok(Self {
+            offer_tx,
+            answer_rx,
+            threads: shared.threads,
+            thread: PipelineThread { mainloop, handle: Some(pipeline_thread) },
+            sleep_inhibitor,
+            failure: shared.failure,
+            milestones: shared.milestones,
+            latency: shared.latency,
+        })
+    }
 
-        //will block until we get the sdp answer or all tx are dropped
-        let Ok(sdp_answer) = rx.recv() else {
+    /// Completes the pipeline with the sdp offer of the mobile, or with an
+    /// offer of the host if None, and the settings of the call
+    pub fn connect(
+        self, sdp_offer: Option<String>, call_settings: CallSettings,
+    ) -> Result<WebrtcPipeline> {
+        let PreparedPipeline {
+            offer_tx,
+            answer_rx,
+            threads,
+            thread,
+            sleep_inhibitor,
+            failure,
+            milestones,
+            latency,
+        } = self;
+        let CallSettings {
+            turn_servers,
+            sdp_mungers,
+            bandwidth,
+            latency_test,
+            jitter_ms,
+            record_to,
+            ..
+        } = call_settings;
+
+        let sdp_offer = sdp_offer
+            .map(|sdp_offer| sdp_munger::munge_offer(&sdp_mungers, sdp_offer));
+        offer_tx
+            .send(CallOffer {
+                sdp_offer,
+                turn_servers,
+                bandwidth,
+                latency_test,
+                jitter_ms,
+                record_to,
+            })
+            .map_err(|_| anyhow!("Pipeline stopped before the offer"))?;
+
+        //will block until we get the local sdp or all tx are dropped
+        let Ok((sdp_answer, webrtcbin)) = answer_rx.recv() else {
             return Err(anyhow!("Failed to get sdp answer"));
         };
+        let sdp_answer = sdp_munger::munge_answer(&sdp_mungers, sdp_answer);
 
         Ok(WebrtcPipeline {
-            mainloop,
-            pipeline_thread: Some(pipeline_thread),
+            _thread: thread,
             sdp_answer,
+            webrtcbin,
+            sdp_mungers,
+            cpu_sampler: Mutex::new(CpuSampler::new(threads)),
+            _stream: sleep_inhibitor
+                .map(|inhibitor| inhibitor.stream_started()),
+            failure,
+            milestones,
+            latency,
         })
     }
+}
 
+#[derive(Debug)]
+pub struct WebrtcPipeline {
+    _thread: PipelineThread,
+    //local description, the host offer when the host negotiates
+    sdp_answer: String,
+    webrtcbin: gst::Element,
+    sdp_mungers: SdpMungers,
+    cpu_sampler: Mutex<CpuSampler>,
+    //released once the pipeline thread is joined
+    _stream: Option<StreamGuard>,
+    failure: PipelineFailure,
+    milestones: PipelineMilestones,
+    latency: PipelineLatency,
+}
+
+impl WebrtcPipeline {
     pub fn get_sdp_answer(&self) -> String {
         self.sdp_answer.clone()
     }
-}
 
-impl Drop for WebrtcPipeline {
-    fn drop(&mut self) {
-        info!("Dropping WebrtcPipeline");
-        self.mainloop.quit();
-        if let Some(handle) = self.pipeline_thread.take() {
-            if let Err(e) = handle.join() {
-                error!("Failed to join pipeline thread: {:?}", e);
-            }
+    /// Applies the answer of the mobile to the host offer
+    pub fn set_remote_answer(&self, sdp_answer: String) -> Result<()> {
+        let sdp_answer = sdp_munger::munge_offer(&self.sdp_mungers, sdp_answer);
+        let sdp = gst_sdp::SDPMessage::parse_buffer(sdp_answer.as_bytes())?;
+        let answer = gst_webrtc::WebRTCSessionDescription::new(
+            gst_webrtc::WebRTCSDPType::Answer,
+            sdp,
+        );
+
+        self.webrtcbin.emit_by_name::<()>(
+            "set-remote-description",
+            &[&answer, &None::<gst::Promise>],
+        );
+
+        Ok(())
+    }
+
+    /// CPU usage of the pipeline since the previous call, and the latency
+    /// of the call in a latency test
+    pub fn stream_stats(&self) -> StreamStats {
+        let stats = match self.cpu_sampler.lock() {
+            Ok(mut sampler) => sampler.sample(),
+            Err(_) => StreamStats::default(),
+        };
+        StreamStats {
+            latency: self.latency.lock().ok().and_then(|meter| meter.stats()),
+            ..stats
         }
     }
+
+    /// Error posted by the pipeline since the previous call
+    pub fn take_failure(&self) -> Option<String> {
+        self.failure.lock().ok().and_then(|mut failure| failure.take())
+    }
+
+    /// Times of the ICE connection and of the first frame, once reached
+    pub fn milestones(&self) -> StreamMilestones {
+        self.milestones.lock().map(|milestones| *milestones).unwrap_or_default()
+    }
 }
 
 //create the gstreamer pipeline
 fn create_pipeline(
-    main_loop: glib::MainLoop, vdevice: String, sdp_offer: String,
-    tx: mpsc::Sender<String>, video_prop: VideoProp,
+    main_loop: glib::MainLoop, vdevice: String,
+    offer_rx: mpsc::Receiver<CallOffer>,
+    tx: mpsc::Sender<(String, gst::Element)>, video_prop: VideoProp,
+    shared: PipelineShared, settings: PipelineSettings,
 ) -> Result<()> {
+    let PipelineShared { threads, failure, milestones, latency } = shared;
+
     gst::init()?;
 
+    //the main loop runs in this thread
+    threads.register_current();
+
     let pipeline = Pipeline::default();
 
     let webrtcbin = ElementFactory::make("webrtcbin").build()?;
@@ -103,11 +396,22 @@ fn create_pipeline(
     let videosink = ElementFactory::make("autovideosink").build()?;
 
     let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let videobalance = ElementFactory::make("videobalance").build()?;
     let videoconvert2 = ElementFactory::make("videoconvert").build()?;
     let videoscale = ElementFactory::make("videoscale").build()?;
     let videoscale2 = ElementFactory::make("videoscale").build()?;
     let videorate = ElementFactory::make("videorate").build()?;
 
+    //output caps, restricted to scale down the output under CPU pressure
+    let scale_caps = ElementFactory::make("capsfilter").build()?;
+
+    //the camera or the privacy placeholder feed the outputs
+    let privacy_selector = ElementFactory::make("input-selector").build()?;
+
+    //the output is split between the local sink and the extra outputs
+    let output_tee = ElementFactory::make("tee").build()?;
+    let sink_queue = ElementFactory::make("queue").build()?;
+
     videorate.set_property("max-rate", video_prop.fps as i32);
 
     //setting video properties
@@ -129,19 +433,25 @@ fn create_pipeline(
     let v4l_dev = Device::with_path(&vdevice)
         .map_err(|e| anyhow!("Failed to create v4l2 device: {:?}", e))?;
 
-    //set NV12 format and resolution 540x960
-    let mut format = v4l_dev
+    //set the first format accepted by the device, at the size of the stream
+    let offered = StreamTiming::from_video_prop(&video_prop);
+    let format = v4l_dev
         .format()
         .map_err(|e| anyhow!("Failed to get v4l2 device format: {:?}", e))?;
     info!("v4l2 format: {:?}", format);
 
-    format.fourcc = FourCC::new(b"NV12");
-    format.width = 540;
-    format.height = 960;
+    let output_format = output_format::negotiate(|output_format| {
+        let mut format = format;
+        format.fourcc = FourCC::new(output_format.fourcc);
+        format.width = offered.width;
+        format.height = offered.height;
+
+        let format = v4l_dev.set_format(&format).map_err(|e| {
+            anyhow!("Failed to set v4l2 device format: {:?}", e)
+        })?;
 
-    v4l_dev
-        .set_format(&format)
-        .map_err(|e| anyhow!("Failed to set v4l2 device format: {:?}", e))?;
+        Ok(format.fourcc == FourCC::new(output_format.fourcc))
+    })?;
 
     //read the format again
     let format = v4l_dev
@@ -150,6 +460,26 @@ fn create_pipeline(
 
     info!("v4l2 format after configured: {:?}", format);
 
+    //the consuming apps pick their mode from the size and the interval the
+    //device enumerates, they follow the output caps
+    let mut loopback_timing =
+        LoopbackTiming::new(offered, move |timing: &StreamTiming| {
+            let mut format = v4l_dev.format()?;
+            format.width = timing.width;
+            format.height = timing.height;
+            v4l_dev.set_format(&format)?;
+            let (num, den) = timing.interval();
+            v4l_dev
+                .set_params(&Parameters::new(v4l::Fraction::new(num, den)))?;
+            Ok(())
+        });
+    loopback_timing.update(offered);
+
+    //apply the image controls set on the device by the consuming app, none
+    //with v4l2loopback that doesn't expose them
+    let controls_source =
+        control_bridge::watch_controls(&vdevice, videobalance.clone());
+
     //v4l2sink.set_property("device", &vdevice);
 
     let appsink = ElementFactory::make("appsink").build()?;
@@ -158,7 +488,7 @@ fn create_pipeline(
 
     //set the caps for the appsink
     let caps = gst::Caps::builder("video/x-raw")
-        .field("format", "I420")
+        .field("format", output_format.gst_format)
         .field("width", 540)
         .field("height", 960)
         .field("framerate", Fraction::new(video_prop.fps as i32, 1))
@@ -192,65 +522,148 @@ fn create_pipeline(
         Some(FlowReturn::Ok.to_value())
     });
 
-    pipeline.add_many(&[
+    pipeline.add_many([
         &webrtcbin,
         &decodebin,
         &queue,
-        //&rtph264depay,
-        //&h264parse,
-        //&h264dec,
         &videoconvert,
+        &videobalance,
         &videoscale,
-        //&capsfilter,
-        //&videoconvert2,
-        //&videoscale2,
-        //&v4l2sink,
-        //&appsink,
+        &scale_caps,
+        &privacy_selector,
+        &output_tee,
+        &sink_queue,
         &videosink,
     ])?;
 
-    gst::Element::link_many(&[
-        &queue,
-        // &rtph264depay,
-        // &h264parse,
-        // &h264dec,
-        &videoconvert,
+    gst::Element::link_many([&queue, &videoconvert])?;
+
+    //the denoise and sharpen stages come before the image controls
+    let post_processing = match &settings.filters {
+        Some(filters) => Some(PostProcessing::add(
+            &pipeline,
+            &videoconvert,
+            &videobalance,
+            filters.presets(),
+        )?),
+        None => {
+            videoconvert.link(&videobalance)?;
+            None
+        }
+    };
+
+    gst::Element::link_many([
+        &videobalance,
         &videoscale,
-        //&capsfilter,
-        // &videoconvert2,
-        // &videoscale2,
-        //&v4l2sink,
-        //&appsink,
+        &scale_caps,
+        &privacy_selector,
+        &output_tee,
+        &sink_queue,
         &videosink,
     ])?;
 
+    let camera_pad = scale_caps
+        .static_pad("src")
+        .and_then(|pad| pad.peer())
+        .ok_or(anyhow!("Camera not linked to the privacy selector"))?;
+    let placeholder_pad =
+        add_privacy_placeholder(&pipeline, &privacy_selector, &video_prop)?;
+
+    //first decoded frame of the camera, the placeholder doesn't count
+    let milestones_clone = milestones.clone();
+    camera_pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+        if let Ok(mut milestones) = milestones_clone.lock() {
+            milestones.first_frame.get_or_insert_with(Instant::now);
+        }
+        gst::PadProbeReturn::Remove
+    });
+
+    //every output switches at once, the pipeline is gone once the selector
+    //can't be upgraded
+    let privacy_selector = privacy_selector.downgrade();
+    settings.privacy.add_output(Box::new(move |enabled| {
+        let Some(selector) = privacy_selector.upgrade() else {
+            return false;
+        };
+        let pad = if enabled { &placeholder_pad } else { &camera_pad };
+        selector.set_property("active-pad", pad);
+        true
+    }))?;
+
+    follow_output_caps(&output_tee, loopback_timing)?;
+
+    //extra outputs of the camera
+    if let Some(channel) = &settings.rtsp_channel {
+        let intervideosink = ElementFactory::make("intervideosink")
+            .property("channel", channel)
+            .build()?;
+        add_output_branch(&pipeline, &output_tee, vec![intervideosink])?;
+    }
+
+    if let Some(channel) = &settings.composite_channel {
+        let intervideosink = ElementFactory::make("intervideosink")
+            .property("channel", channel)
+            .build()?;
+        add_output_branch(&pipeline, &output_tee, vec![intervideosink])?;
+    }
+
+    //a backend that can't be built doesn't stop the camera
+    for backend in settings.outputs.iter() {
+        if let Err(e) = backend.build_elements().and_then(|elements| {
+            add_output_branch(&pipeline, &output_tee, elements)
+        }) {
+            error!("Failed to add output {:?}: {:?}", backend, e);
+        }
+    }
+
+    //preview of the output, a failure doesn't stop the camera
+    if let Some(slot) = settings.thumbnail.clone() {
+        if let Err(e) = add_thumbnail_branch(
+            &pipeline,
+            &output_tee,
+            slot,
+            settings.thumbnail_width,
+        ) {
+            error!("Failed to add the thumbnail: {:?}", e);
+        }
+    }
+
+    //closed until the offer asks for a latency test, a failure doesn't stop
+    //the camera
+    let latency_valve = add_latency_branch(&pipeline, &output_tee, latency)
+        .inspect_err(|e| error!("Failed to add the latency test: {:?}", e))
+        .ok();
+
     //configure decodebin
     let queue_clone = queue.clone();
 
+    //called from C, a panic can't unwind out of the callback
     decodebin.connect("pad-added", false, move |values| {
-        let _decodebin = values[0].get::<gst::Element>().unwrap();
-        let pad = values[1].get::<gst::Pad>().unwrap();
+        isolate("Decodebin pad-added", || {
+            let _decodebin = values[0].get::<gst::Element>().unwrap();
+            let pad = values[1].get::<gst::Pad>().unwrap();
 
-        let caps = pad.current_caps().unwrap();
-        let name = caps.structure(0).unwrap().name();
+            let caps = pad.current_caps().unwrap();
+            let name = caps.structure(0).unwrap().name();
 
-        if name.starts_with("video/") {
-            let sink_pad = queue_clone.static_pad("sink").unwrap();
+            if name.starts_with("video/") {
+                let sink_pad = queue_clone.static_pad("sink").unwrap();
 
-            if sink_pad.is_linked() {
-                info!("Decodebin pad is already linked to queue");
-                return None;
-            }
-
-            match pad.link(&sink_pad) {
-                Ok(_) => {
-                    info!("Linked decodebin to queue successfully.");
+                if sink_pad.is_linked() {
+                    info!("Decodebin pad is already linked to queue");
+                    return;
                 }
-                Err(err) => {
-                    info!("Failed to link decodebin: {:?}", err);
+
+                match pad.link(&sink_pad) {
+                    Ok(_) => {
+                        info!("Linked decodebin to queue successfully.");
+                    }
+                    Err(err) => {
+                        info!("Failed to link decodebin: {:?}", err);
+                    }
                 }
             }
-        }
+        });
 
         None
     });
@@ -306,11 +719,25 @@ fn create_pipeline(
         None
     });
 
-    webrtcbin
-        .connect("on-negotiation-needed", false, move |_values| {
+    //set once the offer of the call is known to be created by the host
+    let host_offer = Arc::new(AtomicBool::new(false));
+    let host_offer_clone = host_offer.clone();
+
+    webrtcbin.connect("on-negotiation-needed", false, move |values| {
+        if !host_offer_clone.load(Ordering::SeqCst) {
             info!("Negotiation needed signal received (waiting for an external offer)...");
-            None
-        });
+            return None;
+        }
+
+        let Ok(webrtc) = values[0].get::<gst::Element>() else {
+            error!("Expected webrtcbin element");
+            return None;
+        };
+
+        info!("Negotiation needed signal received, creating the host offer");
+        create_host_offer(&webrtc);
+        None
+    });
 
     webrtcbin.connect("on-ice-candidate", false, move |values| {
         let Ok(_) = values[0].get::<gst::Element>() else {
@@ -350,29 +777,70 @@ fn create_pipeline(
 
             info!("ICE gathering state changed: {:?}", state);
             if state == gst_webrtc::WebRTCICEGatheringState::Complete {
-                let Ok(sdp_answer) = webrtcbin_clone
+                let Ok(local_sdp) = webrtcbin_clone
                     .property::<gst_webrtc::WebRTCSessionDescription>(
                         "local-description",
                     )
                     .sdp()
                     .as_text()
                 else {
-                    error!("Failed to get SDP answer");
+                    error!("Failed to get local SDP");
                     return;
                 };
 
-                debug!("Sending SDP answer to main thread {}", sdp_answer);
-                let Ok(_) = tx_clone.send(sdp_answer) else {
-                    error!("Failed to send SDP answer to main thread");
+                debug!("Sending local SDP to main thread {}", local_sdp);
+                let Ok(_) = tx_clone.send((local_sdp, webrtcbin_clone.clone()))
+                else {
+                    error!("Failed to send local SDP to main thread");
                     return;
                 };
             }
         },
     );
 
+    webrtcbin.connect_notify(
+        Some("ice-connection-state"),
+        move |webrtc, _pspec| {
+            let state = webrtc
+                .property::<gst_webrtc::WebRTCICEConnectionState>(
+                    "ice-connection-state",
+                );
+            info!("ICE connection state changed: {:?}", state);
+
+            if matches!(
+                state,
+                gst_webrtc::WebRTCICEConnectionState::Connected
+                    | gst_webrtc::WebRTCICEConnectionState::Completed
+            ) {
+                if let Ok(mut milestones) = milestones.lock() {
+                    milestones.ice_connected.get_or_insert_with(Instant::now);
+                }
+            }
+        },
+    );
+
     // bus error handling
     let bus = pipeline.bus().ok_or(anyhow!("Failed to get bus"))?;
 
+    //the streaming threads post the status from the thread itself, so they
+    //can be attributed to this pipeline
+    let threads_clone = threads.clone();
+    bus.set_sync_handler(move |_, msg| {
+        if let gst::MessageView::StreamStatus(status) = msg.view() {
+            match status.get().0 {
+                gst::StreamStatusType::Enter => {
+                    threads_clone.register_current()
+                }
+                gst::StreamStatusType::Leave => {
+                    threads_clone.unregister_current()
+                }
+                _ => (),
+            }
+        }
+
+        gst::BusSyncReply::Pass
+    });
+
     let main_loop_clone = main_loop.clone();
 
     let _bus_watch = bus.add_watch(move |_, msg| {
@@ -393,6 +861,10 @@ fn create_pipeline(
                     err.error(),
                     err.debug()
                 );
+                //the first error is the cause of the others
+                if let Ok(mut failure) = failure.lock() {
+                    failure.get_or_insert_with(|| err.error().to_string());
+                }
                 //main_loop.quit()
             }
             _ => (),
@@ -404,6 +876,101 @@ fn create_pipeline(
 
     pipeline.set_state(gst::State::Playing)?;
 
+    //a standby pipeline waits here until the offer arrives
+    let Ok(CallOffer {
+        sdp_offer,
+        turn_servers,
+        bandwidth,
+        latency_test,
+        jitter_ms,
+        record_to,
+    }) = offer_rx.recv()
+    else {
+        info!("Pipeline discarded before the offer");
+        if let Some(source) = controls_source {
+            source.remove();
+        }
+        pipeline.set_state(gst::State::Null)?;
+        return Ok(());
+    };
+
+    //the jitter buffer of the received streams, none until the offer
+    webrtcbin.set_property("latency", jitter_ms);
+
+    for turn_server in turn_servers.iter() {
+        if !webrtcbin.emit_by_name::<bool>("add-turn-server", &[turn_server]) {
+            error!("TURN server rejected by webrtcbin");
+        }
+    }
+
+    if bandwidth.is_enabled() {
+        police_ingress(&decodebin, bandwidth)?;
+    }
+
+    if let Some(valve) = latency_valve.filter(|_| latency_test) {
+        info!("Measuring the latency from the test pattern");
+        valve.set_property("drop", false);
+    }
+
+    //recorded from the start of the stream, a failure doesn't stop the
+    //camera
+    if let Some(path) = record_to {
+        info!("Recording the camera to {}", path.display());
+        if let Err(e) = recording::build_elements(&path).and_then(|elements| {
+            add_output_branch(&pipeline, &output_tee, elements)
+        }) {
+            error!("Failed to record to {}: {:?}", path.display(), e);
+        }
+    }
+
+    match sdp_offer {
+        Some(sdp_offer) => set_mobile_offer(&webrtcbin, &sdp_offer)?,
+        None => {
+            //on-negotiation-needed creates the offer once the transceiver
+            //is added
+            host_offer.store(true, Ordering::SeqCst);
+            let caps = gst::Caps::from_str(HOST_OFFER_CAPS)?;
+            webrtcbin.emit_by_name::<gst_webrtc::WebRTCRTPTransceiver>(
+                "add-transceiver",
+                &[&gst_webrtc::WebRTCRTPTransceiverDirection::Recvonly, &caps],
+            );
+        }
+    }
+
+    let pressure_source = (settings.cpu_pressure.enabled
+        || settings.battery_saver.is_some())
+    .then(|| watch_quality(&settings, scale_caps, &video_prop));
+
+    let filters_source = settings.filters.clone().zip(post_processing).map(
+        |(filters, post_processing)| watch_filters(filters, post_processing),
+    );
+
+    // Start the main loop in a separate thread
+    info!("Starting main loop");
+
+    main_loop.run();
+
+    info!("Main loop stopped");
+
+    if let Some(source) = pressure_source {
+        source.remove();
+    }
+
+    if let Some(source) = filters_source {
+        source.remove();
+    }
+
+    if let Some(source) = controls_source {
+        source.remove();
+    }
+
+    pipeline.set_state(gst::State::Null)?;
+
+    Ok(())
+}
+
+//applies the mobile offer and answers it
+fn set_mobile_offer(webrtcbin: &gst::Element, sdp_offer: &str) -> Result<()> {
     /*
         let sdp_offer = "v=0\r\no=- 4611733054762223410 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\nc=IN IP4 0.0.0.0\r\na=mid:0\r\na=sendonly\r\na=rtcp-mux\r\na=rtpmap:96 VP8/90000\r\n";
     */
@@ -490,14 +1057,344 @@ fn create_pipeline(
         &[&offer, &promise_offer],
     );
 
-    // Start the main loop in a separate thread
-    info!("Starting main loop");
+    Ok(())
+}
 
-    main_loop.run();
+//offer of the host, the answer of the mobile is applied with
+//`WebrtcPipeline::set_remote_answer`
+fn create_host_offer(webrtcbin: &gst::Element) {
+    let webrtcbin_clone = webrtcbin.clone();
+    let promise = gst::Promise::with_change_func(move |reply| {
+        let Ok(Some(reply)) = reply else {
+            error!("Offer creation future got no response");
+            return;
+        };
 
-    info!("Main loop stopped");
+        let Ok(offer) =
+            reply.get::<gst_webrtc::WebRTCSessionDescription>("offer")
+        else {
+            error!("Failed to get SDP offer from reply");
+            return;
+        };
 
-    pipeline.set_state(gst::State::Null)?;
+        webrtcbin_clone.emit_by_name::<()>(
+            "set-local-description",
+            &[&offer, &None::<gst::Promise>],
+        );
+    });
+
+    webrtcbin.emit_by_name::<()>(
+        "create-offer",
+        &[&None::<gst::Structure>, &promise],
+    );
+}
+
+//scale down the output while the host CPU is under pressure
+//the output is reduced under CPU pressure or on battery
+fn watch_quality(
+    settings: &PipelineSettings, scale_caps: gst::Element,
+    video_prop: &VideoProp,
+) -> glib::SourceId {
+    let config = &settings.cpu_pressure;
+    let battery_saver = settings.battery_saver.clone();
+    let mut pressure = CpuPressure::new(config);
+    let mut host_cpu = HostCpuSampler::default();
+    let mut current = QualityLevel::Full;
+
+    //half of the resolution, kept even for the chroma subsampling
+    let (width, height) = video_prop.resolution;
+    let reduced_caps = gst::Caps::builder("video/x-raw")
+        .field("width", ((width / 2) & !1) as i32)
+        .field("height", ((height / 2) & !1) as i32)
+        .build();
+
+    let sample_cpu = config.enabled;
+    glib::timeout_add_seconds(config.sample_secs, move || {
+        if sample_cpu {
+            if let Some(cpu) = host_cpu.sample() {
+                pressure.update(cpu);
+            }
+        }
+
+        let on_battery = battery_saver
+            .as_ref()
+            .is_some_and(|power| power.borrow().on_battery());
+        let level =
+            if on_battery { QualityLevel::Reduced } else { pressure.level() };
+        if level == current {
+            return glib::ControlFlow::Continue;
+        }
+        current = level;
+
+        let caps = match level {
+            QualityLevel::Full => gst::Caps::new_any(),
+            QualityLevel::Reduced => reduced_caps.clone(),
+        };
+
+        info!("Setting output caps to {:?}", caps);
+        scale_caps.set_property("caps", &caps);
+
+        glib::ControlFlow::Continue
+    })
+}
+
+//switches the post-processing to the presets of the live config
+fn watch_filters(
+    filters: DeviceFilters, mut post_processing: PostProcessing,
+) -> glib::SourceId {
+    glib::timeout_add_seconds(FILTERS_CHECK_SECS, move || {
+        post_processing.apply(filters.presets());
+        glib::ControlFlow::Continue
+    })
+}
+
+//advertises the size and the rate of the output on the loopback device
+//whenever they change, e.g. scaled down under CPU pressure
+fn follow_output_caps<F>(
+    output_tee: &gst::Element, loopback_timing: LoopbackTiming<F>,
+) -> Result<()>
+where
+    F: FnMut(&StreamTiming) -> Result<()> + Send + 'static,
+{
+    let sink_pad = output_tee
+        .static_pad("sink")
+        .ok_or(anyhow!("Failed to get output tee sink pad"))?;
+
+    let loopback_timing = Mutex::new(loopback_timing);
+    sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let Some(gst::PadProbeData::Event(event)) = &info.data else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let gst::EventView::Caps(caps) = event.view() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(structure) = caps.caps().structure(0) else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        if let Ok(mut loopback_timing) = loopback_timing.lock() {
+            let timing = loopback_timing.latest().with_caps(
+                structure.get::<i32>("width").ok(),
+                structure.get::<i32>("height").ok(),
+                structure
+                    .get::<Fraction>("framerate")
+                    .ok()
+                    .map(|fps| (fps.numer(), fps.denom())),
+            );
+            loopback_timing.update(timing);
+        }
+
+        gst::PadProbeReturn::Ok
+    });
 
     Ok(())
 }
+
+//drops the received RTP over the bandwidth caps
+fn police_ingress(
+    decodebin: &gst::Element, policer: BandwidthPolicer,
+) -> Result<()> {
+    let sink_pad = decodebin
+        .static_pad("sink")
+        .ok_or(anyhow!("Failed to get decodebin sink pad"))?;
+
+    sink_pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        let Some(buffer) = info.buffer() else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        if policer.admit(buffer.size(), Instant::now()) {
+            gst::PadProbeReturn::Ok
+        } else {
+            debug!("RTP packet over the bandwidth cap dropped");
+            gst::PadProbeReturn::Drop
+        }
+    });
+
+    Ok(())
+}
+
+//add the "Camera disabled" frames to the selector, returns its sink pad
+fn add_privacy_placeholder(
+    pipeline: &Pipeline, selector: &gst::Element, video_prop: &VideoProp,
+) -> Result<gst::Pad> {
+    let (width, height) = video_prop.resolution;
+
+    let source = ElementFactory::make("videotestsrc")
+        .property("is-live", true)
+        .property_from_str("pattern", "black")
+        .build()?;
+    let overlay = ElementFactory::make("textoverlay")
+        .property("text", "Camera disabled")
+        .property_from_str("valignment", "center")
+        .property_from_str("halignment", "center")
+        .build()?;
+    let convert = ElementFactory::make("videoconvert").build()?;
+    let caps = ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", width as i32)
+                .field("height", height as i32)
+                .field("framerate", Fraction::new(video_prop.fps as i32, 1))
+                .build(),
+        )
+        .build()?;
+
+    pipeline.add_many([&source, &overlay, &convert, &caps])?;
+    gst::Element::link_many([&source, &overlay, &convert, &caps, selector])?;
+
+    caps.static_pad("src")
+        .and_then(|pad| pad.peer())
+        .ok_or(anyhow!("Placeholder not linked to the privacy selector"))
+}
+
+//add a branch from the tee through the elements, the queue drops frames so
+//a slow output can't stall the other ones
+fn add_output_branch(
+    pipeline: &Pipeline, tee: &gst::Element, elements: Vec<gst::Element>,
+) -> Result<()> {
+    let queue = ElementFactory::make("queue")
+        .property_from_str("leaky", "downstream")
+        .property("max-size-buffers", 2u32)
+        .build()?;
+
+    let branch: Vec<&gst::Element> =
+        std::iter::once(&queue).chain(elements.iter()).collect();
+
+    pipeline.add_many(branch.iter().copied())?;
+    let linked = gst::Element::link_many(
+        std::iter::once(tee).chain(branch.iter().copied()),
+    )
+    .map_err(anyhow::Error::from)
+    //a branch added once the pipeline plays starts with it
+    .and_then(|_| {
+        branch.iter().try_for_each(|element| {
+            element.sync_state_with_parent().map_err(anyhow::Error::from)
+        })
+    });
+
+    //a failed branch leaves nothing behind in the pipeline
+    if let Err(e) = linked {
+        for element in branch.iter() {
+            let _ = element.set_state(gst::State::Null);
+        }
+        let _ = pipeline.remove_many(branch.iter().copied());
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+//add a branch keeping the output as a JPEG once per second, the frames are
+//dropped and scaled down before the encoder to bound its CPU usage
+fn add_thumbnail_branch(
+    pipeline: &Pipeline, tee: &gst::Element, slot: Arc<ThumbnailSlot>,
+    width: u32,
+) -> Result<()> {
+    let videorate = ElementFactory::make("videorate")
+        .property("drop-only", true)
+        .build()?;
+    let videoscale = ElementFactory::make("videoscale").build()?;
+    let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let caps = ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("width", width.max(16) as i32)
+                .field("framerate", Fraction::new(THUMBNAIL_FPS, 1))
+                .build(),
+        )
+        .build()?;
+    let jpegenc = ElementFactory::make("jpegenc")
+        .property("quality", THUMBNAIL_QUALITY)
+        .build()?;
+
+    let appsink = ElementFactory::make("appsink")
+        .property("emit-signals", true)
+        .property("sync", false)
+        .property("max-buffers", 1u32)
+        .property("drop", true)
+        .build()?;
+    appsink.connect("new-sample", false, move |values| {
+        let jpeg = values[0]
+            .get::<gst_app::AppSink>()
+            .ok()
+            .and_then(|appsink| appsink.pull_sample().ok())
+            .and_then(|sample| sample.buffer_owned())
+            .and_then(|buffer| {
+                buffer.map_readable().ok().map(|map| map.as_slice().to_vec())
+            });
+        if let Some(jpeg) = jpeg {
+            slot.set(jpeg);
+        }
+
+        Some(FlowReturn::Ok.to_value())
+    });
+
+    add_output_branch(
+        pipeline,
+        tee,
+        vec![videorate, videoscale, videoconvert, caps, jpegenc, appsink],
+    )
+}
+
+//add a branch decoding the latency test pattern from the output, scaled to
+//a few pixels a cell, returns the valve passing the frames to the decoder
+fn add_latency_branch(
+    pipeline: &Pipeline, tee: &gst::Element, latency: PipelineLatency,
+) -> Result<gst::Element> {
+    let valve = ElementFactory::make("valve").property("drop", true).build()?;
+    //the pattern fills the frame, the borders would shift the cells
+    let videoscale = ElementFactory::make("videoscale")
+        .property("add-borders", false)
+        .build()?;
+    let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let caps = ElementFactory::make("capsfilter")
+        .property(
+            "caps",
+            gst::Caps::builder("video/x-raw")
+                .field("format", "GRAY8")
+                .field("width", LATENCY_WIDTH as i32)
+                .field("height", LATENCY_HEIGHT as i32)
+                .build(),
+        )
+        .build()?;
+
+    let appsink = ElementFactory::make("appsink")
+        .property("emit-signals", true)
+        .property("sync", false)
+        .property("max-buffers", 1u32)
+        .property("drop", true)
+        .build()?;
+    appsink.connect("new-sample", false, move |values| {
+        let timestamp = values[0]
+            .get::<gst_app::AppSink>()
+            .ok()
+            .and_then(|appsink| appsink.pull_sample().ok())
+            .and_then(|sample| sample.buffer_owned())
+            .and_then(|buffer| {
+                let map = buffer.map_readable().ok()?;
+                latency_pattern::decode(
+                    map.as_slice(),
+                    LATENCY_WIDTH,
+                    LATENCY_HEIGHT,
+                )
+            });
+        if let Some(timestamp) = timestamp {
+            if let Ok(mut meter) = latency.lock() {
+                meter.record(timestamp, latency_pattern::now_ms());
+            }
+        }
+
+        Some(FlowReturn::Ok.to_value())
+    });
+
+    add_output_branch(
+        pipeline,
+        tee,
+        vec![valve.clone(), videoscale, videoconvert, caps, appsink],
+    )?;
+
+    Ok(valve)
+}